@@ -0,0 +1,3 @@
+//! the shared and static C libraries of curiefense, that export the functions of `curiefense::ffi`, declared in
+//! `curiefense/include/curiefense.h`
+pub use curiefense::ffi::*;
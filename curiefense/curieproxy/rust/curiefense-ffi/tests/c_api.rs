@@ -0,0 +1,77 @@
+//! compiles a C program against `curiefense/include/curiefense.h`, and links it with the shared library that cargo
+//! built along with the tests, so that the header stays in sync with the `ffi` module
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const PROGRAM: &str = r#"
+#include <stdio.h>
+#include <string.h>
+#include "curiefense.h"
+
+#define CHECK(cond) do { if (!(cond)) { fprintf(stderr, "failed: %s\n", #cond); return 1; } } while (0)
+
+int main(void) {
+    const char *request_map = "{\"headers\": {\"host\": \"www.example.com\"}, \"cookies\": {}, \"args\": {},"
+        " \"attrs\": {\"path\": \"/\", \"method\": \"GET\", \"ip\": \"127.0.0.1\", \"query\": \"\","
+        " \"authority\": null, \"uri\": \"/\", \"tags\": {}}}";
+    char *session_id = NULL;
+    CHECK(curiefense_session_init(request_map, &session_id) == CURIEFENSE_OK);
+    CHECK(session_id != NULL);
+
+    curiefense_decision decision;
+    memset(&decision, 0, sizeof decision);
+    CHECK(curiefense_session_decision(session_id, &decision) == CURIEFENSE_OK);
+    CHECK(strcmp(decision.action, "pass") == 0);
+    CHECK(!decision.blocking);
+    CHECK(decision.headers_len == 0);
+    curiefense_decision_free(&decision);
+    CHECK(decision.action == NULL);
+
+    CHECK(curiefense_session_clean(session_id) == CURIEFENSE_OK);
+    CHECK(curiefense_session_decision(session_id, &decision) == CURIEFENSE_UNKNOWN_SESSION);
+    curiefense_free(session_id);
+
+    CHECK(curiefense_session_clean("not a session id") == CURIEFENSE_INVALID_SESSION_ID);
+    char *error = curiefense_last_error();
+    CHECK(error != NULL);
+    curiefense_free(error);
+    return 0;
+}
+"#;
+
+/// the directory of the test binary, where cargo also puts the libraries of the crate
+fn deps_dir() -> PathBuf {
+    std::env::current_exe().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn header_links() {
+    let deps = deps_dir();
+    assert!(
+        deps.join("libcuriefense_ffi.so").exists(),
+        "no shared library in {}",
+        deps.display()
+    );
+    let out = std::env::temp_dir().join(format!("curiefense-c-api-{}", std::process::id()));
+    let source = out.with_extension("c");
+    std::fs::write(&source, PROGRAM).unwrap();
+    let include = Path::new(env!("CARGO_MANIFEST_DIR")).join("../curiefense/include");
+
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args(["-std=c99", "-Wall", "-Werror", "-o"])
+        .arg(&out)
+        .arg(&source)
+        .arg("-I")
+        .arg(&include)
+        .arg("-L")
+        .arg(&deps)
+        .arg(format!("-Wl,-rpath,{}", deps.display()))
+        .arg("-lcuriefense_ffi")
+        .status()
+        .unwrap();
+    assert!(status.success(), "the program did not compile");
+    let status = Command::new(&out).status().unwrap();
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&out);
+    assert!(status.success(), "the program failed");
+}
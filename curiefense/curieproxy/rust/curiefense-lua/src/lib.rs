@@ -10,11 +10,13 @@ use curiefense::session::JRequestMap;
 use curiefense::utils::RequestMeta;
 use mlua::prelude::*;
 use std::collections::HashMap;
+use std::time::Duration;
 
 use curiefense::inspect_generic_request_map;
 use curiefense::interface::{Decision, Grasshopper};
-use curiefense::logs::Logs;
+use curiefense::logs::{LogLevel, Logs};
 use curiefense::session;
+use curiefense::session::SessionError;
 use curiefense::utils::{map_request, InspectionResult};
 use curiefense::content_filter_check_generic_request_map;
 
@@ -187,9 +189,8 @@ pub fn inspect_request_map(_lua: &Lua, args: (String, Option<LuaTable>)) -> LuaR
             return Ok(Decision::Pass.to_json_raw(jvalue, logs));
         }
     };
-    let (rinfo, itags) = jmap.into_request_info();
-
     let mut logs = Logs::default();
+    let (rinfo, itags) = jmap.into_request_info(&mut logs);
     let (res, tags) = inspect_generic_request_map("/config/current/config", grasshopper, &rinfo, itags, &mut logs);
     let updated_request_map = match update_tags(jvalue, tags) {
         Ok(v) => v,
@@ -230,16 +231,16 @@ where
 /// runs the passed function, assuming the argument is a string
 fn with_str<F, R>(lua: &Lua, session_id: LuaValue, f: F) -> anyhow::Result<R>
 where
-    F: FnOnce(&str) -> anyhow::Result<R>,
+    F: FnOnce(&str) -> Result<R, SessionError>,
 {
     let decoded: String = FromLua::from_lua(session_id, lua).map_err(|rr| anyhow!("{}", rr))?;
-    f(&decoded)
+    Ok(f(&decoded)?)
 }
 
 /// runs the underlying string using function, catching mlua errors
 fn wrap_session<F, R>(lua: &Lua, session_id: LuaValue, f: F) -> LuaResult<(Option<R>, Option<String>)>
 where
-    F: FnOnce(&str) -> anyhow::Result<R>,
+    F: FnOnce(&str) -> Result<R, SessionError>,
 {
     lua_result(with_str(lua, session_id, f))
 }
@@ -251,11 +252,11 @@ fn wrap_session_json<F, R: serde::Serialize>(
     f: F,
 ) -> LuaResult<(Option<String>, Option<String>)>
 where
-    F: FnOnce(&mut Logs, &str) -> anyhow::Result<R>,
+    F: FnOnce(&mut Logs, &str) -> Result<R, SessionError>,
 {
     lua_log_result(|logs| {
         with_str(lua, session_id, |s| {
-            f(logs, s).and_then(|r| serde_json::to_string(&r).map_err(|rr| anyhow!("{}", rr)))
+            f(logs, s).and_then(|r| serde_json::to_string(&r).map_err(SessionError::from))
         })
     })
 }
@@ -263,10 +264,11 @@ where
 /// runs the underlying string using, Decision returning, function, catching mlua errors
 fn wrap_session_decision<F>(lua: &Lua, session_id: LuaValue, f: F) -> LuaResult<(Option<String>, Option<String>)>
 where
-    F: FnOnce(&str) -> anyhow::Result<Decision>,
+    F: FnOnce(&str) -> Result<Decision, SessionError>,
 {
     lua_result(with_str(lua, session_id, |s| {
-        f(s).and_then(|r| session::session_serialize_request_map(s).map(|v| r.to_json_raw(v, Logs::default())))
+        let decision = f(s).and_then(|r| session::session_interpolate_decision(s, r))?;
+        session::session_serialize_request_map(s).map(|v| decision.to_json_raw(v, Logs::default()))
     }))
 }
 
@@ -278,24 +280,204 @@ fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
     exports.set("inspect_request", lua.create_function(lua_inspect_request)?)?;
     // content filter inspection
     exports.set("inspect_content_filter", lua.create_function(lua_inspect_content_filter)?)?;
+    exports.set(
+        "content_filter_stats",
+        lua.create_function(|_: &Lua, _: ()| {
+            lua_result(
+                serde_json::to_string(&curiefense::contentfilter::content_filter_stats()).map_err(anyhow::Error::from),
+            )
+        })?,
+    )?;
+    exports.set(
+        "simulate",
+        lua.create_function(|_: &Lua, (config_blob, request_map): (String, String)| {
+            lua_result(
+                curiefense::engine::simulate(&config_blob, &request_map)
+                    .and_then(|result| serde_json::to_string(&result).map_err(anyhow::Error::from)),
+            )
+        })?,
+    )?;
+    exports.set(
+        "reset_content_filter_stats",
+        lua.create_function(|_: &Lua, _: ()| {
+            curiefense::contentfilter::reset_content_filter_stats();
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "set_learning",
+        lua.create_function(|_: &Lua, enabled: bool| {
+            curiefense::learning::set_learning(enabled);
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "reset_learning",
+        lua.create_function(|_: &Lua, _: ()| {
+            curiefense::learning::reset_learning();
+            Ok(())
+        })?,
+    )?;
+    exports.set(
+        "learning_export",
+        lua.create_function(|_: &Lua, _: ()| Ok(curiefense::learning::learning_export().to_string()))?,
+    )?;
+    exports.set(
+        "reset_reputation",
+        lua.create_function(|_: &Lua, _: ()| {
+            curiefense::reputation::reset_reputation();
+            Ok(())
+        })?,
+    )?;
 
     // session functions
     exports.set(
         "init_config",
         lua.create_function(|_: &Lua, _: ()| Ok(session::init_config()))?,
     )?;
+    exports.set(
+        "set_flow_storage",
+        lua.create_function(|_: &Lua, backend: String| {
+            lua_result(
+                match backend.as_str() {
+                    "redis" => curiefense::flow::set_flow_storage(Box::new(curiefense::flow::RedisFlowStorage)),
+                    "memory" => {
+                        curiefense::flow::set_flow_storage(Box::new(curiefense::flow::InMemoryFlowStorage::default()))
+                    }
+                    _ => Err(anyhow!("Unknown flow storage backend {}", backend)),
+                }
+                .map(|()| true),
+            )
+        })?,
+    )?;
+    exports.set(
+        "reload_config",
+        lua.create_function(|_: &Lua, basepath: String| {
+            lua_result(
+                curiefense::config::reload_config(&basepath)
+                    .map_err(|rr| anyhow!("{}", rr))
+                    .and_then(|report| Ok(serde_json::to_string(&report)?)),
+            )
+        })?,
+    )?;
+    exports.set(
+        "reload_tenant_config",
+        lua.create_function(|_: &Lua, (tenant, basepath): (String, String)| {
+            lua_result(
+                curiefense::config::reload_tenant_config(&tenant, &basepath)
+                    .map_err(|rr| anyhow!("{}", rr))
+                    .and_then(|report| Ok(serde_json::to_string(&report)?)),
+            )
+        })?,
+    )?;
+    exports.set(
+        "remove_tenant_config",
+        lua.create_function(|_: &Lua, tenant: String| {
+            lua_result(curiefense::config::remove_tenant_config(&tenant).map_err(|rr| anyhow!("{}", rr)))
+        })?,
+    )?;
+    exports.set(
+        "load_anonymous_networks",
+        lua.create_function(|_: &Lua, path: String| {
+            lua_result(curiefense::anonymous::load_anonymous_networks(&path).map(|()| true))
+        })?,
+    )?;
+    exports.set(
+        "validate_config",
+        lua.create_function(|_: &Lua, basepath: String| {
+            lua_result(
+                serde_json::to_string(&curiefense::config::validate_config(&basepath)).map_err(|rr| anyhow!("{}", rr)),
+            )
+        })?,
+    )?;
+    exports.set(
+        "init_config_from_json",
+        lua.create_function(|_: &Lua, config_blob: String| Ok(session::init_config_from_json(&config_blob)))?,
+    )?;
     exports.set(
         "session_init",
         lua.create_function(|lua: &Lua, encoded_request_map: LuaValue| {
             wrap_session(lua, encoded_request_map, session::session_init)
         })?,
     )?;
+    exports.set(
+        "session_init_with_ttl",
+        lua.create_function(|_: &Lua, (encoded_request_map, ttl): (String, u64)| {
+            lua_result(
+                session::session_init_with_ttl(&encoded_request_map, Duration::from_secs(ttl))
+                    .map_err(anyhow::Error::from),
+            )
+        })?,
+    )?;
     exports.set(
         "session_clean",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session(lua, session_id, |s| session::clean_session(s).map(|()| true))
         })?,
     )?;
+    exports.set(
+        "session_is_bypassed",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session(lua, session_id, session::session_is_bypassed)
+        })?,
+    )?;
+    exports.set(
+        "session_gc",
+        lua.create_function(|_: &Lua, _: ()| lua_result(session::session_gc().map_err(anyhow::Error::from)))?,
+    )?;
+    exports.set(
+        "clean_all_sessions",
+        lua.create_function(|_: &Lua, _: ()| lua_result(session::clean_all_sessions().map_err(anyhow::Error::from)))?,
+    )?;
+    exports.set(
+        "clean_tenant_sessions",
+        lua.create_function(|_: &Lua, tenant: String| {
+            lua_result(session::clean_tenant_sessions(&tenant).map_err(anyhow::Error::from))
+        })?,
+    )?;
+    exports.set(
+        "set_max_sessions",
+        lua.create_function(|_: &Lua, (max_sessions, eviction): (Option<usize>, Option<String>)| {
+            let eviction = match eviction.as_deref() {
+                None | Some("reject") => Ok(session::EvictionPolicy::Reject),
+                Some("evict_oldest") => Ok(session::EvictionPolicy::EvictOldest),
+                Some(other) => Err(anyhow::anyhow!("unknown eviction policy {}", other)),
+            };
+            lua_result(eviction.and_then(|eviction| {
+                session::set_max_sessions(max_sessions, eviction)
+                    .map(|()| true)
+                    .map_err(anyhow::Error::from)
+            }))
+        })?,
+    )?;
+    exports.set(
+        "session_capacity_stats",
+        lua.create_function(|_: &Lua, _: ()| {
+            lua_result(
+                session::session_capacity_stats()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|stats| Ok(serde_json::to_string(&stats)?)),
+            )
+        })?,
+    )?;
+    exports.set(
+        "active_sessions",
+        lua.create_function(|_: &Lua, _: ()| lua_result(session::active_sessions().map_err(anyhow::Error::from)))?,
+    )?;
+    exports.set(
+        "active_session_count",
+        lua.create_function(|_: &Lua, _: ()| lua_result(session::active_session_count().map_err(anyhow::Error::from)))?,
+    )?;
+    exports.set(
+        "oldest_session_age",
+        lua.create_function(|_: &Lua, _: ()| {
+            lua_result(
+                session::oldest_session_age()
+                    .map(|age| age.map(|d| d.as_secs_f64()))
+                    .map_err(anyhow::Error::from),
+            )
+        })?,
+    )?;
     exports.set(
         "session_serialize_request_map",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
@@ -308,36 +490,259 @@ fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
             wrap_session_json(lua, session_id, |_, uuid| session::session_match_securitypolicy(uuid))
         })?,
     )?;
+    exports.set(
+        "session_match_securitypolicy_verbose",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| {
+                session::session_match_securitypolicy_verbose(uuid)
+            })
+        })?,
+    )?;
     exports.set(
         "session_tag_request",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session_json(lua, session_id, |_, uuid| session::session_tag_request(uuid))
         })?,
     )?;
+    exports.set(
+        "session_tag_request_with_humanity",
+        lua.create_function(|lua: &Lua, (session_id, is_human): (LuaValue, bool)| {
+            wrap_session_json(lua, session_id, |_, uuid| {
+                session::session_tag_request_with_humanity(uuid, is_human)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_add_tags",
+        lua.create_function(|lua: &Lua, (session_id, tags): (LuaValue, Vec<String>)| {
+            wrap_session_json(lua, session_id, |_, uuid| {
+                let tags: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+                session::session_add_tags(uuid, &tags)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_report_bad",
+        lua.create_function(|lua: &Lua, (session_id, weight): (LuaValue, f64)| {
+            wrap_session(lua, session_id, |uuid| session::session_report_bad(uuid, weight))
+        })?,
+    )?;
     exports.set(
         "session_limit_check",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session_decision(lua, session_id, session::session_limit_check)
         })?,
     )?;
+    exports.set(
+        "session_limit_check_with_challenge",
+        lua.create_function(
+            |lua: &Lua, (session_id, lua_grasshopper): (LuaValue, Option<LuaTable>)| {
+                let grasshopper = lua_grasshopper.map(Luagrasshopper);
+                wrap_session_decision(lua, session_id, |uuid| {
+                    session::session_limit_check_with_challenge(uuid, grasshopper)
+                })
+            },
+        )?,
+    )?;
+    exports.set(
+        "session_limit_release",
+        lua.create_function(|lua: &Lua, (session_id, limit_id): (LuaValue, String)| {
+            wrap_session(lua, session_id, |uuid| session::session_limit_release(uuid, &limit_id))
+        })?,
+    )?;
+    exports.set(
+        "session_limit_status",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_limit_status(uuid))
+        })?,
+    )?;
     exports.set(
         "session_acl_check",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session_json(lua, session_id, |_, uuid| session::session_acl_check(uuid))
         })?,
     )?;
+    exports.set(
+        "session_acl_explain",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_acl_explain(uuid))
+        })?,
+    )?;
     exports.set(
         "session_content_filter_check",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session_decision(lua, session_id, session::session_content_filter_check)
         })?,
     )?;
+    exports.set(
+        "session_content_filter_check_mode",
+        lua.create_function(|lua: &Lua, (session_id, report_only): (LuaValue, bool)| {
+            wrap_session_decision(lua, session_id, |uuid| {
+                session::session_content_filter_check_mode(uuid, report_only)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_body_limit_check",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_body_limit_check)
+        })?,
+    )?;
+    exports.set(
+        "session_smuggling_check",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_smuggling_check)
+        })?,
+    )?;
+    exports.set(
+        "session_smuggling_check_mode",
+        lua.create_function(|lua: &Lua, (session_id, report_only): (LuaValue, bool)| {
+            wrap_session_decision(lua, session_id, |uuid| {
+                session::session_smuggling_check_mode(uuid, report_only)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_content_filter_matches",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_content_filter_matches(uuid))
+        })?,
+    )?;
+    exports.set(
+        "session_content_filter_score",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_content_filter_score(uuid))
+        })?,
+    )?;
+    exports.set(
+        "session_content_filter_feed",
+        lua.create_function(|lua: &Lua, (session_id, chunk): (LuaValue, LuaString)| {
+            wrap_session_decision(lua, session_id, |uuid| {
+                session::session_content_filter_feed(uuid, chunk.as_bytes()).map(|d| d.unwrap_or(Decision::Pass))
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_content_filter_finish",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_content_filter_finish)
+        })?,
+    )?;
+    exports.set(
+        "session_response_init",
+        lua.create_function(|lua: &Lua, (session_id, response_map): (LuaValue, String)| {
+            wrap_session(lua, session_id, |uuid| {
+                session::session_response_init(uuid, &response_map).map(|()| true)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_response_check",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_response_check)
+        })?,
+    )?;
+    exports.set(
+        "session_count_response",
+        lua.create_function(|lua: &Lua, (session_id, status): (LuaValue, u16)| {
+            wrap_session(lua, session_id, |uuid| {
+                session::session_count_response(uuid, status).map(|()| true)
+            })
+        })?,
+    )?;
     exports.set(
         "session_flow_check",
         lua.create_function(|lua: &Lua, session_id: LuaValue| {
             wrap_session_decision(lua, session_id, session::session_flow_check)
         })?,
     )?;
+    exports.set(
+        "session_flow_check_with_challenge",
+        lua.create_function(
+            |lua: &Lua, (session_id, lua_grasshopper): (LuaValue, Option<LuaTable>)| {
+                let grasshopper = lua_grasshopper.map(Luagrasshopper);
+                wrap_session_decision(lua, session_id, |uuid| {
+                    session::session_flow_check_with_challenge(uuid, grasshopper)
+                })
+            },
+        )?,
+    )?;
+    exports.set(
+        "session_evaluate",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_evaluate)
+        })?,
+    )?;
+    exports.set(
+        "session_snapshot",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session(lua, session_id, session::session_snapshot)
+        })?,
+    )?;
+    exports.set(
+        "session_restore",
+        lua.create_function(|lua: &Lua, blob: LuaValue| wrap_session(lua, blob, session::session_restore))?,
+    )?;
+    exports.set(
+        "session_clone",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| wrap_session(lua, session_id, session::session_clone))?,
+    )?;
+    exports.set(
+        "session_timings",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_timings(uuid))
+        })?,
+    )?;
+    exports.set(
+        "session_peek",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_json(lua, session_id, |_, uuid| session::session_peek(uuid))
+        })?,
+    )?;
+    exports.set(
+        "session_access_log",
+        lua.create_function(|lua: &Lua, (session_id, flat): (LuaValue, Option<bool>)| {
+            wrap_session_json(lua, session_id, |_, uuid| {
+                let record = session::session_access_log(uuid)?;
+                if flat.unwrap_or(false) {
+                    Ok(serde_json::to_value(record.to_key_values())?)
+                } else {
+                    Ok(serde_json::to_value(record)?)
+                }
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_current_decision",
+        lua.create_function(|lua: &Lua, session_id: LuaValue| {
+            wrap_session_decision(lua, session_id, session::session_current_decision)
+        })?,
+    )?;
+    exports.set(
+        "session_set_decision",
+        lua.create_function(|lua: &Lua, (session_id, action): (LuaValue, Option<String>)| {
+            wrap_session_decision(lua, session_id, |uuid| {
+                let decision = match action {
+                    None => Decision::Pass,
+                    Some(a) => Decision::Action(serde_json::from_str(&a)?),
+                };
+                session::session_set_decision(uuid, decision)
+            })
+        })?,
+    )?;
+    exports.set(
+        "session_logs",
+        lua.create_function(|lua: &Lua, (session_id, min_level): (LuaValue, Option<String>)| {
+            wrap_session_json(lua, session_id, |_, uuid| {
+                let min_level = match min_level {
+                    None => LogLevel::Debug,
+                    Some(l) => LogLevel::parse(&l)
+                        .ok_or_else(|| SessionError::Other(anyhow!("unknown log level {}", l)))?,
+                };
+                session::session_logs(uuid, min_level)
+            })
+        })?,
+    )?;
 
     // iptools exports
     exports.set("new_ip_set", lua.create_function(iptools::new_ip_set)?)?;
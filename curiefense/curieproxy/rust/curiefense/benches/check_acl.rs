@@ -2,7 +2,7 @@ use criterion::*;
 use rand::{distributions::Alphanumeric, Rng};
 
 use curiefense::acl::check_acl;
-use curiefense::config::raw::AclProfile;
+use curiefense::config::raw::{AclOrder, AclProfile};
 use curiefense::interface::Tags;
 
 fn tags_vec(sz: usize) -> Vec<String> {
@@ -31,6 +31,8 @@ fn gen_profile(sz: usize) -> AclProfile {
         deny_bot: tags_vec(sz).into_iter().collect(),
         passthrough: tags_vec(sz).into_iter().collect(),
         force_deny: tags_vec(sz).into_iter().collect(),
+        order: AclOrder::default(),
+        stage_order: Vec::new(),
     }
 }
 
@@ -0,0 +1,80 @@
+use criterion::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use curiefense::config::contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
+use curiefense::config::raw::{RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterRule};
+use curiefense::contentfilter::content_filter_check;
+use curiefense::logs::Logs;
+use curiefense::utils::{map_request, RequestInfo, RequestMeta};
+
+fn rules() -> ContentFilterRules {
+    let raw = RawContentFilterRule {
+        id: "100000".to_string(),
+        name: "bench".to_string(),
+        msg: "bench".to_string(),
+        operand: "select.*from".to_string(),
+        severity: 5,
+        certainity: 5,
+        category: "sqli".to_string(),
+        subcategory: "bench".to_string(),
+        max_regex_compiled_bytes: None,
+        score: None,
+        json_path: None,
+        sections: None,
+    };
+    resolve_rules(&mut Logs::default(), vec![raw], &HashMap::new()).unwrap()
+}
+
+/// a profile with `sz` name regex entries for the arguments, none restricting the argument values, resolved like the
+/// profiles of the configuration
+fn gen_profile(sz: usize, with_set: bool) -> ContentFilterProfile {
+    let mut raws: Vec<RawContentFilterProfile> =
+        serde_json::from_str(&std::fs::read_to_string("../../config/json/contentfilter-profiles.json").unwrap())
+            .unwrap();
+    raws[0].ignore_alphanum = false;
+    raws[0].args.regex = (0..sz)
+        .map(|i| RawContentFilterEntryMatch {
+            key: format!("^arg{}_[a-z]+$", i),
+            reg: None,
+            restrict: false,
+            exclusions: None,
+        })
+        .collect();
+    let mut profile = ContentFilterProfile::resolve(&mut Logs::default(), raws, &HashMap::new())
+        .remove("__default__")
+        .unwrap();
+    assert!(profile.sections.args.regex_set.is_some());
+    if !with_set {
+        profile.sections.args.regex_set = None;
+    }
+    profile
+}
+
+fn gen_request(nargs: usize) -> RequestInfo {
+    let query: Vec<String> = (0..nargs).map(|i| format!("p{}=v-{}", i, i)).collect();
+    let meta = RequestMeta {
+        authority: Some("localhost".to_string()),
+        method: "GET".to_string(),
+        path: format!("/?{}", query.join("&")),
+        extra: HashMap::new(),
+    };
+    let mut logs = Logs::default();
+    map_request(&mut logs, "127.0.0.1".to_string(), HashMap::new(), meta, None).unwrap()
+}
+
+fn name_regexes(c: &mut Criterion) {
+    let hsdb = RwLock::new(Some(rules()));
+    let rinfo = gen_request(20);
+    let mut group = c.benchmark_group("content filter name regexes");
+    for (name, with_set) in [("linear", false), ("set", true)].iter() {
+        let profile = gen_profile(500, *with_set);
+        group.bench_with_input(BenchmarkId::new(*name, 500), &profile, |b, profile| {
+            b.iter(|| content_filter_check(black_box(&rinfo), profile, &hsdb.read().unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, name_regexes);
+criterion_main!(benches);
@@ -1,6 +1,6 @@
 use curiefense::config::hostmap::*;
-use curiefense::config::raw::AclProfile;
-use curiefense::config::utils::Matching;
+use curiefense::config::raw::{AclOrder, AclProfile};
+use curiefense::config::utils::{matching_set, Matching};
 use curiefense::config::contentfilter::ContentFilterProfile;
 use curiefense::config::Config;
 use curiefense::logs::Logs;
@@ -21,6 +21,7 @@ fn gen_bogus_config(sz: usize) -> Config {
                 id: format!("abcd{}", i),
                 name: format!("Dummy hostmap {}", i),
                 entries: Vec::new(),
+                entries_set: None,
                 default: None,
             },
         })
@@ -35,6 +36,8 @@ fn gen_bogus_config(sz: usize) -> Config {
         deny_bot: HashSet::new(),
         passthrough: HashSet::new(),
         force_deny: HashSet::new(),
+        order: AclOrder::default(),
+        stage_order: Vec::new(),
     };
 
     let dummy_entries: Vec<Matching<SecurityPolicy>> = (0..sz)
@@ -47,6 +50,12 @@ fn gen_bogus_config(sz: usize) -> Config {
                 content_filter_active: false,
                 content_filter_profile: ContentFilterProfile::default(),
                 limits: Vec::new(),
+                methods: None,
+                inspect_preflight: false,
+                max_body_size: None,
+                timezone: None,
+                param_presence: Vec::new(),
+                rollout: Rollout::Disabled,
             },
         })
         .collect();
@@ -54,6 +63,7 @@ fn gen_bogus_config(sz: usize) -> Config {
     def.default = Some(HostMap {
         id: "__default__".into(),
         name: "__default__".into(),
+        entries_set: matching_set(&dummy_entries),
         entries: dummy_entries,
         default: Some(SecurityPolicy {
             name: "selected".into(),
@@ -62,16 +72,37 @@ fn gen_bogus_config(sz: usize) -> Config {
             content_filter_active: false,
             content_filter_profile: ContentFilterProfile::default(),
             limits: Vec::new(),
+            methods: None,
+            inspect_preflight: false,
+            max_body_size: None,
+            timezone: None,
+            param_presence: Vec::new(),
+            rollout: Rollout::Disabled,
         }),
     });
 
+    def.securitypolicies_set = matching_set(&def.securitypolicies);
     def
 }
 
+/// the same configuration, without the regex sets, so that entries are scanned linearly
+fn linear_config(cfg: &Config) -> Config {
+    let mut linear = cfg.clone();
+    linear.securitypolicies_set = None;
+    for e in linear.securitypolicies.iter_mut() {
+        e.inner.entries_set = None;
+    }
+    if let Some(d) = linear.default.as_mut() {
+        d.entries_set = None;
+    }
+    linear
+}
+
 fn gen_rinfo() -> RequestInfo {
     RequestInfo {
         cookies: RequestField::default(),
         headers: RequestField::default(),
+        header_list: Vec::new(),
         rinfo: RInfo {
             meta: RequestMeta {
                 authority: Some("my.host.name".into()),
@@ -84,6 +115,7 @@ fn gen_rinfo() -> RequestInfo {
                 ipstr: "1.2.3.4".into(),
                 ip: None,
                 location: None,
+                accuracy_radius: None,
                 in_eu: None,
                 city_name: None,
                 country_iso: None,
@@ -92,13 +124,27 @@ fn gen_rinfo() -> RequestInfo {
                 continent_code: None,
                 asn: None,
                 company: None,
+                subdivision: None,
+                threat_categories: Vec::new(),
             },
             qinfo: QueryInfo {
                 qpath: "/non/matching/path".into(),
                 query: String::new(),
                 uri: None,
                 args: RequestField::default(),
+                json_paths: HashMap::new(),
             },
+            is_upgrade: false,
+            upgrade_protocol: None,
+            graphql: None,
+            tls_fingerprint: None,
+            decompress_bomb: false,
+            decompress_failed: false,
+            traceparent: None,
+            http_version: None,
+            alpn: None,
+            body_size: None,
+            client_cert: None,
         },
     }
 }
@@ -118,5 +164,21 @@ fn forms_string_map(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, forms_string_map);
+fn linear_vs_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Security Policy search, 2000 entries");
+    let rinfo = gen_rinfo();
+    let cfg = gen_bogus_config(2000);
+    let linear = linear_config(&cfg);
+    for (name, config) in [("linear", &linear), ("regex set", &cfg)].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), config, |b, config| {
+            b.iter(|| {
+                let mut logs = Logs::default();
+                let (_, umap) = match_securitypolicy(black_box(&rinfo), black_box(config), &mut logs).unwrap();
+                assert_eq!(umap.name, "selected");
+            })
+        });
+    }
+}
+
+criterion_group!(benches, forms_string_map, linear_vs_set);
 criterion_main!(benches);
@@ -0,0 +1,57 @@
+use criterion::*;
+use std::time::{Duration, Instant};
+
+use curiefense::session::{clean_session, session_init};
+
+const THREADS: u64 = 32;
+
+fn request_map(i: u64) -> String {
+    serde_json::json!({
+        "headers": {"host": "www.example.com", "user-agent": "bench"},
+        "cookies": {},
+        "args": {"a": i.to_string()},
+        "attrs": {
+            "path": "/bench/",
+            "method": "GET",
+            "ip": "127.0.0.1",
+            "query": format!("a={}", i),
+            "authority": null,
+            "uri": format!("/bench/?a={}", i),
+            "tags": {}
+        }
+    })
+    .to_string()
+}
+
+/// 32 threads creating and cleaning sessions, each thread running its share of the iterations
+fn contention_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("session_contention");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("init_clean_32_threads", |b| {
+        b.iter_custom(|iters| {
+            let per_thread = iters.div_ceil(THREADS);
+            let start = Instant::now();
+            let handles: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    std::thread::spawn(move || {
+                        let rmap = request_map(t);
+                        for _ in 0..per_thread {
+                            let session_id = session_init(&rmap).unwrap();
+                            clean_session(&session_id).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+            // the threads can run a few more cycles than requested
+            let elapsed: Duration = start.elapsed();
+            elapsed.mul_f64(iters as f64 / (per_thread * THREADS).max(1) as f64)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, contention_bench);
+criterion_main!(benches);
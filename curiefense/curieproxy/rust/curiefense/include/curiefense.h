@@ -0,0 +1,79 @@
+/*
+ * C entry points of curiefense, built with the `ffi` feature, see the "C API" section of NOTES.md.
+ *
+ * The functions return a status code, CURIEFENSE_OK on success, and write their results to out-params. The
+ * strings they return are released with curiefense_free, and the decisions with curiefense_decision_free.
+ */
+#ifndef CURIEFENSE_H
+#define CURIEFENSE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum curiefense_status {
+    CURIEFENSE_OK = 0,
+    /* a pointer was NULL, or a string was not valid UTF-8 */
+    CURIEFENSE_INVALID_ARGUMENT = 1,
+    CURIEFENSE_INVALID_SESSION_ID = 2,
+    CURIEFENSE_UNKNOWN_SESSION = 3,
+    CURIEFENSE_LOCK_POISONED = 4,
+    CURIEFENSE_NO_SECURITY_POLICY = 5,
+    CURIEFENSE_DESERIALIZE_FAILED = 6,
+    CURIEFENSE_INVALID_REQUEST_MAP = 7,
+    CURIEFENSE_INVALID_TAG = 8,
+    CURIEFENSE_UNKNOWN_TENANT = 9,
+    CURIEFENSE_NO_RESPONSE = 10,
+    CURIEFENSE_CAPACITY = 11,
+    CURIEFENSE_OTHER = 12,
+    /* the call panicked */
+    CURIEFENSE_PANIC = 13
+} curiefense_status;
+
+typedef struct curiefense_header {
+    char *name;
+    char *value;
+} curiefense_header;
+
+/* the pointers are NULL when the call failed, and all but action are NULL when the request passed */
+typedef struct curiefense_decision {
+    /* "pass", or the type of the action, such as "block" or "monitor" */
+    char *action;
+    /* the request must be stopped, and answered with status, content and headers */
+    bool blocking;
+    uint32_t status;
+    char *content;
+    /* the check that produced the action, such as "acl" */
+    char *initiator;
+    curiefense_header *headers;
+    size_t headers_len;
+} curiefense_decision;
+
+/* creates a session from a JSON request map, its id is released with curiefense_free */
+curiefense_status curiefense_session_init(const char *request_map, char **session_id_out);
+
+/* runs all the checks of the session */
+curiefense_status curiefense_session_evaluate(const char *session_id, curiefense_decision *decision_out);
+
+/* the decision reached by the checks that were run */
+curiefense_status curiefense_session_decision(const char *session_id, curiefense_decision *decision_out);
+
+curiefense_status curiefense_session_clean(const char *session_id);
+
+/* the message of the last error of the calling thread, or NULL, released with curiefense_free */
+char *curiefense_last_error(void);
+
+void curiefense_free(char *s);
+
+/* releases the strings and headers of a decision, and resets it */
+void curiefense_decision_free(curiefense_decision *decision);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CURIEFENSE_H */
@@ -0,0 +1,274 @@
+/// access log records, whose schema does not depend on the format of the request maps
+///
+/// The records are built by `session::session_access_log`, and can be serialized to JSON, or to flat key-value pairs
+/// with `AccessLogRecord::to_key_values`. Fields can be added to a version of the schema, but renaming or removing a
+/// field bumps `ACCESS_LOG_VERSION`.
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::interface::{Decision, Tags};
+use crate::requestfields::RequestField;
+use crate::utils::{ClientCert, GeoIp, RequestInfo};
+
+/// the version of the schema of the records
+pub const ACCESS_LOG_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessLogRecord {
+    pub version: u32,
+    pub session_id: String,
+    pub tenant: Option<String>,
+    /// the UNIX time at which the record was built, in milliseconds
+    pub timestamp: u64,
+    pub request: AccessLogRequest,
+    /// the response returned by curiefense, or the response of the upstream server when it was attached with
+    /// `session::session_response_init`
+    pub response: Option<AccessLogResponse>,
+    pub decision: AccessLogDecision,
+    pub securitypolicy: Option<AccessLogPolicy>,
+    /// the tags, in alphabetical order
+    pub tags: Vec<String>,
+    /// the values of the tags that have one
+    pub tag_values: BTreeMap<String, String>,
+    pub geo: AccessLogGeo,
+    pub timings: AccessLogTimings,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogRequest {
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub query: String,
+    pub http_version: Option<String>,
+    pub client_ip: String,
+    pub headers: BTreeMap<String, String>,
+    pub cookies: BTreeMap<String, String>,
+    /// the query and body arguments
+    pub args: BTreeMap<String, String>,
+    /// the size of the body as it was received
+    pub body_size: Option<usize>,
+    /// the certificate presented by the client with mutual TLS
+    pub client_cert: Option<ClientCert>,
+}
+
+fn sorted(field: &RequestField) -> BTreeMap<String, String> {
+    field.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+impl AccessLogRequest {
+    pub fn new(rinfo: &RequestInfo) -> Self {
+        AccessLogRequest {
+            method: rinfo.rinfo.meta.method.clone(),
+            host: rinfo.rinfo.host.clone(),
+            path: rinfo.rinfo.qinfo.qpath.clone(),
+            query: rinfo.rinfo.qinfo.query.clone(),
+            http_version: rinfo.rinfo.http_version.clone(),
+            client_ip: rinfo.rinfo.geoip.ipstr.clone(),
+            headers: sorted(&rinfo.headers),
+            cookies: sorted(&rinfo.cookies),
+            args: sorted(&rinfo.rinfo.qinfo.args),
+            body_size: rinfo.rinfo.body_size,
+            client_cert: rinfo.rinfo.client_cert.clone(),
+        }
+    }
+}
+
+/// the values of the tags that have one
+pub fn tag_values(tags: &Tags) -> BTreeMap<String, String> {
+    tags.iter_values()
+        .filter_map(|(k, v)| v.map(|v| (k.clone(), v.clone())))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogResponse {
+    pub status: u32,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogDecision {
+    /// `pass`, or the type of the action, such as `block` or `monitor`
+    pub action: String,
+    pub blocking: bool,
+    /// the check that produced the action, such as `content_filter`, None when the request passed
+    pub initiator: Option<String>,
+    /// the ids of the rules, or the tags, that matched, see `DecisionReason::matched_rules`
+    pub matched_rules: Vec<String>,
+}
+
+impl AccessLogDecision {
+    /// the decision and the response of a session
+    pub fn new(decision: &Decision) -> (Self, Option<AccessLogResponse>) {
+        match decision.action() {
+            None => (
+                AccessLogDecision {
+                    action: "pass".to_string(),
+                    blocking: false,
+                    initiator: None,
+                    matched_rules: Vec::new(),
+                },
+                None,
+            ),
+            Some(action) => {
+                let atype = serde_json::to_value(action.atype)
+                    .ok()
+                    .and_then(|v| v.as_str().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                let blocking = action.atype.is_blocking();
+                let response = if blocking {
+                    Some(AccessLogResponse {
+                        status: action.status,
+                        headers: action
+                            .headers
+                            .iter()
+                            .flatten()
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect(),
+                    })
+                } else {
+                    None
+                };
+                (
+                    AccessLogDecision {
+                        action: atype,
+                        blocking,
+                        initiator: Some(action.decision_reason.initiator().to_string()),
+                        matched_rules: action.decision_reason.matched_rules(),
+                    },
+                    response,
+                )
+            }
+        }
+    }
+}
+
+/// the matched security policy, designated by the names of its host map and its entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogPolicy {
+    pub hostmap: String,
+    pub name: String,
+    pub acl_profile: String,
+    pub content_filter_profile: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessLogGeo {
+    pub country_iso: Option<String>,
+    pub country_name: Option<String>,
+    pub continent_code: Option<String>,
+    pub subdivision: Option<String>,
+    pub city: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub asn: Option<u32>,
+    pub company: Option<String>,
+}
+
+impl AccessLogGeo {
+    pub fn new(geoip: &GeoIp) -> Self {
+        AccessLogGeo {
+            country_iso: geoip.country_iso.clone(),
+            country_name: geoip.country_name.clone(),
+            continent_code: geoip.continent_code.clone(),
+            subdivision: geoip.subdivision.clone(),
+            city: geoip.city_name.clone(),
+            latitude: geoip.location.map(|(lat, _)| lat),
+            longitude: geoip.location.map(|(_, lon)| lon),
+            asn: geoip.asn,
+            company: geoip.company.clone(),
+        }
+    }
+}
+
+/// the time spent in each stage, and since the session was created, in microseconds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AccessLogTimings {
+    pub tagging: u64,
+    pub limit: u64,
+    pub acl: u64,
+    pub content_filter: u64,
+    pub flow: u64,
+    pub total: u64,
+}
+
+/// adds the scalar values of a json value, with dotted keys, arrays of scalars being joined with commas
+fn flatten(prefix: &str, value: &serde_json::Value, out: &mut BTreeMap<String, String>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", prefix, k)
+        }
+    };
+    match value {
+        serde_json::Value::Null => (),
+        serde_json::Value::String(s) => {
+            out.insert(prefix.to_string(), s.clone());
+        }
+        serde_json::Value::Object(o) => {
+            for (k, v) in o {
+                flatten(&key(k), v, out);
+            }
+        }
+        serde_json::Value::Array(a) if a.iter().all(|v| !v.is_object() && !v.is_array()) => {
+            let values: Vec<String> = a
+                .iter()
+                .map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect();
+            out.insert(prefix.to_string(), values.join(","));
+        }
+        serde_json::Value::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(&key(&i.to_string()), v, out);
+            }
+        }
+        other => {
+            out.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+impl AccessLogRecord {
+    /// the record as flat key-value pairs, such as `request.headers.user-agent`, the missing values being left out
+    pub fn to_key_values(&self) -> BTreeMap<String, String> {
+        let mut out = BTreeMap::new();
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten("", &value, &mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_keys() {
+        let value = serde_json::json!({
+            "version": 1,
+            "request": {"headers": {"user-agent": "curl"}, "http_version": null},
+            "tags": ["a", "b"],
+            "empty": [],
+            "nested": [{"x": true}]
+        });
+        let mut out = BTreeMap::new();
+        flatten("", &value, &mut out);
+        let expected: BTreeMap<String, String> = [
+            ("version", "1"),
+            ("request.headers.user-agent", "curl"),
+            ("tags", "a,b"),
+            ("empty", ""),
+            ("nested.0.x", "true"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(out, expected);
+    }
+}
@@ -1,13 +1,18 @@
-use crate::config::raw::AclProfile;
-use crate::interface::Tags;
+use crate::config::raw::{AclOrder, AclProfile, AclStage};
+use crate::interface::{tagify, Tags};
+use crate::utils::ip_in_net;
 
+use ipnet::IpNet;
 use serde::Serialize;
 use std::collections::HashSet;
+use std::net::IpAddr;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AclDecision {
     pub allowed: bool,
     pub tags: Vec<String>,
+    /// the stage that decided
+    pub stage: AclStage,
 }
 
 #[derive(Debug, Serialize)]
@@ -24,25 +29,303 @@ pub struct BotHuman {
     pub human: Option<AclDecision>,
 }
 
+/// tags of the request that match the entries of an ACL stage, sorted
+fn matching_tags(checks: &HashSet<String>, tags: &Tags) -> Vec<String> {
+    let mut matching: Vec<String> = checks.intersection(tags.as_hash_ref()).cloned().collect();
+    matching.sort();
+    matching
+}
+
+/// the tags matching each ACL stage, for debugging purposes
+#[derive(Debug, Serialize)]
+pub struct AclExplanation {
+    pub force_deny: Vec<String>,
+    pub passthrough: Vec<String>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_bot: Vec<String>,
+    pub deny_bot: Vec<String>,
+    /// the result of the ACL check
+    pub result: AclResult,
+}
+
+/// an IP range used in ACL profiles, written as `ip:10.0.0.0/8` or `ip:2001:db8::/32`
+///
+/// requests whose address belongs to the range get the tag, so that the ACL entry can be matched like all the others
+#[derive(Debug, Clone)]
+pub struct AclNetwork {
+    pub net: IpNet,
+    pub tag: String,
+}
+
+impl AclNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip_in_net(&self.net, ip)
+    }
+}
+
+/// replaces the IP range entries of an ACL profile with their tag, returning the ranges
+pub fn resolve_acl_networks(acl: &mut AclProfile) -> Vec<AclNetwork> {
+    let mut networks = Vec::new();
+    for entries in [
+        &mut acl.force_deny,
+        &mut acl.passthrough,
+        &mut acl.allow,
+        &mut acl.deny,
+        &mut acl.allow_bot,
+        &mut acl.deny_bot,
+    ] {
+        let ranges: Vec<(String, IpNet)> = entries
+            .iter()
+            .filter_map(|e| {
+                let net = e.strip_prefix("ip:")?;
+                if !net.contains('/') {
+                    return None;
+                }
+                net.parse().ok().map(|n| (e.clone(), n))
+            })
+            .collect();
+        for (entry, net) in ranges {
+            let tag = tagify(&entry);
+            entries.remove(&entry);
+            entries.insert(tag.clone());
+            networks.push(AclNetwork { net, tag });
+        }
+    }
+    networks
+}
+
+pub fn explain_acl(tags: &Tags, acl: &AclProfile) -> AclExplanation {
+    AclExplanation {
+        force_deny: matching_tags(&acl.force_deny, tags),
+        passthrough: matching_tags(&acl.passthrough, tags),
+        allow: matching_tags(&acl.allow, tags),
+        deny: matching_tags(&acl.deny, tags),
+        allow_bot: matching_tags(&acl.allow_bot, tags),
+        deny_bot: matching_tags(&acl.deny_bot, tags),
+        result: check_acl(tags, acl),
+    }
+}
+
+const ALLOW_OVERRIDES: [AclStage; 6] = [
+    AclStage::ForceDeny,
+    AclStage::Passthrough,
+    AclStage::Allow,
+    AclStage::Deny,
+    AclStage::AllowBot,
+    AclStage::DenyBot,
+];
+
+const DENY_OVERRIDES: [AclStage; 6] = [
+    AclStage::ForceDeny,
+    AclStage::Passthrough,
+    AclStage::Deny,
+    AclStage::Allow,
+    AclStage::DenyBot,
+    AclStage::AllowBot,
+];
+
+impl AclStage {
+    fn allows(self) -> bool {
+        matches!(self, AclStage::Passthrough | AclStage::Allow | AclStage::AllowBot)
+    }
+
+    fn entries(self, acl: &AclProfile) -> &HashSet<String> {
+        match self {
+            AclStage::ForceDeny => &acl.force_deny,
+            AclStage::Passthrough => &acl.passthrough,
+            AclStage::Allow => &acl.allow,
+            AclStage::Deny => &acl.deny,
+            AclStage::AllowBot => &acl.allow_bot,
+            AclStage::DenyBot => &acl.deny_bot,
+        }
+    }
+}
+
+/// the order in which the stages of the profile are evaluated
+pub fn acl_stage_order(acl: &AclProfile) -> Vec<AclStage> {
+    match acl.order {
+        AclOrder::AllowOverrides => ALLOW_OVERRIDES.to_vec(),
+        AclOrder::DenyOverrides => DENY_OVERRIDES.to_vec(),
+        AclOrder::FirstMatch => {
+            let mut order: Vec<AclStage> = Vec::new();
+            for stage in acl.stage_order.iter().chain(ALLOW_OVERRIDES.iter()) {
+                if !order.contains(stage) {
+                    order.push(*stage);
+                }
+            }
+            order
+        }
+    }
+}
+
+/// walks the stages in order: the first matching stage decides, for the whole request when it is `force_deny` or
+/// `passthrough`, otherwise for humans or bots, the other kind being decided by its first matching stage
+///
+/// A `force_deny` or `passthrough` stage that matches once only one kind is decided decides the other one.
 pub fn check_acl(tags: &Tags, acl: &AclProfile) -> AclResult {
-    let subcheck = |checks: &HashSet<String>, allowed: bool| {
-        let tags: Vec<String> = checks.intersection(tags.as_hash_ref()).cloned().collect();
-        if tags.is_empty() {
-            None
-        } else {
-            Some(AclDecision { allowed, tags })
+    let mut bot = None;
+    let mut human = None;
+    for stage in acl_stage_order(acl) {
+        let matching = matching_tags(stage.entries(acl), tags);
+        if matching.is_empty() {
+            continue;
         }
-    };
-    subcheck(&acl.force_deny, false)
-        .map(AclResult::Passthrough)
-        .or_else(|| subcheck(&acl.passthrough, true).map(AclResult::Passthrough))
-        .unwrap_or_else(|| {
-            let botresult = subcheck(&acl.allow_bot, true).or_else(|| subcheck(&acl.deny_bot, false));
-            let humanresult = subcheck(&acl.allow, true).or_else(|| subcheck(&acl.deny, false));
-
-            AclResult::Match(BotHuman {
-                bot: botresult,
-                human: humanresult,
-            })
-        })
+        let decision = AclDecision {
+            allowed: stage.allows(),
+            tags: matching,
+            stage,
+        };
+        match stage {
+            AclStage::ForceDeny | AclStage::Passthrough => {
+                if bot.is_none() && human.is_none() {
+                    return AclResult::Passthrough(decision);
+                }
+                human.get_or_insert_with(|| decision.clone());
+                bot.get_or_insert(decision);
+            }
+            AclStage::Allow | AclStage::Deny => {
+                if human.is_none() {
+                    human = Some(decision)
+                }
+            }
+            AclStage::AllowBot | AclStage::DenyBot => {
+                if bot.is_none() {
+                    bot = Some(decision)
+                }
+            }
+        }
+    }
+    AclResult::Match(BotHuman { bot, human })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explain() {
+        let mut acl = AclProfile::default();
+        acl.deny.insert("all".to_string());
+        acl.allow.insert("partner".to_string());
+        acl.deny_bot.insert("all".to_string());
+        acl.force_deny.insert("evil".to_string());
+        let tags = Tags::from_slice(&["all".to_string(), "partner".to_string()]);
+        let explanation = explain_acl(&tags, &acl);
+        assert!(explanation.force_deny.is_empty());
+        assert!(explanation.passthrough.is_empty());
+        assert_eq!(explanation.allow, vec!["partner".to_string()]);
+        assert_eq!(explanation.deny, vec!["all".to_string()]);
+        assert_eq!(explanation.deny_bot, vec!["all".to_string()]);
+        match explanation.result {
+            AclResult::Match(bh) => {
+                assert!(bh.human.unwrap().allowed);
+                assert!(!bh.bot.unwrap().allowed);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    /// the result of each order, for requests matching the given stages
+    ///
+    /// `P(allowed)` is a passthrough result, `H(allowed)` and `B(allowed)` the human and bot decisions, `-` no match
+    #[test]
+    fn order_truth_table() {
+        use AclStage::*;
+        let first_match = vec![Deny, Passthrough, AllowBot, ForceDeny, Allow, DenyBot];
+        #[rustfmt::skip]
+        let table: Vec<(Vec<AclStage>, &str, &str, &str)> = vec![
+            // matching stages                  allow_overrides    deny_overrides     first_match
+            (vec![],                            "-",               "-",               "-"),
+            (vec![ForceDeny, Passthrough],      "P(false)",        "P(false)",        "P(true)"),
+            (vec![Passthrough, Deny],           "P(true)",         "P(true)",         "H(false)B(true)"),
+            (vec![ForceDeny, Allow],            "P(false)",        "P(false)",        "P(false)"),
+            (vec![Allow, Deny],                 "H(true)",         "H(false)",        "H(false)"),
+            (vec![Allow],                       "H(true)",         "H(true)",         "H(true)"),
+            (vec![Deny],                        "H(false)",        "H(false)",        "H(false)"),
+            (vec![AllowBot, DenyBot],           "B(true)",         "B(false)",        "B(true)"),
+            (vec![Allow, DenyBot],              "H(true)B(false)", "H(true)B(false)", "H(true)B(false)"),
+            (vec![Deny, AllowBot, DenyBot],     "H(false)B(true)", "H(false)B(false)", "H(false)B(true)"),
+            (vec![AllowBot, ForceDeny, Allow],  "P(false)",        "P(false)",        "H(false)B(true)"),
+            (vec![Allow, Deny, ForceDeny],      "P(false)",        "P(false)",        "H(false)B(false)"),
+            (vec![Deny, Passthrough, AllowBot], "P(true)",         "P(true)",         "H(false)B(true)"),
+        ];
+        let show = |r: &AclResult| match r {
+            AclResult::Passthrough(d) => format!("P({})", d.allowed),
+            AclResult::Match(bh) => {
+                let mut out = String::new();
+                if let Some(h) = &bh.human {
+                    out += &format!("H({})", h.allowed);
+                }
+                if let Some(b) = &bh.bot {
+                    out += &format!("B({})", b.allowed);
+                }
+                if out.is_empty() {
+                    out.push('-');
+                }
+                out
+            }
+        };
+        for (stages, allow_overrides, deny_overrides, first) in table {
+            let mut acl = AclProfile::default();
+            acl.stage_order = first_match.clone();
+            for stage in &stages {
+                let tag = format!("{:?}", stage).to_lowercase();
+                match stage {
+                    ForceDeny => &mut acl.force_deny,
+                    Passthrough => &mut acl.passthrough,
+                    Allow => &mut acl.allow,
+                    Deny => &mut acl.deny,
+                    AllowBot => &mut acl.allow_bot,
+                    DenyBot => &mut acl.deny_bot,
+                }
+                .insert(tag);
+            }
+            let tags = Tags::from_slice(
+                &stages
+                    .iter()
+                    .map(|s| format!("{:?}", s).to_lowercase())
+                    .collect::<Vec<_>>(),
+            );
+            for (order, expected) in [
+                (AclOrder::AllowOverrides, allow_overrides),
+                (AclOrder::DenyOverrides, deny_overrides),
+                (AclOrder::FirstMatch, first),
+            ] {
+                acl.order = order;
+                let result = check_acl(&tags, &acl);
+                assert_eq!(show(&result), expected, "{:?} with {:?}", order, stages);
+                // the deciding stage is reported, along with its own tags
+                let decisions = match &result {
+                    AclResult::Passthrough(d) => vec![d],
+                    AclResult::Match(bh) => bh.human.iter().chain(bh.bot.iter()).collect(),
+                };
+                for d in decisions {
+                    assert!(stages.contains(&d.stage));
+                    assert_eq!(d.tags, vec![format!("{:?}", d.stage).to_lowercase()]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn first_match_default_order() {
+        // the missing stages are appended in the allow_overrides order
+        let mut acl = AclProfile::default();
+        acl.order = AclOrder::FirstMatch;
+        acl.stage_order = vec![AclStage::DenyBot, AclStage::Deny];
+        assert_eq!(
+            acl_stage_order(&acl),
+            vec![
+                AclStage::DenyBot,
+                AclStage::Deny,
+                AclStage::ForceDeny,
+                AclStage::Passthrough,
+                AclStage::Allow,
+                AclStage::AllowBot
+            ]
+        );
+        acl.stage_order = Vec::new();
+        assert_eq!(acl_stage_order(&acl), ALLOW_OVERRIDES.to_vec());
+    }
 }
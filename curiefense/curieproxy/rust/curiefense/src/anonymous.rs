@@ -0,0 +1,261 @@
+/// Tor exit nodes, open proxies and hosting providers
+///
+/// The networks are loaded with `load_anonymous_networks`, independently of the main configuration, either from a
+/// json list or from a MaxMind Anonymous IP database. The client IP is tagged with `anon:<category>` by
+/// `tag_request`.
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use maxminddb::{geoip2::AnonymousIp, Reader};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnonCategory {
+    Tor,
+    Proxy,
+    Hosting,
+}
+
+const CATEGORIES: [AnonCategory; 3] = [AnonCategory::Tor, AnonCategory::Proxy, AnonCategory::Hosting];
+
+impl AnonCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnonCategory::Tor => "tor",
+            AnonCategory::Proxy => "proxy",
+            AnonCategory::Hosting => "hosting",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// an entry of the json list, such as `{"network": "185.220.101.0/24", "category": "tor"}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RawAnonymousNetwork {
+    /// a network, or a single address
+    pub network: String,
+    pub category: AnonCategory,
+}
+
+/// the upper and lower bounds of a network, IPv4 addresses being mapped to IPv6
+fn bounds(net: &IpNet) -> (u128, u128) {
+    let mapped = |ip: IpAddr| match ip {
+        IpAddr::V4(i4) => u128::from(i4.to_ipv6_mapped()),
+        IpAddr::V6(i6) => u128::from(i6),
+    };
+    (mapped(net.network()), mapped(net.broadcast()))
+}
+
+/// disjoint sorted address ranges, with the categories of each range, looked up by binary search
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnonymousNetworks {
+    /// (first address, last address, category bits)
+    ranges: Vec<(u128, u128, u8)>,
+}
+
+impl AnonymousNetworks {
+    /// builds the ranges, overlapping networks being split so that each range has all the categories covering it
+    pub fn new(networks: &[(IpNet, AnonCategory)]) -> Self {
+        // (address, category, is the start of a range), the ends being the first address after the range
+        let mut events: Vec<(u128, AnonCategory, bool)> = Vec::new();
+        for (net, category) in networks {
+            let (start, end) = bounds(net);
+            events.push((start, *category, true));
+            if let Some(after) = end.checked_add(1) {
+                events.push((after, *category, false));
+            }
+        }
+        events.sort_by_key(|(addr, _, _)| *addr);
+
+        let mut ranges: Vec<(u128, u128, u8)> = Vec::new();
+        let mut counts = [0usize; 3];
+        let mut idx = 0;
+        while idx < events.len() {
+            let addr = events[idx].0;
+            while idx < events.len() && events[idx].0 == addr {
+                let (_, category, start) = events[idx];
+                let count = &mut counts[category as usize];
+                if start {
+                    *count += 1;
+                } else {
+                    *count -= 1;
+                }
+                idx += 1;
+            }
+            let bits = CATEGORIES
+                .iter()
+                .filter(|c| counts[**c as usize] > 0)
+                .fold(0, |acc, c| acc | c.bit());
+            // the current range stops right before this address, it is extended by the next event
+            let end = match events.get(idx) {
+                Some((next, _, _)) => next - 1,
+                None => u128::MAX,
+            };
+            if bits == 0 {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.2 == bits && last.1.checked_add(1) == Some(addr) => last.1 = end,
+                _ => ranges.push((addr, end, bits)),
+            }
+        }
+        AnonymousNetworks { ranges }
+    }
+
+    /// parses the json list entries, the invalid networks being reported as errors
+    pub fn from_entries(entries: &[RawAnonymousNetwork]) -> anyhow::Result<Self> {
+        let networks = entries
+            .iter()
+            .map(|e| {
+                let net: IpNet = match e.network.parse() {
+                    Ok(n) => n,
+                    Err(_) => IpNet::from(
+                        e.network
+                            .parse::<IpAddr>()
+                            .map_err(|rr| anyhow::anyhow!("invalid anonymous network {}: {}", e.network, rr))?,
+                    ),
+                };
+                Ok((net, e.category))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(AnonymousNetworks::new(&networks))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Vec<AnonCategory> {
+        let addr = match ip {
+            IpAddr::V4(i4) => u128::from(i4.to_ipv6_mapped()),
+            IpAddr::V6(i6) => u128::from(i6),
+        };
+        let idx = self.ranges.partition_point(|(start, _, _)| *start <= addr);
+        match idx.checked_sub(1).map(|i| self.ranges[i]) {
+            Some((_, end, bits)) if addr <= end => CATEGORIES.iter().filter(|c| bits & c.bit() != 0).copied().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// where the anonymous networks come from
+enum AnonymousSource {
+    List(AnonymousNetworks),
+    MaxMind(Reader<Vec<u8>>),
+}
+
+impl AnonymousSource {
+    fn lookup(&self, ip: IpAddr) -> Vec<AnonCategory> {
+        match self {
+            AnonymousSource::List(networks) => networks.lookup(ip),
+            AnonymousSource::MaxMind(db) => match db.lookup::<AnonymousIp>(ip) {
+                Err(_) => Vec::new(),
+                Ok(record) => [
+                    (record.is_tor_exit_node, AnonCategory::Tor),
+                    (record.is_public_proxy, AnonCategory::Proxy),
+                    (record.is_hosting_provider, AnonCategory::Hosting),
+                ]
+                .iter()
+                .filter(|(flag, _)| flag.unwrap_or(false))
+                .map(|(_, category)| *category)
+                .collect(),
+            },
+        }
+    }
+}
+
+lazy_static! {
+    static ref ANONYMOUS: RwLock<Option<Arc<AnonymousSource>>> = RwLock::new(None);
+}
+
+/// loads the anonymous networks, replacing the previous ones
+///
+/// Files ending in `.mmdb` are read as MaxMind Anonymous IP databases, the other files being json lists of
+/// `RawAnonymousNetwork`. The previous networks are kept when loading fails.
+pub fn load_anonymous_networks(path: &str) -> anyhow::Result<()> {
+    let source = if Path::new(path).extension().map(|e| e == "mmdb").unwrap_or(false) {
+        AnonymousSource::MaxMind(
+            Reader::open_readfile(path).map_err(|rr| anyhow::anyhow!("could not read {}: {}", path, rr))?,
+        )
+    } else {
+        let entries: Vec<RawAnonymousNetwork> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        AnonymousSource::List(AnonymousNetworks::from_entries(&entries)?)
+    };
+    set_source(Some(source));
+    Ok(())
+}
+
+/// removes the anonymous networks, no `anon:` tags being added anymore
+pub fn clear_anonymous_networks() {
+    set_source(None);
+}
+
+fn set_source(source: Option<AnonymousSource>) {
+    if let Ok(mut w) = ANONYMOUS.write() {
+        *w = source.map(Arc::new);
+    }
+}
+
+/// the categories of an address, empty when no networks were loaded
+pub fn anonymous_categories(ip: IpAddr) -> Vec<AnonCategory> {
+    let source = ANONYMOUS.read().ok().and_then(|r| r.clone());
+    match source {
+        None => Vec::new(),
+        Some(s) => s.lookup(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AnonCategory::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+        let networks = AnonymousNetworks::new(&[
+            (net("10.0.0.0/8"), Hosting),
+            (net("10.1.0.0/16"), Proxy),
+            (net("10.1.2.3/32"), Tor),
+            (net("2001:db8::/32"), Tor),
+            (net("ffff:ffff::/32"), Proxy),
+        ]);
+        assert_eq!(networks.lookup(addr("9.255.255.255")), vec![]);
+        assert_eq!(networks.lookup(addr("10.0.0.0")), vec![Hosting]);
+        assert_eq!(networks.lookup(addr("10.1.0.0")), vec![Proxy, Hosting]);
+        assert_eq!(networks.lookup(addr("10.1.2.3")), vec![Tor, Proxy, Hosting]);
+        assert_eq!(networks.lookup(addr("10.1.2.4")), vec![Proxy, Hosting]);
+        assert_eq!(networks.lookup(addr("10.2.0.0")), vec![Hosting]);
+        assert_eq!(networks.lookup(addr("10.255.255.255")), vec![Hosting]);
+        assert_eq!(networks.lookup(addr("11.0.0.0")), vec![]);
+        assert_eq!(networks.lookup(addr("::ffff:10.1.2.3")), vec![Tor, Proxy, Hosting]);
+        assert_eq!(networks.lookup(addr("2001:db8:1::1")), vec![Tor]);
+        assert_eq!(networks.lookup(addr("2001:db9::")), vec![]);
+        assert_eq!(
+            networks.lookup(addr("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")),
+            vec![Proxy]
+        );
+    }
+
+    #[test]
+    fn json_list() {
+        let entries: Vec<RawAnonymousNetwork> = serde_json::from_value(serde_json::json!([
+            {"network": "192.0.2.0/24", "category": "tor"},
+            {"network": "192.0.2.7", "category": "proxy"}
+        ]))
+        .unwrap();
+        let networks = AnonymousNetworks::from_entries(&entries).unwrap();
+        assert_eq!(networks.lookup(addr("192.0.2.7")), vec![Tor, Proxy]);
+        assert_eq!(networks.lookup(addr("192.0.2.8")), vec![Tor]);
+
+        let invalid: Vec<RawAnonymousNetwork> =
+            serde_json::from_value(serde_json::json!([{"network": "192.0.2.0/33", "category": "tor"}])).unwrap();
+        assert!(AnonymousNetworks::from_entries(&invalid).is_err());
+    }
+}
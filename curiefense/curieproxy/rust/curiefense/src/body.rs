@@ -14,15 +14,16 @@ use serde_json::Value;
 use std::io::Read;
 use xmlparser::{ElementEnd, EntityDefinition, ExternalId, Token};
 
+use crate::jsonpath::{JsonPaths, JsonSegment};
 use crate::logs::Logs;
 use crate::requestfields::RequestField;
 use crate::utils::url::parse_urlencoded_params_bytes;
 
-fn json_path(prefix: &[String]) -> String {
+fn json_path(prefix: &[JsonSegment]) -> String {
     if prefix.is_empty() {
         "JSON_ROOT".to_string()
     } else {
-        prefix.join("_")
+        prefix.iter().map(|s| s.to_string()).collect::<Vec<_>>().join("_")
     }
 }
 
@@ -31,40 +32,34 @@ fn json_path(prefix: &[String]) -> String {
 ///   * keys for objects ;
 ///   * indices for lists.
 ///
-/// Scalar values are converted to string, with lowercase booleans and null values.
-fn flatten_json(args: &mut RequestField, prefix: &mut Vec<String>, value: Value) {
-    match value {
+/// Scalar values are converted to string, with lowercase booleans and null values. The path of each value is
+/// recorded in `paths`.
+fn flatten_json(args: &mut RequestField, paths: &mut JsonPaths, prefix: &mut Vec<JsonSegment>, value: Value) {
+    let scalar = match value {
         Value::Array(array) => {
-            prefix.push(String::new());
-            let idx = prefix.len() - 1;
             for (i, v) in array.into_iter().enumerate() {
-                prefix[idx] = format!("{}", i);
-                flatten_json(args, prefix, v);
+                prefix.push(JsonSegment::Index(i));
+                flatten_json(args, paths, prefix, v);
+                prefix.pop();
             }
-            prefix.pop();
+            return;
         }
         Value::Object(mp) => {
-            prefix.push(String::new());
-            let idx = prefix.len() - 1;
             for (k, v) in mp.into_iter() {
-                prefix[idx] = k;
-                flatten_json(args, prefix, v);
+                prefix.push(JsonSegment::Key(k));
+                flatten_json(args, paths, prefix, v);
+                prefix.pop();
             }
-            prefix.pop();
+            return;
         }
-        Value::String(str) => {
-            args.add(json_path(prefix), str);
-        }
-        Value::Bool(b) => {
-            args.add(json_path(prefix), (if b { "true" } else { "false" }).to_string());
-        }
-        Value::Number(n) => {
-            args.add(json_path(prefix), format!("{}", n));
-        }
-        Value::Null => {
-            args.add(json_path(prefix), "null".to_string());
-        }
-    }
+        Value::String(str) => str,
+        Value::Bool(b) => (if b { "true" } else { "false" }).to_string(),
+        Value::Number(n) => format!("{}", n),
+        Value::Null => "null".to_string(),
+    };
+    let name = json_path(prefix);
+    paths.entry(name.clone()).or_default().push(prefix.clone());
+    args.add(name, scalar);
 }
 
 /// This should work with a stream of json items, not deserialize all at once
@@ -76,12 +71,13 @@ fn flatten_json(args: &mut RequestField, prefix: &mut Vec<String>, value: Value)
 ///  * map/10000 -> +33.534%
 ///
 /// next idea: adapting https://github.com/Geal/nom/blob/master/examples/json_iterator.rs
-fn json_body(args: &mut RequestField, body: &[u8]) -> Result<(), String> {
+fn json_body(args: &mut RequestField, body: &[u8]) -> Result<JsonPaths, String> {
     let value: Value = serde_json::from_slice(body).map_err(|rr| format!("Invalid JSON body: {}", rr))?;
 
     let mut prefix = Vec::new();
-    flatten_json(args, &mut prefix, value);
-    Ok(())
+    let mut paths = JsonPaths::new();
+    flatten_json(args, &mut paths, &mut prefix, value);
+    Ok(paths)
 }
 
 /// builds the XML path for a given stack, by appending key names with their indices
@@ -232,18 +228,21 @@ fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8])
 /// body parsing function
 ///
 /// fails if the
+///
+/// returns the JSON paths of the arguments, which are only set for JSON bodies
 pub fn parse_body(
     logs: &mut Logs,
     args: &mut RequestField,
     mcontent_type: Option<&str>,
     body: &[u8],
-) -> Result<(), String> {
+) -> Result<JsonPaths, String> {
     logs.debug("body parsing started");
+    let no_paths = |()| JsonPaths::new();
 
     if let Some(content_type) = mcontent_type {
         logs.debug(format!("parsing content type: {}", content_type));
         if let Some(boundary) = content_type.strip_prefix("multipart/form-data; boundary=") {
-            return multipart_form_encoded(boundary, args, body);
+            return multipart_form_encoded(boundary, args, body).map(no_paths);
         }
 
         if content_type.ends_with("/json") {
@@ -251,16 +250,16 @@ pub fn parse_body(
         }
 
         if content_type.ends_with("/xml") {
-            return xml_body(args, body);
+            return xml_body(args, body).map(no_paths);
         }
 
         if content_type == "application/x-www-form-urlencoded" {
-            return forms_body(args, body);
+            return forms_body(args, body).map(no_paths);
         }
     }
 
     // unhandled content type, default to json and forms_body
-    json_body(args, body).or_else(|_| forms_body(args, body))
+    json_body(args, body).or_else(|_| forms_body(args, body).map(no_paths))
 }
 
 #[cfg(test)]
@@ -360,6 +359,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn json_paths() {
+        let mut logs = Logs::default();
+        let mut args = RequestField::default();
+        let body = br#"{"a": {"b": "1"}, "a_b": "2", "l": [[3]]}"#;
+        let paths = parse_body(&mut logs, &mut args, Some("application/json"), body).unwrap();
+        let key = |k: &str| JsonSegment::Key(k.to_string());
+        let mut collided = paths["a_b"].clone();
+        collided.sort_by_key(|p| p.len());
+        assert_eq!(collided, vec![vec![key("a_b")], vec![key("a"), key("b")]]);
+        assert_eq!(
+            paths["l_0_0"],
+            vec![vec![key("l"), JsonSegment::Index(0), JsonSegment::Index(0)]]
+        );
+        assert!(
+            parse_body(&mut logs, &mut args, Some("application/x-www-form-urlencoded"), b"a=b")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
     #[test]
     fn arguments_collision() {
         let mut logs = Logs::default();
@@ -3,29 +3,58 @@ pub mod hostmap;
 pub mod limit;
 pub mod globalfilter;
 pub mod raw;
+pub mod responsetemplate;
+pub mod settings;
+pub mod tlsfingerprint;
 pub mod utils;
 pub mod contentfilter;
+pub mod crs;
 
 use lazy_static::lazy_static;
-use regex::Regex;
-use std::collections::HashMap;
+use regex::{Regex, RegexSet};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 
-use crate::logs::Logs;
+use crate::acl::{resolve_acl_networks, AclNetwork};
+use crate::logs::{LogLevel, Logs};
+use crate::schedule::{Schedule, TimeZone};
+use crate::securitypolicy::find_securitypolicy;
 use flow::{flow_resolve, FlowElement, SequenceKey};
-use hostmap::{HostMap, SecurityPolicy};
+use hostmap::{Canary, HostIndex, HostKey, HostMap, Rollout, SecurityPolicy, ROLLOUT_BUCKETS};
 use limit::{Limit};
 use globalfilter::GlobalFilterSection;
-use raw::{AclProfile, RawFlowEntry, RawHostMap, RawLimit, RawGlobalFilterSection, RawSecurityPolicy, RawContentFilterProfile, RawContentFilterGroup};
-use utils::Matching;
+use raw::{AclProfile, RawFlowEntry, RawHostMap, RawLimit, RawGlobalFilterSection, RawSecurityPolicy, RawContentFilterProfile, RawContentFilterRule, RawContentFilterGroup, RawResponseTemplate, RawSettings, RawTlsFingerprint};
+use responsetemplate::{response_templates_resolve, ResponseTemplate};
+use settings::{Settings, TrustedSources};
+use tlsfingerprint::{tls_fingerprints_resolve, TlsFingerprint};
+use utils::{matching_set, Matching};
+use crs::{parse_secrules, CRS_RULES_FILE};
 use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules, ContentFilterGroup};
 
 lazy_static! {
     pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::empty());
     pub static ref HSDB: RwLock<Option<ContentFilterRules>> = RwLock::new(None);
+    /// the tenant configurations, the sessions without a tenant use `CONFIG` and `HSDB`
+    pub static ref TENANT_CONFIGS: RwLock<HashMap<TenantId, Arc<TenantConfig>>> = RwLock::new(HashMap::new());
+    /// held during the reloads, so that a configuration is not replaced by an older one built concurrently
+    static ref RELOAD: Mutex<()> = Mutex::new(());
+}
+
+pub type TenantId = String;
+
+/// the configuration of a tenant, along with its content filter database
+pub struct TenantConfig {
+    pub config: RwLock<Config>,
+    pub hsdb: RwLock<Option<ContentFilterRules>>,
+}
+
+/// returns the configuration of a tenant, or `None` when it has never been loaded
+pub fn tenant_config(tenant: &str) -> Option<Arc<TenantConfig>> {
+    TENANT_CONFIGS.read().ok()?.get(tenant).cloned()
 }
 
 pub fn with_config<R, F>(basepath: &str, logs: &mut Logs, f: F) -> Option<R>
@@ -45,17 +74,23 @@ where
         }
     };
     let r = f(logs, &newconfig);
-    match CONFIG.write() {
-        Ok(mut w) => *w = newconfig,
-        Err(rr) => logs.error(rr),
-    };
-    match HSDB.write() {
-        Ok(mut dbw) => *dbw = Some(newhsdb),
-        Err(rr) => logs.error(rr),
-    };
+    replace_config(logs, newconfig, newhsdb);
     Some(r)
 }
 
+/// swaps the configuration and the hyperscan database, holding both write locks so that they are always
+/// consistent
+pub fn replace_config(logs: &mut Logs, newconfig: Config, newhsdb: ContentFilterRules) {
+    match (CONFIG.write(), HSDB.write()) {
+        (Ok(mut w), Ok(mut dbw)) => {
+            *w = newconfig;
+            *dbw = Some(newhsdb);
+        }
+        (Err(rr), _) => logs.error(rr),
+        (_, Err(rr)) => logs.error(rr),
+    }
+}
+
 pub fn with_config_default_path<R, F>(logs: &mut Logs, f: F) -> Option<R>
 where
     F: FnOnce(&mut Logs, &Config) -> R,
@@ -63,9 +98,231 @@ where
     with_config("/config/current/config", logs, f)
 }
 
+/// a problem found when loading the configuration
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub severity: LogLevel,
+    /// the faulty part of the configuration, starting with the file name, such as
+    /// `contentfilter-profiles[__default__].args.names[2].reg`
+    ///
+    /// top level entries are designated by their id, and sub-entries by their position
+    pub component: Option<String>,
+    pub message: String,
+}
+
+/// the warnings and errors of a configuration load, as structured issues
+pub fn config_issues(logs: &Logs) -> Vec<ConfigIssue> {
+    logs.logs
+        .iter()
+        .filter(|l| l.level >= LogLevel::Warning)
+        .map(|l| ConfigIssue {
+            severity: l.level.clone(),
+            component: l.component.clone(),
+            message: l.message.clone(),
+        })
+        .collect()
+}
+
+/// loads the configuration found in `basepath`, like `reload`, and reports the issues without applying it
+pub fn validate_config(basepath: &str) -> Vec<ConfigIssue> {
+    let mut logs = Logs::default();
+    let mut bjson = PathBuf::from(basepath);
+    bjson.push("json");
+    Config::load(&mut logs, SystemTime::now(), &ConfigSource::Directory(bjson));
+    config_issues(&logs)
+}
+
+/// added and removed identifiers in a configuration section, sorted
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChangeSet {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChangeSet {
+    fn new(old: Vec<String>, new: Vec<String>) -> Self {
+        let old: HashSet<String> = old.into_iter().collect();
+        let new: HashSet<String> = new.into_iter().collect();
+        let mut added: Vec<String> = new.difference(&old).cloned().collect();
+        let mut removed: Vec<String> = old.difference(&new).cloned().collect();
+        added.sort();
+        removed.sort();
+        ChangeSet { added, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// what changed during a successful reload
+///
+/// Entries are identified by their id, so modified entries that kept their id are not listed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadReport {
+    /// false if the configuration was not modified since the last load, in which case nothing is reloaded
+    pub reloaded: bool,
+    pub securitypolicies: ChangeSet,
+    pub content_filter_profiles: ChangeSet,
+    pub content_filter_groups: ChangeSet,
+    pub content_filter_rules: ChangeSet,
+    pub flows: ChangeSet,
+    pub globalfilters_before: usize,
+    pub globalfilters_after: usize,
+}
+
+impl ReloadReport {
+    fn new(old: &Config, oldhsdb: Option<&ContentFilterRules>, new: &Config, newhsdb: &ContentFilterRules) -> Self {
+        let hostmap_ids = |cfg: &Config| -> Vec<String> {
+            cfg.securitypolicies
+                .iter()
+                .map(|m| m.inner.id.clone())
+                .chain(cfg.default.iter().map(|h| h.id.clone()))
+                .collect()
+        };
+        let rule_ids = |db: Option<&ContentFilterRules>| -> Vec<String> {
+            db.map(|db| db.ids.iter().map(|r| r.id.clone()).collect())
+                .unwrap_or_default()
+        };
+        ReloadReport {
+            reloaded: true,
+            securitypolicies: ChangeSet::new(hostmap_ids(old), hostmap_ids(new)),
+            content_filter_profiles: ChangeSet::new(
+                old.content_filter_profiles.keys().cloned().collect(),
+                new.content_filter_profiles.keys().cloned().collect(),
+            ),
+            content_filter_groups: ChangeSet::new(
+                old.content_filter_groups.keys().cloned().collect(),
+                new.content_filter_groups.keys().cloned().collect(),
+            ),
+            content_filter_rules: ChangeSet::new(rule_ids(oldhsdb), rule_ids(Some(newhsdb))),
+            flows: ChangeSet::new(
+                old.flows.keys().map(|k| k.0.clone()).collect(),
+                new.flows.keys().map(|k| k.0.clone()).collect(),
+            ),
+            globalfilters_before: old.globalfilters.len(),
+            globalfilters_after: new.globalfilters.len(),
+        }
+    }
+}
+
+/// reasons for a failed reload, in which case the previous configuration is left untouched
+#[derive(Debug)]
+pub enum ReloadError {
+    /// the new configuration had errors or warnings, which are all listed
+    Invalid(Vec<String>),
+    /// a lock on the configuration, or on the content filter database, was poisoned
+    LockPoisoned(String),
+}
+
+impl std::fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReloadError::Invalid(problems) => write!(f, "invalid configuration: {}", problems.join(", ")),
+            ReloadError::LockPoisoned(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// reloads the configuration from `basepath`, in an all-or-nothing way
+///
+/// The new configuration and content filter database are first built and validated, and only swapped in
+/// when no warning or error was encountered.
+pub fn reload_config(basepath: &str) -> Result<ReloadReport, ReloadError> {
+    reload_config_into(&CONFIG, &HSDB, basepath)
+}
+
+fn reload_config_into(
+    config: &RwLock<Config>,
+    hsdb: &RwLock<Option<ContentFilterRules>>,
+    basepath: &str,
+) -> Result<ReloadReport, ReloadError> {
+    let poisoned = |rr: String| ReloadError::LockPoisoned(format!("Could not get configuration lock {}", rr));
+    // the modification check and the swap must not be interleaved with another reload
+    let _reloading = RELOAD.lock().map_err(|rr| poisoned(rr.to_string()))?;
+    let mut logs = Logs::default();
+    let loaded = config
+        .read()
+        .map_err(|rr| poisoned(rr.to_string()))?
+        .reload(&mut logs, basepath);
+    let (newconfig, newhsdb) = match loaded {
+        None => return Ok(ReloadReport::default()),
+        Some(cfginfo) => cfginfo,
+    };
+    let problems: Vec<String> = logs
+        .logs
+        .iter()
+        .filter(|l| l.level >= LogLevel::Warning)
+        .map(|l| l.message.clone())
+        .collect();
+    if !problems.is_empty() {
+        return Err(ReloadError::Invalid(problems));
+    }
+
+    // both locks are held while swapping, so that readers never see an inconsistent state
+    let mut w = config.write().map_err(|rr| poisoned(rr.to_string()))?;
+    let mut dbw = hsdb.write().map_err(|rr| poisoned(rr.to_string()))?;
+    let report = ReloadReport::new(&w, dbw.as_ref(), &newconfig, &newhsdb);
+    *w = newconfig;
+    *dbw = Some(newhsdb);
+    Ok(report)
+}
+
+/// reloads the configuration of a tenant from `basepath`, see `reload_config`
+///
+/// The tenant is created by its first successful load. The other tenants, and the default configuration, are never
+/// affected.
+pub fn reload_tenant_config(tenant: &str, basepath: &str) -> Result<ReloadReport, ReloadError> {
+    let poisoned = |rr: String| ReloadError::LockPoisoned(format!("Could not get TENANT_CONFIGS write lock {}", rr));
+    if let Some(existing) = tenant_config(tenant) {
+        return reload_config_into(&existing.config, &existing.hsdb, basepath);
+    }
+    // the first load is done under the write lock, so that concurrent first loads do not build two tenants, the
+    // last one being silently dropped
+    let mut tenants = TENANT_CONFIGS.write().map_err(|rr| poisoned(rr.to_string()))?;
+    let existing = match tenants.get(tenant) {
+        Some(existing) => existing.clone(),
+        None => {
+            let fresh = TenantConfig {
+                config: RwLock::new(Config::empty()),
+                hsdb: RwLock::new(None),
+            };
+            let report = reload_config_into(&fresh.config, &fresh.hsdb, basepath)?;
+            tenants.insert(tenant.to_string(), Arc::new(fresh));
+            return Ok(report);
+        }
+    };
+    drop(tenants);
+    reload_config_into(&existing.config, &existing.hsdb, basepath)
+}
+
+/// removes the configuration of a tenant, returning false if it was not loaded
+///
+/// The checks of the sessions of this tenant then fail with `UnknownTenant`.
+pub fn remove_tenant_config(tenant: &str) -> Result<bool, ReloadError> {
+    let mut tenants = TENANT_CONFIGS
+        .write()
+        .map_err(|rr| ReloadError::LockPoisoned(format!("Could not get TENANT_CONFIGS write lock {}", rr)))?;
+    Ok(tenants.remove(tenant).is_some())
+}
+
+/// where the configuration files are read from
+enum ConfigSource<'a> {
+    /// a directory containing one json file per configuration type
+    Directory(PathBuf),
+    /// a json object, whose keys are the configuration file names, without the `.json` extension
+    Blob(&'a serde_json::Map<String, serde_json::Value>),
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub securitypolicies: Vec<Matching<HostMap>>,
+    /// the host map patterns, see `matching_set`, host maps are scanned linearly when not set
+    pub securitypolicies_set: Option<RegexSet>,
+    /// the host maps with a host name or wildcard key, that take precedence over the regex keys
+    pub securitypolicies_index: HostIndex,
     pub globalfilters: Vec<GlobalFilterSection>,
     pub default: Option<HostMap>,
     pub last_mod: SystemTime,
@@ -73,6 +330,15 @@ pub struct Config {
     pub flows: HashMap<SequenceKey, Vec<FlowElement>>,
     pub content_filter_profiles: HashMap<String, ContentFilterProfile>,
     pub content_filter_groups: HashMap<String, ContentFilterGroup>,
+    /// the IP ranges of all the ACL profiles
+    pub acl_networks: Vec<AclNetwork>,
+    /// known TLS fingerprints, indexed by their lowercased hash
+    pub tls_fingerprints: HashMap<String, TlsFingerprint>,
+    /// the block responses that actions can reference, indexed by their id
+    pub response_templates: HashMap<String, ResponseTemplate>,
+    pub settings: Settings,
+    /// the baseline policies, along with the names of their host maps, empty unless `multiple_policies` is set
+    pub baseline_policies: Vec<(String, SecurityPolicy)>,
 }
 
 fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
@@ -84,8 +350,128 @@ fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
 
 #[allow(clippy::too_many_arguments)]
 impl Config {
+    fn resolve_acl_profile(
+        logs: &mut Logs,
+        component: String,
+        acls: &HashMap<String, AclProfile>,
+        id: &str,
+    ) -> AclProfile {
+        match acls.get(id) {
+            Some(p) => p.clone(),
+            None => {
+                logs.warning_at(component, format!("Unknown ACL profile {}", id));
+                AclProfile::default()
+            }
+        }
+    }
+
+    /// the content filter profile, with the argument limits of the security policy entry
+    fn resolve_content_filter_profile(
+        logs: &mut Logs,
+        component: String,
+        contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
+        id: &str,
+        rawmap: &RawSecurityPolicy,
+    ) -> ContentFilterProfile {
+        let mut content_filter_profile: ContentFilterProfile = match contentfilterprofiles.get(id) {
+            Some(p) => p.clone(),
+            None => {
+                logs.warning_at(component, format!("Unknown Content Filter profile {}", id));
+                ContentFilterProfile::default()
+            }
+        };
+        if let Some(max_args) = rawmap.max_args {
+            content_filter_profile.sections.args.max_count = max_args;
+        }
+        if let Some(max_arg_length) = rawmap.max_arg_length {
+            content_filter_profile.sections.args.max_length = max_arg_length;
+        }
+        if rawmap.max_total_args_length.is_some() {
+            content_filter_profile.max_total_args_length = rawmap.max_total_args_length;
+        }
+        content_filter_profile
+    }
+
+    fn resolve_limits(
+        logs: &mut Logs,
+        component: String,
+        limits: &HashMap<String, Limit>,
+        name: &str,
+        limit_ids: &[String],
+    ) -> Vec<Limit> {
+        let mut olimits: Vec<Limit> = Vec::new();
+        for lid in limit_ids {
+            match from_map(&limits, lid) {
+                Ok(lm) => olimits.push(lm),
+                Err(rr) => logs.error_at(
+                    component.clone(),
+                    format!("When resolving limits in rawmap {}, {}", name, rr),
+                ),
+            }
+        }
+        olimits
+    }
+
+    /// the canary alternative of an entry, the settings it does not override are those of the entry
+    fn resolve_canary(
+        logs: &mut Logs,
+        component: &str,
+        rawmap: &RawSecurityPolicy,
+        stable: &SecurityPolicy,
+        limits: &HashMap<String, Limit>,
+        acls: &HashMap<String, AclProfile>,
+        contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
+    ) -> Rollout {
+        let raw = match &rawmap.canary {
+            None => return Rollout::Disabled,
+            Some(r) => r,
+        };
+        if !(0.0..=100.0).contains(&raw.percentage) {
+            logs.warning_at(
+                format!("{}.percentage", component),
+                format!("Canary percentage {} is not between 0 and 100", raw.percentage),
+            );
+        }
+        let name = raw.name.clone().unwrap_or_else(|| format!("{}-canary", stable.name));
+        let policy = SecurityPolicy {
+            name,
+            acl_active: raw.acl_active.unwrap_or(stable.acl_active),
+            acl_profile: match &raw.acl_profile {
+                Some(id) => Config::resolve_acl_profile(logs, format!("{}.acl_profile", component), acls, id),
+                None => stable.acl_profile.clone(),
+            },
+            content_filter_active: raw.content_filter_active.unwrap_or(stable.content_filter_active),
+            content_filter_profile: match &raw.content_filter_profile {
+                Some(id) => Config::resolve_content_filter_profile(
+                    logs,
+                    format!("{}.content_filter_profile", component),
+                    contentfilterprofiles,
+                    id,
+                    rawmap,
+                ),
+                None => stable.content_filter_profile.clone(),
+            },
+            limits: match &raw.limit_ids {
+                Some(ids) => {
+                    Config::resolve_limits(logs, format!("{}.limit_ids", component), limits, &rawmap.name, ids)
+                }
+                None => stable.limits.clone(),
+            },
+            methods: stable.methods.clone(),
+            inspect_preflight: stable.inspect_preflight,
+            max_body_size: stable.max_body_size,
+            timezone: stable.timezone.clone(),
+            param_presence: stable.param_presence.clone(),
+            rollout: Rollout::Canary,
+        };
+        let buckets = (raw.percentage.clamp(0.0, 100.0) * f64::from(ROLLOUT_BUCKETS) / 100.0).round() as u32;
+        Rollout::Stable(Box::new(Canary { buckets, policy }))
+    }
+
+    /// `component` designates the host map, in the issues that are logged
     fn resolve_security_policies(
         logs: &mut Logs,
+        component: &str,
         rawmaps: Vec<RawSecurityPolicy>,
         limits: &HashMap<String, Limit>,
         acls: &HashMap<String, AclProfile>,
@@ -93,53 +479,114 @@ impl Config {
     ) -> (Vec<Matching<SecurityPolicy>>, Option<SecurityPolicy>) {
         let mut default: Option<SecurityPolicy> = None;
         let mut entries: Vec<Matching<SecurityPolicy>> = Vec::new();
+        // the active flags of each entry, as configured, then those of its canary
+        let mut active_flags: Vec<[Option<bool>; 4]> = Vec::new();
 
-        for rawmap in rawmaps {
-            let acl_profile: AclProfile = match acls.get(&rawmap.acl_profile) {
-                Some(p) => p.clone(),
-                None => {
-                    logs.warning(format!("Unknown ACL profile {}", &rawmap.acl_profile));
-                    AclProfile::default()
-                }
-            };
-            let content_filter_profile: ContentFilterProfile = match contentfilterprofiles.get(&rawmap.content_filter_profile) {
-                Some(p) => p.clone(),
-                None => {
-                    logs.warning(format!("Unknown Content Filter profile {}", &rawmap.content_filter_profile));
-                    ContentFilterProfile::default()
-                }
-            };
-            let mut olimits: Vec<Limit> = Vec::new();
-            for lid in rawmap.limit_ids {
-                match from_map(&limits, &lid) {
-                    Ok(lm) => olimits.push(lm),
-                    Err(rr) => logs.error(format!("When resolving limits in rawmap {}, {}", rawmap.name, rr)),
-                }
-            }
+        for (idx, rawmap) in rawmaps.into_iter().enumerate() {
+            let entry_component = format!("{}.map[{}]", component, idx);
+            let acl_profile = Config::resolve_acl_profile(
+                logs,
+                format!("{}.acl_profile", entry_component),
+                acls,
+                &rawmap.acl_profile,
+            );
+            let content_filter_profile = Config::resolve_content_filter_profile(
+                logs,
+                format!("{}.content_filter_profile", entry_component),
+                contentfilterprofiles,
+                &rawmap.content_filter_profile,
+                &rawmap,
+            );
+            let olimits = Config::resolve_limits(
+                logs,
+                format!("{}.limit_ids", entry_component),
+                limits,
+                &rawmap.name,
+                &rawmap.limit_ids,
+            );
             let mapname = rawmap.name.clone();
-            let securitypolicy = SecurityPolicy {
-                acl_active: rawmap.acl_active,
+            let mut securitypolicy = SecurityPolicy {
+                acl_active: rawmap.acl_active.unwrap_or(false),
                 acl_profile,
-                content_filter_active: rawmap.content_filter_active,
+                content_filter_active: rawmap.content_filter_active.unwrap_or(false),
                 content_filter_profile,
                 limits: olimits,
-                name: rawmap.name,
+                name: rawmap.name.clone(),
+                methods: rawmap
+                    .methods
+                    .as_ref()
+                    .map(|ms| ms.iter().map(|m| m.to_uppercase()).collect()),
+                inspect_preflight: rawmap.inspect_preflight,
+                max_body_size: rawmap.max_body_size,
+                timezone: rawmap.timezone.as_ref().and_then(|tz| match TimeZone::parse(tz) {
+                    Ok(timezone) => Some(timezone),
+                    Err(rr) => {
+                        logs.error_at(format!("{}.timezone", entry_component), rr);
+                        None
+                    }
+                }),
+                param_presence: rawmap.param_presence.clone(),
+                rollout: Rollout::Disabled,
             };
+            let canary_component = format!("{}.canary", entry_component);
+            securitypolicy.rollout = Config::resolve_canary(
+                logs,
+                &canary_component,
+                &rawmap,
+                &securitypolicy,
+                limits,
+                acls,
+                contentfilterprofiles,
+            );
+            let canary_flags = rawmap
+                .canary
+                .as_ref()
+                .map(|c| {
+                    (
+                        c.acl_active.or(rawmap.acl_active),
+                        c.content_filter_active.or(rawmap.content_filter_active),
+                    )
+                })
+                .unwrap_or((None, None));
             if rawmap.match_ == "__default__" || (rawmap.match_ == "/" && securitypolicy.name == "default") {
                 if default.is_some() {
-                    logs.warning("Multiple __default__ maps");
+                    logs.warning_at(entry_component, "Multiple __default__ maps");
                 }
                 default = Some(securitypolicy);
             } else {
                 match Regex::new(&rawmap.match_) {
-                    Err(rr) => logs.warning(format!(
-                        "Invalid regex {} in entry {}: {}",
-                        &rawmap.match_, &mapname, rr
-                    )),
-                    Ok(matcher) => entries.push(Matching { matcher, inner: securitypolicy }),
+                    Err(rr) => logs.warning_at(
+                        format!("{}.match", entry_component),
+                        format!("Invalid regex {} in entry {}: {}", &rawmap.match_, &mapname, rr),
+                    ),
+                    Ok(matcher) => {
+                        entries.push(Matching {
+                            matcher,
+                            inner: securitypolicy,
+                        });
+                        active_flags.push([
+                            rawmap.acl_active,
+                            rawmap.content_filter_active,
+                            canary_flags.0,
+                            canary_flags.1,
+                        ]);
+                    }
                 };
             }
         }
+        // entries that do not set the active flags inherit them from the default entry, each flag independently
+        if let Some(d) = &default {
+            for (entry, flags) in entries.iter_mut().zip(active_flags) {
+                let [acl_active, content_filter_active, canary_acl_active, canary_content_filter_active] = flags;
+                entry.inner.acl_active = acl_active.unwrap_or(d.acl_active);
+                entry.inner.content_filter_active = content_filter_active.unwrap_or(d.content_filter_active);
+                if let Rollout::Stable(canary) = &mut entry.inner.rollout {
+                    canary.policy.acl_active = canary_acl_active.unwrap_or(d.acl_active);
+                    canary.policy.content_filter_active =
+                        canary_content_filter_active.unwrap_or(d.content_filter_active);
+                }
+            }
+        }
         entries.sort_by_key(|x: &Matching<SecurityPolicy>| usize::MAX - x.matcher.as_str().len());
         (entries, default)
     }
@@ -155,56 +602,123 @@ impl Config {
         rawcontentfiltergroups: Vec<RawContentFilterGroup>,
         container_name: Option<String>,
         rawflows: Vec<RawFlowEntry>,
+        rawtlsfingerprints: Vec<RawTlsFingerprint>,
+        rawresponsetemplates: Vec<RawResponseTemplate>,
+        rawsettings: RawSettings,
     ) -> Config {
         let mut default: Option<HostMap> = None;
         let mut securitypolicies: Vec<Matching<HostMap>> = Vec::new();
+        let mut securitypolicies_index = HostIndex::default();
 
-        let limits = Limit::resolve(logs, rawlimits);
+        let response_templates = response_templates_resolve(logs, rawresponsetemplates);
+        let limits = Limit::resolve(logs, rawlimits, &response_templates);
         let content_filter_groups = ContentFilterGroup::resolve(rawcontentfiltergroups);
         let content_filter_profiles = ContentFilterProfile::resolve(logs, rawcontentfilterprofiles, &content_filter_groups);
-        let acls = rawacls.into_iter().map(|a| (a.id.clone(), a)).collect();
+        let mut acl_networks = Vec::new();
+        let acls = rawacls
+            .into_iter()
+            .map(|mut a| {
+                acl_networks.extend(resolve_acl_networks(&mut a));
+                (a.id.clone(), a)
+            })
+            .collect();
+        acl_networks.sort_by(|a: &AclNetwork, b: &AclNetwork| a.tag.cmp(&b.tag));
+        acl_networks.dedup_by(|a, b| a.tag == b.tag);
 
         // build the entries while looking for the default entry
         for rawmap in rawmaps {
-            let (entries, default_entry) = Config::resolve_security_policies(logs, rawmap.map, &limits, &acls, &content_filter_profiles);
+            let component = format!("securitypolicy[{}]", rawmap.id);
+            let (entries, default_entry) = Config::resolve_security_policies(
+                logs,
+                &component,
+                rawmap.map,
+                &limits,
+                &acls,
+                &content_filter_profiles,
+            );
             if default_entry.is_none() {
-                logs.warning(format!(
-                    "HostMap entry '{}', id '{}' does not have a default entry",
-                    rawmap.name, rawmap.id
-                ));
+                logs.warning_at(
+                    format!("{}.map", component),
+                    format!(
+                        "HostMap entry '{}', id '{}' does not have a default entry",
+                        rawmap.name, rawmap.id
+                    ),
+                );
             }
             let mapname = rawmap.name.clone();
+            let entries_set = matching_set(&entries);
+            if entries_set.is_none() {
+                logs.debug(format!("HostMap '{}' entries will be scanned linearly", mapname));
+            }
             let hostmap = HostMap {
                 id: rawmap.id,
                 name: rawmap.name,
                 entries,
+                entries_set,
                 default: default_entry,
             };
             if rawmap.match_ == "__default__" {
                 if default.is_some() {
-                    logs.error(format!(
-                        "HostMap entry '{}', id '{}' has several default entries",
-                        hostmap.name, hostmap.id
-                    ));
+                    logs.error_at(
+                        format!("{}.match", component),
+                        format!(
+                            "HostMap entry '{}', id '{}' has several default entries",
+                            hostmap.name, hostmap.id
+                        ),
+                    );
                 }
                 default = Some(hostmap);
             } else {
-                match Regex::new(&rawmap.match_) {
-                    Err(rr) => logs.error(format!("Invalid regex {} in entry {}: {}", &rawmap.match_, mapname, rr)),
-                    Ok(matcher) => securitypolicies.push(Matching {
-                        matcher,
-                        inner: hostmap,
-                    }),
+                let key = HostKey::parse(&rawmap.match_);
+                match key.regex(&rawmap.match_) {
+                    Err(rr) => logs.error_at(
+                        format!("{}.match", component),
+                        format!("Invalid regex {} in entry {}: {}", &rawmap.match_, mapname, rr),
+                    ),
+                    Ok(matcher) => {
+                        if !securitypolicies_index.insert(&key, securitypolicies.len()) {
+                            logs.warning_at(
+                                format!("{}.match", component),
+                                format!("HostMap entry '{}' has the same host as a previous entry", mapname),
+                            );
+                        }
+                        securitypolicies.push(Matching {
+                            matcher,
+                            inner: hostmap,
+                        })
+                    }
                 }
             }
         }
 
-        let globalfilters = GlobalFilterSection::resolve(logs, rawglobalfilters);
+        let globalfilters = GlobalFilterSection::resolve(logs, rawglobalfilters, &response_templates);
 
-        let flows = flow_resolve(logs, rawflows);
+        let flows = flow_resolve(logs, rawflows, &response_templates);
 
-        Config {
+        let securitypolicies_set = matching_set(&securitypolicies);
+        let baseline_refs = if rawsettings.multiple_policies.unwrap_or(false) {
+            rawsettings.baseline_policies.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let schedule = rawsettings
+            .schedule
+            .as_ref()
+            .and_then(|raw| match Schedule::resolve(raw) {
+                Ok(schedule) => Some(schedule),
+                Err(rr) => {
+                    logs.error_at("settings.schedule".to_string(), rr);
+                    None
+                }
+            });
+        let trusted = rawsettings
+            .trusted
+            .as_ref()
+            .map(|raw| TrustedSources::resolve(logs, raw));
+        let mut config = Config {
             securitypolicies,
+            securitypolicies_set,
+            securitypolicies_index,
             globalfilters,
             default,
             last_mod,
@@ -212,17 +726,38 @@ impl Config {
             flows,
             content_filter_profiles,
             content_filter_groups,
+            acl_networks,
+            tls_fingerprints: tls_fingerprints_resolve(logs, rawtlsfingerprints),
+            response_templates,
+            settings: Settings::resolve(rawsettings),
+            baseline_policies: Vec::new(),
+        };
+        config.settings.schedule = schedule;
+        config.settings.trusted = trusted;
+        for (i, pref) in baseline_refs.iter().enumerate() {
+            match find_securitypolicy(&config, &pref.hostmap, &pref.name) {
+                Some(policy) => {
+                    let baseline = (pref.hostmap.clone(), policy.clone());
+                    config.baseline_policies.push(baseline)
+                }
+                None => logs.warning_at(
+                    format!("settings.baseline_policies[{}]", i),
+                    format!("Unknown security policy {}/{}", pref.hostmap, pref.name),
+                ),
+            }
         }
+        config
     }
 
     fn load_config_file<A: serde::de::DeserializeOwned>(logs: &mut Logs, base: &Path, fname: &str) -> Vec<A> {
         let mut path = base.to_path_buf();
         path.push(fname);
         let fullpath = path.to_str().unwrap_or(fname).to_string();
+        let key = fname.trim_end_matches(".json");
         let file = match std::fs::File::open(path) {
             Ok(f) => f,
             Err(rr) => {
-                logs.error(format!("when loading {}: {}", fullpath, rr));
+                logs.error_at(key.to_string(), format!("when loading {}: {}", fullpath, rr));
                 return Vec::new();
             }
         };
@@ -230,15 +765,143 @@ impl Config {
             Ok(vs) => vs,
             Err(rr) => {
                 // if it is not a json array, abort early and do not resolve anything
-                logs.error(format!("when parsing {}: {}", fullpath, rr));
+                logs.error_at(key.to_string(), format!("when parsing {}: {}", fullpath, rr));
                 return Vec::new();
             }
         };
+        Config::resolve_config_entries(logs, key, &fullpath, values)
+    }
+
+    fn load_config_blob<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        blob: &serde_json::Map<String, serde_json::Value>,
+        fname: &str,
+    ) -> Vec<A> {
+        let key = fname.trim_end_matches(".json");
+        let values: Vec<serde_json::Value> = match blob.get(key) {
+            Some(serde_json::Value::Array(vs)) => vs.clone(),
+            Some(_) => {
+                logs.error_at(key.to_string(), format!("when parsing {}: not an array", key));
+                return Vec::new();
+            }
+            None => {
+                logs.error_at(
+                    key.to_string(),
+                    format!("when loading {}: missing from the configuration blob", key),
+                );
+                return Vec::new();
+            }
+        };
+        Config::resolve_config_entries(logs, key, key, values)
+    }
+
+    /// same as `load_config_entries`, but a missing file, or key, is not an error
+    fn load_optional_config_entries<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        source: &ConfigSource,
+        fname: &str,
+    ) -> Vec<A> {
+        let present = match source {
+            ConfigSource::Directory(base) => base.join(fname).exists(),
+            ConfigSource::Blob(blob) => blob.contains_key(fname.trim_end_matches(".json")),
+        };
+        if present {
+            Config::load_config_entries(logs, source, fname)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// loads a file holding a single json object, a missing file, or key, yields the default value
+    fn load_optional_config_object<A: serde::de::DeserializeOwned + Default>(
+        logs: &mut Logs,
+        source: &ConfigSource,
+        fname: &str,
+    ) -> A {
+        let key = fname.trim_end_matches(".json");
+        let value: serde_json::Value = match source {
+            ConfigSource::Directory(base) => {
+                let path = base.join(fname);
+                if !path.exists() {
+                    return A::default();
+                }
+                let parsed = std::fs::read_to_string(&path)
+                    .map_err(|rr| rr.to_string())
+                    .and_then(|content| serde_json::from_str(&content).map_err(|rr| rr.to_string()));
+                match parsed {
+                    Ok(v) => v,
+                    Err(rr) => {
+                        logs.error_at(key.to_string(), format!("when loading {}: {}", path.display(), rr));
+                        return A::default();
+                    }
+                }
+            }
+            ConfigSource::Blob(blob) => match blob.get(key) {
+                Some(v) => v.clone(),
+                None => return A::default(),
+            },
+        };
+        serde_json::from_value(value).unwrap_or_else(|rr| {
+            logs.error_at(key.to_string(), format!("when resolving {}: {}", fname, rr));
+            A::default()
+        })
+    }
+
+    /// loads a text file, or a string from the blob, whose key is the file name without its extension; a missing
+    /// file, or key, yields None
+    fn load_optional_config_text(logs: &mut Logs, source: &ConfigSource, fname: &str) -> Option<String> {
+        let key = fname.rsplit_once('.').map(|(k, _)| k).unwrap_or(fname);
+        match source {
+            ConfigSource::Directory(base) => {
+                let path = base.join(fname);
+                if !path.exists() {
+                    return None;
+                }
+                std::fs::read_to_string(&path)
+                    .map_err(|rr| logs.error_at(key.to_string(), format!("when loading {}: {}", path.display(), rr)))
+                    .ok()
+            }
+            ConfigSource::Blob(blob) => match blob.get(key) {
+                None => None,
+                Some(serde_json::Value::String(content)) => Some(content.clone()),
+                Some(_) => {
+                    logs.error_at(key.to_string(), format!("when parsing {}: not a string", key));
+                    None
+                }
+            },
+        }
+    }
+
+    fn load_config_entries<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        source: &ConfigSource,
+        fname: &str,
+    ) -> Vec<A> {
+        match source {
+            ConfigSource::Directory(base) => Config::load_config_file(logs, base, fname),
+            ConfigSource::Blob(blob) => Config::load_config_blob(logs, blob, fname),
+        }
+    }
+
+    fn resolve_config_entries<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        key: &str,
+        fullpath: &str,
+        values: Vec<serde_json::Value>,
+    ) -> Vec<A> {
         let mut out = Vec::new();
-        for value in values {
+        for (idx, value) in values.into_iter().enumerate() {
+            // entries are designated by their id in the issues, or by their position when it is missing
+            let entry = match value.get("id").and_then(|i| i.as_str()) {
+                Some(id) => id.to_string(),
+                None => idx.to_string(),
+            };
             // for each entry, try to resolve it as a raw configuration value, failing otherwise
             match serde_json::from_value(value) {
-                Err(rr) => logs.error(format!("when resolving entry from {}: {}", fullpath, rr)),
+                Err(rr) => logs.error_at(
+                    format!("{}[{}]", key, entry),
+                    format!("when resolving entry from {}: {}", fullpath, rr),
+                ),
                 Ok(v) => out.push(v),
             }
         }
@@ -260,14 +923,49 @@ impl Config {
         let mut bjson = PathBuf::from(basepath);
         bjson.push("json");
 
-        let securitypolicy = Config::load_config_file(logs, &bjson, "securitypolicy.json");
-        let globalfilters = Config::load_config_file(logs, &bjson, "globalfilter-lists.json");
-        let limits = Config::load_config_file(logs, &bjson, "limits.json");
-        let acls = Config::load_config_file(logs, &bjson, "acl-profiles.json");
-        let contentfilterprofiles = Config::load_config_file(logs, &bjson, "contentfilter-profiles.json");
-        let contentfiltergroups = Config::load_config_file(logs, &bjson, "contentfilter-groups.json");
-        let contentfilterrules = Config::load_config_file(logs, &bjson, "contentfilter-rules.json");
-        let flows = Config::load_config_file(logs, &bjson, "flow-control.json");
+        Some(Config::load(logs, last_mod, &ConfigSource::Directory(bjson)))
+    }
+
+    /// loads a full configuration bundle from a json object, whose keys are the configuration file names
+    /// without the `.json` extension (`securitypolicy`, `acl-profiles`, `limits`, ...)
+    pub fn from_json(logs: &mut Logs, blob: &str) -> Option<(Config, ContentFilterRules)> {
+        let values: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(blob) {
+            Ok(vs) => vs,
+            Err(rr) => {
+                logs.error(format!("when parsing the configuration blob: {}", rr));
+                return None;
+            }
+        };
+        logs.debug("Loading new configuration from blob - CFGLOAD");
+        Some(Config::load(logs, SystemTime::now(), &ConfigSource::Blob(&values)))
+    }
+
+    fn load(logs: &mut Logs, last_mod: SystemTime, source: &ConfigSource) -> (Config, ContentFilterRules) {
+        let securitypolicy = Config::load_config_entries(logs, source, "securitypolicy.json");
+        let globalfilters = Config::load_config_entries(logs, source, "globalfilter-lists.json");
+        let limits = Config::load_config_entries(logs, source, "limits.json");
+        let acls = Config::load_config_entries(logs, source, "acl-profiles.json");
+        let contentfilterprofiles = Config::load_config_entries(logs, source, "contentfilter-profiles.json");
+        let contentfiltergroups = Config::load_config_entries(logs, source, "contentfilter-groups.json");
+        let mut contentfilterrules: Vec<RawContentFilterRule> =
+            Config::load_config_entries(logs, source, "contentfilter-rules.json");
+        if let Some(content) = Config::load_optional_config_text(logs, source, CRS_RULES_FILE) {
+            let known: HashSet<String> = contentfilterrules.iter().map(|r| r.id.clone()).collect();
+            for rule in parse_secrules(logs, &content) {
+                if known.contains(&rule.id) {
+                    logs.warning_at(
+                        format!("contentfilter-rules-crs[{}]", rule.id),
+                        "rule skipped, its id is already used in contentfilter-rules",
+                    );
+                } else {
+                    contentfilterrules.push(rule);
+                }
+            }
+        }
+        let flows = Config::load_config_entries(logs, source, "flow-control.json");
+        let tlsfingerprints = Config::load_optional_config_entries(logs, source, "tls-fingerprints.json");
+        let responsetemplates = Config::load_optional_config_entries(logs, source, "response-templates.json");
+        let settings = Config::load_optional_config_object(logs, source, "settings.json");
 
         let container_name = std::fs::read_to_string("/etc/hostname")
             .ok()
@@ -284,17 +982,22 @@ impl Config {
             contentfiltergroups,
             container_name,
             flows,
+            tlsfingerprints,
+            responsetemplates,
+            settings,
         );
-        let hsdb = resolve_rules(contentfilterrules, &config.content_filter_groups).unwrap_or_else(|rr| {
-            logs.error(rr);
+        let hsdb = resolve_rules(logs, contentfilterrules, &config.content_filter_groups).unwrap_or_else(|rr| {
+            logs.error_at("contentfilter-rules".to_string(), rr);
             ContentFilterRules::empty()
         });
-        Some((config, hsdb))
+        (config, hsdb)
     }
 
     pub fn empty() -> Config {
         Config {
             securitypolicies: Vec::new(),
+            securitypolicies_set: None,
+            securitypolicies_index: HostIndex::default(),
             globalfilters: Vec::new(),
             last_mod: SystemTime::UNIX_EPOCH,
             default: None,
@@ -302,6 +1005,372 @@ impl Config {
             flows: HashMap::new(),
             content_filter_profiles: HashMap::new(),
             content_filter_groups: HashMap::new(),
+            acl_networks: Vec::new(),
+            tls_fingerprints: HashMap::new(),
+            response_templates: HashMap::new(),
+            settings: Settings::default(),
+            baseline_policies: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> serde_json::Value {
+        let content = std::fs::read_to_string(format!("../../config/json/{}.json", name)).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    /// writes a configuration directory, with the fixtures and the given security policy match
+    fn write_config_dir(dir: &Path, hostmap_id: &str, hostmap_match: &str) {
+        // the directory is recreated, so that its modification time changes
+        let _ = std::fs::remove_dir_all(dir);
+        let json = dir.join("json");
+        std::fs::create_dir_all(&json).unwrap();
+        for name in &[
+            "limits",
+            "acl-profiles",
+            "contentfilter-profiles",
+            "contentfilter-groups",
+            "flow-control",
+            "contentfilter-rules",
+        ] {
+            std::fs::write(json.join(format!("{}.json", name)), fixture(name).to_string()).unwrap();
         }
+        std::fs::write(json.join("globalfilter-lists.json"), "[]").unwrap();
+        let mut securitypolicy = fixture("securitypolicy");
+        let mut hostmap = securitypolicy[0].clone();
+        hostmap["id"] = serde_json::json!(hostmap_id);
+        hostmap["match"] = serde_json::json!(hostmap_match);
+        securitypolicy.as_array_mut().unwrap().push(hostmap);
+        std::fs::write(json.join("securitypolicy.json"), securitypolicy.to_string()).unwrap();
+    }
+
+    #[test]
+    fn validation_issues() {
+        let dir = std::env::temp_dir().join(format!("curiefense-validate-{}", std::process::id()));
+        write_config_dir(&dir, "bad", "(unclosed");
+        let mut profiles = fixture("contentfilter-profiles");
+        profiles[0]["args"]["names"] = serde_json::json!([
+            {"key": "ok", "reg": "^[0-9]+$", "restrict": false},
+            {"key": "broken", "reg": "[a-", "restrict": false},
+        ]);
+        let json = dir.join("json");
+        std::fs::write(json.join("contentfilter-profiles.json"), profiles.to_string()).unwrap();
+        let mut limits: Vec<serde_json::Value> = serde_json::from_value(fixture("limits")).unwrap();
+        limits.push(serde_json::json!({"id": "incomplete"}));
+        std::fs::write(json.join("limits.json"), serde_json::Value::from(limits).to_string()).unwrap();
+
+        let issues = validate_config(dir.to_str().unwrap());
+        let components: Vec<Option<&str>> = issues.iter().map(|i| i.component.as_deref()).collect();
+        assert!(components.contains(&Some("securitypolicy[bad].match")), "{:?}", issues);
+        assert!(components.contains(&Some("contentfilter-profiles[__default__].args.names[1].reg")));
+        assert!(components.contains(&Some("limits[incomplete]")));
+        assert!(issues.iter().all(|i| i.severity >= LogLevel::Warning));
+
+        // the plain logs carry the same information
+        let mut logs = Logs::default();
+        Config::load(&mut logs, SystemTime::now(), &ConfigSource::Directory(json));
+        let lines = logs.to_stringvec();
+        let expected = "securitypolicy[bad].match: Invalid regex";
+        assert!(lines.iter().any(|l| l.contains(expected)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reload_rollback() {
+        let dir = std::env::temp_dir().join(format!("curiefense-reload-{}", std::process::id()));
+        let basepath = dir.to_str().unwrap();
+        let config = RwLock::new(Config::empty());
+        let hsdb = RwLock::new(None);
+
+        write_config_dir(&dir, "good", "^example\\.com$");
+        let report = reload_config_into(&config, &hsdb, basepath).unwrap();
+        assert!(report.reloaded);
+        assert_eq!(report.securitypolicies.added, vec!["__default__", "good"]);
+        assert!(!report.content_filter_rules.added.is_empty());
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write_config_dir(&dir, "bad", "(unclosed");
+        match reload_config_into(&config, &hsdb, basepath) {
+            Err(ReloadError::Invalid(problems)) => assert!(problems.iter().any(|p| p.contains("Invalid regex"))),
+            r => panic!("unexpected reload result {:?}", r),
+        }
+        let cfg = config.read().unwrap();
+        assert_eq!(cfg.securitypolicies.len(), 1);
+        assert_eq!(cfg.securitypolicies[0].inner.id, "good");
+        assert!(hsdb.read().unwrap().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_reloads() {
+        let dir = std::env::temp_dir().join(format!("curiefense-concurrent-{}", std::process::id()));
+        let basepath = dir.to_str().unwrap();
+        let config = RwLock::new(Config::empty());
+        let hsdb = RwLock::new(None);
+        write_config_dir(&dir, "good", "^example\\.com$");
+
+        // the configuration is only built once, the other reloads see it is up to date
+        let reloaded = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| reload_config_into(&config, &hsdb, basepath).unwrap().reloaded))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|r| *r).count()
+        });
+        assert_eq!(reloaded, 1);
+        assert_eq!(config.read().unwrap().securitypolicies[0].inner.id, "good");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tenant_reload() {
+        let dir = std::env::temp_dir().join(format!("curiefense-tenants-{}", std::process::id()));
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        write_config_dir(&first, "first", "^first\\.com$");
+        write_config_dir(&second, "second", "(unclosed");
+
+        let hostmaps = |tenant: &str| -> Vec<String> {
+            let tcfg = tenant_config(tenant).unwrap();
+            let cfg = tcfg.config.read().unwrap();
+            cfg.securitypolicies.iter().map(|m| m.inner.id.clone()).collect()
+        };
+        let report = reload_tenant_config("tenant-first", first.to_str().unwrap()).unwrap();
+        assert!(report.reloaded);
+        assert_eq!(hostmaps("tenant-first"), vec!["first"]);
+        // a tenant is only created by a valid configuration
+        assert!(reload_tenant_config("tenant-second", second.to_str().unwrap()).is_err());
+        assert!(tenant_config("tenant-second").is_none());
+
+        write_config_dir(&second, "second", "^second\\.com$");
+        reload_tenant_config("tenant-second", second.to_str().unwrap()).unwrap();
+        assert_eq!(hostmaps("tenant-second"), vec!["second"]);
+        assert_eq!(hostmaps("tenant-first"), vec!["first"]);
+        assert!(CONFIG.read().unwrap().securitypolicies.is_empty());
+
+        assert!(remove_tenant_config("tenant-second").unwrap());
+        assert!(tenant_config("tenant-second").is_none());
+        assert!(!remove_tenant_config("tenant-second").unwrap());
+        assert_eq!(hostmaps("tenant-first"), vec!["first"]);
+
+        // concurrent first loads all apply to the same tenant
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let path = first.to_str().unwrap().to_string();
+                std::thread::spawn(move || {
+                    reload_tenant_config("tenant-concurrent", &path).unwrap();
+                    tenant_config("tenant-concurrent").unwrap()
+                })
+            })
+            .collect();
+        let tenants: Vec<Arc<TenantConfig>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(tenants.iter().all(|t| Arc::ptr_eq(t, &tenants[0])));
+        assert_eq!(hostmaps("tenant-concurrent"), vec!["first"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_from_blob() {
+        let mut blob = serde_json::Map::new();
+        for name in &[
+            "securitypolicy",
+            "limits",
+            "acl-profiles",
+            "contentfilter-profiles",
+            "contentfilter-groups",
+            "flow-control",
+            "contentfilter-rules",
+        ] {
+            blob.insert(name.to_string(), fixture(name));
+        }
+        blob.insert("globalfilter-lists".to_string(), serde_json::json!([]));
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &serde_json::Value::Object(blob.clone()).to_string()).unwrap();
+        assert_eq!(logs.logs.len(), 1, "{:?}", logs.to_stringvec());
+        assert!(cfg.default.is_some());
+        assert!(cfg.content_filter_profiles.contains_key("__default__"));
+        assert!(!cfg.flows.is_empty());
+
+        blob.remove("limits");
+        let mut logs = Logs::default();
+        assert!(Config::from_json(&mut logs, &serde_json::Value::Object(blob).to_string()).is_some());
+        assert!(logs.to_stringvec().iter().any(|l| l.contains("limits")));
+
+        let mut logs = Logs::default();
+        assert!(Config::from_json(&mut logs, "[]").is_none());
+    }
+
+    #[test]
+    fn path_active_overrides() {
+        use crate::securitypolicy::match_securitypolicy;
+        use crate::utils::{map_request, RequestMeta};
+
+        let mut blob = serde_json::Map::new();
+        for name in &["limits", "acl-profiles", "contentfilter-profiles", "contentfilter-groups", "flow-control"] {
+            blob.insert(name.to_string(), fixture(name));
+        }
+        let mut securitypolicy = fixture("securitypolicy");
+        let parent = &mut securitypolicy[0]["map"][0];
+        parent["acl_active"] = serde_json::json!(true);
+        parent["content_filter_active"] = serde_json::json!(true);
+        let mut child = parent.clone();
+        child["match"] = serde_json::json!("^/noisy");
+        child["name"] = serde_json::json!("noisy");
+        child["content_filter_active"] = serde_json::json!(false);
+        child.as_object_mut().unwrap().remove("acl_active");
+        securitypolicy[0]["map"].as_array_mut().unwrap().push(child);
+        blob.insert("securitypolicy".to_string(), securitypolicy);
+        blob.insert("globalfilter-lists".to_string(), serde_json::json!([]));
+        blob.insert("contentfilter-rules".to_string(), fixture("contentfilter-rules"));
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &serde_json::Value::Object(blob).to_string()).unwrap();
+        let matched = |path: &str| {
+            let meta = RequestMeta {
+                authority: Some("localhost".to_string()),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                extra: HashMap::new(),
+            };
+            let rinfo = map_request(
+                &mut Logs::default(),
+                "127.0.0.1".to_string(),
+                HashMap::new(),
+                meta,
+                None,
+            )
+            .unwrap();
+            let (_, policy) = match_securitypolicy(&rinfo, &cfg, &mut Logs::default()).unwrap();
+            (policy.name.clone(), policy.acl_active, policy.content_filter_active)
+        };
+        assert_eq!(matched("/"), ("default".to_string(), true, true));
+        // the ACL flag is inherited from the default entry
+        assert_eq!(matched("/noisy/endpoint"), ("noisy".to_string(), true, false));
+    }
+
+    #[test]
+    fn crs_rules() {
+        let mut blob = serde_json::Map::new();
+        for name in &[
+            "limits",
+            "acl-profiles",
+            "contentfilter-profiles",
+            "contentfilter-groups",
+            "flow-control",
+            "securitypolicy",
+            "contentfilter-rules",
+        ] {
+            blob.insert(name.to_string(), fixture(name));
+        }
+        blob.insert("globalfilter-lists".to_string(), serde_json::json!([]));
+        blob.insert(
+            "contentfilter-rules-crs".to_string(),
+            serde_json::json!(concat!(
+                "SecRule ARGS \"@rx crs-test-[0-9]+\" \"id:990001,msg:'crs test',severity:'WARNING'\"\n",
+                "SecRule ARGS \"@rx duplicate\" \"id:100000\"\n",
+            )),
+        );
+
+        let mut logs = Logs::default();
+        let (_, hsdb) = Config::from_json(&mut logs, &serde_json::Value::Object(blob).to_string()).unwrap();
+        let rule = hsdb.ids.iter().find(|r| r.id == "990001").unwrap();
+        assert_eq!((rule.msg.as_str(), rule.severity), ("crs test", 3));
+        assert_eq!(hsdb.ids.iter().filter(|r| r.id == "100000").count(), 1);
+        assert!(logs
+            .logs
+            .iter()
+            .any(|l| l.component.as_deref() == Some("contentfilter-rules-crs[100000]")));
+    }
+
+    #[test]
+    fn security_policy_arg_limits() {
+        let mut blob = serde_json::Map::new();
+        for name in &["limits", "acl-profiles", "contentfilter-profiles", "contentfilter-groups", "flow-control"] {
+            blob.insert(name.to_string(), fixture(name));
+        }
+        let mut securitypolicy = fixture("securitypolicy");
+        securitypolicy[0]["map"][0]["max_args"] = serde_json::json!(3);
+        securitypolicy[0]["map"][0]["max_total_args_length"] = serde_json::json!(100);
+        blob.insert("securitypolicy".to_string(), securitypolicy);
+        blob.insert("globalfilter-lists".to_string(), serde_json::json!([]));
+        blob.insert("contentfilter-rules".to_string(), fixture("contentfilter-rules"));
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &serde_json::Value::Object(blob).to_string()).unwrap();
+        let profile = cfg.default.unwrap().default.unwrap().content_filter_profile;
+        assert_eq!(profile.sections.args.max_count, 3);
+        assert_eq!(profile.max_total_args_length, Some(100));
+        // not overriden
+        let base = &cfg.content_filter_profiles["__default__"];
+        assert_eq!(profile.sections.args.max_length, base.sections.args.max_length);
+        assert_eq!(base.max_total_args_length, None);
+    }
+
+    #[test]
+    fn canary_resolution() {
+        let mut blob = serde_json::Map::new();
+        for name in &[
+            "limits",
+            "acl-profiles",
+            "contentfilter-profiles",
+            "contentfilter-groups",
+            "flow-control",
+        ] {
+            blob.insert(name.to_string(), fixture(name));
+        }
+        let mut securitypolicy = fixture("securitypolicy");
+        let entry = &mut securitypolicy[0]["map"][0];
+        entry["acl_active"] = serde_json::json!(true);
+        entry["max_args"] = serde_json::json!(3);
+        entry["canary"] = serde_json::json!({"percentage": 5, "content_filter_active": true});
+        let mut other = entry.clone();
+        other["match"] = serde_json::json!("^/api");
+        other["name"] = serde_json::json!("api");
+        other["canary"] = serde_json::json!({"percentage": 250, "name": "strict api", "acl_profile": "unknown"});
+        other.as_object_mut().unwrap().remove("acl_active");
+        securitypolicy[0]["map"].as_array_mut().unwrap().push(other);
+        blob.insert("securitypolicy".to_string(), securitypolicy);
+        blob.insert("globalfilter-lists".to_string(), serde_json::json!([]));
+        blob.insert("contentfilter-rules".to_string(), fixture("contentfilter-rules"));
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &serde_json::Value::Object(blob).to_string()).unwrap();
+        let hostmap = cfg.default.unwrap();
+        let default = hostmap.default.unwrap();
+        let canary = match default.rollout {
+            Rollout::Stable(c) => c,
+            r => panic!("unexpected rollout {:?}", r),
+        };
+        assert_eq!(canary.buckets, 500);
+        assert_eq!(canary.policy.name, "default-canary");
+        assert!(canary.policy.acl_active);
+        assert!(canary.policy.content_filter_active);
+        assert_eq!(canary.policy.acl_profile.id, default.acl_profile.id);
+        assert_eq!(canary.policy.content_filter_profile.sections.args.max_count, 3);
+        assert!(matches!(canary.policy.rollout, Rollout::Canary));
+
+        let api = &hostmap.entries[0].inner;
+        let canary = match &api.rollout {
+            Rollout::Stable(c) => c,
+            r => panic!("unexpected rollout {:?}", r),
+        };
+        assert_eq!(canary.buckets, ROLLOUT_BUCKETS);
+        assert_eq!(canary.policy.name, "strict api");
+        // inherited from the default entry, as for the entry itself
+        assert!(api.acl_active);
+        assert!(canary.policy.acl_active);
+        let lines = logs.to_stringvec();
+        assert!(
+            lines.iter().any(|l| l.contains(".map[1].canary.percentage")),
+            "{:?}",
+            lines
+        );
+        assert!(lines.iter().any(|l| l.contains(".map[1].canary.acl_profile")));
     }
 }
@@ -1,12 +1,21 @@
-use crate::config::raw::{RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterProperties, RawContentFilterRule, RawContentFilterGroup};
-use crate::logs::Logs;
+use crate::config::raw::{RawArgSchema, RawContentFilterEntryMatch, RawContentFilterNormalization, RawContentFilterProfile, RawContentFilterProperties, RawContentFilterRule, RawContentFilterGroup, UnknownArgs};
+use crate::config::utils::{glob_regex, Matching};
+use crate::contentfilter::rule_counter;
+use crate::jsonpath::JsonSelector;
+use crate::learning::{type_pattern, FREE_TEXT};
+use crate::logs::{LogLevel, Logs};
+use anyhow::Context;
 
-use hyperscan::prelude::{pattern, Builder, CompileFlags, Pattern, Patterns, VectoredDatabase};
-use hyperscan::Vectored;
-use regex::Regex;
+use hyperscan::prelude::{
+    pattern, BlockDatabase, Builder, CompileFlags, Pattern, Patterns, StreamingDatabase, VectoredDatabase,
+};
+use hyperscan::{StreamingMode, Vectored};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Section<A> {
@@ -21,33 +30,185 @@ pub struct ContentFilterProfile {
     pub id: String,
     pub name: String,
     pub ignore_alphanum: bool,
+    /// GraphQL queries that are nested deeper are blocked
+    pub graphql_max_depth: Option<usize>,
+    /// maximum size of all the arguments, names and values
+    pub max_total_args_length: Option<usize>,
+    /// anomaly scoring threshold, when set, the signature matches only block when their total score reaches it
+    pub blocking_threshold: Option<u32>,
+    /// blocks the requests with malformed fields, see `RequestInfo::field_anomalies`
+    pub block_invalid_characters: bool,
+    /// signature ids that are not matched against the arguments whose name matches the glob pattern
+    pub arg_exclusions: Vec<Matching<HashSet<String>>>,
+    pub normalization: ContentFilterNormalization,
+    /// the response phase signature checks, see `response::response_stage`
+    pub inspect_responses: bool,
+    pub max_response_size: Option<usize>,
+    /// the values are only scanned by libinjection and the signatures up to this length, in bytes
+    pub max_scan_length: Option<usize>,
+    pub regex_limits: RegexLimits,
+    pub arg_schema: Option<ArgSchema>,
     pub sections: Section<ContentFilterSection>,
 }
 
+/// the declared arguments of a profile, see `contentfilter::schema_violations`
+#[derive(Debug, Clone)]
+pub struct ArgSchema {
+    pub args: HashMap<String, ArgSpec>,
+    pub unknown_args: UnknownArgs,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArgSpec {
+    pub arg_type: String,
+    /// the pattern of the value classifier of the type, None for free text
+    pub regex: Option<Regex>,
+    pub max_length: Option<usize>,
+}
+
+impl ArgSchema {
+    /// the types are resolved with the value classifiers of the learning mode, as they are when the profile is loaded
+    fn resolve(raw: RawArgSchema, limits: &RegexLimits) -> anyhow::Result<Self> {
+        let mut args = HashMap::new();
+        for (name, spec) in raw.args {
+            let arg_type = spec.arg_type.unwrap_or_else(|| FREE_TEXT.to_string());
+            let regex = if arg_type == FREE_TEXT {
+                None
+            } else {
+                let path = || ProfilePath(format!("arg_schema.args.{}.type", name));
+                let pattern = type_pattern(&arg_type)
+                    .ok_or_else(|| anyhow::anyhow!("unknown type {}", arg_type))
+                    .with_context(path)?;
+                Some(limits.build(&pattern, false).with_context(path)?)
+            };
+            args.insert(
+                name,
+                ArgSpec {
+                    arg_type,
+                    regex,
+                    max_length: spec.max_length,
+                },
+            );
+        }
+        Ok(ArgSchema {
+            args,
+            unknown_args: raw.unknown_args,
+        })
+    }
+}
+
+/// the limits of the regex engine for the name and value regexes of a profile, the defaults of the `regex` crate being
+/// used when they are not set
+///
+/// A regex that does not fit in `size_limit` fails to compile, and a search that fills `dfa_size_limit` falls back to a
+/// slower engine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegexLimits {
+    pub size_limit: Option<usize>,
+    pub dfa_size_limit: Option<usize>,
+}
+
+impl RegexLimits {
+    fn build(&self, pattern: &str, case_insensitive: bool) -> Result<Regex, regex::Error> {
+        let mut builder = RegexBuilder::new(pattern);
+        builder.case_insensitive(case_insensitive);
+        if let Some(limit) = self.size_limit {
+            builder.size_limit(limit);
+        }
+        if let Some(limit) = self.dfa_size_limit {
+            builder.dfa_size_limit(limit);
+        }
+        builder.build()
+    }
+
+    fn build_set<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        patterns: I,
+        case_insensitive: bool,
+    ) -> Result<RegexSet, regex::Error> {
+        let mut builder = RegexSetBuilder::new(patterns);
+        builder.case_insensitive(case_insensitive);
+        if let Some(limit) = self.size_limit {
+            builder.size_limit(limit);
+        }
+        if let Some(limit) = self.dfa_size_limit {
+            builder.dfa_size_limit(limit);
+        }
+        builder.build()
+    }
+}
+
+/// transformations applied to the argument values before they are matched, to defeat encoding tricks
+///
+/// The default is to match the values as they were decoded once by the query and body parsers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentFilterNormalization {
+    /// maximum number of additional percent-decoding passes, overlong UTF-8 sequences are decoded as well
+    pub decode_passes: usize,
+    /// unicode NFKC normalization, so that fullwidth letters match their ASCII counterparts
+    pub nfkc: bool,
+    pub lowercase: bool,
+}
+
+impl ContentFilterNormalization {
+    pub fn is_identity(&self) -> bool {
+        self == &ContentFilterNormalization::default()
+    }
+
+    fn resolve(raw: Option<RawContentFilterNormalization>) -> Self {
+        raw.map(|r| ContentFilterNormalization {
+            decode_passes: r.decode_passes,
+            nfkc: r.nfkc,
+            lowercase: r.lowercase,
+        })
+        .unwrap_or_default()
+    }
+}
+
 impl Default for ContentFilterProfile {
     fn default() -> Self {
         ContentFilterProfile {
             id: "__default__".to_string(),
             name: "default contentfilter".to_string(),
             ignore_alphanum: true,
+            graphql_max_depth: None,
+            max_total_args_length: None,
+            blocking_threshold: None,
+            block_invalid_characters: false,
+            arg_exclusions: Vec::new(),
+            normalization: ContentFilterNormalization::default(),
+            inspect_responses: false,
+            max_response_size: None,
+            max_scan_length: None,
+            regex_limits: RegexLimits::default(),
+            arg_schema: None,
             sections: Section {
                 headers: ContentFilterSection {
                     max_count: 42,
                     max_length: 1024,
+                    length_exempt: HashSet::new(),
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    regex_set: None,
+                    case_sensitive: CaseSensitivity::default_for(SectionIdx::Headers),
                 },
                 args: ContentFilterSection {
                     max_count: 512,
                     max_length: 1024,
+                    length_exempt: HashSet::new(),
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    regex_set: None,
+                    case_sensitive: CaseSensitivity::default_for(SectionIdx::Args),
                 },
                 cookies: ContentFilterSection {
                     max_count: 42,
                     max_length: 1024,
+                    length_exempt: HashSet::new(),
                     names: HashMap::new(),
                     regex: Vec::new(),
+                    regex_set: None,
+                    case_sensitive: CaseSensitivity::default_for(SectionIdx::Cookies),
                 },
             },
         }
@@ -58,8 +219,31 @@ impl Default for ContentFilterProfile {
 pub struct ContentFilterSection {
     pub max_count: usize,
     pub max_length: usize,
+    /// lowercased names of the entries whose values are not limited by `max_length`
+    pub length_exempt: HashSet<String>,
     pub names: HashMap<String, ContentFilterEntryMatch>,
     pub regex: Vec<(Regex, ContentFilterEntryMatch)>,
+    /// all the `regex` name patterns, in the same order, so that a parameter name is only scanned once
+    pub regex_set: Option<RegexSet>,
+    /// when the names are not case sensitive, the `names` keys are lowercased
+    pub case_sensitive: CaseSensitivity,
+}
+
+/// how the entry names and values of a section are compared with the name entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaseSensitivity {
+    pub names: bool,
+    pub values: bool,
+}
+
+impl CaseSensitivity {
+    /// header names are not case sensitive, the cookie and argument names, and all the values, are
+    pub fn default_for(idx: SectionIdx) -> Self {
+        CaseSensitivity {
+            names: idx != SectionIdx::Headers,
+            values: true,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -75,6 +259,23 @@ pub enum SectionIdx {
     Headers,
     Cookies,
     Args,
+    /// the query string as it was received, only scanned by the rules that list it in their `sections`
+    RawQuery,
+}
+
+/// the sections that the rules without a `sections` list apply to
+pub const DEFAULT_RULE_SECTIONS: [SectionIdx; 3] = [SectionIdx::Headers, SectionIdx::Cookies, SectionIdx::Args];
+
+impl SectionIdx {
+    pub fn parse(name: &str) -> Option<SectionIdx> {
+        match name {
+            "headers" => Some(SectionIdx::Headers),
+            "cookies" => Some(SectionIdx::Cookies),
+            "args" => Some(SectionIdx::Args),
+            "raw_query" => Some(SectionIdx::RawQuery),
+            _ => None,
+        }
+    }
 }
 
 impl<A> Section<A> {
@@ -82,7 +283,8 @@ impl<A> Section<A> {
         match idx {
             SectionIdx::Headers => &self.headers,
             SectionIdx::Cookies => &self.cookies,
-            SectionIdx::Args => &self.args,
+            // the raw query is made of the arguments, and shares their settings
+            SectionIdx::Args | SectionIdx::RawQuery => &self.args,
         }
     }
 
@@ -90,7 +292,7 @@ impl<A> Section<A> {
         match idx {
             SectionIdx::Headers => &mut self.headers,
             SectionIdx::Cookies => &mut self.cookies,
-            SectionIdx::Args => &mut self.args,
+            SectionIdx::Args | SectionIdx::RawQuery => &mut self.args,
         }
     }
 }
@@ -110,7 +312,9 @@ where
 
 pub struct ContentFilterRules {
     pub db: VectoredDatabase,
-    pub ids: Vec<ContentFilterRule>,
+    /// the same patterns, compiled for stream scanning, shared with the open streams
+    pub stream_db: Option<Arc<StreamingDatabase>>,
+    pub ids: Arc<Vec<ContentFilterRule>>,
 }
 
 impl ContentFilterRules {
@@ -118,7 +322,8 @@ impl ContentFilterRules {
         let pattern: Pattern = pattern! { "^TEST$" };
         ContentFilterRules {
             db: pattern.build().unwrap(),
-            ids: Vec::new(),
+            stream_db: pattern.build().ok().map(Arc::new),
+            ids: Arc::new(Vec::new()),
         }
     }
 }
@@ -153,10 +358,12 @@ impl ContentFilterGroup {
 
 fn mk_entry_match(
     em: RawContentFilterEntryMatch,
+    case_sensitive: CaseSensitivity,
+    limits: &RegexLimits,
     content_filter_groups: &HashMap<String, ContentFilterGroup>
 ) -> anyhow::Result<(String, ContentFilterEntryMatch)> {
     Ok((
-        em.key,
+        if case_sensitive.names { em.key } else { em.key.to_lowercase() },
         ContentFilterEntryMatch {
             restrict: em.restrict,
             exclusions: em.exclusions.unwrap_or_else(|| HashMap::new())
@@ -174,63 +381,140 @@ fn mk_entry_match(
                 })
                 .flatten()
                 .collect::<HashSet<_>>(),
-            reg: em.reg.map(|s| Regex::new(&s)).transpose()?, // lol not Haskell
+            reg: em.reg.map(|s| limits.build(&s, !case_sensitive.values)).transpose()?,
         },
     ))
 }
 
+/// the part of a content filter profile an error is about, relative to the profile, such as `args.names[2].reg`
+#[derive(Debug)]
+struct ProfilePath(String);
+
+impl std::fmt::Display for ProfilePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "in {}", self.0)
+    }
+}
+
 fn mk_section(
+    idx: SectionIdx,
     props: RawContentFilterProperties,
     max_length: usize, max_count: usize,
+    length_exempt: &[String],
+    limits: &RegexLimits,
     content_filter_groups: &HashMap<String, ContentFilterGroup>
 ) -> anyhow::Result<ContentFilterSection> {
+    let name = match idx {
+        SectionIdx::Headers => "headers",
+        SectionIdx::Cookies => "cookies",
+        SectionIdx::Args => "args",
+        SectionIdx::RawQuery => "raw_query",
+    };
+    let default = CaseSensitivity::default_for(idx);
+    let case_sensitive = CaseSensitivity {
+        names: props.case_sensitive_names.unwrap_or(default.names),
+        values: props.case_sensitive_values.unwrap_or(default.values),
+    };
     let mnames: anyhow::Result<HashMap<String, ContentFilterEntryMatch>> = props
         .names
         .into_iter()
-        .map(|e| {
-            mk_entry_match(e, content_filter_groups)
+        .enumerate()
+        .map(|(i, e)| {
+            mk_entry_match(e, case_sensitive, limits, content_filter_groups)
+                .context(ProfilePath(format!("{}.names[{}].reg", name, i)))
         })
         .collect();
     let mregex: anyhow::Result<Vec<(Regex, ContentFilterEntryMatch)>> = props
         .regex
         .into_iter()
-        .map(|e| {
-            let (s, v) = mk_entry_match(e, content_filter_groups)?;
-            let re = Regex::new(&s)?;
+        .enumerate()
+        .map(|(i, e)| {
+            let names_sensitive = CaseSensitivity {
+                names: true,
+                ..case_sensitive
+            };
+            let (s, v) = mk_entry_match(e, names_sensitive, limits, content_filter_groups)
+                .context(ProfilePath(format!("{}.regex[{}].reg", name, i)))?;
+            let re = limits
+                .build(&s, !case_sensitive.names)
+                .context(ProfilePath(format!("{}.regex[{}].key", name, i)))?;
             Ok((re, v))
         })
         .collect();
+    let regex = mregex?;
+    // falls back to a linear scan when the set can't be built
+    let regex_set = if regex.is_empty() {
+        None
+    } else {
+        limits
+            .build_set(regex.iter().map(|(re, _)| re.as_str()), !case_sensitive.names)
+            .ok()
+    };
     Ok(ContentFilterSection {
         max_count,
         max_length,
+        length_exempt: length_exempt.iter().map(|n| n.to_lowercase()).collect(),
         names: mnames?,
-        regex: mregex?,
+        regex,
+        regex_set,
+        case_sensitive,
     })
 }
 
+fn mk_arg_exclusions(raw: HashMap<String, Vec<String>>) -> Vec<Matching<HashSet<String>>> {
+    raw.into_iter()
+        .map(|(name, ids)| Matching {
+            matcher: glob_regex(&name),
+            inner: ids.into_iter().collect(),
+        })
+        .collect()
+}
+
 fn convert_entry(
     entry: RawContentFilterProfile,
     content_filter_groups: &HashMap<String, ContentFilterGroup>
 ) -> anyhow::Result<(String, ContentFilterProfile)> {
+    let regex_limits = RegexLimits {
+        size_limit: entry.regex_size_limit,
+        dfa_size_limit: entry.regex_dfa_size_limit,
+    };
     Ok((
         entry.id.clone(),
         ContentFilterProfile {
             id: entry.id,
             name: entry.name,
             ignore_alphanum: entry.ignore_alphanum,
+            graphql_max_depth: entry.graphql_max_depth,
+            max_total_args_length: entry.max_total_args_length,
+            blocking_threshold: entry.blocking_threshold,
+            block_invalid_characters: entry.block_invalid_characters,
+            arg_exclusions: mk_arg_exclusions(entry.arg_exclusions),
+            normalization: ContentFilterNormalization::resolve(entry.normalization),
+            inspect_responses: entry.inspect_responses,
+            max_response_size: entry.max_response_size,
+            max_scan_length: entry.max_scan_length,
+            regex_limits,
+            arg_schema: entry.arg_schema.map(|raw| ArgSchema::resolve(raw, &regex_limits)).transpose()?,
             sections: Section {
-                headers: mk_section(entry.headers, entry.max_header_length, entry.max_headers_count,
-                    content_filter_groups)?,
-                cookies: mk_section(entry.cookies, entry.max_cookie_length, entry.max_cookies_count,
-                    content_filter_groups)?,
-                args: mk_section(entry.args, entry.max_arg_length, entry.max_args_count,
-                    content_filter_groups)?,
+                headers: mk_section(SectionIdx::Headers, entry.headers, entry.max_header_length, entry.max_headers_count,
+                    &entry.length_exempt_headers, &regex_limits, content_filter_groups)?,
+                cookies: mk_section(SectionIdx::Cookies, entry.cookies, entry.max_cookie_length, entry.max_cookies_count,
+                    &[], &regex_limits, content_filter_groups)?,
+                args: mk_section(SectionIdx::Args, entry.args, entry.max_arg_length, entry.max_args_count,
+                    &[], &regex_limits, content_filter_groups)?,
             },
         },
     ))
 }
 
 impl ContentFilterProfile {
+    /// the signature is excluded for this argument name by the argument exclusions
+    pub fn arg_excluded(&self, name: &str, rule_id: &str) -> bool {
+        self.arg_exclusions
+            .iter()
+            .any(|e| e.inner.contains(rule_id) && e.matcher.is_match(name))
+    }
+
     pub fn resolve(
         logs: &mut Logs,
         raw: Vec<RawContentFilterProfile>,
@@ -243,7 +527,13 @@ impl ContentFilterProfile {
                 Ok((k, v)) => {
                     out.insert(k, v);
                 }
-                Err(rr) => logs.error(format!("content filter id {}: {:?}", id, rr)),
+                Err(rr) => {
+                    let component = match rr.downcast_ref::<ProfilePath>() {
+                        Some(path) => format!("contentfilter-profiles[{}].{}", id, path.0),
+                        None => format!("contentfilter-profiles[{}]", id),
+                    };
+                    logs.error_at(component, format!("content filter id {}: {:?}", id, rr))
+                }
             }
         }
         out
@@ -261,6 +551,23 @@ pub struct ContentFilterRule {
     pub category: String,
     pub subcategory: String,
     pub groups: HashMap<String, String>,
+    /// weight in anomaly scoring mode, rules without a score are worth the blocking threshold
+    pub score: Option<u32>,
+    /// the rule only applies to the JSON body values selected by this selector
+    pub json_selector: Option<JsonSelector>,
+    /// the sections whose values the rule is matched against, `DEFAULT_RULE_SECTIONS` by default
+    pub sections: Vec<SectionIdx>,
+    /// the number of matches of the rule, see `contentfilter::content_filter_stats`
+    pub hits: Arc<AtomicU64>,
+    /// the operand, compiled by the regex crate, to report the part of the values that matched, `None` when the
+    /// regex crate does not support it
+    pub substring: Option<Regex>,
+}
+
+impl ContentFilterRule {
+    pub fn applies_to(&self, section: SectionIdx) -> bool {
+        self.sections.contains(&section)
+    }
 }
 
 fn convert_rule(entry: &ContentFilterRule) -> anyhow::Result<Pattern> {
@@ -270,7 +577,21 @@ fn convert_rule(entry: &ContentFilterRule) -> anyhow::Result<Pattern> {
     )
 }
 
+/// compiled size budget of a single rule, when it does not set its own `max_regex_compiled_bytes`
+pub const DEFAULT_MAX_REGEX_COMPILED_BYTES: usize = 8 << 20;
+
+/// size of the database holding only this pattern
+fn compiled_size(pattern: &Pattern) -> anyhow::Result<usize> {
+    let db: BlockDatabase = pattern.build()?;
+    db.size()
+}
+
+/// builds the rules database
+///
+/// rules that do not compile, or whose compiled size exceeds their budget, are skipped and reported in the logs,
+/// at the info level so that a reload does not fail because of them
 pub fn resolve_rules(
+    logs: &mut Logs,
     raws: Vec<RawContentFilterRule>,
     content_filter_groups: &HashMap<String, ContentFilterGroup>
 ) -> anyhow::Result<ContentFilterRules> {
@@ -280,8 +601,42 @@ pub fn resolve_rules(
             rule_id_groups.entry(rule_id.to_string()).or_default().insert(cfg.id.clone(), cfg.name.clone());
         }
     }
-    let rules: Vec<ContentFilterRule> = raws.into_iter().map(|raw| (
-        ContentFilterRule {
+    let mut rules: Vec<ContentFilterRule> = Vec::new();
+    let mut patterns: Vec<Pattern> = Vec::new();
+    for raw in raws {
+        let budget = raw.max_regex_compiled_bytes.unwrap_or(DEFAULT_MAX_REGEX_COMPILED_BYTES);
+        // a rule with a broken selector is skipped, instead of applying to the whole request
+        let json_selector = match raw.json_path.as_deref().map(JsonSelector::parse).transpose() {
+            Ok(selector) => selector,
+            Err(rr) => {
+                logs.warning_at(format!("contentfilter-rules[{}].json_path", raw.id), rr);
+                continue;
+            }
+        };
+        let sections = match &raw.sections {
+            None => DEFAULT_RULE_SECTIONS.to_vec(),
+            Some(names) => {
+                let mut sections = Vec::new();
+                for name in names {
+                    match SectionIdx::parse(name) {
+                        Some(section) => sections.push(section),
+                        None => logs.warning_at(
+                            format!("contentfilter-rules[{}].sections", raw.id),
+                            format!("unknown section {}", name),
+                        ),
+                    }
+                }
+                sections
+            }
+        };
+        // the flags of the hyperscan pattern, see `convert_rule`
+        let substring = RegexBuilder::new(&raw.operand)
+            .case_insensitive(true)
+            .multi_line(true)
+            .dot_matches_new_line(true)
+            .build()
+            .ok();
+        let rule = ContentFilterRule {
             id: raw.id.clone(),
             name: raw.name,
             msg: raw.msg,
@@ -294,12 +649,84 @@ pub fn resolve_rules(
                 Some(groups) => groups,
                 None => HashMap::new(),
             },
+            score: raw.score,
+            json_selector,
+            sections,
+            hits: rule_counter(&raw.id),
+            substring,
+        };
+        let component = format!("contentfilter-rules[{}].operand", rule.id);
+        let pattern = convert_rule(&rule)?;
+        match compiled_size(&pattern) {
+            Err(rr) => logs.log_at(
+                LogLevel::Info,
+                component,
+                format!("rule {} skipped, it does not compile: {}", rule.id, rr),
+            ),
+            Ok(size) if size > budget => logs.log_at(
+                LogLevel::Info,
+                component,
+                format!(
+                    "rule {} skipped, it compiles to {} bytes, over {}",
+                    rule.id, size, budget
+                ),
+            ),
+            Ok(_) => {
+                rules.push(rule);
+                patterns.push(pattern);
+            }
         }
-    )).collect();
-    let patterns: anyhow::Result<Vec<Pattern>> = rules.iter().map(convert_rule).collect();
-    let ptrns: Patterns = Patterns::from_iter(patterns?);
+    }
+    let ptrns: Patterns = Patterns::from_iter(patterns);
     Ok(ContentFilterRules {
         db: ptrns.build::<Vectored>()?,
-        ids: rules,
+        // request bodies can still be checked as a whole when stream compilation fails
+        stream_db: ptrns.build::<StreamingMode>().ok().map(Arc::new),
+        ids: Arc::new(rules),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::content_filter_rule;
+
+    fn mk_rule(id: &str, operand: String, max_regex_compiled_bytes: Option<usize>) -> RawContentFilterRule {
+        RawContentFilterRule {
+            max_regex_compiled_bytes,
+            ..content_filter_rule(id, &operand)
+        }
+    }
+
+    #[test]
+    fn regex_size_limit() {
+        let pattern = "[a-z]{1,500}";
+        assert!(RegexLimits::default().build(pattern, false).is_ok());
+        let limits = RegexLimits {
+            size_limit: Some(1 << 10),
+            dfa_size_limit: None,
+        };
+        assert!(limits.build(pattern, false).is_err());
+        assert!(limits.build("^[a-z]+$", false).is_ok());
+        assert!(limits.build_set([pattern], false).is_err());
+    }
+
+    #[test]
+    fn oversized_rule_skipped() {
+        let alternation: Vec<String> = (0..2000).map(|i| format!("w{}x{}", i, i * 7)).collect();
+        let raws = vec![
+            mk_rule("small", "select.*from".to_string(), None),
+            mk_rule("huge", format!("({})", alternation.join("|")), Some(1 << 12)),
+            mk_rule("unbounded", "union.*select".to_string(), None),
+        ];
+        let mut logs = Logs::default();
+        let rules = resolve_rules(&mut logs, raws, &HashMap::new()).unwrap();
+        let ids: Vec<&str> = rules.ids.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["small", "unbounded"]);
+        assert_eq!(logs.logs.len(), 1, "{:?}", logs.to_stringvec());
+        let component = logs.logs[0].component.as_deref();
+        assert_eq!(component, Some("contentfilter-rules[huge].operand"));
+        // skipping a rule does not make the configuration invalid
+        assert!(logs.logs[0].level < LogLevel::Warning);
+    }
+}
@@ -0,0 +1,414 @@
+/// content filter rules written as ModSecurity `SecRule` directives, such as the OWASP CRS ones
+///
+/// Only the rules that can be expressed as a single pattern are converted. The variables of a rule select the content
+/// filter sections it applies to, the rules that only target some fields of a section being skipped. Decoding
+/// is configured in the content filter profiles, and patterns are case insensitive, so that the transformations
+/// that only decode or lowercase the values are accepted. The other directives and rules are skipped, and reported
+/// in the logs.
+use crate::config::raw::RawContentFilterRule;
+use crate::logs::{LogLevel, Logs};
+
+/// the file holding the rules, next to the json files, or the key of the configuration blob holding them as a string
+pub const CRS_RULES_FILE: &str = "contentfilter-rules-crs.conf";
+
+/// the request fields that the content filter inspects, with the section holding them
+const VARIABLES: &[(&str, &str)] = &[
+    ("ARGS", "args"),
+    ("ARGS_GET", "args"),
+    ("ARGS_POST", "args"),
+    ("ARGS_NAMES", "args"),
+    ("ARGS_GET_NAMES", "args"),
+    ("ARGS_POST_NAMES", "args"),
+    ("REQUEST_HEADERS", "headers"),
+    ("REQUEST_HEADERS_NAMES", "headers"),
+    ("REQUEST_COOKIES", "cookies"),
+    ("REQUEST_COOKIES_NAMES", "cookies"),
+    // the path is not inspected, only the query string of the URI is
+    ("REQUEST_URI", "raw_query"),
+    ("REQUEST_URI_RAW", "raw_query"),
+    ("REQUEST_LINE", "raw_query"),
+    ("QUERY_STRING", "raw_query"),
+    ("REQUEST_BODY", "args"),
+    ("XML", "args"),
+    ("FILES", "args"),
+    ("FILES_NAMES", "args"),
+];
+
+/// transformations that the profile normalization and case insensitive matching cover
+const TRANSFORMATIONS: &[&str] = &["none", "lowercase", "urldecode", "urldecodeuni", "utf8tounicode"];
+
+/// transformations that are not applied, the rule matching fewer values than with ModSecurity
+const IGNORED_TRANSFORMATIONS: &[&str] = &[
+    "htmlentitydecode",
+    "jsdecode",
+    "cssdecode",
+    "escapeseqdecode",
+    "removenulls",
+    "replacenulls",
+    "compresswhitespace",
+    "normalisepath",
+    "normalizepath",
+    "normalisepathwin",
+    "normalizepathwin",
+    "cmdline",
+];
+
+/// the logical lines of the file, continuation lines being joined, with the number of their first line
+fn logical_lines(content: &str) -> Vec<(usize, String)> {
+    let mut out = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (idx, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if current.is_none() && (trimmed.is_empty() || trimmed.starts_with('#')) {
+            continue;
+        }
+        let (start, mut text) = current.take().unwrap_or((idx + 1, String::new()));
+        match trimmed.strip_suffix('\\') {
+            Some(part) => {
+                text.push_str(part);
+                text.push(' ');
+                current = Some((start, text));
+            }
+            None => {
+                text.push_str(trimmed);
+                out.push((start, text));
+            }
+        }
+    }
+    out.extend(current);
+    out
+}
+
+/// splits a directive on whitespace, double quoted arguments keeping their spaces, and their backslashes except
+/// those escaping quotes
+fn split_arguments(line: &str) -> Result<Vec<String>, String> {
+    let mut out = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        let mut arg = String::new();
+        match chars.peek() {
+            None => return Ok(out),
+            Some('"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated quoted argument".to_string()),
+                        Some('"') => break,
+                        Some('\\') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            arg.push('"');
+                        }
+                        Some(c) => arg.push(c),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(c) = chars.peek().copied().filter(|c| !c.is_whitespace()) {
+                    chars.next();
+                    arg.push(c);
+                }
+            }
+        }
+        out.push(arg);
+    }
+}
+
+/// splits the actions on commas, except in single quoted values, whose quotes are removed
+fn split_actions(actions: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = actions.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => match chars.next() {
+                Some('\'') => current.push('\''),
+                Some(other) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            '\'' => quoted = !quoted,
+            ',' if !quoted => out.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    out.push(current);
+    out.into_iter()
+        .filter(|a| !a.trim().is_empty())
+        .map(|a| match a.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim().to_string()),
+            None => (a.trim().to_lowercase(), String::new()),
+        })
+        .collect()
+}
+
+/// the pattern of an operator
+fn operator_pattern(operator: &str) -> Result<String, String> {
+    let operator = operator.trim();
+    if operator.starts_with('!') {
+        return Err("negated operators are not supported".to_string());
+    }
+    let (name, argument) = match operator.strip_prefix('@') {
+        None => ("rx", operator),
+        Some(op) => match op.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (op, ""),
+        },
+    };
+    let lname = name.to_lowercase();
+    if !["rx", "pm", "contains", "beginswith", "endswith", "streq"].contains(&lname.as_str()) {
+        return Err(format!("operator @{} is not supported", name));
+    }
+    if argument.is_empty() {
+        return Err(format!("operator @{} has no argument", name));
+    }
+    match lname.as_str() {
+        "rx" => Ok(argument.to_string()),
+        "pm" => Ok(format!(
+            "(?:{})",
+            argument
+                .split_whitespace()
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join("|")
+        )),
+        "contains" => Ok(regex::escape(argument)),
+        "beginswith" => Ok(format!("^{}", regex::escape(argument))),
+        "endswith" => Ok(format!("{}$", regex::escape(argument))),
+        _ => Ok(format!("^{}$", regex::escape(argument))),
+    }
+}
+
+/// the sections holding the variables of a rule, exclusions being ignored
+///
+/// Variables that target some fields, such as `REQUEST_HEADERS:User-Agent`, are rejected, as the rule would be matched
+/// against all the fields of the section.
+fn variable_sections(variables: &str) -> Result<Vec<String>, String> {
+    let mut sections: Vec<String> = Vec::new();
+    for variable in variables.split('|').map(|v| v.trim()) {
+        if variable.starts_with('!') {
+            continue;
+        }
+        if variable.starts_with('&') {
+            return Err(format!("variable {} counts values, which is not supported", variable));
+        }
+        let (name, selector) = match variable.split_once(':') {
+            Some((name, selector)) => (name.to_uppercase(), Some(selector)),
+            None => (variable.to_uppercase(), None),
+        };
+        let section = VARIABLES
+            .iter()
+            .find(|(v, _)| *v == name)
+            .map(|(_, section)| *section)
+            .ok_or_else(|| format!("variable {} is not supported", variable))?;
+        // the `/*` XPath selects the whole document
+        if selector.map(|s| !(name == "XML" && s == "/*")).unwrap_or(false) {
+            return Err(format!(
+                "variable {} targets some fields only, which is not supported",
+                variable
+            ));
+        }
+        if !sections.iter().any(|s| s == section) {
+            sections.push(section.to_string());
+        }
+    }
+    if sections.is_empty() {
+        Err("the rule has no variables".to_string())
+    } else {
+        Ok(sections)
+    }
+}
+
+/// ModSecurity severities are syslog levels, the content filter severities growing with the level of the threat,
+/// so that `CRITICAL` is 5, as the default rules
+fn severity(value: &str) -> Option<u8> {
+    const LEVELS: [&str; 8] = [
+        "EMERGENCY",
+        "ALERT",
+        "CRITICAL",
+        "ERROR",
+        "WARNING",
+        "NOTICE",
+        "INFO",
+        "DEBUG",
+    ];
+    let level = match value.parse::<u8>() {
+        Ok(n) if n < 8 => n,
+        Ok(_) => return None,
+        Err(_) => LEVELS.iter().position(|l| l.eq_ignore_ascii_case(value))? as u8,
+    };
+    Some(7 - level)
+}
+
+/// converts a `SecRule` directive, or explains why it can't be
+fn convert_secrule(logs: &mut Logs, component: &str, args: &[String]) -> Result<RawContentFilterRule, String> {
+    let (variables, operator, actions) = match args {
+        [variables, operator, actions] => (variables, operator, split_actions(actions)),
+        _ => return Err(format!("expected 3 arguments, got {}", args.len())),
+    };
+    let mut id = None;
+    let mut msg = None;
+    let mut rseverity = None;
+    let mut category = None;
+    for (name, value) in &actions {
+        match name.as_str() {
+            "id" => id = Some(value.clone()),
+            "msg" => msg = Some(value.clone()),
+            "severity" => rseverity = Some(severity(value).ok_or_else(|| format!("invalid severity {}", value))?),
+            "tag" if category.is_none() => category = value.strip_prefix("attack-").map(|c| c.to_string()),
+            "t" => {
+                let transformation = value.to_lowercase();
+                if IGNORED_TRANSFORMATIONS.contains(&transformation.as_str()) {
+                    logs.log_at(
+                        LogLevel::Info,
+                        component.to_string(),
+                        format!("transformation {} is not applied", value),
+                    );
+                } else if !TRANSFORMATIONS.contains(&transformation.as_str()) {
+                    return Err(format!("transformation {} is not supported", value));
+                }
+            }
+            "chain" => return Err("chained rules are not supported".to_string()),
+            _ => {}
+        }
+    }
+    let id = id.ok_or_else(|| "the rule has no id".to_string())?;
+    let sections = variable_sections(variables)?;
+    let operand = operator_pattern(operator)?;
+    Ok(RawContentFilterRule {
+        name: id.clone(),
+        msg: msg.unwrap_or_else(|| format!("CRS rule {}", id)),
+        id,
+        operand,
+        severity: rseverity.unwrap_or(5),
+        certainity: 5,
+        category: category.unwrap_or_else(|| "generic".to_string()),
+        subcategory: "crs".to_string(),
+        max_regex_compiled_bytes: None,
+        score: None,
+        json_path: None,
+        sections: Some(sections),
+    })
+}
+
+/// converts the `SecRule` directives of a ModSecurity configuration file
+///
+/// every other directive, and the rules that can't be converted, are skipped with a warning; a rule with the `chain`
+/// action is skipped along with the rules chained to it
+pub fn parse_secrules(logs: &mut Logs, content: &str) -> Vec<RawContentFilterRule> {
+    let mut rules = Vec::new();
+    let mut in_chain = false;
+    for (lineno, line) in logical_lines(content) {
+        let component = format!("contentfilter-rules-crs[line {}]", lineno);
+        let args = match split_arguments(&line) {
+            Ok(args) => args,
+            Err(rr) => {
+                logs.warning_at(component, rr);
+                continue;
+            }
+        };
+        let (directive, args) = match args.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        if directive != "SecRule" {
+            logs.warning_at(component, format!("directive {} is not supported", directive));
+            continue;
+        }
+        let chained = args
+            .get(2)
+            .map(|actions| split_actions(actions).iter().any(|(name, _)| name == "chain"))
+            .unwrap_or(false);
+        // the chained rules have no id of their own
+        if in_chain {
+            in_chain = chained;
+            continue;
+        }
+        in_chain = chained;
+        match convert_secrule(logs, &component, args) {
+            Ok(rule) => rules.push(rule),
+            Err(rr) => logs.warning_at(component, format!("rule skipped, {}", rr)),
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+# SQL injection
+SecRule REQUEST_COOKIES|!REQUEST_COOKIES:/__utm/|ARGS_NAMES|ARGS|XML:/* \
+    "@rx (?i)\bunion\b.{1,100}?\bselect\b" \
+    "id:942100,phase:2,block,capture,t:none,t:urlDecodeUni,t:lowercase,\
+    msg:'SQL Injection Attack: union select',tag:'application-multi',tag:'attack-sqli',severity:'CRITICAL',\
+    setvar:'tx.sql_injection_score=+%{tx.critical_anomaly_score}'"
+SecRule ARGS_NAMES|FILES_NAMES "@pm .htaccess web.config" "id:930130,t:htmlEntityDecode,severity:'ERROR',msg:'it\'s a file'"
+SecRule TX:DETECTION_PARANOIA_LEVEL "@lt 1" "id:942011,phase:1,pass,nolog,skipAfter:END-REQUEST-942-APPLICATION-ATTACK-SQLI"
+SecAction "id:900000,phase:1,nolog,pass,setvar:tx.paranoia_level=1"
+SecRule ARGS "@detectSQLi" "id:942101,phase:2,block"
+SecRule REQUEST_HEADERS:User-Agent "@contains nikto" "id:913100,chain"
+    SecRule REQUEST_HEADERS:Accept "@streq */*" "t:none"
+SecRule ARGS "@rx [\"'];" "id:941999,t:base64Decode"
+SecRule REQUEST_URI "@beginsWith /admin" "id:920999,msg:'admin'"
+SecRule REQUEST_HEADERS:User-Agent "@contains sqlmap" "id:913101"
+SecRule ARGS|!ARGS:q|REQUEST_HEADERS "@rx <script" "id:941100"
+SecRule REQUEST_FILENAME "@endsWith .php" "id:920440"
+"#;
+
+    #[test]
+    fn secrules() {
+        let mut logs = Logs::default();
+        let rules = parse_secrules(&mut logs, RULES);
+        let ids: Vec<&str> = rules.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["942100", "930130", "920999", "941100"]);
+
+        let sqli = &rules[0];
+        assert_eq!(sqli.operand, r"(?i)\bunion\b.{1,100}?\bselect\b");
+        assert_eq!(sqli.msg, "SQL Injection Attack: union select");
+        assert_eq!((sqli.severity, sqli.category.as_str()), (5, "sqli"));
+        assert_eq!(sqli.sections, Some(vec!["cookies".to_string(), "args".to_string()]));
+        let file = &rules[1];
+        assert_eq!(file.operand, r"(?:\.htaccess|web\.config)");
+        assert_eq!((file.severity, file.msg.as_str()), (4, "it's a file"));
+        assert_eq!(rules[2].operand, "^/admin");
+        assert_eq!(rules[2].msg, "admin");
+        assert_eq!(rules[2].sections, Some(vec!["raw_query".to_string()]));
+        // the exclusions are ignored
+        assert_eq!(rules[3].sections, Some(vec!["args".to_string(), "headers".to_string()]));
+
+        let warnings: Vec<String> = logs
+            .logs
+            .iter()
+            .filter(|l| l.level == LogLevel::Warning)
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(warnings.len(), 7, "{:?}", warnings);
+        for expected in &[
+            "line 9]: rule skipped, variable TX:DETECTION_PARANOIA_LEVEL is not supported",
+            "line 10]: directive SecAction is not supported",
+            "line 11]: rule skipped, operator @detectSQLi is not supported",
+            "line 12]: rule skipped, chained rules are not supported",
+            "line 14]: rule skipped, transformation base64Decode is not supported",
+            "line 16]: rule skipped, variable REQUEST_HEADERS:User-Agent targets some fields only, which is not supported",
+            "line 18]: rule skipped, variable REQUEST_FILENAME is not supported",
+        ] {
+            assert!(warnings.iter().any(|w| w.contains(expected)), "{}", expected);
+        }
+    }
+
+    #[test]
+    fn severities() {
+        assert_eq!(severity("CRITICAL"), Some(5));
+        assert_eq!(severity("notice"), Some(2));
+        assert_eq!(severity("0"), Some(7));
+        assert_eq!(severity("8"), None);
+        assert_eq!(severity("SEVERE"), None);
+    }
+}
@@ -3,6 +3,7 @@ use std::collections::{HashMap, HashSet};
 
 use crate::config::limit::{resolve_selector_map, resolve_selectors};
 use crate::config::raw::{RawFlowEntry, RawFlowStep, RawLimitSelector};
+use crate::config::responsetemplate::ResponseTemplate;
 use crate::config::utils::{RequestSelector, RequestSelectorCondition};
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
@@ -56,7 +57,7 @@ pub struct FlowElement {
 }
 
 impl FlowEntry {
-    fn convert(rawentry: RawFlowEntry) -> anyhow::Result<FlowEntry> {
+    fn convert(rawentry: RawFlowEntry, templates: &HashMap<String, ResponseTemplate>) -> anyhow::Result<FlowEntry> {
         let mkey: anyhow::Result<Vec<RequestSelector>> = rawentry.key.into_iter().map(resolve_selector_map).collect();
         let msequence: anyhow::Result<Vec<FlowStep>> = rawentry.sequence.into_iter().map(FlowStep::convert).collect();
         let sequence = msequence?;
@@ -68,7 +69,8 @@ impl FlowEntry {
             name: rawentry.name,
             active: rawentry.active,
             timeframe: rawentry.timeframe,
-            action: SimpleAction::resolve(&rawentry.action).with_context(|| "when resolving the action entry")?,
+            action: SimpleAction::resolve(&rawentry.action, templates)
+                .with_context(|| "when resolving the action entry")?,
             key: mkey?,
             sequence,
         })
@@ -102,7 +104,11 @@ impl FlowStep {
     }
 }
 
-pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> HashMap<SequenceKey, Vec<FlowElement>> {
+pub fn flow_resolve(
+    logs: &mut Logs,
+    rawentries: Vec<RawFlowEntry>,
+    templates: &HashMap<String, ResponseTemplate>,
+) -> HashMap<SequenceKey, Vec<FlowElement>> {
     let mut out: HashMap<SequenceKey, Vec<FlowElement>> = HashMap::new();
 
     // entries are created with steps in order
@@ -110,8 +116,9 @@ pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> HashMap<S
         if !rawentry.active {
             continue;
         }
-        match FlowEntry::convert(rawentry) {
-            Err(rr) => logs.warning(rr),
+        let component = format!("flow-control[{}]", rawentry.id);
+        match FlowEntry::convert(rawentry, templates) {
+            Err(rr) => logs.warning_at(component, rr),
             Ok(entry) => {
                 let nsteps = entry.sequence.len();
                 for (stepid, step) in entry.sequence.into_iter().enumerate() {
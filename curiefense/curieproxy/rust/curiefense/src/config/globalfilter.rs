@@ -3,12 +3,14 @@ use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use regex::Regex;
 use serde_json::{from_value, Value};
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 
 use crate::config::raw::{
     GlobalFilterEntryType, RawGlobalFilterSSection, RawGlobalFilterSSectionEntry, RawGlobalFilterSection, Relation,
 };
-use crate::interface::{SimpleAction, Tags};
+use crate::config::responsetemplate::ResponseTemplate;
+use crate::interface::{tagify, SimpleAction, Tags};
 use crate::logs::Logs;
 
 #[derive(Debug, Clone)]
@@ -17,6 +19,78 @@ pub struct GlobalFilterSection {
     pub relation: Relation,
     pub sections: Vec<GlobalFilterSSection>,
     pub action: Option<SimpleAction>,
+    pub tag_templates: Vec<TagTemplate>,
+}
+
+/// captured values are truncated to this many characters before they become part of a tag
+pub const MAX_CAPTURED_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Group(String),
+}
+
+/// a tag that is built from the named groups captured by the regexes of a global filter section, such as
+/// `tenant:${slug}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl TagTemplate {
+    pub fn parse(template: &str) -> Result<TagTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unterminated group in tag template {}", template))?;
+            let name = &rest[start + 2..start + end];
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid group name '{}' in tag template {}", name, template));
+            }
+            parts.push(TemplatePart::Group(name.to_string()));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(TagTemplate { parts })
+    }
+
+    /// the names of the groups the template refers to
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|p| match p {
+            TemplatePart::Group(name) => Some(name.as_str()),
+            TemplatePart::Literal(_) => None,
+        })
+    }
+
+    /// the tag, or None when one of the groups did not capture anything
+    ///
+    /// Captured values are truncated and tagified, and can't contain colons, so that they can't create qualified tags
+    /// of their own.
+    pub fn render(&self, captures: &HashMap<String, String>) -> Option<String> {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => out += literal,
+                TemplatePart::Group(name) => {
+                    let raw: String = captures.get(name)?.chars().take(MAX_CAPTURED_LENGTH).collect();
+                    let value = tagify(&raw).replace(':', "-");
+                    if value.is_empty() {
+                        return None;
+                    }
+                    out += &value;
+                }
+            }
+        }
+        Some(out)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -68,6 +142,29 @@ pub enum GlobalFilterEntryE {
     Authority(SingleEntry),
 }
 
+impl GlobalFilterEntryE {
+    /// the regex of the entries that match strings
+    pub fn regex(&self) -> Option<&Regex> {
+        match self {
+            GlobalFilterEntryE::Args(p) | GlobalFilterEntryE::Cookies(p) | GlobalFilterEntryE::Header(p) => {
+                p.re.as_ref()
+            }
+            GlobalFilterEntryE::Path(s)
+            | GlobalFilterEntryE::Query(s)
+            | GlobalFilterEntryE::Uri(s)
+            | GlobalFilterEntryE::Country(s)
+            | GlobalFilterEntryE::Method(s)
+            | GlobalFilterEntryE::Company(s)
+            | GlobalFilterEntryE::Authority(s) => s.re.as_ref(),
+            GlobalFilterEntryE::Ip(_)
+            | GlobalFilterEntryE::Network(_)
+            | GlobalFilterEntryE::Range4(_)
+            | GlobalFilterEntryE::Range6(_)
+            | GlobalFilterEntryE::Asn(_) => None,
+        }
+    }
+}
+
 /// tries to aggregate ip ranges
 pub fn optimize_ipranges(rel: Relation, unoptimized: Vec<GlobalFilterEntry>) -> Vec<GlobalFilterEntry> {
     let mut p4: Vec<Ipv4Net> = Vec::new();
@@ -187,7 +284,11 @@ pub fn optimize_ipranges(rel: Relation, unoptimized: Vec<GlobalFilterEntry>) ->
 
 impl GlobalFilterSection {
     // what an ugly function :(
-    pub fn resolve(logs: &mut Logs, rawglobalfilters: Vec<RawGlobalFilterSection>) -> Vec<GlobalFilterSection> {
+    pub fn resolve(
+        logs: &mut Logs,
+        rawglobalfilters: Vec<RawGlobalFilterSection>,
+        templates: &HashMap<String, ResponseTemplate>,
+    ) -> Vec<GlobalFilterSection> {
         /// build a global filter entry for "single" conditions
         fn single<F>(conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
         where
@@ -207,7 +308,7 @@ impl GlobalFilterSection {
         }
 
         /// build a global filter entry for "single" conditions that match strings
-        fn single_re<F>(logs: &mut Logs, conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
+        fn single_re<F>(logs: &mut Logs, path: &str, conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
         where
             F: FnOnce(SingleEntry) -> GlobalFilterEntryE,
         {
@@ -218,7 +319,7 @@ impl GlobalFilterSection {
                         re: match Regex::new(s) {
                             Ok(r) => Some(r),
                             Err(rr) => {
-                                logs.error(format!("Bad regex {}: {}", s, rr));
+                                logs.error_at(path.to_string(), format!("Bad regex {}: {}", s, rr));
                                 None
                             }
                         },
@@ -229,7 +330,7 @@ impl GlobalFilterSection {
         }
 
         /// build a global filter entry for "pair" conditions
-        fn pair<F>(logs: &mut Logs, conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
+        fn pair<F>(logs: &mut Logs, path: &str, conv: F, val: Value) -> anyhow::Result<GlobalFilterEntry>
         where
             F: FnOnce(PairEntry) -> GlobalFilterEntryE,
         {
@@ -245,7 +346,7 @@ impl GlobalFilterSection {
                         re: match Regex::new(&v) {
                             Ok(r) => Some(r),
                             Err(rr) => {
-                                logs.error(format!("Bad regex {}: {}", v, rr));
+                                logs.error_at(path.to_string(), format!("Bad regex {}: {}", v, rr));
                                 None
                             }
                         },
@@ -259,7 +360,7 @@ impl GlobalFilterSection {
                         re: match Regex::new(nval) {
                             Ok(r) => Some(r),
                             Err(rr) => {
-                                logs.error(format!("Bad regex {}: {}", nval, rr));
+                                logs.error_at(path.to_string(), format!("Bad regex {}: {}", nval, rr));
                                 None
                             }
                         },
@@ -270,7 +371,13 @@ impl GlobalFilterSection {
         }
 
         // convert a json value
-        fn convert_entry(logs: &mut Logs, tp: GlobalFilterEntryType, val: Value) -> anyhow::Result<GlobalFilterEntry> {
+        // `path` designates the entry, for the bad regex issues
+        fn convert_entry(
+            logs: &mut Logs,
+            path: &str,
+            tp: GlobalFilterEntryType,
+            val: Value,
+        ) -> anyhow::Result<GlobalFilterEntry> {
             match tp {
                 GlobalFilterEntryType::Ip => single(
                     |rawip| {
@@ -282,26 +389,32 @@ impl GlobalFilterSection {
                     },
                     val,
                 ),
-                GlobalFilterEntryType::Args => pair(logs, GlobalFilterEntryE::Args, val),
-                GlobalFilterEntryType::Cookies => pair(logs, GlobalFilterEntryE::Cookies, val),
-                GlobalFilterEntryType::Headers => pair(logs, GlobalFilterEntryE::Header, val),
-                GlobalFilterEntryType::Path => single_re(logs, GlobalFilterEntryE::Path, val),
-                GlobalFilterEntryType::Query => single_re(logs, GlobalFilterEntryE::Query, val),
-                GlobalFilterEntryType::Uri => single_re(logs, GlobalFilterEntryE::Uri, val),
-                GlobalFilterEntryType::Country => single_re(logs, GlobalFilterEntryE::Country, val),
-                GlobalFilterEntryType::Method => single_re(logs, GlobalFilterEntryE::Method, val),
+                GlobalFilterEntryType::Args => pair(logs, path, GlobalFilterEntryE::Args, val),
+                GlobalFilterEntryType::Cookies => pair(logs, path, GlobalFilterEntryE::Cookies, val),
+                GlobalFilterEntryType::Headers => pair(logs, path, GlobalFilterEntryE::Header, val),
+                GlobalFilterEntryType::Path => single_re(logs, path, GlobalFilterEntryE::Path, val),
+                GlobalFilterEntryType::Query => single_re(logs, path, GlobalFilterEntryE::Query, val),
+                GlobalFilterEntryType::Uri => single_re(logs, path, GlobalFilterEntryE::Uri, val),
+                GlobalFilterEntryType::Country => single_re(logs, path, GlobalFilterEntryE::Country, val),
+                GlobalFilterEntryType::Method => single_re(logs, path, GlobalFilterEntryE::Method, val),
                 GlobalFilterEntryType::Asn => single(|rawasn| Ok(GlobalFilterEntryE::Asn(rawasn.parse()?)), val),
-                GlobalFilterEntryType::Company => single_re(logs, GlobalFilterEntryE::Company, val),
-                GlobalFilterEntryType::Authority => single_re(logs, GlobalFilterEntryE::Authority, val),
+                GlobalFilterEntryType::Company => single_re(logs, path, GlobalFilterEntryE::Company, val),
+                GlobalFilterEntryType::Authority => single_re(logs, path, GlobalFilterEntryE::Authority, val),
             }
         }
-        fn convert_subsection(logs: &mut Logs, ss: RawGlobalFilterSSection) -> anyhow::Result<GlobalFilterSSection> {
+        fn convert_subsection(
+            logs: &mut Logs,
+            path: &str,
+            ss: RawGlobalFilterSSection,
+        ) -> anyhow::Result<GlobalFilterSSection> {
             // convert all entries individually
             let rentries: anyhow::Result<Vec<GlobalFilterEntry>> = ss
                 .entries
                 .into_iter()
-                .map(|RawGlobalFilterSSectionEntry { tp, vl, comment }| {
-                    convert_entry(logs, tp, vl).with_context(|| format!("Entry type={:?} comment={:?}", tp, comment))
+                .enumerate()
+                .map(|(idx, RawGlobalFilterSSectionEntry { tp, vl, comment })| {
+                    convert_entry(logs, &format!("{}.entries[{}]", path, idx), tp, vl)
+                        .with_context(|| format!("Entry type={:?} comment={:?}", tp, comment))
                 })
                 .collect();
             Ok(GlobalFilterSSection {
@@ -309,34 +422,70 @@ impl GlobalFilterSection {
                 entries: optimize_ipranges(ss.relation, rentries?),
             })
         }
-        fn convert_section(logs: &mut Logs, s: RawGlobalFilterSection) -> anyhow::Result<GlobalFilterSection> {
+        fn convert_section(
+            logs: &mut Logs,
+            s: RawGlobalFilterSection,
+            templates: &HashMap<String, ResponseTemplate>,
+        ) -> anyhow::Result<GlobalFilterSection> {
             let sname = &s.name;
             let sid = &s.id;
             let rsubsections: anyhow::Result<Vec<GlobalFilterSSection>> = s
                 .rule
                 .sections
                 .into_iter()
-                .map(|ss| convert_subsection(logs, ss))
+                .enumerate()
+                .map(|(idx, ss)| {
+                    let path = format!("globalfilter-lists[{}].rule.sections[{}]", sid, idx);
+                    convert_subsection(logs, &path, ss)
+                })
                 .collect();
             let subsections: Vec<GlobalFilterSSection> = rsubsections
                 .with_context(|| format!("global filter configuration error in section id={}, name={}", sid, sname))?;
             let action = match &s.action {
-                Some(ma) => Some(SimpleAction::resolve(ma).with_context(|| "when resolving the action entry")?),
+                Some(ma) => {
+                    Some(SimpleAction::resolve(ma, templates).with_context(|| "when resolving the action entry")?)
+                }
                 None => None,
             };
+            // the templates can only refer to the groups of the section entries
+            let groups: HashSet<&str> = subsections
+                .iter()
+                .flat_map(|ss| ss.entries.iter())
+                .filter_map(|e| e.entry.regex())
+                .flat_map(|re| re.capture_names().flatten())
+                .collect();
+            let mut tag_templates = Vec::new();
+            for (idx, raw) in s.tag_templates.iter().enumerate() {
+                let component = format!("globalfilter-lists[{}].tag_templates[{}]", sid, idx);
+                match TagTemplate::parse(raw) {
+                    Err(rr) => logs.error_at(component, rr),
+                    Ok(template) => {
+                        let missing = template.groups().find(|g| !groups.contains(g)).map(|g| g.to_string());
+                        match missing {
+                            Some(missing) => logs.error_at(
+                                component,
+                                format!("tag template {} refers to the unknown group {}", raw, missing),
+                            ),
+                            None => tag_templates.push(template),
+                        }
+                    }
+                }
+            }
             Ok(GlobalFilterSection {
                 tags: Tags::from_slice(&s.tags),
                 relation: s.rule.relation,
                 sections: subsections,
                 action,
+                tag_templates,
             })
         }
 
         let mut out = Vec::new();
 
         for rgf in rawglobalfilters.into_iter().filter(|s| s.active) {
-            match convert_section(logs, rgf) {
-                Err(rr) => logs.error(rr),
+            let component = format!("globalfilter-lists[{}]", rgf.id);
+            match convert_section(logs, rgf, templates) {
+                Err(rr) => logs.error_at(component, rr),
                 Ok(gfilter) => out.push(gfilter),
             }
         }
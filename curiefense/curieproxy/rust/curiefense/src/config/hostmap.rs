@@ -2,6 +2,9 @@ use crate::config::limit::Limit;
 use crate::config::raw::AclProfile;
 use crate::config::utils::Matching;
 use crate::config::contentfilter::ContentFilterProfile;
+use crate::schedule::TimeZone;
+use regex::{Regex, RegexSet};
+use std::collections::HashMap;
 
 /// the default entry is statically encoded so that it is certain it exists
 #[derive(Debug, Clone)]
@@ -9,9 +12,99 @@ pub struct HostMap {
     pub id: String,
     pub name: String,
     pub entries: Vec<Matching<SecurityPolicy>>,
+    /// the entries patterns, see `matching_set`, entries are scanned linearly when not set
+    pub entries_set: Option<RegexSet>,
     pub default: Option<SecurityPolicy>,
 }
 
+/// the kind of a host map key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKey {
+    /// a host name, such as `api.example.com`, compared case insensitively and without the port
+    Exact(String),
+    /// `*.example.com`, matching all the subdomains, stored as its `.example.com` suffix
+    Wildcard(String),
+    Regex,
+}
+
+fn is_host_name(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+}
+
+impl HostKey {
+    pub fn parse(key: &str) -> HostKey {
+        match key.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && is_host_name(&suffix[1..]) => {
+                HostKey::Wildcard(suffix.to_ascii_lowercase())
+            }
+            _ if is_host_name(key) => HostKey::Exact(key.to_ascii_lowercase()),
+            _ => HostKey::Regex,
+        }
+    }
+
+    /// the regex of the key, that is used when the host is matched against the regex keys
+    ///
+    /// host names keep their previous meaning of an unanchored regex, so that a key such as `example.com` still
+    /// matches `www.example.com` when there is no better match
+    pub fn regex(&self, key: &str) -> Result<Regex, regex::Error> {
+        match self {
+            HostKey::Wildcard(suffix) => Regex::new(&format!("(?i)^[^:]+{}(:\\d+)?$", regex::escape(suffix))),
+            HostKey::Exact(_) | HostKey::Regex => Regex::new(key),
+        }
+    }
+}
+
+/// the host name of an authority, lowercased and without its port
+pub fn host_name(authority: &str) -> String {
+    let host = match authority.rsplit_once(':') {
+        Some((h, port)) if port.bytes().all(|b| b.is_ascii_digit()) && (!h.contains(':') || h.ends_with(']')) => h,
+        _ => authority,
+    };
+    host.to_ascii_lowercase()
+}
+
+/// the host maps whose key is a host name or a wildcard, by position in the host map list
+#[derive(Debug, Clone, Default)]
+pub struct HostIndex {
+    exact: HashMap<String, usize>,
+    /// sorted by decreasing suffix length, so that the most specific wildcard is found first
+    wildcards: Vec<(String, usize)>,
+}
+
+impl HostIndex {
+    /// indexes a host map, returns false when a host map with the same key is already indexed
+    pub fn insert(&mut self, key: &HostKey, idx: usize) -> bool {
+        match key {
+            HostKey::Exact(host) => {
+                if self.exact.contains_key(host) {
+                    return false;
+                }
+                self.exact.insert(host.clone(), idx);
+            }
+            HostKey::Wildcard(suffix) => {
+                if self.wildcards.iter().any(|(s, _)| s == suffix) {
+                    return false;
+                }
+                self.wildcards.push((suffix.clone(), idx));
+                self.wildcards.sort_by_key(|(s, _)| usize::MAX - s.len());
+            }
+            HostKey::Regex => (),
+        }
+        true
+    }
+
+    /// the host map of an exact key, or else of the most specific wildcard
+    pub fn find(&self, authority: &str) -> Option<usize> {
+        let host = host_name(authority);
+        self.exact.get(&host).copied().or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+                .map(|(_, idx)| *idx)
+        })
+    }
+}
+
 /// a map entry, with links to the acl and content filter profiles
 #[derive(Debug, Clone)]
 pub struct SecurityPolicy {
@@ -21,4 +114,47 @@ pub struct SecurityPolicy {
     pub content_filter_active: bool,
     pub content_filter_profile: ContentFilterProfile,
     pub limits: Vec<Limit>,
+    /// upper case HTTP methods this entry is restricted to, or None when it applies to all methods
+    pub methods: Option<Vec<String>>,
+    /// when not set, CORS preflight requests skip the content filter
+    pub inspect_preflight: bool,
+    /// overrides the `max_body_size` setting
+    pub max_body_size: Option<usize>,
+    /// overrides the timezone of the `schedule` setting
+    pub timezone: Option<TimeZone>,
+    /// the arguments whose presence is tagged, see `engine::policy_param_stage`
+    pub param_presence: Vec<String>,
+    pub rollout: Rollout,
+}
+
+/// number of buckets the clients are split into for canary rollouts, so that percentages have two decimals
+pub const ROLLOUT_BUCKETS: u32 = 10000;
+
+/// the part a security policy plays in a canary rollout
+#[derive(Debug, Clone)]
+pub enum Rollout {
+    /// the policy has no canary alternative
+    Disabled,
+    /// the policy sends a share of the clients to its canary alternative
+    Stable(Box<Canary>),
+    /// the policy is the canary alternative of another one
+    Canary,
+}
+
+impl Rollout {
+    /// `stable` or `canary`, for the policies that are part of a rollout
+    pub fn tag(&self) -> Option<&'static str> {
+        match self {
+            Rollout::Disabled => None,
+            Rollout::Stable(_) => Some("stable"),
+            Rollout::Canary => Some("canary"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Canary {
+    /// number of buckets, out of `ROLLOUT_BUCKETS`, that are sent to the canary policy
+    pub buckets: u32,
+    pub policy: SecurityPolicy,
 }
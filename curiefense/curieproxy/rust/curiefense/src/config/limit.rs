@@ -3,7 +3,8 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
-use crate::config::raw::{RawLimit, RawLimitSelector};
+use crate::config::raw::{LimitAlgorithm, RawLimit, RawLimitSelector};
+use crate::config::responsetemplate::ResponseTemplate;
 use crate::config::utils::{
     decode_request_selector_condition, resolve_selector_raw, RequestSelector, RequestSelectorCondition, SelectorType,
 };
@@ -20,6 +21,10 @@ pub struct Limit {
     pub include: HashSet<String>,
     pub pairwith: Option<RequestSelector>,
     pub key: Vec<RequestSelector>,
+    pub algorithm: LimitAlgorithm,
+    /// when not empty, the requests are only checked, and the responses with one of these statuses are counted by
+    /// `limit::limit_count_response`
+    pub response_statuses: HashSet<u16>,
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +54,7 @@ pub fn resolve_selectors(rawsel: RawLimitSelector) -> anyhow::Result<Vec<Request
 }
 
 impl Limit {
-    fn convert(rawlimit: RawLimit) -> anyhow::Result<(String, Limit)> {
+    fn convert(rawlimit: RawLimit, templates: &HashMap<String, ResponseTemplate>) -> anyhow::Result<(String, Limit)> {
         let mkey: anyhow::Result<Vec<RequestSelector>> = rawlimit.key.into_iter().map(resolve_selector_map).collect();
         let key = mkey.with_context(|| "when converting the key entry")?;
         let pairwith = resolve_selector_map(rawlimit.pairwith).ok();
@@ -57,10 +62,16 @@ impl Limit {
         for thr in rawlimit.thresholds {
             thresholds.push(LimitThreshold {
                 limit: thr.limit.parse().with_context(|| "when converting the limit")?,
-                action: SimpleAction::resolve(&thr.action).with_context(|| "when resolving the action entry")?,
+                action: SimpleAction::resolve(&thr.action, templates)
+                    .with_context(|| "when resolving the action entry")?,
             })
         }
         thresholds.sort_unstable_by(limit_order);
+        if !rawlimit.response_statuses.is_empty() && rawlimit.algorithm == LimitAlgorithm::Concurrency {
+            return Err(anyhow::anyhow!(
+                "response statuses can't be counted by a concurrency limit"
+            ));
+        }
         Ok((
             rawlimit.id.clone(),
             Limit {
@@ -72,18 +83,24 @@ impl Limit {
                 thresholds,
                 pairwith,
                 key,
+                algorithm: rawlimit.algorithm,
+                response_statuses: rawlimit.response_statuses.into_iter().collect(),
             },
         ))
     }
-    pub fn resolve(logs: &mut Logs, rawlimits: Vec<RawLimit>) -> HashMap<String, Limit> {
+    pub fn resolve(
+        logs: &mut Logs,
+        rawlimits: Vec<RawLimit>,
+        templates: &HashMap<String, ResponseTemplate>,
+    ) -> HashMap<String, Limit> {
         let mut out = HashMap::new();
         for rl in rawlimits {
             let curid = rl.id.clone();
-            match Limit::convert(rl) {
+            match Limit::convert(rl, templates) {
                 Ok((nm, lm)) => {
                     out.insert(nm, lm);
                 }
-                Err(rr) => logs.error(format!("limit id {}: {:?}", curid, rr)),
+                Err(rr) => logs.error_at(format!("limits[{}]", curid), format!("limit id {}: {:?}", curid, rr)),
             }
         }
         out
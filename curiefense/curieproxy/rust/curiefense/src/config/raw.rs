@@ -23,9 +23,57 @@ pub struct RawSecurityPolicy {
     pub name: String,
     pub acl_profile: String,
     pub content_filter_profile: String,
-    pub acl_active: bool,
-    pub content_filter_active: bool,
+    /// when not set, the value of the host map default entry is used
+    #[serde(default)]
+    pub acl_active: Option<bool>,
+    /// when not set, the value of the host map default entry is used
+    #[serde(default)]
+    pub content_filter_active: Option<bool>,
     pub limit_ids: Vec<String>,
+    /// overrides the content filter profile argument limits
+    #[serde(default)]
+    pub max_args: Option<usize>,
+    #[serde(default)]
+    pub max_arg_length: Option<usize>,
+    #[serde(default)]
+    pub max_total_args_length: Option<usize>,
+    /// restricts the entry to these HTTP methods, the entry matches all methods when not set
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    /// when set, CORS preflight requests go through the content filter
+    #[serde(default)]
+    pub inspect_preflight: bool,
+    /// requests with larger bodies are blocked, overrides the `max_body_size` setting
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// the timezone of the `time:` tags, overrides the timezone of the `schedule` setting
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// names of the arguments whose presence is tagged, with `has-param:<name>` or `missing-param:<name>`
+    #[serde(default)]
+    pub param_presence: Vec<String>,
+    /// an alternative policy, that a share of the clients is sent to
+    #[serde(default)]
+    pub canary: Option<RawCanary>,
+}
+
+/// the canary alternative of a security policy entry, the settings that are not set are those of the entry
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawCanary {
+    /// share of the clients that are sent to the canary policy, in percent
+    pub percentage: f64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub acl_profile: Option<String>,
+    #[serde(default)]
+    pub content_filter_profile: Option<String>,
+    #[serde(default)]
+    pub acl_active: Option<bool>,
+    #[serde(default)]
+    pub content_filter_active: Option<bool>,
+    #[serde(default)]
+    pub limit_ids: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
@@ -44,6 +92,9 @@ pub struct RawGlobalFilterSection {
     pub tags: Vec<String>,
     pub rule: RawGlobalFilterRule,
     pub action: Option<RawAction>,
+    /// tags built from the named groups of the entry regexes, such as `tenant:${slug}`
+    #[serde(default)]
+    pub tag_templates: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -128,7 +179,24 @@ pub struct RawLimit {
     #[serde(default)]
     pub exclude: Vec<String>,
     pub pairwith: HashMap<String, String>,
+    #[serde(default)]
+    pub algorithm: LimitAlgorithm,
+    /// when set, only the responses with one of these statuses are counted
+    #[serde(default)]
+    pub response_statuses: Vec<u16>,
+}
 
+/// how the requests of a limit are counted
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAlgorithm {
+    /// a counter that is reset at the end of each timeframe
+    #[default]
+    Fixed,
+    /// the counts of the current and previous timeframes, the latter weighted by its overlap with the sliding window
+    Sliding,
+    /// the number of requests in flight, until they are released, the timeframe being the lifetime of the gauge
+    Concurrency,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -202,6 +270,9 @@ pub struct RawActionParams {
     pub content: Option<String>,
     pub location: Option<String>,
     pub duration: Option<String>,
+    /// the id of a response template, that provides the status, headers and body of the response
+    #[serde(default)]
+    pub template: Option<String>,
 }
 
 impl std::default::Default for RawActionParams {
@@ -215,6 +286,7 @@ impl std::default::Default for RawActionParams {
             content: None,
             location: None,
             duration: None,
+            template: None,
         }
     }
 }
@@ -229,6 +301,36 @@ pub struct AclProfile {
     pub deny_bot: HashSet<String>,
     pub passthrough: HashSet<String>,
     pub force_deny: HashSet<String>,
+    #[serde(default)]
+    pub order: AclOrder,
+    /// the stage order used by `first_match`, stages that are not listed are appended in the default order
+    #[serde(default)]
+    pub stage_order: Vec<AclStage>,
+}
+
+/// an ACL stage
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AclStage {
+    ForceDeny,
+    Passthrough,
+    Allow,
+    Deny,
+    AllowBot,
+    DenyBot,
+}
+
+/// the precedence of the ACL stages, when several of them match
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AclOrder {
+    /// force_deny, passthrough, then allow before deny, for humans and bots
+    #[default]
+    AllowOverrides,
+    /// force_deny, passthrough, then deny before allow, for humans and bots
+    DenyOverrides,
+    /// the stages are evaluated in the order of `stage_order`
+    FirstMatch,
 }
 
 impl AclProfile {
@@ -242,6 +344,8 @@ impl AclProfile {
             deny_bot: HashSet::new(),
             passthrough: HashSet::new(),
             force_deny: HashSet::new(),
+            order: AclOrder::default(),
+            stage_order: Vec::new(),
         }
     }
 }
@@ -257,15 +361,94 @@ pub struct RawContentFilterProfile {
     pub max_headers_count: usize,
     pub max_cookies_count: usize,
     pub max_args_count: usize,
+    /// headers that can be longer than `max_header_length`, such as `authorization`
+    #[serde(default)]
+    pub length_exempt_headers: Vec<String>,
+    #[serde(default)]
+    pub graphql_max_depth: Option<usize>,
+    #[serde(default)]
+    pub max_total_args_length: Option<usize>,
+    /// enables anomaly scoring, signature matches only block when their total score reaches this value
+    #[serde(default)]
+    pub blocking_threshold: Option<u32>,
+    /// blocks the requests with control characters, or invalid UTF-8, in their headers, cookies or arguments
+    #[serde(default)]
+    pub block_invalid_characters: bool,
+    /// rule ids that are not matched against the arguments, by argument name, names can be glob patterns
+    #[serde(default)]
+    pub arg_exclusions: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub normalization: Option<RawContentFilterNormalization>,
+    /// scans the response headers and body with the signatures, off by default
+    #[serde(default)]
+    pub inspect_responses: bool,
+    /// responses with larger bodies, in bytes, are blocked, no limit by default
+    #[serde(default)]
+    pub max_response_size: Option<usize>,
+    /// libinjection and the signatures only scan this many bytes of each value, no limit by default
+    #[serde(default)]
+    pub max_scan_length: Option<usize>,
+    /// size limits, in bytes, of the compiled name and value regexes of the profile, see `RegexLimits`
+    #[serde(default)]
+    pub regex_size_limit: Option<usize>,
+    #[serde(default)]
+    pub regex_dfa_size_limit: Option<usize>,
+    /// the declared arguments, the requests with other arguments, or with invalid values, are blocked
+    #[serde(default)]
+    pub arg_schema: Option<RawArgSchema>,
     pub args: RawContentFilterProperties,
     pub headers: RawContentFilterProperties,
     pub cookies: RawContentFilterProperties,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawArgSchema {
+    /// by argument name
+    #[serde(default)]
+    pub args: HashMap<String, RawArgSpec>,
+    #[serde(default)]
+    pub unknown_args: UnknownArgs,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawArgSpec {
+    /// the name of a value classifier of the learning mode, such as `int`, defaults to `text`
+    #[serde(rename = "type", default)]
+    pub arg_type: Option<String>,
+    #[serde(default)]
+    pub max_length: Option<usize>,
+}
+
+/// what happens to the arguments that are not declared by the schema
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownArgs {
+    #[default]
+    Block,
+    /// the arguments are only tagged, while the schema is being completed
+    Report,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawContentFilterNormalization {
+    #[serde(default)]
+    pub decode_passes: usize,
+    #[serde(default)]
+    pub nfkc: bool,
+    #[serde(default)]
+    pub lowercase: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RawContentFilterProperties {
     pub names: Vec<RawContentFilterEntryMatch>,
     pub regex: Vec<RawContentFilterEntryMatch>,
+    /// defaults to false for the headers, and true for the cookies and arguments
+    #[serde(default)]
+    pub case_sensitive_names: Option<bool>,
+    /// defaults to true
+    #[serde(default)]
+    pub case_sensitive_values: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -294,6 +477,19 @@ pub struct RawContentFilterRule {
     pub certainity: u8,
     pub category: String,
     pub subcategory: String,
+    /// the rule is skipped when its compiled form is larger, see `DEFAULT_MAX_REGEX_COMPILED_BYTES`
+    #[serde(default)]
+    pub max_regex_compiled_bytes: Option<usize>,
+    /// weight of the rule, for the profiles in anomaly scoring mode
+    #[serde(default)]
+    pub score: Option<u32>,
+    /// restricts the rule to some values of JSON bodies, as a JSONPath or JSON Pointer, see `JsonSelector`
+    #[serde(default)]
+    pub json_path: Option<String>,
+    /// the sections the rule applies to, among `headers`, `cookies`, `args` and `raw_query`, all but `raw_query` by
+    /// default
+    #[serde(default)]
+    pub sections: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -321,3 +517,148 @@ pub struct RawFlowStep {
     #[serde(default)]
     pub args: HashMap<String, String>,
 }
+
+/// a known TLS client fingerprint, from the `tls-fingerprints.json` file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawTlsFingerprint {
+    /// JA3 or JA4 fingerprint
+    pub fingerprint: String,
+    pub client: String,
+    /// regex matching the user agents of the client
+    pub user_agent: String,
+}
+
+/// the global settings, from the `settings.json` file
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawSettings {
+    /// methods that are tagged `method:safe`, defaults to GET, HEAD, OPTIONS and TRACE
+    #[serde(default)]
+    pub safe_methods: Option<Vec<String>>,
+    /// the parts of the request that make the `reqfp:` fingerprint
+    #[serde(default)]
+    pub fingerprint: Option<RawFingerprintFields>,
+    /// evaluates the baseline policies along with the matched security policy, off by default
+    #[serde(default)]
+    pub multiple_policies: Option<bool>,
+    /// security policies that apply to all the requests, when `multiple_policies` is set
+    #[serde(default)]
+    pub baseline_policies: Option<Vec<RawPolicyRef>>,
+    /// requests with larger bodies, in bytes, are blocked, no limit by default
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// stops the session checks of expensive requests, no budget by default
+    #[serde(default)]
+    pub eval_budget: Option<RawEvalBudget>,
+    /// business hours, for the `time:` tags, no time tags by default
+    #[serde(default)]
+    pub schedule: Option<RawSchedule>,
+    /// requests that skip all the checks, such as health checks, no trusted sources by default
+    #[serde(default)]
+    pub trusted: Option<RawTrustedSources>,
+    /// the decay of the IP reputation scores, and the levels of the `reputation:` tags
+    #[serde(default)]
+    pub reputation: Option<RawReputation>,
+}
+
+/// such as `{"half_life_secs": 3600, "thresholds": {"high": 10, "medium": 3}}`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawReputation {
+    /// the time it takes for a score to halve, an hour by default
+    #[serde(default)]
+    pub half_life_secs: Option<u64>,
+    /// the levels, and the minimum score that reaches them, `{"high": 10}` by default
+    #[serde(default)]
+    pub thresholds: Option<HashMap<String, f64>>,
+}
+
+/// a request is trusted when it matches any of the entries
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawTrustedSources {
+    /// client addresses or networks, such as `10.0.0.0/8`
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// tags of the request map
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<RawTrustedHeader>,
+}
+
+/// a header whose value is a shared secret, such as `{"name": "x-mesh-secret", "secret": "..."}`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawTrustedHeader {
+    pub name: String,
+    pub secret: String,
+}
+
+/// the business hours, such as `{"timezone": "Europe/Paris", "days": ["mon", "tue"], "start": "09:00", "end": "18:00"}`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawSchedule {
+    /// a POSIX TZ string or IANA name, defaults to UTC
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// the business days, monday to friday by default
+    #[serde(default)]
+    pub days: Option<Vec<String>>,
+    /// `hh:mm`, 09:00 by default
+    #[serde(default)]
+    pub start: Option<String>,
+    /// `hh:mm`, excluded, 17:00 by default
+    #[serde(default)]
+    pub end: Option<String>,
+}
+
+/// the limits of the evaluation of a request, the checks are skipped once one of them is exceeded
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawEvalBudget {
+    /// the number of headers, cookies and arguments
+    #[serde(default)]
+    pub max_fields: Option<usize>,
+    /// the time since the session was created
+    #[serde(default)]
+    pub max_duration_ms: Option<u64>,
+    #[serde(default)]
+    pub action: RawBudgetAction,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RawBudgetAction {
+    #[default]
+    Block,
+    Pass,
+}
+
+/// a security policy, designated by the name of its host map and its own name
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawPolicyRef {
+    pub hostmap: String,
+    pub name: String,
+}
+
+/// the fields that are not set keep their default value
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawFingerprintFields {
+    #[serde(default)]
+    pub method: Option<bool>,
+    #[serde(default)]
+    pub path: Option<bool>,
+    #[serde(default)]
+    pub args: Option<bool>,
+    /// replaces the default `content-type` header
+    #[serde(default)]
+    pub headers: Option<Vec<String>>,
+}
+
+/// a block response, from the `response-templates.json` file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawResponseTemplate {
+    pub id: String,
+    pub status: u32,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use crate::config::raw::RawResponseTemplate;
+use crate::interface::Action;
+use crate::logs::Logs;
+
+/// a response for blocked requests, that actions can reference instead of using the default response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseTemplate {
+    pub status: u32,
+    /// the response headers, including the content type
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl ResponseTemplate {
+    /// replaces the body of the action, and adds the template headers to its headers
+    ///
+    /// the status is not changed, as it is resolved along with the action
+    pub fn apply(&self, action: &mut Action) {
+        action.content = self.body.clone();
+        if !self.headers.is_empty() {
+            action
+                .headers
+                .get_or_insert_with(HashMap::new)
+                .extend(self.headers.clone());
+        }
+    }
+}
+
+/// the values of the placeholders of the response bodies
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext {
+    /// `${request_id}`, the session id, or the `x-request-id` header
+    pub request_id: String,
+    /// `${tags}`, comma separated
+    pub tags: Vec<String>,
+    /// `${matched_rule}`, see `DecisionReason::matched_rule`
+    pub matched_rule: String,
+}
+
+/// how the placeholder values are escaped, so that they can't break out of their context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escaping {
+    Html,
+    /// the values are escaped as the contents of a json string
+    Json,
+}
+
+impl Escaping {
+    /// json escaping for the json content types, html escaping for all the others
+    pub fn for_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(ct) if ct.to_lowercase().contains("json") => Escaping::Json,
+            _ => Escaping::Html,
+        }
+    }
+
+    fn escape(&self, value: &str) -> String {
+        let mut out = String::with_capacity(value.len());
+        for c in value.chars() {
+            match (self, c) {
+                (Escaping::Html, '&') => out.push_str("&amp;"),
+                (Escaping::Html, '<') => out.push_str("&lt;"),
+                (Escaping::Html, '>') => out.push_str("&gt;"),
+                (Escaping::Html, '"') => out.push_str("&quot;"),
+                (Escaping::Html, '\'') => out.push_str("&#x27;"),
+                (Escaping::Json, '"') => out.push_str("\\\""),
+                (Escaping::Json, '\\') => out.push_str("\\\\"),
+                // also escaped in json, so that the body can be embedded in a script element
+                (Escaping::Json, '<') => out.push_str("\\u003c"),
+                (Escaping::Json, '>') => out.push_str("\\u003e"),
+                (Escaping::Json, c) if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+                (_, c) => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+/// replaces the `${request_id}`, `${tags}` and `${matched_rule}` placeholders of a response body
+///
+/// the other `${...}` sequences are kept as they are
+pub fn interpolate(body: &str, escaping: Escaping, ctx: &TemplateContext) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder.find('}').and_then(|end| {
+            let value = match &placeholder[2..end] {
+                "request_id" => ctx.request_id.clone(),
+                "tags" => ctx.tags.join(", "),
+                "matched_rule" => ctx.matched_rule.clone(),
+                _ => return None,
+            };
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                out.push_str(&escaping.escape(&value));
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                out.push_str("${");
+                rest = &placeholder[2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// resolves the response templates, indexed by their id
+///
+/// templates whose status is not a valid HTTP status code are left out
+pub fn response_templates_resolve(
+    logs: &mut Logs,
+    raws: Vec<RawResponseTemplate>,
+) -> HashMap<String, ResponseTemplate> {
+    let mut out = HashMap::new();
+    for raw in raws {
+        if !(100..=599).contains(&raw.status) {
+            logs.error_at(
+                format!("response-templates[{}].status", raw.id),
+                format!("response template {}: invalid status code {}", raw.id, raw.status),
+            );
+            continue;
+        }
+        let mut headers = raw.headers;
+        if let Some(content_type) = raw.content_type {
+            headers.insert("content-type".to_string(), content_type);
+        }
+        out.insert(
+            raw.id,
+            ResponseTemplate {
+                status: raw.status,
+                headers,
+                body: raw.body,
+            },
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawAction;
+    use crate::interface::{ActionType, Decision, DecisionReason, SimpleAction};
+
+    fn templates(logs: &mut Logs) -> HashMap<String, ResponseTemplate> {
+        let raws = serde_json::from_value(serde_json::json!([
+            {"id": "json", "status": 429, "headers": {"x-reason": "slow down"}, "body": "{\"error\": \"slow down\"}",
+             "content_type": "application/json"},
+            {"id": "html", "status": 403, "body": "<p>request ${request_id}, ${tags}, ${matched_rule} ${other}</p>",
+             "content_type": "text/html"},
+            {"id": "jsonid", "status": 403, "body": "{\"id\": \"${request_id}\", \"tags\": \"${tags}\"}",
+             "content_type": "application/json; charset=utf-8"},
+            {"id": "bad", "status": 1000}
+        ]))
+        .unwrap();
+        response_templates_resolve(logs, raws)
+    }
+
+    fn action(templates: &HashMap<String, ResponseTemplate>, raw: serde_json::Value) -> anyhow::Result<Decision> {
+        let raw: RawAction = serde_json::from_value(raw).unwrap();
+        let action = SimpleAction::resolve(&raw, templates)?;
+        Ok(action.to_decision_no_challenge(serde_json::Value::Null, DecisionReason::Unknown))
+    }
+
+    #[test]
+    fn status_validation() {
+        let mut logs = Logs::default();
+        let templates = templates(&mut logs);
+        let mut ids: Vec<&String> = templates.keys().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["html", "json", "jsonid"]);
+        assert_eq!(logs.logs.len(), 1);
+        assert_eq!(
+            logs.logs[0].component.as_deref(),
+            Some("response-templates[bad].status")
+        );
+    }
+
+    #[test]
+    fn applied_to_blocking_actions() {
+        let templates = templates(&mut Logs::default());
+        let block = match action(&templates, serde_json::json!({"params": {"template": "json"}})).unwrap() {
+            Decision::Action(a) => a,
+            _ => panic!("should block"),
+        };
+        assert_eq!(block.status, 429);
+        assert_eq!(block.content, "{\"error\": \"slow down\"}");
+        let headers = block.headers.unwrap();
+        assert_eq!(
+            headers.get("content-type").map(|s| s.as_str()),
+            Some("application/json")
+        );
+        assert_eq!(headers.get("x-reason").map(|s| s.as_str()), Some("slow down"));
+
+        // an explicit status overrides the template status
+        match action(
+            &templates,
+            serde_json::json!({"params": {"template": "json", "status": "403"}}),
+        )
+        .unwrap()
+        {
+            Decision::Action(a) => assert_eq!(a.status, 403),
+            _ => panic!("should block"),
+        }
+
+        // header alterations do not send a response
+        let raw = serde_json::json!({"type": "request_header", "params": {"template": "json", "headers": {"a": "b"}}});
+        match action(&templates, raw).unwrap() {
+            Decision::Action(a) => {
+                assert_eq!(a.atype, ActionType::AlterHeaders);
+                assert_eq!(a.content, "curiefense - request denied");
+                assert!(!a.headers.unwrap().contains_key("content-type"));
+            }
+            _ => panic!("should alter headers"),
+        }
+
+        assert!(action(&templates, serde_json::json!({"params": {"template": "missing"}})).is_err());
+    }
+
+    #[test]
+    fn placeholders() {
+        use crate::interface::Tags;
+
+        let templates = templates(&mut Logs::default());
+        let tags: Tags = serde_json::from_value(serde_json::json!(["<script>alert(1)</script>"])).unwrap();
+        let reason = DecisionReason::ContentFilter {
+            rule_ids: vec!["100001".to_string(), "100002".to_string()],
+        };
+        let render = |id: &str, request_id: &str| {
+            let raw: RawAction = serde_json::from_value(serde_json::json!({"params": {"template": id}})).unwrap();
+            let mut decision = SimpleAction::resolve(&raw, &templates)
+                .unwrap()
+                .to_decision_no_challenge(serde_json::Value::Null, reason.clone());
+            decision.interpolate(request_id, &tags);
+            match decision {
+                Decision::Action(a) => a.content,
+                _ => panic!("should block"),
+            }
+        };
+
+        assert_eq!(
+            render("html", "a1b2"),
+            "<p>request a1b2, &lt;script&gt;alert(1)&lt;/script&gt;, 100001,100002 ${other}</p>"
+        );
+        let body = render("jsonid", "\"quoted\"");
+        assert_eq!(
+            body,
+            "{\"id\": \"\\\"quoted\\\"\", \"tags\": \"\\u003cscript\\u003ealert(1)\\u003c/script\\u003e\"}"
+        );
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["id"], "\"quoted\"");
+        assert_eq!(parsed["tags"], "<script>alert(1)</script>");
+        // bodies without placeholders are left alone
+        assert_eq!(render("json", "a1b2"), "{\"error\": \"slow down\"}");
+    }
+}
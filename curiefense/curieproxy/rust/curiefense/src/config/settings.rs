@@ -0,0 +1,257 @@
+use ipnet::IpNet;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::config::raw::{
+    RawBudgetAction, RawEvalBudget, RawFingerprintFields, RawReputation, RawSettings, RawTrustedSources,
+};
+use crate::interface::Tags;
+use crate::logs::Logs;
+use crate::schedule::Schedule;
+use crate::utils::RequestInfo;
+
+/// the methods that are tagged `method:safe` when no list is configured
+pub const DEFAULT_SAFE_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "TRACE"];
+
+/// global settings, from the `settings.json` file, that apply before a security policy is selected
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// upper case methods that get the `method:safe` tag, the other methods get `method:unsafe`
+    pub safe_methods: HashSet<String>,
+    pub fingerprint: FingerprintFields,
+    /// the session checks evaluate the baseline policies, and the most restrictive decision wins
+    pub multiple_policies: bool,
+    /// requests with larger bodies are blocked, unless their security policy has its own limit
+    pub max_body_size: Option<usize>,
+    pub eval_budget: Option<EvalBudget>,
+    /// the business hours of the `time:` tags, resolved by `Config::resolve`, that logs its errors
+    pub schedule: Option<Schedule>,
+    /// the sessions of these requests skip all the checks, see `session::session_is_bypassed`
+    pub trusted: Option<TrustedSources>,
+    pub reputation: Reputation,
+}
+
+/// how the scores of the `reputation` module decay, and the levels they are tagged with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reputation {
+    pub half_life: Duration,
+    /// the levels, by decreasing minimum score
+    pub thresholds: Vec<(String, f64)>,
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Reputation {
+            half_life: Duration::from_secs(3600),
+            thresholds: vec![("high".to_string(), 10.0)],
+        }
+    }
+}
+
+impl Reputation {
+    fn resolve(raw: RawReputation) -> Self {
+        let default = Reputation::default();
+        let thresholds = match raw.thresholds {
+            None => default.thresholds,
+            Some(thresholds) => {
+                let mut thresholds: Vec<(String, f64)> = thresholds.into_iter().collect();
+                thresholds.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+                thresholds
+            }
+        };
+        Reputation {
+            half_life: raw.half_life_secs.map(Duration::from_secs).unwrap_or(default.half_life),
+            thresholds,
+        }
+    }
+
+    /// the highest level that a score reaches, zero scores having no level
+    pub fn level(&self, score: f64) -> Option<&str> {
+        if score <= 0.0 {
+            return None;
+        }
+        self.thresholds
+            .iter()
+            .find(|(_, min)| score >= *min)
+            .map(|(level, _)| level.as_str())
+    }
+}
+
+/// the requests that are not inspected at all
+#[derive(Debug, Clone, Default)]
+pub struct TrustedSources {
+    pub networks: Vec<IpNet>,
+    pub tags: Vec<String>,
+    /// lower case header names, and their secret values
+    pub headers: Vec<(String, String)>,
+}
+
+/// compares secrets in a time that only depends on their lengths
+fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl TrustedSources {
+    /// invalid networks are logged, and ignored
+    pub fn resolve(logs: &mut Logs, raw: &RawTrustedSources) -> Self {
+        let networks = raw
+            .networks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, network)| {
+                let parsed = network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from));
+                match parsed {
+                    Ok(net) => Some(net),
+                    Err(rr) => {
+                        logs.error_at(
+                            format!("settings.trusted.networks[{}]", idx),
+                            format!("invalid network {}: {}", network, rr),
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+        TrustedSources {
+            networks,
+            tags: raw.tags.clone(),
+            headers: raw
+                .headers
+                .iter()
+                .map(|h| (h.name.to_lowercase(), h.secret.clone()))
+                .collect(),
+        }
+    }
+
+    /// the entry that the request matches, such as `network 10.0.0.0/8`, None when it is not trusted
+    pub fn matching(&self, rinfo: &RequestInfo, tags: &Tags) -> Option<String> {
+        if let Some(ip) = rinfo.rinfo.geoip.ip {
+            if let Some(net) = self.networks.iter().find(|net| net.contains(&ip)) {
+                return Some(format!("network {}", net));
+            }
+        }
+        if let Some(tag) = self.tags.iter().find(|tag| tags.contains(tag)) {
+            return Some(format!("tag {}", tag));
+        }
+        self.headers
+            .iter()
+            .find(|(name, secret)| {
+                rinfo
+                    .headers
+                    .get(name)
+                    .map(|value| secret_eq(value, secret))
+                    .unwrap_or(false)
+            })
+            .map(|(name, _)| format!("header {}", name))
+    }
+}
+
+/// the limits of the session checks, see `session::budget_decision`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalBudget {
+    pub max_fields: Option<usize>,
+    pub max_duration: Option<Duration>,
+    /// the checks return a block action once the budget is exceeded, instead of passing
+    pub block: bool,
+}
+
+/// the limit that was exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetExceeded {
+    Fields(usize),
+    Duration(Duration),
+}
+
+impl EvalBudget {
+    fn resolve(raw: RawEvalBudget) -> Self {
+        EvalBudget {
+            max_fields: raw.max_fields,
+            max_duration: raw.max_duration_ms.map(Duration::from_millis),
+            block: raw.action == RawBudgetAction::Block,
+        }
+    }
+
+    /// checks the number of fields of the request, and the time spent since the evaluation started
+    pub fn exceeded(&self, fields: usize, elapsed: Duration) -> Option<BudgetExceeded> {
+        if self.max_fields.map(|max| fields > max).unwrap_or(false) {
+            return Some(BudgetExceeded::Fields(fields));
+        }
+        match self.max_duration {
+            Some(max) if elapsed > max => Some(BudgetExceeded::Duration(elapsed)),
+            _ => None,
+        }
+    }
+}
+
+/// the parts of the request that make its `reqfp:` fingerprint
+#[derive(Debug, Clone)]
+pub struct FingerprintFields {
+    pub method: bool,
+    /// the path, with its numeric and identifier segments replaced with placeholders
+    pub path: bool,
+    /// the sorted argument names
+    pub args: bool,
+    /// lowercased names of the headers whose values are part of the fingerprint
+    pub headers: Vec<String>,
+}
+
+impl Default for FingerprintFields {
+    fn default() -> Self {
+        FingerprintFields {
+            method: true,
+            path: true,
+            args: true,
+            headers: vec!["content-type".to_string()],
+        }
+    }
+}
+
+impl FingerprintFields {
+    fn resolve(raw: RawFingerprintFields) -> Self {
+        let default = FingerprintFields::default();
+        FingerprintFields {
+            method: raw.method.unwrap_or(default.method),
+            path: raw.path.unwrap_or(default.path),
+            args: raw.args.unwrap_or(default.args),
+            headers: raw
+                .headers
+                .map(|hs| hs.iter().map(|h| h.to_lowercase()).collect())
+                .unwrap_or(default.headers),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            safe_methods: DEFAULT_SAFE_METHODS.iter().map(|m| m.to_string()).collect(),
+            fingerprint: FingerprintFields::default(),
+            multiple_policies: false,
+            max_body_size: None,
+            eval_budget: None,
+            schedule: None,
+            trusted: None,
+            reputation: Reputation::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn resolve(raw: RawSettings) -> Self {
+        let mut settings = Settings::default();
+        if let Some(methods) = raw.safe_methods {
+            settings.safe_methods = methods.iter().map(|m| m.to_uppercase()).collect();
+        }
+        if let Some(fingerprint) = raw.fingerprint {
+            settings.fingerprint = FingerprintFields::resolve(fingerprint);
+        }
+        settings.multiple_policies = raw.multiple_policies.unwrap_or(false);
+        settings.max_body_size = raw.max_body_size;
+        settings.eval_budget = raw.eval_budget.map(EvalBudget::resolve);
+        settings.reputation = raw.reputation.map(Reputation::resolve).unwrap_or_default();
+        settings
+    }
+}
@@ -0,0 +1,36 @@
+use regex::RegexBuilder;
+use std::collections::HashMap;
+
+use crate::config::raw::RawTlsFingerprint;
+use crate::logs::Logs;
+
+/// a known TLS fingerprint, with the client it belongs to
+#[derive(Debug, Clone)]
+pub struct TlsFingerprint {
+    pub client: String,
+    /// user agents sent by that client, matched case insensitively
+    pub user_agent: regex::Regex,
+}
+
+/// resolves the known fingerprints, indexed by their lowercased hash
+pub fn tls_fingerprints_resolve(logs: &mut Logs, raws: Vec<RawTlsFingerprint>) -> HashMap<String, TlsFingerprint> {
+    let mut out = HashMap::new();
+    for raw in raws {
+        match RegexBuilder::new(&raw.user_agent).case_insensitive(true).build() {
+            Err(rr) => logs.error_at(
+                format!("tls-fingerprints[{}].user_agent", raw.fingerprint),
+                format!("tls fingerprint {}: invalid user agent regex: {}", raw.fingerprint, rr),
+            ),
+            Ok(user_agent) => {
+                out.insert(
+                    raw.fingerprint.to_lowercase(),
+                    TlsFingerprint {
+                        client: raw.client,
+                        user_agent,
+                    },
+                );
+            }
+        }
+    }
+    out
+}
@@ -1,4 +1,4 @@
-use regex::Regex;
+use regex::{Regex, RegexSet};
 
 #[derive(Debug, Clone)]
 pub enum RequestSelector {
@@ -14,6 +14,8 @@ pub enum RequestSelector {
     Header(String),
     Company,
     Authority,
+    GraphqlDepth,
+    GraphqlFields,
 }
 
 #[derive(Debug, Clone)]
@@ -42,6 +44,8 @@ pub fn decode_attribute(s: &str) -> Option<RequestSelector> {
         "asn" => Some(RequestSelector::Asn),
         "company" => Some(RequestSelector::Company),
         "authority" => Some(RequestSelector::Authority),
+        "graphql_depth" => Some(RequestSelector::GraphqlDepth),
+        "graphql_fields" => Some(RequestSelector::GraphqlFields),
         _ => None,
     }
 }
@@ -89,3 +93,46 @@ pub struct Matching<A> {
     pub matcher: Regex,
     pub inner: A,
 }
+
+/// an anchored regex matching the same names as a glob pattern, where `*` matches any string and `?` any character
+pub fn glob_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    // all the other characters are escaped, so that the regex is always valid
+    Regex::new(&pattern).unwrap()
+}
+
+/// builds a regex set from the matchers, in the same order, so that the first matching entry is found in a single
+/// pass instead of trying all regexes in turn
+///
+/// returns None when the set can't be built, for example when it is too large
+pub fn matching_set<A>(entries: &[Matching<A>]) -> Option<RegexSet> {
+    RegexSet::new(entries.iter().map(|e| e.matcher.as_str())).ok()
+}
+
+/// index of the first entry matching the target, using the regex set when it is available
+pub fn first_match<A>(entries: &[Matching<A>], set: Option<&RegexSet>, target: &str) -> Option<usize> {
+    first_match_filtered(entries, set, target, |_| true)
+}
+
+/// same as `first_match`, but skips the entries that are rejected by `filter`
+pub fn first_match_filtered<A, F: Fn(&A) -> bool>(
+    entries: &[Matching<A>],
+    set: Option<&RegexSet>,
+    target: &str,
+    filter: F,
+) -> Option<usize> {
+    match set {
+        Some(s) => s.matches(target).into_iter().find(|&i| filter(&entries[i].inner)),
+        None => entries
+            .iter()
+            .position(|e| filter(&e.inner) && e.matcher.is_match(target)),
+    }
+}
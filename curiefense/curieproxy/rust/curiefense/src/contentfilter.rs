@@ -1,11 +1,25 @@
+use hyperscan::prelude::{Scratch, Stream, StreamingDatabase};
 use hyperscan::Matching;
 use libinjection::{sqli, xss};
+use serde::Serialize;
 use serde_json::{json, Value};
+use unicode_normalization::UnicodeNormalization;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-use crate::config::contentfilter::{Section, SectionIdx, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterSection, ContentFilterRules, ContentFilterRule};
-use crate::interface::{Action, ActionType};
-use crate::requestfields::RequestField;
+mod stats;
+pub use stats::{content_filter_stats, reset_content_filter_stats};
+pub(crate) use stats::{rule_counter, uncounted};
+
+use crate::config::raw::UnknownArgs;
+use crate::config::contentfilter::{Section, SectionIdx, ContentFilterEntryMatch, ContentFilterNormalization, ContentFilterProfile, ContentFilterSection, ContentFilterRules, ContentFilterRule};
+use crate::interface::{Action, ActionType, Decision, DecisionReason};
+use crate::jsonpath::JsonPaths;
+use crate::learning::{self, learning_enabled};
+use crate::logs::Logs;
+use crate::requestfields::{FieldAnomaly, RequestField};
+use crate::utils::url::{decode_overlong_utf8, urldecode_repeated};
 use crate::utils::RequestInfo;
 
 #[derive(Debug, Clone)]
@@ -27,6 +41,31 @@ pub struct ContentFilterMatch {
     pub ids: Vec<ContentFilterRule>,
 }
 
+/// a single rule match, for reporting purposes
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentFilterRuleMatch {
+    pub rule_id: String,
+    pub section: SectionIdx,
+    /// name of the offending header, cookie or argument, empty when the whole section is concerned
+    pub name: String,
+    /// the part of the value that matched, or the whole value when it can't be found
+    ///
+    /// It is found with the regex crate, whose semantics differ from those of hyperscan, so that it may not be the
+    /// part hyperscan matched, such as when several parts match, or the whole value when the regex crate does not
+    /// support the operand.
+    pub matched: String,
+    /// the rule is excluded for this argument by the profile, so that the match does not block
+    pub suppressed: bool,
+}
+
+/// finds the part of the value that a hyperscan rule matched, with the regex compiled when the rules were built
+fn matched_substring(sig: &ContentFilterRule, value: &str) -> String {
+    sig.substring
+        .as_ref()
+        .and_then(|re| re.find(value).map(|m| m.as_str().to_string()))
+        .unwrap_or_else(|| value.to_string())
+}
+
 #[derive(Debug, Clone)]
 pub enum ContentFilterBlock {
     TooManyEntries(SectionIdx),
@@ -35,9 +74,131 @@ pub enum ContentFilterBlock {
     SqlInjection(ContentFilterMatched, String), // fingerprint
     Xss(ContentFilterMatched),
     Policies(Vec<ContentFilterMatch>),
+    GraphqlTooDeep(usize),
+    /// the total size of the arguments
+    ArgsTooLarge(usize),
+    /// the decompressed body exceeds the decompression limits
+    DecompressBomb,
+    /// the body is malformed, or uses an unsupported content-encoding
+    DecompressFailed,
+    /// a control character, or invalid UTF-8, in the name or value of an entry
+    InvalidCharacters(SectionIdx, String, FieldAnomaly),
+    /// the arguments that do not conform to the schema of the profile
+    SchemaViolation(Vec<SchemaViolation>),
+}
+
+/// an argument that does not conform to the `arg_schema` of the profile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaViolation {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SchemaViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "violation")]
+pub enum SchemaViolationKind {
+    /// the argument is not declared
+    Unknown,
+    /// the value is not of the declared type
+    Type {
+        expected: String,
+    },
+    Length {
+        length: usize,
+        max_length: usize,
+    },
+}
+
+impl SchemaViolationKind {
+    fn describe(&self) -> String {
+        match self {
+            SchemaViolationKind::Unknown => "unknown".to_string(),
+            SchemaViolationKind::Type { expected } => format!("not {}", expected),
+            SchemaViolationKind::Length { max_length, .. } => format!("longer than {}", max_length),
+        }
+    }
 }
 
 impl ContentFilterBlock {
+    /// identifiers of the rules that caused the block, signature ids for hyperscan matches
+    pub fn rule_ids(&self) -> Vec<String> {
+        match self {
+            ContentFilterBlock::Policies(ids) => ids
+                .iter()
+                .flat_map(|m| m.ids.iter().map(|sig| sig.id.clone()))
+                .collect(),
+            ContentFilterBlock::TooManyEntries(_) => vec!["too-many-entries".to_string()],
+            ContentFilterBlock::EntryTooLarge(_, _) => vec!["entry-too-large".to_string()],
+            ContentFilterBlock::Mismatch(_) => vec!["restrict-mismatch".to_string()],
+            ContentFilterBlock::SqlInjection(_, _) => vec!["libinjection-sqli".to_string()],
+            ContentFilterBlock::Xss(_) => vec!["libinjection-xss".to_string()],
+            ContentFilterBlock::GraphqlTooDeep(_) => vec!["graphql-too-deep".to_string()],
+            ContentFilterBlock::ArgsTooLarge(_) => vec!["args-too-large".to_string()],
+            ContentFilterBlock::DecompressBomb => vec!["decompress-bomb".to_string()],
+            ContentFilterBlock::DecompressFailed => vec!["decompress-failed".to_string()],
+            ContentFilterBlock::InvalidCharacters(_, _, anomaly) => vec![anomaly.tag().to_string()],
+            ContentFilterBlock::SchemaViolation(_) => vec!["schema-violation".to_string()],
+        }
+    }
+
+    /// the total score of the signature matches, rules without a score being worth `threshold`
+    ///
+    /// a rule that matches several values is counted once per value, the other blocks are worth nothing
+    pub fn anomaly_score(&self, threshold: u32) -> u32 {
+        match self {
+            ContentFilterBlock::Policies(matches) => matches
+                .iter()
+                .flat_map(|m| m.ids.iter())
+                .map(|sig| sig.score.unwrap_or(threshold))
+                .fold(0, u32::saturating_add),
+            _ => 0,
+        }
+    }
+
+    /// the detailed list of rules that caused the block
+    pub fn rule_matches(&self) -> Vec<ContentFilterRuleMatch> {
+        let single = |rule_id: &str, section: SectionIdx, name: &str, matched: &str| {
+            vec![ContentFilterRuleMatch {
+                rule_id: rule_id.to_string(),
+                section,
+                name: name.to_string(),
+                matched: matched.to_string(),
+                suppressed: false,
+            }]
+        };
+        match self {
+            ContentFilterBlock::Policies(ids) => ids
+                .iter()
+                .flat_map(|m| {
+                    m.ids.iter().map(move |sig| ContentFilterRuleMatch {
+                        rule_id: sig.id.clone(),
+                        section: m.matched.section,
+                        name: m.matched.name.clone(),
+                        matched: matched_substring(sig, &m.matched.value),
+                        suppressed: false,
+                    })
+                })
+                .collect(),
+            ContentFilterBlock::TooManyEntries(idx) => single("too-many-entries", *idx, "", ""),
+            ContentFilterBlock::EntryTooLarge(idx, name) => single("entry-too-large", *idx, name, ""),
+            ContentFilterBlock::Mismatch(m) => single("restrict-mismatch", m.section, &m.name, &m.value),
+            ContentFilterBlock::SqlInjection(m, _) => single("libinjection-sqli", m.section, &m.name, &m.value),
+            ContentFilterBlock::Xss(m) => single("libinjection-xss", m.section, &m.name, &m.value),
+            ContentFilterBlock::GraphqlTooDeep(depth) => {
+                single("graphql-too-deep", SectionIdx::Args, "", &depth.to_string())
+            }
+            ContentFilterBlock::ArgsTooLarge(size) => single("args-too-large", SectionIdx::Args, "", &size.to_string()),
+            ContentFilterBlock::DecompressBomb => single("decompress-bomb", SectionIdx::Args, "", ""),
+            ContentFilterBlock::DecompressFailed => single("decompress-failed", SectionIdx::Args, "", ""),
+            ContentFilterBlock::InvalidCharacters(idx, name, anomaly) => single(anomaly.tag(), *idx, name, ""),
+            ContentFilterBlock::SchemaViolation(violations) => violations
+                .iter()
+                .flat_map(|v| single("schema-violation", SectionIdx::Args, &v.name, &v.kind.describe()))
+                .collect(),
+        }
+    }
+
     pub fn to_action(&self) -> Action {
         let reason = match self {
             ContentFilterBlock::Policies(ids) => ids
@@ -100,6 +261,52 @@ impl ContentFilterBlock {
                 "value": wmatch.value,
                 "msg": "Mismatch"
             }),
+            ContentFilterBlock::GraphqlTooDeep(depth) => json!({
+                "initiator": "content_filter",
+                "value": "GraphQL query too deep",
+                "depth": depth
+            }),
+            ContentFilterBlock::ArgsTooLarge(size) => json!({
+                "section": SectionIdx::Args,
+                "initiator": "content_filter",
+                "value": "Arguments too large",
+                "size": size
+            }),
+            ContentFilterBlock::DecompressBomb => json!({
+                "section": SectionIdx::Args,
+                "initiator": "content_filter",
+                "value": "Decompressed body too large"
+            }),
+            ContentFilterBlock::DecompressFailed => json!({
+                "section": SectionIdx::Args,
+                "initiator": "content_filter",
+                "value": "Body decompression failed"
+            }),
+            ContentFilterBlock::InvalidCharacters(idx, nm, anomaly) => json!({
+                "section": idx,
+                "name": nm,
+                "initiator": "content_filter",
+                "value": "Invalid characters",
+                "anomaly": anomaly
+            }),
+            ContentFilterBlock::SchemaViolation(violations) => json!({
+                "section": SectionIdx::Args,
+                "initiator": "content_filter",
+                "value": "Schema violation",
+                "violations": violations
+            }),
+        };
+        let extra_tag = match self {
+            ContentFilterBlock::TooManyEntries(SectionIdx::Args) => Some("too-many-args"),
+            ContentFilterBlock::EntryTooLarge(SectionIdx::Args, _) | ContentFilterBlock::ArgsTooLarge(_) => {
+                Some("arg-too-long")
+            }
+            ContentFilterBlock::EntryTooLarge(SectionIdx::Headers, _) => Some("header-too-long"),
+            ContentFilterBlock::EntryTooLarge(SectionIdx::Cookies, _) => Some("cookie-too-long"),
+            ContentFilterBlock::DecompressBomb => Some("decompress-bomb"),
+            ContentFilterBlock::DecompressFailed => Some("decompress-failed"),
+            ContentFilterBlock::InvalidCharacters(_, _, anomaly) => Some(anomaly.tag()),
+            _ => None,
         };
 
         Action {
@@ -110,7 +317,10 @@ impl ContentFilterBlock {
             headers: None,
             reason,
             content: "Access denied".to_string(),
-            extra_tags: None,
+            extra_tags: extra_tag.map(|t| std::iter::once(t.to_string()).collect()),
+            decision_reason: DecisionReason::ContentFilter {
+                rule_ids: self.rule_ids(),
+            },
         }
     }
 }
@@ -133,43 +343,352 @@ impl Default for Omitted {
 pub fn content_filter_check(
     rinfo: &RequestInfo,
     profile: &ContentFilterProfile,
-    hsdb: std::sync::RwLockReadGuard<Option<ContentFilterRules>>,
+    hsdb: &Option<ContentFilterRules>,
+) -> Result<(), ContentFilterBlock> {
+    content_filter_check_scored(&mut Logs::default(), rinfo, profile, hsdb).0
+}
+
+/// same as `content_filter_check`, also returning the anomaly score of the signature matches when the profile has a
+/// blocking threshold
+///
+/// The score is not computed when the request was blocked before the signatures were matched. The matches that were
+/// suppressed by the argument exclusions are logged. The shape of the request is recorded when learning is on, see
+/// `learning::set_learning`.
+pub fn content_filter_check_scored(
+    logs: &mut Logs,
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    hsdb: &Option<ContentFilterRules>,
+) -> (Result<(), ContentFilterBlock>, Option<u32>) {
+    if learning_enabled() && !stats::paused() {
+        learning::observe(rinfo);
+    }
+    let mut score = None;
+    let result = content_filter_run(logs, rinfo, profile, hsdb, &mut score);
+    if let Err(block) = &result {
+        stats::count_hits(block);
+    }
+    (result, score)
+}
+
+fn content_filter_run(
+    logs: &mut Logs,
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    hsdb: &Option<ContentFilterRules>,
+    score: &mut Option<u32>,
 ) -> Result<(), ContentFilterBlock> {
     use SectionIdx::*;
     let mut omit = Default::default();
+    let sections = normalized_sections(rinfo, &profile.normalization);
 
-    let getsection = |idx| match idx {
-        Headers => &rinfo.headers,
-        Cookies => &rinfo.cookies,
-        Args => &rinfo.rinfo.qinfo.args,
-    };
+    if rinfo.rinfo.decompress_bomb {
+        return Err(ContentFilterBlock::DecompressBomb);
+    }
+    if rinfo.rinfo.decompress_failed {
+        return Err(ContentFilterBlock::DecompressFailed);
+    }
+    if let Some(block) = graphql_check(rinfo, profile) {
+        return Err(block);
+    }
+    if let Some(block) = args_size_check(rinfo, profile) {
+        return Err(block);
+    }
+    if let Some(block) = invalid_characters_check(rinfo, profile) {
+        return Err(block);
+    }
+    if let Some(block) = schema_check(rinfo, profile) {
+        return Err(block);
+    }
 
     // check section profiles
     for idx in &[Headers, Cookies, Args] {
         section_check(
             *idx,
             profile.sections.get(*idx),
-            getsection(*idx),
+            sections.get(*idx),
             profile.ignore_alphanum,
             &mut omit,
         )?;
     }
 
-    let mut hca_keys: HashMap<String, (SectionIdx, String)> = HashMap::new();
+    let mut hca_keys: ScannedValues = HashMap::new();
 
     // run libinjection on non-whitelisted sections
     for idx in &[Headers, Cookies, Args] {
-        injection_check(*idx, getsection(*idx), &omit, &mut hca_keys)?;
+        injection_check(
+            *idx,
+            sections.get(*idx),
+            &omit,
+            profile.max_scan_length,
+            &mut hca_keys,
+            None,
+        )?;
     }
+    add_raw_query(rinfo, profile.max_scan_length, &mut hca_keys);
 
     // finally, hyperscan check
-    match hyperscan(hca_keys, hsdb, &omit.exclusions) {
+    let found = hyperscan(
+        logs,
+        hca_keys,
+        hsdb,
+        &omit.exclusions,
+        profile,
+        &rinfo.rinfo.qinfo.json_paths,
+    )
+    .map(|(block, suppressed)| {
+        for m in suppressed {
+            logs.info(format!("suppressed content filter match {}", json!(m)));
+        }
+        block
+    });
+    match found {
         Err(rr) => {
-            println!("Hyperscan failed {}", rr);
+            logs.error(format!("Hyperscan failed {}", rr));
             Ok(())
         }
-        Ok(None) => Ok(()),
-        Ok(Some(block)) => Err(block),
+        Ok(None) => {
+            *score = profile.blocking_threshold.map(|_| 0);
+            Ok(())
+        }
+        Ok(Some(block)) => match profile.blocking_threshold {
+            None => Err(block),
+            Some(threshold) => {
+                let total = block.anomaly_score(threshold);
+                *score = Some(total);
+                if total >= threshold {
+                    Err(block)
+                } else {
+                    stats::count_hits(&block);
+                    Ok(())
+                }
+            }
+        },
+    }
+}
+
+/// the anomaly score of the request, summing the scores of all the signature matches
+///
+/// Unlike `content_filter_check_scored`, the score is computed even when other checks would block the request.
+pub fn content_filter_score(
+    logs: &mut Logs,
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    hsdb: &Option<ContentFilterRules>,
+) -> u32 {
+    let threshold = profile.blocking_threshold.unwrap_or(0);
+    all_blocks(logs, rinfo, profile, hsdb, &mut Vec::new())
+        .iter()
+        .map(|b| b.anomaly_score(threshold))
+        .fold(0, u32::saturating_add)
+}
+
+/// Runs the Content Filter checks, but does not stop on the first match, returning all the rules that matched
+///
+/// the matches that were suppressed by the argument exclusions come last
+pub fn content_filter_matches(
+    logs: &mut Logs,
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    hsdb: &Option<ContentFilterRules>,
+) -> Vec<ContentFilterRuleMatch> {
+    let mut suppressed = Vec::new();
+    let mut out: Vec<ContentFilterRuleMatch> = all_blocks(logs, rinfo, profile, hsdb, &mut suppressed)
+        .iter()
+        .flat_map(|b| b.rule_matches())
+        .collect();
+    out.extend(suppressed);
+    out
+}
+
+/// all the results of the Content Filter checks, without stopping on the first match
+fn all_blocks(
+    logs: &mut Logs,
+    rinfo: &RequestInfo,
+    profile: &ContentFilterProfile,
+    hsdb: &Option<ContentFilterRules>,
+    suppressed: &mut Vec<ContentFilterRuleMatch>,
+) -> Vec<ContentFilterBlock> {
+    use SectionIdx::*;
+    let mut omit = Default::default();
+    let sections = normalized_sections(rinfo, &profile.normalization);
+    let mut blocks: Vec<ContentFilterBlock> = graphql_check(rinfo, profile)
+        .into_iter()
+        .chain(args_size_check(rinfo, profile))
+        .chain(invalid_characters_check(rinfo, profile))
+        .chain(schema_check(rinfo, profile))
+        .collect();
+    if rinfo.rinfo.decompress_bomb {
+        blocks.push(ContentFilterBlock::DecompressBomb);
+    }
+    if rinfo.rinfo.decompress_failed {
+        blocks.push(ContentFilterBlock::DecompressFailed);
+    }
+
+    for idx in &[Headers, Cookies, Args] {
+        if let Err(block) = section_check(
+            *idx,
+            profile.sections.get(*idx),
+            sections.get(*idx),
+            profile.ignore_alphanum,
+            &mut omit,
+        ) {
+            blocks.push(block);
+        }
+    }
+
+    let mut hca_keys: ScannedValues = HashMap::new();
+    for idx in &[Headers, Cookies, Args] {
+        // can't fail when blocks are collected
+        let _ = injection_check(
+            *idx,
+            sections.get(*idx),
+            &omit,
+            profile.max_scan_length,
+            &mut hca_keys,
+            Some(&mut blocks),
+        );
+    }
+    add_raw_query(rinfo, profile.max_scan_length, &mut hca_keys);
+
+    match hyperscan(
+        logs,
+        hca_keys,
+        hsdb,
+        &omit.exclusions,
+        profile,
+        &rinfo.rinfo.qinfo.json_paths,
+    ) {
+        Err(rr) => logs.error(format!("Hyperscan failed {}", rr)),
+        Ok((block, found_suppressed)) => {
+            blocks.extend(block);
+            suppressed.extend(found_suppressed);
+        }
+    }
+
+    blocks
+}
+
+fn graphql_check(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Option<ContentFilterBlock> {
+    match (rinfo.rinfo.graphql, profile.graphql_max_depth) {
+        (Some(info), Some(max_depth)) if info.depth > max_depth => Some(ContentFilterBlock::GraphqlTooDeep(info.depth)),
+        _ => None,
+    }
+}
+
+/// cheap check on the total size of the arguments, that runs before the per argument checks
+fn args_size_check(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Option<ContentFilterBlock> {
+    let max = profile.max_total_args_length?;
+    let size: usize = rinfo.rinfo.qinfo.args.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if size > max {
+        Some(ContentFilterBlock::ArgsTooLarge(size))
+    } else {
+        None
+    }
+}
+
+/// first malformed field, when the profile blocks them
+fn invalid_characters_check(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Option<ContentFilterBlock> {
+    if !profile.block_invalid_characters {
+        return None;
+    }
+    let (idx, anomaly, name) = rinfo.field_anomalies().into_iter().next()?;
+    Some(ContentFilterBlock::InvalidCharacters(idx, name.to_string(), anomaly))
+}
+
+/// the arguments that do not conform to the `arg_schema` of the profile, including the unknown arguments that are
+/// only reported, sorted by name
+pub fn schema_violations(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Vec<SchemaViolation> {
+    let schema = match &profile.arg_schema {
+        None => return Vec::new(),
+        Some(schema) => schema,
+    };
+    let mut out: Vec<SchemaViolation> = rinfo
+        .rinfo
+        .qinfo
+        .args
+        .iter()
+        .filter_map(|(name, value)| {
+            let kind = match schema.args.get(name) {
+                None => SchemaViolationKind::Unknown,
+                Some(spec) => match spec.max_length {
+                    Some(max_length) if value.len() > max_length => SchemaViolationKind::Length {
+                        length: value.len(),
+                        max_length,
+                    },
+                    _ if spec.regex.as_ref().map(|re| !re.is_match(value)).unwrap_or(false) => {
+                        SchemaViolationKind::Type {
+                            expected: spec.arg_type.clone(),
+                        }
+                    }
+                    _ => return None,
+                },
+            };
+            Some(SchemaViolation {
+                name: name.clone(),
+                kind,
+            })
+        })
+        .collect();
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    out
+}
+
+/// the schema violations that block the request
+fn schema_check(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> Option<ContentFilterBlock> {
+    let report_unknown = profile
+        .arg_schema
+        .as_ref()
+        .map(|s| s.unknown_args == UnknownArgs::Report)
+        .unwrap_or(false);
+    let violations: Vec<SchemaViolation> = schema_violations(rinfo, profile)
+        .into_iter()
+        .filter(|v| !(report_unknown && v.kind == SchemaViolationKind::Unknown))
+        .collect();
+    if violations.is_empty() {
+        None
+    } else {
+        Some(ContentFilterBlock::SchemaViolation(violations))
+    }
+}
+
+/// normalizes an argument value before it is matched
+pub fn normalize_value(normalization: &ContentFilterNormalization, value: &str) -> String {
+    let mut out = if normalization.decode_passes > 0 {
+        let decoded = decode_overlong_utf8(&urldecode_repeated(value, normalization.decode_passes));
+        String::from_utf8_lossy(&decoded).into_owned()
+    } else {
+        value.to_string()
+    };
+    if normalization.nfkc {
+        out = out.nfkc().collect();
+    }
+    if normalization.lowercase {
+        out = out.to_lowercase();
+    }
+    out
+}
+
+/// the sections that are checked, the argument values being normalized according to the profile
+///
+/// The request information is left untouched, so the original values are the ones that are logged.
+fn normalized_sections<'a>(
+    rinfo: &'a RequestInfo,
+    normalization: &ContentFilterNormalization,
+) -> Section<Cow<'a, RequestField>> {
+    let args = &rinfo.rinfo.qinfo.args;
+    Section {
+        headers: Cow::Borrowed(&rinfo.headers),
+        cookies: Cow::Borrowed(&rinfo.cookies),
+        args: if normalization.is_identity() {
+            Cow::Borrowed(args)
+        } else {
+            Cow::Owned(RequestField(
+                args.iter()
+                    .map(|(k, v)| (k.clone(), normalize_value(normalization, v)))
+                    .collect(),
+            ))
+        },
     }
 }
 
@@ -186,7 +705,12 @@ fn section_check(
     }
 
     for (name, value) in params.iter() {
-        if value.len() > section.max_length {
+        let key: Cow<str> = if section.case_sensitive.names {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(name.to_lowercase())
+        };
+        if value.len() > section.max_length && !section.length_exempt.contains(name) {
             return Err(ContentFilterBlock::EntryTooLarge(idx, name.clone()));
         }
 
@@ -216,31 +740,84 @@ fn section_check(
         };
 
         // check name rules
-        for entry in section.names.get(name).iter() {
+        for entry in section.names.get(key.as_ref()).iter() {
             check_entry(entry)?;
         }
 
         // // check regex rules
-        for entry in section
-            .regex
-            .iter()
-            .filter_map(|(re, v)| if re.is_match(name) { Some(v) } else { None })
-        {
-            check_entry(entry)?;
+        match &section.regex_set {
+            Some(set) => {
+                for i in set.matches(&key).into_iter() {
+                    check_entry(&section.regex[i].1)?;
+                }
+            }
+            None => {
+                for entry in section
+                    .regex
+                    .iter()
+                    .filter_map(|(re, v)| if re.is_match(&key) { Some(v) } else { None })
+                {
+                    check_entry(entry)?;
+                }
+            }
         }
     }
 
     Ok(())
 }
 
+/// the part of a value that is scanned, cut at a character boundary
+fn scanned_prefix(value: &str, max_scan_length: Option<usize>) -> &str {
+    match max_scan_length {
+        Some(max) if value.len() > max => {
+            let mut end = max;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            &value[..end]
+        }
+        _ => value,
+    }
+}
+
+/// true when some values of the request, as it was received, are longer than the `max_scan_length` of the profile,
+/// so that they are only scanned in part
+pub fn truncated_scan(rinfo: &RequestInfo, profile: &ContentFilterProfile) -> bool {
+    let max = match profile.max_scan_length {
+        None => return false,
+        Some(max) => max,
+    };
+    rinfo.rinfo.qinfo.query.len() > max
+        || rinfo
+            .headers
+            .iter()
+            .chain(rinfo.cookies.iter())
+            .chain(rinfo.rinfo.qinfo.args.iter())
+            .any(|(_, v)| v.len() > max)
+}
+
+/// runs libinjection, returning on the first match, unless `found` is set, in which case all matches are
+/// collected there
+///
+/// Only the first `max_scan_length` bytes of the values are checked, and stored in `hca_keys`.
 fn injection_check(
     idx: SectionIdx,
     params: &RequestField,
     omit: &Omitted,
-    hca_keys: &mut HashMap<String, (SectionIdx, String)>,
+    max_scan_length: Option<usize>,
+    hca_keys: &mut ScannedValues,
+    mut found: Option<&mut Vec<ContentFilterBlock>>,
 ) -> Result<(), ContentFilterBlock> {
+    let mut report = |block| match found.as_mut() {
+        Some(blocks) => {
+            blocks.push(block);
+            Ok(())
+        }
+        None => Err(block),
+    };
     for (name, value) in params.iter() {
         if !omit.entries.get(idx).contains(name) {
+            let value = scanned_prefix(value, max_scan_length);
             if !omit
                 .exclusions
                 .get(idx)
@@ -250,32 +827,70 @@ fn injection_check(
             {
                 if let Some((b, fp)) = sqli(value) {
                     if b {
-                        return Err(ContentFilterBlock::SqlInjection(
-                            ContentFilterMatched::new(idx, name.clone(), value.clone()),
+                        report(ContentFilterBlock::SqlInjection(
+                            ContentFilterMatched::new(idx, name.clone(), value.to_string()),
                             fp,
-                        ));
+                        ))?;
                     }
                 }
                 if let Some(b) = xss(value) {
                     if b {
-                        return Err(ContentFilterBlock::Xss(ContentFilterMatched::new(idx, name.clone(), value.clone())));
+                        report(ContentFilterBlock::Xss(ContentFilterMatched::new(
+                            idx,
+                            name.clone(),
+                            value.to_string(),
+                        )))?;
                     }
                 }
             }
 
-            hca_keys.insert(value.clone(), (idx, name.clone()));
+            hca_keys.entry(value.to_string()).or_default().push((idx, name.clone()));
         }
     }
 
     Ok(())
 }
 
+/// the values to scan with the signatures, along with all the places they were found at
+type ScannedValues = HashMap<String, Vec<(SectionIdx, String)>>;
+
+/// scans the query string as it was received, for the rules that target the `raw_query` section
+///
+/// The matches have no argument name, and the query is not normalized.
+fn add_raw_query(rinfo: &RequestInfo, max_scan_length: Option<usize>, hca_keys: &mut ScannedValues) {
+    let query = scanned_prefix(&rinfo.rinfo.qinfo.query, max_scan_length);
+    if !query.is_empty() {
+        hca_keys
+            .entry(query.to_string())
+            .or_default()
+            .push((SectionIdx::RawQuery, String::new()));
+    }
+}
+
+/// true when the rule applies to this value, which is always the case for rules without a JSON selector
+fn json_selected(rule: &ContentFilterRule, section: SectionIdx, name: &str, json_paths: &JsonPaths) -> bool {
+    match &rule.json_selector {
+        None => true,
+        Some(selector) => {
+            section == SectionIdx::Args
+                && json_paths
+                    .get(name)
+                    .map(|paths| paths.iter().any(|p| selector.matches(p)))
+                    .unwrap_or(false)
+        }
+    }
+}
+
+/// the signature matches, and the matches that were suppressed by the profile argument exclusions
 fn hyperscan(
-    hca_keys: HashMap<String, (SectionIdx, String)>,
-    hsdb: std::sync::RwLockReadGuard<Option<ContentFilterRules>>,
+    logs: &mut Logs,
+    hca_keys: ScannedValues,
+    hsdb: &Option<ContentFilterRules>,
     exclusions: &Section<HashMap<String, HashSet<String>>>,
-) -> anyhow::Result<Option<ContentFilterBlock>> {
-    let sigs = match &*hsdb {
+    profile: &ContentFilterProfile,
+    json_paths: &JsonPaths,
+) -> anyhow::Result<(Option<ContentFilterBlock>, Vec<ContentFilterRuleMatch>)> {
+    let sigs = match hsdb {
         None => return Err(anyhow::anyhow!("Hyperscan database not loaded")),
         Some(x) => x,
     };
@@ -287,31 +902,113 @@ fn hyperscan(
         Matching::Continue
     })?;
     if !found {
-        return Ok(None);
+        return Ok((None, Vec::new()));
     }
 
     let mut matches = Vec::new();
+    let mut suppressed = Vec::new();
 
     // something matched! but what?
-    for (k, (sid, name)) in hca_keys {
-        let mut ids = Vec::new();
+    for (k, locations) in hca_keys {
+        let mut hits = Vec::new();
         sigs.db.scan(&[k.as_bytes()], &scratch, |id, _, _, _| {
             // TODO this is really ugly, the string hashmap should be converted into a numeric id, or it should be a string in the first place?
             match sigs.ids.get(id as usize) {
-                None => println!("INVALID INDEX ??? {}", id),
-                Some(sig) => {
-                    if exclusions.get(sid).get(&name).map(|ex| ex.contains(&sig.id)) != Some(true) {
-                        ids.push(sig.clone());
+                None => logs.error(format!("Hyperscan returned an invalid signature index {}", id)),
+                Some(sig) => hits.push(sig),
+            }
+            Matching::Continue
+        })?;
+        // each signature is reported once per value, at the first place it applies to
+        let mut ids: Vec<Vec<ContentFilterRule>> = vec![Vec::new(); locations.len()];
+        for sig in hits {
+            let applicable = |(sid, name): &&(SectionIdx, String)| {
+                sig.applies_to(*sid)
+                    && exclusions.get(*sid).get(name).map(|ex| ex.contains(&sig.id)) != Some(true)
+                    && json_selected(sig, *sid, name, json_paths)
+            };
+            let is_suppressed =
+                |(sid, name): &(SectionIdx, String)| *sid == SectionIdx::Args && profile.arg_excluded(name, &sig.id);
+            let candidates: Vec<(usize, &(SectionIdx, String))> =
+                locations.iter().enumerate().filter(|(_, l)| applicable(l)).collect();
+            match candidates.iter().find(|(_, l)| !is_suppressed(l)) {
+                Some((i, _)) => ids[*i].push(sig.clone()),
+                None => {
+                    if let Some((_, (sid, name))) = candidates.first() {
+                        suppressed.push(ContentFilterRuleMatch {
+                            rule_id: sig.id.clone(),
+                            section: *sid,
+                            name: name.clone(),
+                            matched: matched_substring(sig, &k),
+                            suppressed: true,
+                        })
                     }
                 }
             }
+        }
+        for ((sid, name), ids) in locations.into_iter().zip(ids) {
+            if !ids.is_empty() {
+                matches.push(ContentFilterMatch {
+                    matched: ContentFilterMatched::new(sid, name, k.clone()),
+                    ids,
+                })
+            }
+        }
+    }
+    let block = if matches.is_empty() {
+        None
+    } else {
+        Some(ContentFilterBlock::Policies(matches))
+    };
+    Ok((block, suppressed))
+}
+
+/// maximum size of the body excerpt that is reported when a streamed chunk matches
+const STREAM_EXCERPT_SIZE: usize = 256;
+
+/// runs the hyperscan signatures on the headers and the body of a response
+///
+/// As with the streamed bodies, the values are scanned as they are, and the rules with a JSON selector are ignored.
+/// The headers are matched by the rules that target the `headers` section, and the body by those that target `args`.
+/// The body matches are reported as the `response-body` argument.
+pub fn content_filter_response(
+    hsdb: &Option<ContentFilterRules>,
+    headers: &RequestField,
+    body: Option<&[u8]>,
+) -> anyhow::Result<Option<ContentFilterBlock>> {
+    let sigs = hsdb
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Hyperscan database not loaded"))?;
+    let scratch = sigs.db.alloc_scratch()?;
+    let values = headers
+        .iter()
+        .map(|(name, value)| (SectionIdx::Headers, name.as_str(), value.as_bytes()))
+        .chain(body.map(|b| (SectionIdx::Args, "response-body", b)));
+    let mut matches = Vec::new();
+    for (section, name, value) in values {
+        let mut ids: Vec<ContentFilterRule> = Vec::new();
+        let mut end = 0;
+        sigs.db.scan([value], &scratch, |id, _, to, _| {
+            match sigs.ids.get(id as usize) {
+                Some(sig)
+                    if sig.json_selector.is_none()
+                        && sig.applies_to(section)
+                        && !ids.iter().any(|s| s.id == sig.id) =>
+                {
+                    ids.push(sig.clone())
+                }
+                _ => (),
+            }
+            end = end.max(to as usize);
             Matching::Continue
         })?;
         if !ids.is_empty() {
+            let end = end.min(value.len());
+            let excerpt = String::from_utf8_lossy(&value[end.saturating_sub(STREAM_EXCERPT_SIZE)..end]).to_string();
             matches.push(ContentFilterMatch {
-                matched: ContentFilterMatched::new(sid, name, k),
+                matched: ContentFilterMatched::new(section, name.to_string(), excerpt),
                 ids,
-            })
+            });
         }
     }
     Ok(if matches.is_empty() {
@@ -320,3 +1017,803 @@ fn hyperscan(
         Some(ContentFilterBlock::Policies(matches))
     })
 }
+
+/// Runs the hyperscan signatures on a request body that is received in chunks
+///
+/// The match state is kept between chunks, so that a signature spanning several chunks is still found. Only the raw
+/// bytes are scanned, and the rules with a JSON selector, or that do not target the arguments, are ignored. The matches
+/// are reported on the `body` argument, so the profile exclusions of that argument apply.
+pub struct ContentFilterStream {
+    // the stream must be dropped before the database it was opened from
+    stream: Option<Stream>,
+    scratch: Scratch,
+    /// only kept so that the database outlives the stream
+    _db: Arc<StreamingDatabase>,
+    ids: Arc<Vec<ContentFilterRule>>,
+    /// the ids of the signatures that the profile excludes on the body, see `body_exclusions`
+    excluded: HashSet<String>,
+    /// number of bytes scanned so far
+    offset: usize,
+    block: Option<ContentFilterBlock>,
+    /// the errors of the scans, see `take_logs` and `finish`
+    logs: Logs,
+}
+
+// hyperscan streams can be moved between threads, as long as they are not used concurrently
+unsafe impl Send for ContentFilterStream {}
+
+impl Drop for ContentFilterStream {
+    // the stream state is only released when it is closed
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.close(&self.scratch, |_, _, _, _| Matching::Continue);
+        }
+    }
+}
+
+/// the argument the streamed body matches are reported on
+const STREAM_ARG: &str = "body";
+
+/// the signatures excluded on the streamed body, by the `args` entries of the profile and by its argument exclusions
+///
+/// The values of the entries can't be checked on a stream, so their exclusions always apply.
+fn body_exclusions(rules: &ContentFilterRules, profile: &ContentFilterProfile) -> HashSet<String> {
+    let args = &profile.sections.args;
+    let entries = args.names.get(STREAM_ARG).into_iter().chain(
+        args.regex
+            .iter()
+            .filter(|(re, _)| re.is_match(STREAM_ARG))
+            .map(|(_, entry)| entry),
+    );
+    let mut excluded: HashSet<String> = entries.flat_map(|entry| entry.exclusions.iter().cloned()).collect();
+    excluded.extend(
+        rules
+            .ids
+            .iter()
+            .filter(|sig| profile.arg_excluded(STREAM_ARG, &sig.id))
+            .map(|sig| sig.id.clone()),
+    );
+    excluded
+}
+
+impl ContentFilterStream {
+    pub fn new(rules: &ContentFilterRules, profile: &ContentFilterProfile) -> anyhow::Result<Self> {
+        let db = rules
+            .stream_db
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Hyperscan streaming database not available"))?;
+        let stream = db.open_stream()?;
+        let scratch = db.alloc_scratch()?;
+        Ok(ContentFilterStream {
+            stream: Some(stream),
+            scratch,
+            _db: db,
+            ids: rules.ids.clone(),
+            excluded: body_exclusions(rules, profile),
+            offset: 0,
+            block: None,
+            logs: Logs::default(),
+        })
+    }
+
+    /// the number of bytes fed so far
+    pub fn scanned(&self) -> usize {
+        self.offset
+    }
+
+    /// the signatures with the given hyperscan ids, and a block when there are any
+    fn mk_block(&mut self, hs_ids: Vec<u32>, excerpt: String) -> Option<ContentFilterBlock> {
+        let mut ids: Vec<ContentFilterRule> = Vec::new();
+        for id in hs_ids {
+            match self.ids.get(id as usize) {
+                None => self
+                    .logs
+                    .error(format!("Hyperscan returned an invalid signature index {}", id)),
+                Some(sig)
+                    if sig.json_selector.is_some()
+                        || !sig.applies_to(SectionIdx::Args)
+                        || self.excluded.contains(&sig.id) => {}
+                Some(sig) => {
+                    if !ids.iter().any(|s| s.id == sig.id) {
+                        ids.push(sig.clone())
+                    }
+                }
+            }
+        }
+        if ids.is_empty() {
+            None
+        } else {
+            Some(ContentFilterBlock::Policies(vec![ContentFilterMatch {
+                matched: ContentFilterMatched::new(SectionIdx::Args, STREAM_ARG.to_string(), excerpt),
+                ids,
+            }]))
+        }
+    }
+
+    fn decision(&self) -> Option<Decision> {
+        self.block.as_ref().map(|b| Decision::Action(b.to_action()))
+    }
+
+    /// scans the next chunk of the body, returning a decision as soon as a signature matched
+    ///
+    /// Once a block decision has been returned, the following chunks are not scanned and the same decision is
+    /// returned.
+    pub fn feed(&mut self, chunk: &[u8]) -> Option<Decision> {
+        if self.block.is_some() {
+            return self.decision();
+        }
+        let stream = self.stream.as_ref()?;
+        let mut hs_ids = Vec::new();
+        let mut end = 0;
+        let scanned = stream.scan(chunk, &self.scratch, |id, _, to, _| {
+            hs_ids.push(id);
+            end = end.max(to as usize);
+            Matching::Continue
+        });
+        if let Err(rr) = scanned {
+            self.logs.error(format!("Hyperscan failed {}", rr));
+        }
+        // the excerpt only covers the current chunk, even if the match started in a previous one
+        let local_end = end.saturating_sub(self.offset).min(chunk.len());
+        let excerpt = &chunk[local_end.saturating_sub(STREAM_EXCERPT_SIZE)..local_end];
+        self.offset += chunk.len();
+        self.block = self.mk_block(hs_ids, String::from_utf8_lossy(excerpt).to_string());
+        self.decision()
+    }
+
+    /// the logs of the chunks fed since the last call
+    pub fn take_logs(&mut self) -> Logs {
+        std::mem::take(&mut self.logs)
+    }
+
+    /// closes the stream, returning the final decision for the whole body, and the logs that were not taken yet
+    pub fn finish(mut self) -> (Decision, Logs) {
+        if let Some(d) = self.decision() {
+            return (d, self.take_logs());
+        }
+        let mut hs_ids = Vec::new();
+        if let Some(stream) = self.stream.take() {
+            // patterns anchored at the end of the data only match when the stream is closed
+            if let Err(rr) = stream.close(&self.scratch, |id, _, _, _| {
+                hs_ids.push(id);
+                Matching::Continue
+            }) {
+                self.logs.error(format!("Hyperscan failed {}", rr));
+            }
+        }
+        let decision = match self.mk_block(hs_ids, String::new()) {
+            None => Decision::Pass,
+            Some(b) => Decision::Action(b.to_action()),
+        };
+        (decision, self.take_logs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::contentfilter::resolve_rules;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::raw::{
+        RawArgSchema, RawArgSpec, RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterRule,
+    };
+    use crate::config::utils::{glob_regex, Matching};
+    use crate::config::{Config, HSDB};
+    use crate::interface::Tags;
+    use crate::tag_anomaly_score;
+    use crate::testutils::{
+        arg_request, content_filter_rule, content_filter_rules, mk_jmap, mk_policy, request_info, request_map,
+    };
+    use regex::Regex;
+
+    fn fixture_profiles() -> Vec<RawContentFilterProfile> {
+        serde_json::from_str(&std::fs::read_to_string("../../config/json/contentfilter-profiles.json").unwrap())
+            .unwrap()
+    }
+
+    fn resolve(raws: Vec<RawContentFilterProfile>) -> ContentFilterProfile {
+        ContentFilterProfile::resolve(&mut Logs::default(), raws, &HashMap::new())
+            .remove("__default__")
+            .unwrap()
+    }
+
+    /// a profile that also scans the alphanumeric values
+    fn scanning() -> ContentFilterProfile {
+        ContentFilterProfile {
+            ignore_alphanum: false,
+            ..Default::default()
+        }
+    }
+
+    fn restrict(key: &str, reg: &str) -> RawContentFilterEntryMatch {
+        RawContentFilterEntryMatch {
+            key: key.to_string(),
+            reg: Some(reg.to_string()),
+            restrict: true,
+            exclusions: None,
+        }
+    }
+
+    #[test]
+    fn arg_schema() {
+        use crate::engine::content_filter_stage;
+
+        let raws = fixture_profiles();
+        let spec = |arg_type: &str, max_length: Option<usize>| RawArgSpec {
+            arg_type: Some(arg_type.to_string()),
+            max_length,
+        };
+        let mk_profile = |unknown_args: UnknownArgs, types: &[(&str, RawArgSpec)]| {
+            let mut raws = raws.clone();
+            raws[0].arg_schema = Some(RawArgSchema {
+                args: types.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+                unknown_args,
+            });
+            let mut logs = Logs::default();
+            let profile = ContentFilterProfile::resolve(&mut logs, raws, &HashMap::new()).remove("__default__");
+            (profile, logs.to_stringvec())
+        };
+        let (profile, _) = mk_profile(
+            UnknownArgs::Block,
+            &[("page", spec("int", None)), ("q", spec("text", Some(10)))],
+        );
+        let profile = profile.unwrap();
+        let rinfo = |args: &[(&str, &str)]| {
+            let (mut rinfo, _) = mk_jmap(&[], None, false).into_request_info(&mut Logs::default());
+            for (k, v) in args {
+                rinfo.rinfo.qinfo.args.add(k.to_string(), v.to_string());
+            }
+            rinfo
+        };
+        let hsdb = HSDB.read().unwrap();
+        assert!(content_filter_check(&rinfo(&[("page", "12"), ("q", "shoes")]), &profile, &hsdb).is_ok());
+
+        // an int field receiving a string
+        let wrong_type = rinfo(&[("page", "twelve")]);
+        match content_filter_check(&wrong_type, &profile, &hsdb) {
+            Err(ContentFilterBlock::SchemaViolation(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].name, "page");
+                assert_eq!(
+                    violations[0].kind,
+                    SchemaViolationKind::Type {
+                        expected: "int".to_string()
+                    }
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let securitypolicy = SecurityPolicy {
+            content_filter_profile: profile.clone(),
+            ..mk_policy("test")
+        };
+        let mut tags = Tags::default();
+        let block =
+            content_filter_stage(&mut Logs::default(), &hsdb, &wrong_type, &securitypolicy, &mut tags).unwrap_err();
+        assert!(tags.contains("schema-violation:page"));
+        let action = block.to_action();
+        assert_eq!(action.status, 403);
+        assert_eq!(action.reason["violations"][0]["violation"], "type");
+
+        let too_long = schema_violations(&rinfo(&[("q", "a very long query")]), &profile);
+        assert_eq!(
+            too_long[0].kind,
+            SchemaViolationKind::Length {
+                length: 17,
+                max_length: 10
+            }
+        );
+        assert!(content_filter_check(&rinfo(&[("page", "1"), ("debug", "1")]), &profile, &hsdb).is_err());
+
+        // the unknown arguments are only reported during the transition
+        let (profile, _) = mk_profile(UnknownArgs::Report, &[("page", spec("int", None))]);
+        let profile = profile.unwrap();
+        let unknown = rinfo(&[("page", "1"), ("debug", "1")]);
+        assert!(content_filter_check(&unknown, &profile, &hsdb).is_ok());
+        assert_eq!(
+            schema_violations(&unknown, &profile)[0].kind,
+            SchemaViolationKind::Unknown
+        );
+        assert!(content_filter_check(&rinfo(&[("page", "x")]), &profile, &hsdb).is_err());
+
+        let (profile, logs) = mk_profile(UnknownArgs::Block, &[("page", spec("number", None))]);
+        assert!(profile.is_none());
+        assert!(logs
+            .iter()
+            .any(|l| l.contains("contentfilter-profiles[__default__].arg_schema.args.page.type")));
+    }
+
+    #[test]
+    fn header_cookie_limits() {
+        let mut raws = fixture_profiles();
+        raws[0].max_header_length = 8;
+        raws[0].max_cookie_length = 8;
+        raws[0].length_exempt_headers = vec!["Authorization".to_string()];
+        let profile = resolve(raws);
+        let check = |headers: &[(&str, &str)], cookie: Option<&str>| {
+            let mut jvalue = request_map(headers);
+            if let Some(value) = cookie {
+                jvalue["cookies"] = json!({ "session": value });
+            }
+            content_filter_check(&request_info(jvalue), &profile, &HSDB.read().unwrap())
+                .err()
+                .and_then(|block| block.to_action().extra_tags)
+                .unwrap_or_default()
+        };
+
+        assert!(check(&[("x-probe", "a-long-header-value")], None).contains("header-too-long"));
+        assert!(check(&[], Some("a-long-cookie-value")).contains("cookie-too-long"));
+        // exempt headers are compared case insensitively
+        assert!(check(&[("authorization", "Bearer a-long-token")], None).is_empty());
+        assert!(check(&[("x-short", "short")], Some("short")).is_empty());
+    }
+
+    #[test]
+    fn section_case_sensitivity() {
+        let mut raws = fixture_profiles();
+        raws[0].headers.names = vec![restrict("Content-Type", "^application/json$")];
+        raws[0].args.names = vec![restrict("mode", "^safe-mode$")];
+        let blocked = |profile: &ContentFilterProfile, headers: &[(&str, &str)], arg: Option<(&str, &str)>| {
+            let mut jvalue = request_map(headers);
+            if let Some((name, value)) = arg {
+                jvalue["args"] = json!({ name: value });
+            }
+            content_filter_check(&request_info(jvalue), profile, &HSDB.read().unwrap()).is_err()
+        };
+
+        let profile = resolve(raws.clone());
+        for name in &["content-type", "Content-Type", "CONTENT-TYPE"] {
+            assert!(!blocked(&profile, &[(name, "application/json")], None), "{}", name);
+            assert!(blocked(&profile, &[(name, "text/html")], None), "{}", name);
+        }
+        // header values, and argument names and values, are case sensitive
+        assert!(blocked(&profile, &[("content-type", "Application/JSON")], None));
+        assert!(blocked(&profile, &[], Some(("mode", "SAFE-MODE"))));
+        assert!(!blocked(&profile, &[], Some(("Mode", "no-restriction"))));
+
+        raws[0].headers.case_sensitive_values = Some(false);
+        raws[0].args.case_sensitive_names = Some(false);
+        raws[0].args.case_sensitive_values = Some(false);
+        let profile = resolve(raws);
+        assert!(!blocked(&profile, &[("Content-Type", "Application/JSON")], None));
+        assert!(!blocked(&profile, &[], Some(("mode", "SAFE-MODE"))));
+        assert!(blocked(&profile, &[], Some(("Mode", "no-restriction"))));
+    }
+
+    #[test]
+    fn invalid_characters() {
+        use crate::tagging::tag_request;
+        use crate::utils::url::urldecode_str;
+
+        let rinfo = |headers: &[(&str, &str)], arg: &str| {
+            let mut jvalue = request_map(headers);
+            jvalue["args"] = json!({ "q": urldecode_str(arg) });
+            request_info(jvalue)
+        };
+        let tags = |rinfo: &RequestInfo| tag_request(true, &Config::empty(), rinfo).0;
+
+        let crlf = rinfo(&[("x-injected", "a\r\nset-cookie: x=y")], "");
+        assert!(tags(&crlf).contains("ctrl-char"));
+        assert!(!tags(&crlf).contains("invalid-utf8"));
+        // line breaks are fine in the arguments, NUL bytes are not
+        assert!(!tags(&rinfo(&[], "a%0D%0Ab")).contains("ctrl-char"));
+        assert!(tags(&rinfo(&[], "a%00b")).contains("ctrl-char"));
+        let invalid = rinfo(&[], "%ff");
+        assert!(tags(&invalid).contains("invalid-utf8"));
+        assert!(!tags(&rinfo(&[("x-clean", "value")], "caf%C3%A9")).contains("invalid-utf8"));
+        // the raw value is preserved
+        assert_eq!(crlf.headers.get_str("x-injected"), Some("a\r\nset-cookie: x=y"));
+
+        let mut profile = ContentFilterProfile::default();
+        assert!(content_filter_check(&crlf, &profile, &HSDB.read().unwrap()).is_ok());
+        profile.block_invalid_characters = true;
+        let block = content_filter_check(&crlf, &profile, &HSDB.read().unwrap()).unwrap_err();
+        assert_eq!(block.rule_ids(), vec!["ctrl-char".to_string()]);
+        assert_eq!(block.rule_matches()[0].name, "x-injected");
+        let block = content_filter_check(&invalid, &profile, &HSDB.read().unwrap()).unwrap_err();
+        assert_eq!(
+            block.to_action().extra_tags,
+            Some(std::iter::once("invalid-utf8".to_string()).collect())
+        );
+    }
+
+    #[test]
+    fn section_regex_set() {
+        let mut raws = fixture_profiles();
+        raws[0].ignore_alphanum = false;
+        raws[0].args.regex = vec![
+            restrict("^a[0-9]$", "^x+$"),
+            restrict("^b$", "^z$"),
+            restrict("3$", "^y$"),
+        ];
+        let mut profile = resolve(raws);
+        assert!(profile.sections.args.regex_set.is_some());
+        let mut jvalue = request_map(&[]);
+        jvalue["args"] = json!({"a1": "xx", "a3": "xx"});
+        let rinfo = request_info(jvalue);
+        let mismatched =
+            |profile: &ContentFilterProfile| match content_filter_check(&rinfo, profile, &HSDB.read().unwrap()) {
+                Err(ContentFilterBlock::Mismatch(m)) => m.name,
+                r => panic!("unexpected result {:?}", r),
+            };
+
+        let with_set = mismatched(&profile);
+        // the linear scan used when the set can't be built
+        profile.sections.args.regex_set = None;
+        assert_eq!(mismatched(&profile), with_set);
+        assert_eq!(with_set, "a3");
+    }
+
+    #[test]
+    fn normalized_args() {
+        use crate::utils::{map_request, RequestMeta};
+
+        let double = ContentFilterNormalization {
+            decode_passes: 2,
+            ..Default::default()
+        };
+        assert_eq!(normalize_value(&double, "%252e%252e%252f"), "../");
+        let folded = ContentFilterNormalization {
+            nfkc: true,
+            lowercase: true,
+            ..Default::default()
+        };
+        assert_eq!(normalize_value(&folded, "ＳＥＬｅｃｔ"), "select");
+
+        let meta = RequestMeta {
+            authority: Some("localhost".to_string()),
+            method: "GET".to_string(),
+            path: "/?file=%252e%252e%252fetc".to_string(),
+            extra: HashMap::new(),
+        };
+        let rinfo = map_request(
+            &mut Logs::default(),
+            "127.0.0.1".to_string(),
+            HashMap::new(),
+            meta,
+            None,
+        )
+        .unwrap();
+        let mut profile = ContentFilterProfile {
+            ignore_alphanum: false,
+            ..Default::default()
+        };
+        profile.sections.args.names.insert(
+            "file".to_string(),
+            ContentFilterEntryMatch {
+                reg: Some(Regex::new(r"^[^.]*$").unwrap()),
+                restrict: true,
+                exclusions: Default::default(),
+            },
+        );
+        // decoded once by the query parser
+        assert!(content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()).is_ok());
+        profile.normalization = double;
+        match content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()) {
+            Err(ContentFilterBlock::Mismatch(m)) => assert_eq!(m.value, "../etc"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // the original value is kept in the request information
+        assert_eq!(
+            rinfo.rinfo.qinfo.args.get("file").map(|s| s.as_str()),
+            Some("%2e%2e%2fetc")
+        );
+    }
+
+    #[test]
+    fn anomaly_scoring() {
+        let raws = vec![
+            RawContentFilterRule {
+                score: Some(3),
+                ..content_filter_rule("100001", "evil[0-9]+")
+            },
+            RawContentFilterRule {
+                score: Some(2),
+                ..content_filter_rule("100002", "payload")
+            },
+            content_filter_rule("100003", "unscored"),
+        ];
+        let hsdb = Some(content_filter_rules(raws));
+        let binary = scanning();
+        let scoring = ContentFilterProfile {
+            blocking_threshold: Some(5),
+            ..scanning()
+        };
+        let scored = |value: &str, profile: &ContentFilterProfile| {
+            content_filter_check_scored(&mut Logs::default(), &arg_request("q", value), profile, &hsdb)
+        };
+
+        // without a threshold, any match blocks
+        let (result, score) = scored("evil42", &binary);
+        assert!(result.is_err());
+        assert_eq!(score, None);
+
+        let (result, score) = scored("evil42", &scoring);
+        assert!(result.is_ok());
+        assert_eq!(score, Some(3));
+        let (result, score) = scored("evil42 payload", &scoring);
+        assert!(result.is_err());
+        assert_eq!(score, Some(5));
+        // rules without a score block on their own
+        let (result, score) = scored("unscored", &scoring);
+        assert!(result.is_err());
+        assert_eq!(score, Some(5));
+        let (result, score) = scored("harmless", &scoring);
+        assert!(result.is_ok());
+        assert_eq!(score, Some(0));
+
+        let score = |value: &str| content_filter_score(&mut Logs::default(), &arg_request("q", value), &binary, &hsdb);
+        assert_eq!(score("evil42 payload"), 5);
+        assert_eq!(score("unscored"), 0);
+
+        let mut tags = Tags::default();
+        tag_anomaly_score(&mut tags, Some(0));
+        tag_anomaly_score(&mut tags, None);
+        assert!(!tags.contains("anomaly-score:0"));
+        tag_anomaly_score(&mut tags, Some(3));
+        assert!(tags.contains("anomaly-score:3"));
+    }
+
+    #[test]
+    fn rule_hits() {
+        let raws = vec![
+            content_filter_rule("171001", "hitme[0-9]+"),
+            RawContentFilterRule {
+                score: Some(1),
+                ..content_filter_rule("171002", "lowscore")
+            },
+        ];
+        let hsdb = Some(content_filter_rules(raws));
+        let check = |value: &str, profile: &ContentFilterProfile| {
+            content_filter_check_scored(&mut Logs::default(), &arg_request("q", value), profile, &hsdb)
+                .0
+                .is_ok()
+        };
+        let profile = scanning();
+        let hits = |id: &str| content_filter_stats().get(id).copied();
+
+        assert!(!check("hitme1", &profile));
+        assert!(!check("hitme2", &profile));
+        assert!(check("clean", &profile));
+        assert_eq!(hits("171001"), Some(2));
+        // matches below the blocking threshold are counted too
+        let scored = ContentFilterProfile {
+            blocking_threshold: Some(5),
+            ..profile.clone()
+        };
+        assert!(check("lowscore", &scored));
+        assert_eq!(hits("171002"), Some(1));
+
+        reset_content_filter_stats();
+        assert_eq!(hits("171001"), None);
+        assert!(!check("hitme3", &profile));
+        assert_eq!(hits("171001"), Some(1));
+    }
+
+    #[test]
+    fn arg_exclusions() {
+        let raws = vec![
+            content_filter_rule("100001", "evil[0-9]+"),
+            content_filter_rule("100002", "payload"),
+        ];
+        let hsdb = Some(content_filter_rules(raws));
+        let excluded = |glob: &str| Matching {
+            matcher: glob_regex(glob),
+            inner: std::iter::once("100001".to_string()).collect(),
+        };
+        let profile = ContentFilterProfile {
+            arg_exclusions: vec![excluded("comment"), excluded("user_*")],
+            ..scanning()
+        };
+        let check = |name: &str, value: &str| {
+            let mut logs = Logs::default();
+            let (result, _) = content_filter_check_scored(&mut logs, &arg_request(name, value), &profile, &hsdb);
+            (result.is_ok(), logs.to_stringvec().join("\n"))
+        };
+        let matches = |name: &str, value: &str| {
+            content_filter_matches(&mut Logs::default(), &arg_request(name, value), &profile, &hsdb)
+        };
+
+        // exact name
+        let (passed, logs) = check("comment", "evil42");
+        assert!(passed);
+        assert!(logs.contains("suppressed content filter match"));
+        assert!(logs.contains("\"rule_id\":\"100001\""));
+        let found = matches("comment", "evil42");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].suppressed);
+        assert_eq!(found[0].name, "comment");
+        assert_eq!(found[0].matched, "evil42");
+        // only the excluded rules are suppressed
+        assert!(!check("comment", "payload").0);
+        assert!(!check("comments", "evil42").0);
+
+        // wildcard name
+        assert!(check("user_bio", "evil42").0);
+        assert!(check("user_", "evil42").0);
+        assert!(!check("user", "evil42").0);
+        assert!(!check("q", "evil42").0);
+        let flags: Vec<(String, bool)> = matches("user_bio", "evil42 payload")
+            .into_iter()
+            .map(|m| (m.rule_id, m.suppressed))
+            .collect();
+        assert_eq!(flags, vec![("100002".to_string(), false), ("100001".to_string(), true)]);
+
+        assert!(glob_regex("a?c").is_match("abc"));
+        assert!(!glob_regex("a.c").is_match("abc"));
+        assert!(!glob_regex("user_*").is_match("x_user_bio"));
+    }
+
+    #[test]
+    fn json_selectors() {
+        let rule = |id: &str, json_path: &str| RawContentFilterRule {
+            json_path: Some(json_path.to_string()),
+            ..content_filter_rule(id, &format!("evil{}", id))
+        };
+        let raws = vec![
+            rule("1", "$.user.role"),
+            rule("2", "$.items[*].sku"),
+            rule("3", "/matrix/1"),
+            rule("4", "$.missing.key"),
+            rule("5", "$.broken["),
+        ];
+        let mut logs = Logs::default();
+        let rules = resolve_rules(&mut logs, raws, &HashMap::new()).unwrap();
+        let lines = logs.to_stringvec();
+        assert!(lines.iter().any(|l| l.contains("contentfilter-rules[5].json_path")));
+        let hsdb = Some(rules);
+        let profile = scanning();
+        let matched = |body: serde_json::Value| -> Vec<String> {
+            let mut jvalue = request_map(&[("content-type", "application/json")]);
+            jvalue["body"] = json!(body.to_string());
+            let rinfo = request_info(jvalue);
+            let mut ids: Vec<String> = content_filter_matches(&mut Logs::default(), &rinfo, &profile, &hsdb)
+                .into_iter()
+                .map(|m| m.rule_id)
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(matched(json!({"user": {"role": "evil1"}})), vec!["1"]);
+        // outside of the selector
+        assert!(matched(json!({"user": {"name": "evil1"}, "role": "evil1"})).is_empty());
+        assert!(matched(json!({"user_role": "evil1"})).is_empty());
+        // nested arrays
+        let body = json!({
+            "items": [{"sku": "ok"}, {"sku": ["evil2"]}, {"name": "evil2"}],
+            "matrix": [["evil3"], [["evil3"]]],
+        });
+        assert_eq!(matched(body), vec!["2", "3"]);
+        assert!(matched(json!({"matrix": [["evil3"]]})).is_empty());
+        // missing paths are a no-op
+        assert!(matched(json!({"missing": "evil4", "other": {"key": "evil4"}})).is_empty());
+        assert!(matched(json!(["evil1", "evil4"])).is_empty());
+
+        // the selectors only apply to JSON bodies
+        assert!(content_filter_check(&arg_request("user_role", "evil1"), &profile, &hsdb).is_ok());
+    }
+
+    #[test]
+    fn scan_length_limit() {
+        use crate::engine::content_filter_stage;
+
+        let hsdb = Some(content_filter_rules(vec![content_filter_rule("1", "evil-marker")]));
+        let mut profile = scanning();
+        let late = arg_request("comment", &format!("{}evil-marker", "a ".repeat(200)));
+        let early = arg_request("comment", &format!("evil-marker{}", "a ".repeat(200)));
+        assert!(content_filter_check(&late, &profile, &hsdb).is_err());
+        assert!(!truncated_scan(&late, &profile));
+
+        profile.max_scan_length = Some(100);
+        assert!(truncated_scan(&late, &profile));
+        assert!(content_filter_check(&late, &profile, &hsdb).is_ok());
+        assert!(content_filter_check(&early, &profile, &hsdb).is_err());
+        // the prefix stops at a character boundary
+        let multibyte = arg_request("comment", &format!("{}é{}", "a ".repeat(49), "b".repeat(200)));
+        assert!(content_filter_check(&multibyte, &profile, &hsdb).is_ok());
+        assert!(!truncated_scan(&arg_request("comment", "short"), &profile));
+
+        let securitypolicy = SecurityPolicy {
+            content_filter_profile: profile,
+            ..mk_policy("test")
+        };
+        let mut tags = Tags::default();
+        assert!(content_filter_stage(&mut Logs::default(), &hsdb, &late, &securitypolicy, &mut tags).is_ok());
+        assert!(tags.contains("truncated-scan"));
+    }
+
+    #[test]
+    fn raw_query_rules() {
+        let rule = |id: &str, sections: Option<&[&str]>| RawContentFilterRule {
+            sections: sections.map(|ss| ss.iter().map(|s| s.to_string()).collect()),
+            ..content_filter_rule(id, ";cmd=cat%20")
+        };
+        let raws = vec![
+            rule("1", Some(&["raw_query"])),
+            rule("2", None),
+            rule("3", Some(&["args", "query"])),
+        ];
+        let mut logs = Logs::default();
+        let rules = resolve_rules(&mut logs, raws, &HashMap::new()).unwrap();
+        assert!(logs
+            .to_stringvec()
+            .iter()
+            .any(|l| l.contains("contentfilter-rules[3].sections") && l.contains("unknown section query")));
+        let hsdb = Some(rules);
+        let profile = scanning();
+
+        // the parsed argument is named `;cmd`, and its value is decoded, so only the raw query matches
+        let mut jvalue = request_map(&[]);
+        jvalue["attrs"]["uri"] = json!("/?;cmd=cat%20/etc/passwd");
+        jvalue["attrs"]["query"] = json!(";cmd=cat%20/etc/passwd");
+        jvalue["args"] = json!({";cmd": "cat /etc/passwd"});
+        let rinfo = request_info(jvalue);
+        let matches = content_filter_matches(&mut Logs::default(), &rinfo, &profile, &hsdb);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].rule_id, "1");
+        assert_eq!(matches[0].section, SectionIdx::RawQuery);
+        assert_eq!(matches[0].name, "");
+        assert!(content_filter_check(&rinfo, &profile, &hsdb).is_err());
+
+        // the same value in an argument is not scanned by the raw query rules
+        let rinfo = arg_request("q", ";cmd=cat%20");
+        let mut ids: Vec<String> = content_filter_matches(&mut Logs::default(), &rinfo, &profile, &hsdb)
+            .into_iter()
+            .map(|m| m.rule_id)
+            .collect();
+        ids.sort();
+        // the unknown section of rule 3 is ignored, and it still applies to the arguments
+        assert_eq!(ids, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn content_filter_stream() {
+        let rules = content_filter_rules(vec![content_filter_rule("100000", "select.*from")]);
+        let profile = ContentFilterProfile::default();
+
+        // the signature spans two chunks
+        let mut stream = ContentFilterStream::new(&rules, &profile).unwrap();
+        assert!(stream.feed(b"id=1 union sel").is_none());
+        let decision = stream.feed(b"ect password from users");
+        assert!(matches!(decision, Some(Decision::Action(_))));
+        // the stream keeps blocking once a signature matched
+        assert!(matches!(stream.feed(b"harmless"), Some(Decision::Action(_))));
+        assert!(matches!(stream.finish().0, Decision::Action(_)));
+
+        let mut stream = ContentFilterStream::new(&rules, &profile).unwrap();
+        assert!(stream.feed(b"just a ").is_none());
+        assert!(stream.feed(b"selection").is_none());
+        assert!(matches!(stream.finish().0, Decision::Pass));
+
+        // the signatures excluded on the body argument are not reported
+        let excluding = ContentFilterProfile {
+            arg_exclusions: vec![Matching {
+                matcher: glob_regex("bo*"),
+                inner: std::iter::once("100000".to_string()).collect(),
+            }],
+            ..Default::default()
+        };
+        let mut stream = ContentFilterStream::new(&rules, &excluding).unwrap();
+        assert!(stream.feed(b"id=1 union select password from users").is_none());
+        assert!(matches!(stream.finish().0, Decision::Pass));
+    }
+
+    #[test]
+    fn response_signatures() {
+        let raw = RawContentFilterRule {
+            msg: "card number".to_string(),
+            ..content_filter_rule("100001", "4[0-9]{15}")
+        };
+        let rules = Some(content_filter_rules(vec![raw]));
+        let headers: RequestField = std::iter::once(("x-card".to_string(), "none".to_string())).collect();
+        assert!(content_filter_response(&rules, &headers, Some(b"<html>hello</html>"))
+            .unwrap()
+            .is_none());
+        let block = content_filter_response(&rules, &headers, Some(b"card: 4111111111111111"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.rule_ids(), vec!["100001"]);
+        assert_eq!(block.rule_matches()[0].name, "response-body");
+    }
+}
@@ -0,0 +1,112 @@
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::ContentFilterBlock;
+
+/// the rule ids of the blocks that are not caused by signatures, see `ContentFilterBlock::rule_ids`
+const BUILTIN_RULES: [&str; 12] = [
+    "too-many-entries",
+    "entry-too-large",
+    "restrict-mismatch",
+    "libinjection-sqli",
+    "libinjection-xss",
+    "graphql-too-deep",
+    "args-too-large",
+    "decompress-bomb",
+    "decompress-failed",
+    "ctrl-char",
+    "invalid-utf8",
+    "schema-violation",
+];
+
+static BUILTIN_HITS: [AtomicU64; BUILTIN_RULES.len()] = [const { AtomicU64::new(0) }; BUILTIN_RULES.len()];
+
+lazy_static! {
+    /// the hit counters of the signatures, indexed by rule id
+    ///
+    /// the counters are handed to the rules when the rules database is built, see `rule_counter`, so that matches
+    /// are counted without taking this lock. Rules of several databases that share an id share their counter,
+    /// which survives configuration reloads.
+    static ref RULE_HITS: Mutex<HashMap<String, Arc<AtomicU64>>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    /// set while running checks that must not be counted
+    static PAUSED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// the hit counter of a signature, called when the rules database is built
+pub(crate) fn rule_counter(id: &str) -> Arc<AtomicU64> {
+    match RULE_HITS.lock() {
+        Ok(mut hits) => hits.entry(id.to_string()).or_default().clone(),
+        // the hits of this rule are not reported
+        Err(_) => Arc::new(AtomicU64::new(0)),
+    }
+}
+
+/// runs `f` without counting the hits of its content filter checks
+pub(crate) fn uncounted<A, F: FnOnce() -> A>(f: F) -> A {
+    let previous = PAUSED.with(|p| p.replace(true));
+    let out = f();
+    PAUSED.with(|p| p.set(previous));
+    out
+}
+
+/// true while running checks that must not be counted, see `uncounted`
+pub(crate) fn paused() -> bool {
+    PAUSED.with(|p| p.get())
+}
+
+/// increments the hit counters of the rules that caused the block
+pub(crate) fn count_hits(block: &ContentFilterBlock) {
+    if paused() {
+        return;
+    }
+    match block {
+        ContentFilterBlock::Policies(matches) => {
+            for sig in matches.iter().flat_map(|m| m.ids.iter()) {
+                sig.hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        _ => {
+            for id in block.rule_ids() {
+                if let Some(i) = BUILTIN_RULES.iter().position(|r| *r == id) {
+                    BUILTIN_HITS[i].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// number of matches of each content filter rule, since the process started or the last reset
+pub fn content_filter_stats() -> HashMap<String, u64> {
+    let mut out: HashMap<String, u64> = BUILTIN_RULES
+        .iter()
+        .zip(BUILTIN_HITS.iter())
+        .map(|(id, counter)| (id.to_string(), counter.load(Ordering::Relaxed)))
+        .collect();
+    if let Ok(hits) = RULE_HITS.lock() {
+        out.extend(
+            hits.iter()
+                .map(|(id, counter)| (id.clone(), counter.load(Ordering::Relaxed))),
+        );
+    }
+    // the rules that never matched are not listed
+    out.retain(|_, count| *count > 0);
+    out
+}
+
+/// resets all the hit counters
+pub fn reset_content_filter_stats() {
+    for counter in &BUILTIN_HITS {
+        counter.store(0, Ordering::Relaxed);
+    }
+    if let Ok(hits) = RULE_HITS.lock() {
+        for counter in hits.values() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
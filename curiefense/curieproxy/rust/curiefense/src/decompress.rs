@@ -0,0 +1,264 @@
+/// request body decompression
+///
+/// Bodies are decoded according to their `content-encoding` header, so that the content filter inspects the
+/// decompressed data. The supported codings are gzip, deflate and brotli, and several codings can be combined.
+///
+/// The decompressed size is capped, both in absolute terms and relatively to the compressed size, so that a small
+/// body can not expand into gigabytes (a "zip bomb"). Bodies that can't be decompressed are still inspected as they
+/// are, but the requests are flagged, so that they can be blocked.
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use std::borrow::Cow;
+use std::io::Read;
+
+use crate::logs::Logs;
+
+/// default maximum size of a decompressed body, in bytes
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 10 * 1024 * 1024;
+/// default maximum ratio between the decompressed and compressed sizes of a body
+///
+/// gzip and deflate can't exceed a ratio of about 1030, so only brotli or nested codings can reach it
+pub const DEFAULT_MAX_DECOMPRESSION_RATIO: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecompressionLimits {
+    pub max_size: usize,
+    pub max_ratio: usize,
+}
+
+impl Default for DecompressionLimits {
+    fn default() -> Self {
+        DecompressionLimits {
+            max_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_ratio: DEFAULT_MAX_DECOMPRESSION_RATIO,
+        }
+    }
+}
+
+impl DecompressionLimits {
+    /// the limits, with the defaults for the unset values
+    pub fn new(max_size: Option<usize>, max_ratio: Option<usize>) -> Self {
+        DecompressionLimits {
+            max_size: max_size.unwrap_or(DEFAULT_MAX_DECOMPRESSED_SIZE),
+            max_ratio: max_ratio.unwrap_or(DEFAULT_MAX_DECOMPRESSION_RATIO),
+        }
+    }
+
+    /// the maximum decompressed size for a body of this size
+    fn cap(&self, compressed_size: usize) -> usize {
+        self.max_size.min(compressed_size.saturating_mul(self.max_ratio))
+    }
+}
+
+#[derive(Debug)]
+pub enum DecompressError {
+    /// the decompressed body exceeds the size or ratio limits
+    TooLarge,
+    /// the body could not be decoded with the announced coding
+    Malformed(String),
+    /// the coding is not supported
+    Unsupported(String),
+}
+
+impl std::fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecompressError::TooLarge => write!(f, "decompressed body exceeds the limits"),
+            DecompressError::Malformed(rr) => write!(f, "malformed compressed body: {}", rr),
+            DecompressError::Unsupported(coding) => write!(f, "unsupported content encoding {}", coding),
+        }
+    }
+}
+
+/// reads at most `cap` bytes from the decoder
+fn read_capped<R: Read>(decoder: R, cap: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut out = Vec::new();
+    decoder
+        .take(cap as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|rr| DecompressError::Malformed(rr.to_string()))?;
+    if out.len() > cap {
+        return Err(DecompressError::TooLarge);
+    }
+    Ok(out)
+}
+
+fn decode(coding: &str, body: &[u8], cap: usize) -> Result<Vec<u8>, DecompressError> {
+    match coding {
+        "gzip" | "x-gzip" => read_capped(MultiGzDecoder::new(body), cap),
+        // deflate should be zlib wrapped, but some clients send raw deflate data
+        "deflate" => read_capped(ZlibDecoder::new(body), cap).or_else(|rr| match rr {
+            DecompressError::Malformed(_) => read_capped(DeflateDecoder::new(body), cap),
+            _ => Err(rr),
+        }),
+        "br" => read_capped(brotli_decompressor::Decompressor::new(body, 4096), cap),
+        _ => Err(DecompressError::Unsupported(coding.to_string())),
+    }
+}
+
+/// decodes a body according to the content-encoding header value
+///
+/// the codings are undone in the reverse order of the header, and the limits apply to the original body size,
+/// returns `None` when the body is not encoded
+pub fn decompress_body(
+    content_encoding: Option<&str>,
+    body: &[u8],
+    limits: &DecompressionLimits,
+) -> Result<Option<Vec<u8>>, DecompressError> {
+    let codings: Vec<String> = content_encoding
+        .unwrap_or("")
+        .split(',')
+        .map(|c| c.trim().to_ascii_lowercase())
+        .filter(|c| !c.is_empty() && c != "identity")
+        .collect();
+    if codings.is_empty() {
+        return Ok(None);
+    }
+    let cap = limits.cap(body.len());
+    let mut current = decode(&codings[codings.len() - 1], body, cap)?;
+    for coding in codings.iter().rev().skip(1) {
+        current = decode(coding, &current, cap)?;
+    }
+    Ok(Some(current))
+}
+
+/// the body that should be inspected, see `inspected_body`
+#[derive(Debug)]
+pub enum InspectedBody<'a> {
+    /// the body, decompressed when it is encoded
+    Body(Cow<'a, [u8]>),
+    /// the body is malformed, or uses an unsupported coding, and is inspected as it is
+    Failed(&'a [u8]),
+    /// the decompressed body exceeds the limits, and is not inspected
+    TooLarge,
+}
+
+impl<'a> InspectedBody<'a> {
+    pub fn body(&self) -> Option<&[u8]> {
+        match self {
+            InspectedBody::Body(body) => Some(body),
+            InspectedBody::Failed(body) => Some(body),
+            InspectedBody::TooLarge => None,
+        }
+    }
+}
+
+/// decompresses the body that should be inspected, logging the failures
+pub fn inspected_body<'a>(
+    logs: &mut Logs,
+    content_encoding: Option<&str>,
+    body: &'a [u8],
+    limits: &DecompressionLimits,
+) -> InspectedBody<'a> {
+    match decompress_body(content_encoding, body, limits) {
+        Ok(None) => InspectedBody::Body(Cow::Borrowed(body)),
+        Ok(Some(decoded)) => {
+            logs.debug(format!("body decompressed, {} to {} bytes", body.len(), decoded.len()));
+            InspectedBody::Body(Cow::Owned(decoded))
+        }
+        Err(DecompressError::TooLarge) => {
+            logs.warning("decompressed body exceeds the limits");
+            InspectedBody::TooLarge
+        }
+        Err(rr) => {
+            logs.warning(rr);
+            InspectedBody::Failed(body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut e = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        e.write_all(data).unwrap();
+        e.into_inner()
+    }
+
+    #[test]
+    fn codings() {
+        let limits = DecompressionLimits::default();
+        let body = b"{\"q\": \"1' or '1'='1\"}";
+        let decoded = decompress_body(Some("gzip"), &gzip(body), &limits).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&body[..]));
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(body).unwrap();
+        let decoded = decompress_body(Some("Deflate"), &zlib.finish().unwrap(), &limits).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&body[..]));
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(body).unwrap();
+        let decoded = decompress_body(Some("deflate"), &raw.finish().unwrap(), &limits).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&body[..]));
+
+        let decoded = decompress_body(Some("br"), &brotli(body), &limits).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&body[..]));
+
+        // codings are undone in reverse order
+        let decoded = decompress_body(Some("gzip, identity, gzip"), &gzip(&gzip(body)), &limits).unwrap();
+        assert_eq!(decoded.as_deref(), Some(&body[..]));
+
+        assert!(decompress_body(None, body, &limits).unwrap().is_none());
+        assert!(decompress_body(Some("identity"), body, &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn bombs() {
+        let limits = DecompressionLimits::default();
+        let huge = gzip(&vec![0; DEFAULT_MAX_DECOMPRESSED_SIZE + 1]);
+        assert!(matches!(
+            decompress_body(Some("gzip"), &huge, &limits),
+            Err(DecompressError::TooLarge)
+        ));
+
+        let zeros = vec![0; 1 << 20];
+        // about 1 KiB of gzip, and a few bytes of brotli
+        for (coding, body) in &[("gzip", gzip(&zeros)), ("br", brotli(&zeros))] {
+            let too_large = |limits: DecompressionLimits| {
+                matches!(
+                    decompress_body(Some(coding), body, &limits),
+                    Err(DecompressError::TooLarge)
+                )
+            };
+            assert!(too_large(DecompressionLimits {
+                max_size: usize::MAX,
+                max_ratio: 100,
+            }));
+            assert!(too_large(DecompressionLimits {
+                max_size: (1 << 20) - 1,
+                max_ratio: usize::MAX,
+            }));
+            let limits = DecompressionLimits {
+                max_size: 1 << 20,
+                max_ratio: usize::MAX,
+            };
+            assert_eq!(
+                decompress_body(Some(coding), body, &limits).unwrap().unwrap().len(),
+                1 << 20
+            );
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let limits = DecompressionLimits::default();
+        let mut logs = Logs::default();
+        for coding in &["gzip", "deflate", "br", "compress"] {
+            assert!(decompress_body(Some(coding), b"not compressed", &limits).is_err());
+            let inspected = inspected_body(&mut logs, Some(coding), b"not compressed", &limits);
+            assert!(matches!(inspected, InspectedBody::Failed(b"not compressed")));
+        }
+        let truncated = gzip(b"hello world");
+        assert!(decompress_body(Some("gzip"), &truncated[..truncated.len() / 2], &limits).is_err());
+    }
+}
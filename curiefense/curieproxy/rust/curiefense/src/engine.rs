@@ -0,0 +1,662 @@
+/// the request checks, on explicit state
+///
+/// These functions do not use the global configuration, nor the session maps, so that they can be embedded, and so
+/// that several configurations can be used in the same process. The session API is a wrapper around them.
+use serde::Serialize;
+use std::time::{Instant, SystemTime};
+
+use crate::acl::{check_acl, AclDecision, AclResult, BotHuman};
+use crate::acl_block;
+use crate::config::contentfilter::ContentFilterRules;
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::Config;
+use crate::contentfilter::{
+    content_filter_check_scored, content_filter_matches, schema_violations, truncated_scan, uncounted,
+    ContentFilterBlock, ContentFilterRuleMatch,
+};
+use crate::flow::{flow_check, flow_check_global, InMemoryFlowStorage};
+use crate::interface::{
+    challenge_phase01, challenge_phase02, Action, Decision, DecisionReason, Grasshopper, SimpleDecision, Tags,
+};
+use crate::limit::{limit_check, limit_check_with_store, MemoryLimitStore};
+use crate::logs::Logs;
+use crate::securitypolicy::{match_securitypolicy_trace, PolicyMatchStep};
+use crate::session::{JRequestMap, Stage};
+use crate::tag_anomaly_score;
+use crate::tagging::{tag_request, tag_time};
+use crate::utils::RequestInfo;
+
+/// tags the request, and returns the global filter decision
+///
+/// the client is assumed to be human, without a `human` tag, when its humanity is not known
+pub fn tag_stage(cfg: &Config, rinfo: &RequestInfo, tags: &mut Tags, humanity: Option<bool>) -> SimpleDecision {
+    let (new_tags, decision) = tag_request(humanity.unwrap_or(true), cfg, rinfo);
+    tags.extend(new_tags);
+    if let Some(h) = humanity {
+        tags.insert(if h { "human" } else { "bot" });
+    }
+    decision
+}
+
+/// matches the security policy, and tags the request with its identifiers
+///
+/// returns the name of the host map, along with the security policy
+pub fn securitypolicy_stage<'a>(
+    logs: &mut Logs,
+    cfg: &'a Config,
+    rinfo: &RequestInfo,
+    tags: &mut Tags,
+    trace: Option<&mut Vec<PolicyMatchStep>>,
+) -> Option<(String, &'a SecurityPolicy)> {
+    let (hostmap_name, securitypolicy) = match_securitypolicy_trace(rinfo, cfg, logs, trace)?;
+    tags.insert_qualified("securitypolicy", &hostmap_name);
+    tags.insert_qualified("securitypolicy-entry", &securitypolicy.name);
+    tags.insert_qualified("aclid", &securitypolicy.acl_profile.id);
+    tags.insert_qualified("aclname", &securitypolicy.acl_profile.name);
+    tags.insert_qualified("contentfilterid", &securitypolicy.content_filter_profile.id);
+    tags.insert_qualified("contentfiltername", &securitypolicy.content_filter_profile.name);
+    if let Some(tag) = securitypolicy.rollout.tag() {
+        tags.insert(tag);
+    }
+    policy_time_stage(cfg, securitypolicy, tags);
+    policy_param_stage(rinfo, securitypolicy, tags);
+    Some((hostmap_name, securitypolicy))
+}
+
+/// replaces the `time:` tags with those of the timezone of the security policy, when it has one
+pub fn policy_time_stage(cfg: &Config, securitypolicy: &SecurityPolicy, tags: &mut Tags) {
+    if let Some(timezone) = &securitypolicy.timezone {
+        tag_time(cfg, Some(timezone), SystemTime::now(), tags);
+    }
+}
+
+/// tags the presence of the arguments listed by the security policy, with a `has-param:` or a `missing-param:` tag
+///
+/// An argument with an empty value, such as `debug` in `?debug` or `?debug=`, is present.
+pub fn policy_param_stage(rinfo: &RequestInfo, securitypolicy: &SecurityPolicy, tags: &mut Tags) {
+    for name in &securitypolicy.param_presence {
+        if rinfo.rinfo.qinfo.args.get(name).is_some() {
+            tags.insert_qualified("has-param", name);
+        } else {
+            tags.insert_qualified("missing-param", name);
+        }
+    }
+}
+
+/// runs the content filter checks of the security policy, and tags the request with their outcome
+pub fn content_filter_stage(
+    logs: &mut Logs,
+    hsdb: &Option<ContentFilterRules>,
+    rinfo: &RequestInfo,
+    securitypolicy: &SecurityPolicy,
+    tags: &mut Tags,
+) -> Result<(), ContentFilterBlock> {
+    if rinfo.is_preflight() && !securitypolicy.inspect_preflight {
+        logs.debug("CORS preflight request, skipping the content filter");
+        return Ok(());
+    }
+    let (result, score) = content_filter_check_scored(logs, rinfo, &securitypolicy.content_filter_profile, hsdb);
+    if let Err(ContentFilterBlock::GraphqlTooDeep(_)) = result {
+        tags.insert("graphql-too-deep");
+    }
+    if truncated_scan(rinfo, &securitypolicy.content_filter_profile) {
+        tags.insert("truncated-scan");
+    }
+    for violation in schema_violations(rinfo, &securitypolicy.content_filter_profile) {
+        tags.insert_qualified("schema-violation", &violation.name);
+    }
+    tag_anomaly_score(tags, score);
+    result
+}
+
+/// the body size limit of the security policy, or the `max_body_size` setting
+pub fn body_limit(cfg: &Config, securitypolicy: &SecurityPolicy) -> Option<usize> {
+    securitypolicy.max_body_size.or(cfg.settings.max_body_size)
+}
+
+/// blocks the requests whose body is larger than the limit, tagging them with `body-limit`
+pub fn body_limit_stage(limit: Option<usize>, body_size: usize, tags: &mut Tags) -> Option<Decision> {
+    let limit = limit.filter(|l| body_size > *l)?;
+    tags.insert("body-limit");
+    Some(Decision::Action(Action {
+        status: 413,
+        reason: serde_json::json!({"initiator": "body_limit", "size": body_size, "limit": limit}),
+        content: "Payload too large".to_string(),
+        extra_tags: Some(std::iter::once("body-limit".to_string()).collect()),
+        decision_reason: DecisionReason::BodyLimit { limit },
+        ..Action::default()
+    }))
+}
+
+/// the humanity of the client, and the challenge module, when challenges can be issued, see `evaluate_detailed`
+#[derive(Clone, Copy)]
+pub struct Challenge<'a> {
+    pub is_human: bool,
+    pub grasshopper: Option<&'a dyn Grasshopper>,
+}
+
+impl<'a> Challenge<'a> {
+    /// converts a decision, issuing a challenge page for challenge actions when the client is not human
+    fn decision(&self, sdecision: SimpleDecision, rinfo: &RequestInfo) -> Decision {
+        sdecision.into_decision(self.is_human, &self.grasshopper, &rinfo.headers)
+    }
+}
+
+/// the outcome of `evaluate_detailed`
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub decision: Decision,
+    /// the name of the host map, and the security policy, when one matched
+    pub securitypolicy: Option<(String, SecurityPolicy)>,
+    /// the time spent in each stage that ran, in nanoseconds
+    pub durations: Vec<(Stage, u64)>,
+}
+
+/// runs all the checks, in the same order as `inspect_generic_request_map`, and returns the decision and the logs
+///
+/// The evaluation stops at the first final decision. The requester is assumed to be human, so that no challenges are
+/// issued.
+pub fn evaluate(
+    cfg: &Config,
+    hsdb: &Option<ContentFilterRules>,
+    rinfo: &RequestInfo,
+    tags: &mut Tags,
+) -> (Decision, Logs) {
+    let mut logs = Logs::default();
+    let evaluation = evaluate_detailed(&mut logs, cfg, hsdb, rinfo, tags, None);
+    (evaluation.decision, logs)
+}
+
+/// same as `evaluate`, also returning the matched security policy and the duration of each stage
+///
+/// With a `challenge`, the request is tagged `human` or `bot`, the challenge answers are checked, and the challenge
+/// actions, as well as the ACL denials of bots, issue challenge pages when a grasshopper is given. Without it, the
+/// requester is assumed to be human.
+pub fn evaluate_detailed(
+    logs: &mut Logs,
+    cfg: &Config,
+    hsdb: &Option<ContentFilterRules>,
+    rinfo: &RequestInfo,
+    tags: &mut Tags,
+    challenge: Option<Challenge>,
+) -> Evaluation {
+    let mut durations = Vec::new();
+    let mut timed = |stage: Stage, start: Instant| durations.push((stage, start.elapsed().as_nanos() as u64));
+    let mut matched = None;
+    let decision = evaluate_stages(logs, cfg, hsdb, rinfo, tags, &mut matched, &mut timed, None, challenge);
+    Evaluation {
+        decision,
+        securitypolicy: matched.map(|(hostmap_name, securitypolicy)| (hostmap_name, securitypolicy.clone())),
+        durations,
+    }
+}
+
+/// flow and limit counters, used instead of the global storage
+#[derive(Default)]
+struct ScratchCounters {
+    flows: InMemoryFlowStorage,
+    limits: MemoryLimitStore,
+}
+
+/// the outcome of `simulate`
+#[derive(Debug, Serialize)]
+pub struct SimulationResult {
+    /// `pass` or `custom_response`, as with the session check functions
+    pub action: &'static str,
+    pub response: Option<Action>,
+    /// the name of the host map, and of the security policy, when one matched
+    pub securitypolicy: Option<(String, String)>,
+    /// sorted tags of the request
+    pub tags: Vec<String>,
+    /// all the content filter rules matching the request, for the matched security policy
+    pub matches: Vec<ContentFilterRuleMatch>,
+    /// the configuration loading logs, followed by the evaluation logs
+    pub logs: Vec<String>,
+}
+
+/// evaluates a request map against a configuration blob, in the `init_config_from_json` format
+///
+/// The configuration is only used for this request. The flow and limit counters start from zero, and the global
+/// ones, as well as the content filter statistics, are left untouched. Concurrency limits are not checked.
+pub fn simulate(config_blob: &str, request_map: &str) -> anyhow::Result<SimulationResult> {
+    let mut logs = Logs::default();
+    let (cfg, rules) = Config::from_json(&mut logs, config_blob)
+        .ok_or_else(|| anyhow::anyhow!("{}", logs.to_stringvec().join("\n")))?;
+    let hsdb = Some(rules);
+    let jmap: JRequestMap = serde_json::from_str(request_map)?;
+    let (rinfo, mut tags) = jmap.into_request_info(&mut logs);
+
+    let mut scratch = ScratchCounters::default();
+    let mut matched = None;
+    let (decision, matches) = uncounted(|| {
+        let decision = evaluate_stages(
+            &mut logs,
+            &cfg,
+            &hsdb,
+            &rinfo,
+            &mut tags,
+            &mut matched,
+            &mut |_, _| (),
+            Some(&mut scratch),
+            None,
+        );
+        let matches = match &matched {
+            Some((_, securitypolicy)) => {
+                content_filter_matches(&mut logs, &rinfo, &securitypolicy.content_filter_profile, &hsdb)
+            }
+            None => Vec::new(),
+        };
+        (decision, matches)
+    });
+    let (action, response) = match decision {
+        Decision::Pass => ("pass", None),
+        Decision::Action(a) | Decision::Ratelimit(a, _) => ("custom_response", Some(a)),
+    };
+    let mut tags: Vec<String> = tags.as_hash_ref().iter().cloned().collect();
+    tags.sort();
+    Ok(SimulationResult {
+        action,
+        response,
+        securitypolicy: matched.map(|(hostmap_name, securitypolicy)| (hostmap_name, securitypolicy.name.clone())),
+        tags,
+        matches,
+        logs: logs.to_stringvec(),
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn evaluate_stages<'a, T: FnMut(Stage, Instant)>(
+    logs: &mut Logs,
+    cfg: &'a Config,
+    hsdb: &Option<ContentFilterRules>,
+    rinfo: &RequestInfo,
+    tags: &mut Tags,
+    matched: &mut Option<(String, &'a SecurityPolicy)>,
+    timed: &mut T,
+    scratch: Option<&mut ScratchCounters>,
+    challenge: Option<Challenge>,
+) -> Decision {
+    tags.insert("all");
+    logs.debug("Evaluation starts");
+    let decide = |sdecision: SimpleDecision| match &challenge {
+        None => sdecision.into_decision_no_challenge(),
+        Some(c) => c.decision(sdecision, rinfo),
+    };
+
+    let start = Instant::now();
+    let globalfilter_dec = tag_stage(cfg, rinfo, tags, challenge.map(|c| c.is_human));
+    timed(Stage::Tagging, start);
+    logs.debug("request tagged");
+
+    let start = Instant::now();
+    *matched = securitypolicy_stage(logs, cfg, rinfo, tags, None);
+    timed(Stage::SecurityPolicy, start);
+    let securitypolicy = match matched {
+        Some((_, p)) => *p,
+        None => {
+            logs.debug("Could not find a matching securitypolicy");
+            return Decision::Pass;
+        }
+    };
+    if let Some(decision) = body_limit_stage(
+        body_limit(cfg, securitypolicy),
+        rinfo.rinfo.body_size.unwrap_or(0),
+        tags,
+    ) {
+        return decision;
+    }
+
+    let grasshopper = challenge.and_then(|c| c.grasshopper);
+    if let Some(decision) = grasshopper.and_then(|gh| {
+        rinfo
+            .rinfo
+            .qinfo
+            .uri
+            .as_ref()
+            .and_then(|uri| challenge_phase02(&gh, uri, &rinfo.headers))
+    }) {
+        return decision;
+    }
+
+    let decision = decide(globalfilter_dec);
+    if decision.is_final() {
+        return decision;
+    }
+
+    let start = Instant::now();
+    let flow_result = match scratch.as_deref() {
+        None => flow_check_global(logs, &cfg.flows, rinfo, tags),
+        Some(counters) => flow_check(logs, &cfg.flows, rinfo, tags, &counters.flows),
+    };
+    timed(Stage::Flow, start);
+    match flow_result {
+        Err(rr) => logs.error(rr),
+        Ok(sdecision) => {
+            let decision = decide(sdecision);
+            if decision.is_final() {
+                return decision;
+            }
+        }
+    }
+    logs.debug("flow checks done");
+
+    let start = Instant::now();
+    let limit_result = match scratch {
+        None => limit_check(logs, &securitypolicy.name, rinfo, &securitypolicy.limits, tags, None),
+        Some(counters) => limit_check_with_store(
+            logs,
+            &securitypolicy.name,
+            rinfo,
+            &securitypolicy.limits,
+            tags,
+            &mut counters.limits,
+        ),
+    };
+    timed(Stage::Limit, start);
+    let decision = decide(limit_result);
+    if decision.is_final() {
+        return decision;
+    }
+    logs.debug(format!("limit checks done ({} limits)", securitypolicy.limits.len()));
+
+    let start = Instant::now();
+    let acl_result = check_acl(tags, &securitypolicy.acl_profile);
+    timed(Stage::Acl, start);
+    logs.debug(format!("ACL result: {:?}", acl_result));
+    // bots are only considered with a challenge, the requester being assumed to be human otherwise
+    let blockcode: Option<(i32, Vec<String>)> = match acl_result {
+        AclResult::Passthrough(dec) => {
+            if dec.allowed {
+                logs.debug("ACL passthrough detected");
+                return Decision::Pass;
+            } else {
+                logs.debug("ACL force block detected");
+                Some((0, dec.tags))
+            }
+        }
+        AclResult::Match(BotHuman {
+            bot: _,
+            human:
+                Some(AclDecision {
+                    allowed: false,
+                    tags: dtags,
+                    ..
+                }),
+        }) => {
+            logs.debug("ACL human block detected");
+            Some((5, dtags))
+        }
+        // bots are challenged
+        AclResult::Match(BotHuman {
+            bot:
+                Some(AclDecision {
+                    allowed: false,
+                    tags: dtags,
+                    ..
+                }),
+            human: _,
+        }) => match challenge {
+            Some(c) if !c.is_human => match (rinfo.headers.get("user-agent"), c.grasshopper) {
+                (Some(ua), Some(gh)) => {
+                    logs.debug("ACL challenge detected: challenged");
+                    let reason = DecisionReason::Acl { tags: dtags.clone() };
+                    return challenge_phase01(&gh, ua, dtags).with_reason(reason);
+                }
+                (gua, ggh) => {
+                    logs.debug(format!(
+                        "ACL challenge detected: can't challenge, ua={} gh={}",
+                        gua.is_some(),
+                        ggh.is_some()
+                    ));
+                    Some((3, dtags))
+                }
+            },
+            _ => None,
+        },
+        _ => None,
+    };
+    logs.debug(format!("ACL checks done {:?}", blockcode));
+
+    if securitypolicy.acl_active {
+        if let Some((cde, tgs)) = blockcode {
+            return acl_block(true, cde, &tgs);
+        }
+    }
+
+    let start = Instant::now();
+    let content_filter_result = content_filter_stage(logs, hsdb, rinfo, securitypolicy, tags);
+    timed(Stage::ContentFilter, start);
+    logs.debug("Content Filter checks done");
+
+    match content_filter_result {
+        Ok(()) => {
+            // the acl decision is monitored when the acl profile is not active
+            if let Some((cde, tgs)) = blockcode {
+                acl_block(false, cde, &tgs)
+            } else {
+                Decision::Pass
+            }
+        }
+        Err(wb) => {
+            let mut action = wb.to_action();
+            action.block_mode = securitypolicy.content_filter_active;
+            Decision::Action(action)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::HostMap;
+    use crate::config::raw::AclProfile;
+    use crate::testutils;
+    use crate::utils::{map_request, RequestMeta};
+    use std::collections::HashMap;
+
+    /// a configuration whose default policy denies the requests with the given tag
+    fn mk_config(denied: &str) -> Config {
+        let mut acl_profile = AclProfile::default();
+        acl_profile.deny.insert(denied.to_string());
+        let mut cfg = Config::empty();
+        cfg.default = Some(HostMap {
+            id: "__default__".to_string(),
+            name: "__default__".to_string(),
+            entries: Vec::new(),
+            entries_set: None,
+            default: Some(SecurityPolicy {
+                acl_profile,
+                ..testutils::mk_policy("default")
+            }),
+        });
+        cfg
+    }
+
+    #[test]
+    fn independent_configs() {
+        let meta = RequestMeta {
+            authority: Some("example.com".to_string()),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            extra: HashMap::new(),
+        };
+        let rinfo = map_request(&mut Logs::default(), "1.2.3.4".to_string(), HashMap::new(), meta, None).unwrap();
+        let strict = mk_config("all");
+        let lenient = mk_config("nothing");
+
+        let mut tags = Tags::default();
+        let (decision, logs) = evaluate(&strict, &None, &rinfo, &mut tags);
+        assert!(decision.is_blocking());
+        assert!(tags.contains("securitypolicy-entry:default"));
+        assert!(!logs.logs.is_empty());
+
+        let mut tags = Tags::default();
+        let mut logs = Logs::default();
+        let evaluation = evaluate_detailed(&mut logs, &lenient, &None, &rinfo, &mut tags, None);
+        assert!(!evaluation.decision.is_blocking());
+        assert_eq!(
+            evaluation.securitypolicy.map(|(h, p)| (h, p.name)),
+            Some(("__default__".to_string(), "default".to_string()))
+        );
+        let stages: Vec<Stage> = evaluation.durations.iter().map(|(s, _)| *s).collect();
+        assert_eq!(
+            stages,
+            vec![
+                Stage::Tagging,
+                Stage::SecurityPolicy,
+                Stage::Flow,
+                Stage::Limit,
+                Stage::Acl,
+                Stage::ContentFilter
+            ]
+        );
+
+        // without a security policy, the request passes
+        let mut tags = Tags::default();
+        let (decision, _) = evaluate(&Config::empty(), &None, &rinfo, &mut tags);
+        assert!(!decision.is_blocking());
+        assert!(tags.contains("all"));
+    }
+
+    #[test]
+    fn param_presence_tags() {
+        let mk_rinfo = |path: &str| {
+            let meta = RequestMeta {
+                authority: Some("example.com".to_string()),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                extra: HashMap::new(),
+            };
+            map_request(&mut Logs::default(), "1.2.3.4".to_string(), HashMap::new(), meta, None).unwrap()
+        };
+        let mut cfg = mk_config("has-param:debug");
+        let policy = cfg.default.as_mut().unwrap().default.as_mut().unwrap();
+        policy.param_presence = vec!["debug".to_string(), "signature".to_string()];
+        let tagged = |path: &str| {
+            let mut tags = Tags::default();
+            let (decision, _) = evaluate(&cfg, &None, &mk_rinfo(path), &mut tags);
+            let presence: Vec<String> = tags
+                .to_sorted_vec()
+                .into_iter()
+                .filter(|t| t.contains("-param:"))
+                .collect();
+            (decision.is_blocking(), presence)
+        };
+
+        assert_eq!(
+            tagged("/"),
+            (
+                false,
+                vec!["missing-param:debug".to_string(), "missing-param:signature".to_string()]
+            )
+        );
+        // empty values are present
+        assert_eq!(
+            tagged("/?debug"),
+            (
+                true,
+                vec!["has-param:debug".to_string(), "missing-param:signature".to_string()]
+            )
+        );
+        assert_eq!(
+            tagged("/?debug=&signature=abc"),
+            (
+                true,
+                vec!["has-param:debug".to_string(), "has-param:signature".to_string()]
+            )
+        );
+        assert_eq!(
+            tagged("/?debugging=1&signature="),
+            (
+                false,
+                vec!["has-param:signature".to_string(), "missing-param:debug".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn preflight_skips_content_filter() {
+        let mk_rinfo = |method: &str, preflight: bool| {
+            let meta = RequestMeta {
+                authority: Some("example.com".to_string()),
+                method: method.to_string(),
+                path: "/?q=a-long-argument".to_string(),
+                extra: HashMap::new(),
+            };
+            let mut headers = HashMap::new();
+            headers.insert("origin".to_string(), "https://other.example.com".to_string());
+            if preflight {
+                headers.insert("access-control-request-method".to_string(), "POST".to_string());
+            }
+            map_request(&mut Logs::default(), "1.2.3.4".to_string(), headers, meta, None).unwrap()
+        };
+        let mut cfg = mk_config("nothing");
+        let policy = cfg.default.as_mut().unwrap().default.as_mut().unwrap();
+        policy.content_filter_profile.max_total_args_length = Some(4);
+        let check = |cfg: &Config, rinfo: &RequestInfo| evaluate(cfg, &None, rinfo, &mut Tags::default()).0;
+
+        assert!(check(&cfg, &mk_rinfo("GET", false)).is_blocking());
+        // an OPTIONS request without the preflight header is filtered
+        assert!(check(&cfg, &mk_rinfo("OPTIONS", false)).is_blocking());
+        assert!(!check(&cfg, &mk_rinfo("OPTIONS", true)).is_blocking());
+
+        let policy = cfg.default.as_mut().unwrap().default.as_mut().unwrap();
+        policy.inspect_preflight = true;
+        assert!(check(&cfg, &mk_rinfo("OPTIONS", true)).is_blocking());
+    }
+
+    #[test]
+    fn simulation() {
+        let blob = serde_json::json!({
+            "securitypolicy": [{
+                "match": "__default__", "id": "__default__", "name": "__default__",
+                "map": [{"match": "/", "name": "default", "acl_profile": "__default__",
+                         "content_filter_profile": "__default__", "acl_active": true,
+                         "content_filter_active": true, "limit_ids": []}]
+            }],
+            "acl-profiles": [{"id": "__default__", "name": "default", "allow": [], "allow_bot": [], "deny_bot": [],
+                              "passthrough": [], "force_deny": [], "deny": ["simulated-deny"]}],
+            "contentfilter-profiles": [{"id": "__default__", "name": "default", "ignore_alphanum": false,
+                                        "max_header_length": 1024, "max_cookie_length": 1024, "max_arg_length": 1024,
+                                        "max_headers_count": 42, "max_cookies_count": 42, "max_args_count": 512,
+                                        "args": {"names": [], "regex": []}, "headers": {"names": [], "regex": []},
+                                        "cookies": {"names": [], "regex": []}}],
+            "contentfilter-rules": [{"id": "172001", "name": "172001", "msg": "simulated", "operand": "simulate[0-9]+",
+                                     "severity": 5, "certainity": 5, "category": "test", "subcategory": "test"}]
+        })
+        .to_string();
+        let request_map = |arg: &str, tags: serde_json::Value| {
+            let mut jvalue = testutils::request_map(&[("host", "www.example.com")]);
+            jvalue["args"] = serde_json::json!({ "q": arg });
+            jvalue["attrs"]["ip"] = serde_json::json!("1.2.3.4");
+            jvalue["attrs"]["query"] = serde_json::json!(format!("q={}", arg));
+            jvalue["attrs"]["uri"] = serde_json::json!(format!("/?q={}", arg));
+            jvalue["attrs"]["tags"] = tags;
+            jvalue.to_string()
+        };
+
+        let result = simulate(&blob, &request_map("clean", serde_json::json!({}))).unwrap();
+        assert_eq!(result.action, "pass");
+        assert_eq!(
+            result.securitypolicy,
+            Some(("__default__".to_string(), "default".to_string()))
+        );
+        assert!(result.tags.contains(&"all".to_string()));
+        assert!(result.matches.is_empty());
+        assert!(!result.logs.is_empty());
+
+        let result = simulate(&blob, &request_map("simulate42", serde_json::json!({}))).unwrap();
+        assert_eq!(result.action, "custom_response");
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.matches[0].rule_id, "172001");
+        // the simulation does not change the global statistics
+        assert!(!crate::contentfilter::content_filter_stats().contains_key("172001"));
+
+        let result = simulate(&blob, &request_map("clean", serde_json::json!({"simulated-deny": 1}))).unwrap();
+        assert_eq!(result.action, "custom_response");
+        assert_eq!(result.response.map(|a| a.status), Some(403));
+
+        assert!(simulate("not a config", &request_map("clean", serde_json::json!({}))).is_err());
+        assert!(simulate(&blob, "{}").is_err());
+    }
+}
@@ -0,0 +1,404 @@
+/// C entry points for the common session calls, declared in `include/curiefense.h`
+///
+/// The functions return a `CuriefenseStatus`, their results being written to out-params, so that a decision does not
+/// have to be serialized to JSON and parsed again. The strings they allocate are released with `curiefense_free`,
+/// and the decisions with `curiefense_decision_free`. The message of the last error of the calling thread can be
+/// retrieved with `curiefense_last_error`.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, UnwindSafe};
+use std::ptr;
+
+use crate::interface::Decision;
+use crate::session::{self, SessionError};
+
+/// the status codes of the entry points, with the values of the `CURIEFENSE_*` constants of the header
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CuriefenseStatus {
+    Ok = 0,
+    /// a pointer was NULL, or a string was not valid UTF-8
+    InvalidArgument = 1,
+    InvalidSessionId = 2,
+    UnknownSession = 3,
+    LockPoisoned = 4,
+    NoSecurityPolicy = 5,
+    DeserializeFailed = 6,
+    InvalidRequestMap = 7,
+    InvalidTag = 8,
+    UnknownTenant = 9,
+    NoResponse = 10,
+    Capacity = 11,
+    Other = 12,
+    /// the call panicked, the panic being caught so that it does not unwind into the caller
+    Panic = 13,
+}
+
+impl From<&SessionError> for CuriefenseStatus {
+    fn from(rr: &SessionError) -> Self {
+        match rr {
+            SessionError::InvalidSessionId(_) => CuriefenseStatus::InvalidSessionId,
+            SessionError::UnknownSession => CuriefenseStatus::UnknownSession,
+            SessionError::LockPoisoned(_) => CuriefenseStatus::LockPoisoned,
+            SessionError::NoSecurityPolicy => CuriefenseStatus::NoSecurityPolicy,
+            SessionError::DeserializeFailed(_) => CuriefenseStatus::DeserializeFailed,
+            SessionError::InvalidRequestMap(_) => CuriefenseStatus::InvalidRequestMap,
+            SessionError::InvalidTag(_) => CuriefenseStatus::InvalidTag,
+            SessionError::BatchEntry(_, inner) => CuriefenseStatus::from(inner.as_ref()),
+            SessionError::UnknownTenant(_) => CuriefenseStatus::UnknownTenant,
+            SessionError::NoResponse => CuriefenseStatus::NoResponse,
+            SessionError::Capacity(_) => CuriefenseStatus::Capacity,
+            SessionError::Other(_) => CuriefenseStatus::Other,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct CuriefenseHeader {
+    pub name: *mut c_char,
+    pub value: *mut c_char,
+}
+
+/// a decision, whose pointers are NULL when the request passed
+#[repr(C)]
+#[derive(Debug)]
+pub struct CuriefenseDecision {
+    /// `pass`, or the type of the action, such as `block` or `monitor`
+    pub action: *mut c_char,
+    /// the request must be stopped, and answered with `status`, `content` and `headers`
+    pub blocking: bool,
+    pub status: u32,
+    pub content: *mut c_char,
+    /// the check that produced the action, such as `acl`
+    pub initiator: *mut c_char,
+    pub headers: *mut CuriefenseHeader,
+    pub headers_len: usize,
+}
+
+impl CuriefenseDecision {
+    fn empty() -> Self {
+        CuriefenseDecision {
+            action: ptr::null_mut(),
+            blocking: false,
+            status: 0,
+            content: ptr::null_mut(),
+            initiator: ptr::null_mut(),
+            headers: ptr::null_mut(),
+            headers_len: 0,
+        }
+    }
+
+    fn new(decision: &Decision) -> Self {
+        let action = match decision.action() {
+            None => {
+                return CuriefenseDecision {
+                    action: to_c_string("pass"),
+                    ..CuriefenseDecision::empty()
+                }
+            }
+            Some(a) => a,
+        };
+        let atype = serde_json::to_value(action.atype)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .unwrap_or_default();
+        let headers: Box<[CuriefenseHeader]> = action
+            .headers
+            .iter()
+            .flatten()
+            .map(|(k, v)| CuriefenseHeader {
+                name: to_c_string(k),
+                value: to_c_string(v),
+            })
+            .collect();
+        let headers_len = headers.len();
+        CuriefenseDecision {
+            action: to_c_string(&atype),
+            blocking: decision.is_blocking(),
+            status: action.status,
+            content: to_c_string(&action.content),
+            initiator: to_c_string(action.decision_reason.initiator()),
+            headers: if headers_len == 0 {
+                ptr::null_mut()
+            } else {
+                Box::into_raw(headers) as *mut CuriefenseHeader
+            },
+            headers_len,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(msg.replace('\0', "")).ok());
+}
+
+/// the interior NUL bytes are dropped, as they can't be represented
+fn to_c_string(s: &str) -> *mut c_char {
+    match CString::new(s) {
+        Ok(c) => c.into_raw(),
+        Err(_) => CString::new(s.replace('\0', "")).unwrap_or_default().into_raw(),
+    }
+}
+
+/// # Safety
+///
+/// `s` must be NULL, or point to a NUL terminated string.
+unsafe fn from_c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// runs a call, recording its error, and catching its panics
+fn guarded<T, F>(f: F) -> Result<T, CuriefenseStatus>
+where
+    F: FnOnce() -> Result<T, SessionError> + UnwindSafe,
+{
+    match catch_unwind(f) {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(rr)) => {
+            set_last_error(rr.to_string());
+            Err(CuriefenseStatus::from(&rr))
+        }
+        Err(_) => {
+            set_last_error("panic".to_string());
+            Err(CuriefenseStatus::Panic)
+        }
+    }
+}
+
+fn invalid_argument(name: &str) -> CuriefenseStatus {
+    set_last_error(format!("{} is NULL, or is not valid UTF-8", name));
+    CuriefenseStatus::InvalidArgument
+}
+
+/// creates a session, see `session::session_init`, its id being written to `session_id_out`
+///
+/// # Safety
+///
+/// `request_map` must be a NUL terminated string, and `session_id_out` must be valid for writes. The id must be
+/// released with `curiefense_free`.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_session_init(
+    request_map: *const c_char,
+    session_id_out: *mut *mut c_char,
+) -> CuriefenseStatus {
+    let request_map = match from_c_str(request_map) {
+        Some(s) => s,
+        None => return invalid_argument("request_map"),
+    };
+    if session_id_out.is_null() {
+        return invalid_argument("session_id_out");
+    }
+    match guarded(|| session::session_init(request_map)) {
+        Ok(id) => {
+            *session_id_out = to_c_string(&id);
+            CuriefenseStatus::Ok
+        }
+        Err(status) => {
+            *session_id_out = ptr::null_mut();
+            status
+        }
+    }
+}
+
+/// # Safety
+///
+/// `session_id` must be a NUL terminated string, and `decision_out` must be valid for writes.
+unsafe fn decision_call<F>(session_id: *const c_char, decision_out: *mut CuriefenseDecision, f: F) -> CuriefenseStatus
+where
+    F: FnOnce(&str) -> Result<Decision, SessionError> + UnwindSafe,
+{
+    let session_id = match from_c_str(session_id) {
+        Some(s) => s,
+        None => return invalid_argument("session_id"),
+    };
+    if decision_out.is_null() {
+        return invalid_argument("decision_out");
+    }
+    match guarded(|| f(session_id).and_then(|d| session::session_interpolate_decision(session_id, d))) {
+        Ok(decision) => {
+            ptr::write(decision_out, CuriefenseDecision::new(&decision));
+            CuriefenseStatus::Ok
+        }
+        Err(status) => {
+            ptr::write(decision_out, CuriefenseDecision::empty());
+            status
+        }
+    }
+}
+
+/// runs all the checks, see `session::session_evaluate`, the templates of the response being interpolated
+///
+/// # Safety
+///
+/// `session_id` must be a NUL terminated string, and `decision_out` must be valid for writes. The decision must be
+/// released with `curiefense_decision_free`.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_session_evaluate(
+    session_id: *const c_char,
+    decision_out: *mut CuriefenseDecision,
+) -> CuriefenseStatus {
+    decision_call(session_id, decision_out, session::session_evaluate)
+}
+
+/// the decision reached by the checks that were run, see `session::session_current_decision`
+///
+/// # Safety
+///
+/// As for `curiefense_session_evaluate`.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_session_decision(
+    session_id: *const c_char,
+    decision_out: *mut CuriefenseDecision,
+) -> CuriefenseStatus {
+    decision_call(session_id, decision_out, session::session_current_decision)
+}
+
+/// see `session::clean_session`
+///
+/// # Safety
+///
+/// `session_id` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_session_clean(session_id: *const c_char) -> CuriefenseStatus {
+    let session_id = match from_c_str(session_id) {
+        Some(s) => s,
+        None => return invalid_argument("session_id"),
+    };
+    match guarded(|| session::clean_session(session_id)) {
+        Ok(()) => CuriefenseStatus::Ok,
+        Err(status) => status,
+    }
+}
+
+/// the message of the last error of the calling thread, or NULL, to be released with `curiefense_free`
+#[no_mangle]
+pub extern "C" fn curiefense_last_error() -> *mut c_char {
+    LAST_ERROR.with(|e| {
+        e.borrow()
+            .as_ref()
+            .map(|c| c.clone().into_raw())
+            .unwrap_or(ptr::null_mut())
+    })
+}
+
+/// releases a string returned by the entry points, NULL being ignored
+///
+/// # Safety
+///
+/// `s` must be NULL, or a string returned by the entry points that was not already released.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// releases the strings and the headers of a decision, that is reset, so that releasing it twice is harmless
+///
+/// # Safety
+///
+/// `decision` must be NULL, or point to a decision written by the entry points.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_decision_free(decision: *mut CuriefenseDecision) {
+    let decision = match decision.as_mut() {
+        Some(d) => d,
+        None => return,
+    };
+    curiefense_free(decision.action);
+    curiefense_free(decision.content);
+    curiefense_free(decision.initiator);
+    if !decision.headers.is_null() {
+        let headers = Box::from_raw(ptr::slice_from_raw_parts_mut(decision.headers, decision.headers_len));
+        for header in headers.iter() {
+            curiefense_free(header.name);
+            curiefense_free(header.value);
+        }
+    }
+    *decision = CuriefenseDecision::empty();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_map;
+
+    fn take(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let out = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        unsafe { curiefense_free(s) };
+        out
+    }
+
+    #[test]
+    fn session_calls() {
+        let request_map = CString::new(request_map(&[("host", "www.example.com")]).to_string()).unwrap();
+        let mut id = ptr::null_mut();
+        let status = unsafe { curiefense_session_init(request_map.as_ptr(), &mut id) };
+        assert_eq!(status, CuriefenseStatus::Ok);
+        let id = CString::new(take(id)).unwrap();
+
+        let mut decision = CuriefenseDecision::empty();
+        let status = unsafe { curiefense_session_evaluate(id.as_ptr(), &mut decision) };
+        assert_eq!(status, CuriefenseStatus::Ok);
+        assert_eq!(unsafe { CStr::from_ptr(decision.action) }.to_str().unwrap(), "pass");
+        assert!(!decision.blocking);
+        assert!(decision.content.is_null());
+        unsafe { curiefense_decision_free(&mut decision) };
+        assert!(decision.action.is_null());
+        // releasing twice is harmless
+        unsafe { curiefense_decision_free(&mut decision) };
+
+        assert_eq!(unsafe { curiefense_session_clean(id.as_ptr()) }, CuriefenseStatus::Ok);
+        let status = unsafe { curiefense_session_decision(id.as_ptr(), &mut decision) };
+        assert_eq!(status, CuriefenseStatus::UnknownSession);
+        assert!(decision.action.is_null());
+        assert_eq!(take(curiefense_last_error()), "Unknown session id");
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let mut id = ptr::null_mut();
+        let status = unsafe { curiefense_session_init(ptr::null(), &mut id) };
+        assert_eq!(status, CuriefenseStatus::InvalidArgument);
+        assert!(id.is_null());
+        let garbage = CString::new("not a session id").unwrap();
+        assert_eq!(
+            unsafe { curiefense_session_clean(garbage.as_ptr()) },
+            CuriefenseStatus::InvalidSessionId
+        );
+        let request_map = CString::new("{}").unwrap();
+        let status = unsafe { curiefense_session_init(request_map.as_ptr(), &mut id) };
+        assert_ne!(status, CuriefenseStatus::Ok);
+        assert!(id.is_null());
+    }
+
+    #[test]
+    fn decisions() {
+        let mut headers = std::collections::HashMap::new();
+        headers.insert("location".to_string(), "/login".to_string());
+        let decision = Decision::Action(crate::interface::Action {
+            status: 302,
+            content: "moved".to_string(),
+            headers: Some(headers),
+            ..crate::interface::Action::default()
+        });
+        let mut out = CuriefenseDecision::new(&decision);
+        assert_eq!(out.status, 302);
+        assert_eq!(out.headers_len, 1);
+        let header = unsafe { &*out.headers };
+        assert_eq!(unsafe { CStr::from_ptr(header.name) }.to_str().unwrap(), "location");
+        assert_eq!(unsafe { CStr::from_ptr(header.value) }.to_str().unwrap(), "/login");
+        assert_eq!(unsafe { CStr::from_ptr(out.content) }.to_str().unwrap(), "moved");
+        unsafe { curiefense_decision_free(&mut out) };
+        assert!(out.headers.is_null() && out.headers_len == 0);
+    }
+}
@@ -1,10 +1,14 @@
 use crate::Logs;
+use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::config::flow::{FlowElement, SequenceKey};
 use crate::config::utils::RequestSelector;
-use crate::interface::{SimpleDecision, Tags};
-use crate::utils::{check_selector_cond, select_string, RequestInfo};
+use crate::interface::{DecisionReason, SimpleDecision, Tags};
+use crate::redis::{redis_conn, RedisCnx};
+use crate::utils::{check_selector_cond, select_string, ExpiringMap, RequestInfo};
 
 fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
     SequenceKey(ri.rinfo.meta.method.to_string() + &ri.rinfo.host + &ri.rinfo.qinfo.qpath)
@@ -28,90 +32,315 @@ fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
     elem.select.iter().all(|e| check_selector_cond(reqinfo, tags, e))
 }
 
+/// storage backend for the flow control state, that must be shared between all proxy instances for flows to work
+/// across instances
+///
+/// Values are counters, indexed by a key that is derived from the flow entry and the request
+pub trait FlowStorage: Send + Sync {
+    /// returns the value of a counter, 0 if it does not exist
+    fn get(&self, key: &str) -> anyhow::Result<u64>;
+    /// sets the value of a counter, that expires after `timeframe` seconds
+    fn set(&self, key: &str, value: u64, timeframe: u64) -> anyhow::Result<()>;
+    /// increments a counter, returning its new value
+    /// when the counter did not exist, it is created, and expires after `timeframe` seconds
+    fn incr(&self, key: &str, timeframe: u64) -> anyhow::Result<u64>;
+    /// the storage that is used instead of this one for the duration of a flow check, so that resources such as
+    /// connections are only acquired once per check
+    fn for_check(&self) -> Option<Box<dyn FlowStorage>> {
+        None
+    }
+}
+
+/// the historical storage, counters are Redis lists, so that all instances sharing a Redis server share their flows
+pub struct RedisFlowStorage;
+
+impl FlowStorage for RedisFlowStorage {
+    fn get(&self, key: &str) -> anyhow::Result<u64> {
+        RedisFlowConnection::default().get(key)
+    }
+
+    fn set(&self, key: &str, value: u64, timeframe: u64) -> anyhow::Result<()> {
+        RedisFlowConnection::default().set(key, value, timeframe)
+    }
+
+    fn incr(&self, key: &str, timeframe: u64) -> anyhow::Result<u64> {
+        RedisFlowConnection::default().incr(key, timeframe)
+    }
+
+    fn for_check(&self) -> Option<Box<dyn FlowStorage>> {
+        Some(Box::new(RedisFlowConnection::default()))
+    }
+}
+
+/// the Redis storage of a single flow check, that connects on first use, and then keeps its connection
+#[derive(Default)]
+struct RedisFlowConnection(Mutex<Option<RedisCnx>>);
+
+impl RedisFlowConnection {
+    fn with_cnx<A, F>(&self, f: F) -> anyhow::Result<A>
+    where
+        F: FnOnce(&mut redis::Connection) -> anyhow::Result<A>,
+    {
+        let mut cnx = self
+            .0
+            .lock()
+            .map_err(|rr| anyhow::anyhow!("Could not lock the flow connection: {}", rr))?;
+        if cnx.is_none() {
+            *cnx = Some(redis_conn()?);
+        }
+        match cnx.as_mut() {
+            Some(cnx) => f(cnx),
+            None => Err(anyhow::anyhow!("no flow connection")),
+        }
+    }
+}
+
+impl FlowStorage for RedisFlowConnection {
+    fn get(&self, key: &str) -> anyhow::Result<u64> {
+        self.with_cnx(|cnx| {
+            let mlistlen: Option<u64> = redis::cmd("LLEN").arg(key).query(cnx)?;
+            Ok(mlistlen.unwrap_or(0))
+        })
+    }
+
+    fn set(&self, key: &str, value: u64, timeframe: u64) -> anyhow::Result<()> {
+        self.with_cnx(|cnx| {
+            let mut pipe = redis::pipe();
+            pipe.cmd("DEL").arg(key).ignore();
+            if value > 0 {
+                pipe.cmd("LPUSH").arg(key);
+                for _ in 0..value {
+                    pipe.arg("foo");
+                }
+                pipe.ignore().cmd("EXPIRE").arg(key).arg(timeframe).ignore();
+            }
+            pipe.query::<()>(cnx)?;
+            Ok(())
+        })
+    }
+
+    fn incr(&self, key: &str, timeframe: u64) -> anyhow::Result<u64> {
+        self.with_cnx(|cnx| {
+            let (listlen, mexpire): (u64, Option<i64>) = redis::pipe()
+                .cmd("LPUSH")
+                .arg(key)
+                .arg("foo")
+                .cmd("TTL")
+                .arg(key)
+                .query(cnx)?;
+            let expire = mexpire.unwrap_or(-1);
+            if expire < 0 {
+                let _: () = redis::cmd("EXPIRE").arg(key).arg(timeframe).query(cnx)?;
+            }
+            Ok(listlen)
+        })
+    }
+}
+
+/// in-memory storage, for single instance deployments, or tests
+#[derive(Default)]
+pub struct InMemoryFlowStorage {
+    counters: Mutex<ExpiringMap<u64>>,
+}
+
+impl InMemoryFlowStorage {
+    /// runs `f` once the counter of `key` is removed if it expired, see `ExpiringMap::access`
+    fn with_counters<A, F>(&self, key: &str, f: F) -> anyhow::Result<A>
+    where
+        F: FnOnce(&mut HashMap<String, (u64, Instant)>, Instant) -> A,
+    {
+        let mut counters = self
+            .counters
+            .lock()
+            .map_err(|rr| anyhow::anyhow!("Could not lock the flow counters: {}", rr))?;
+        let (counters, now) = counters.access(key);
+        Ok(f(counters, now))
+    }
+}
+
+impl FlowStorage for InMemoryFlowStorage {
+    fn get(&self, key: &str) -> anyhow::Result<u64> {
+        self.with_counters(key, |counters, _| counters.get(key).map(|(v, _)| *v).unwrap_or(0))
+    }
+
+    fn set(&self, key: &str, value: u64, timeframe: u64) -> anyhow::Result<()> {
+        self.with_counters(key, |counters, now| {
+            counters.insert(key.to_string(), (value, now + Duration::from_secs(timeframe)));
+        })
+    }
+
+    fn incr(&self, key: &str, timeframe: u64) -> anyhow::Result<u64> {
+        self.with_counters(key, |counters, now| {
+            let entry = counters
+                .entry(key.to_string())
+                .or_insert_with(|| (0, now + Duration::from_secs(timeframe)));
+            entry.0 += 1;
+            entry.0
+        })
+    }
+}
+
+lazy_static! {
+    static ref FLOW_STORAGE: RwLock<Box<dyn FlowStorage>> = RwLock::new(Box::new(RedisFlowStorage));
+}
+
+/// replaces the storage used by `flow_check_global`, which defaults to `RedisFlowStorage`
+pub fn set_flow_storage(storage: Box<dyn FlowStorage>) -> anyhow::Result<()> {
+    let mut w = FLOW_STORAGE
+        .write()
+        .map_err(|rr| anyhow::anyhow!("Could not get the flow storage write lock: {}", rr))?;
+    *w = storage;
+    Ok(())
+}
+
 enum FlowResult {
     NonLast,
     LastOk,
     LastBlock,
 }
 
-fn check_flow(
-    cnx: &mut redis::Connection,
+fn check_flow<S: FlowStorage + ?Sized>(
+    storage: &S,
     redis_key: &str,
     step: u32,
     timeframe: u64,
     is_last: bool,
 ) -> anyhow::Result<FlowResult> {
-    // first, read from REDIS how many steps already passed
-    let mlistlen: Option<usize> = redis::cmd("LLEN").arg(redis_key).query(cnx)?;
-    let listlen = mlistlen.unwrap_or(0);
+    // first, read from the storage how many steps already passed
+    let listlen = storage.get(redis_key)?;
 
     if is_last {
-        if step as usize == listlen {
+        if step as u64 == listlen {
             Ok(FlowResult::LastOk)
         } else {
             Ok(FlowResult::LastBlock)
         }
     } else {
-        if step as usize == listlen {
-            let (_, mexpire): ((), Option<i64>) = redis::pipe()
-                .cmd("LPUSH")
-                .arg(redis_key)
-                .arg("foo")
-                .cmd("TTL")
-                .arg(redis_key)
-                .query(cnx)?;
-            let expire = mexpire.unwrap_or(-1);
-            if expire < 0 {
-                let _: () = redis::cmd("EXPIRE").arg(redis_key).arg(timeframe).query(cnx)?;
-            }
+        if step as u64 == listlen {
+            storage.incr(redis_key, timeframe)?;
         }
         // never block if not the last step!
         Ok(FlowResult::NonLast)
     }
 }
 
-pub fn flow_check(
+/// runs `flow_check` with the storage registered with `set_flow_storage`
+pub fn flow_check_global(
     logs: &mut Logs,
     flows: &HashMap<SequenceKey, Vec<FlowElement>>,
     reqinfo: &RequestInfo,
     tags: &mut Tags,
+) -> anyhow::Result<SimpleDecision> {
+    let storage = FLOW_STORAGE
+        .read()
+        .map_err(|rr| anyhow::anyhow!("Could not get the flow storage read lock: {}", rr))?;
+    flow_check(logs, flows, reqinfo, tags, storage.as_ref())
+}
+
+pub fn flow_check<S: FlowStorage + ?Sized>(
+    logs: &mut Logs,
+    flows: &HashMap<SequenceKey, Vec<FlowElement>>,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    storage: &S,
 ) -> anyhow::Result<SimpleDecision> {
     let sequence_key = session_sequence_key(reqinfo);
     match flows.get(&sequence_key) {
         None => Ok(SimpleDecision::Pass),
-        Some(elems) => {
-            let mut bad = SimpleDecision::Pass;
-            // do not establish the connection if unneeded
-            let mut cnx = crate::redis::redis_conn()?;
-            for elem in elems.iter() {
-                logs.debug(format!("Testing flow control {} (step {})", elem.name, elem.step));
-                if !flow_match(reqinfo, &tags, elem) {
-                    continue;
+        Some(elems) => match storage.for_check() {
+            Some(held) => check_elements(logs, elems, reqinfo, tags, held.as_ref()),
+            None => check_elements(logs, elems, reqinfo, tags, storage),
+        },
+    }
+}
+
+fn check_elements<S: FlowStorage + ?Sized>(
+    logs: &mut Logs,
+    elems: &[FlowElement],
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    storage: &S,
+) -> anyhow::Result<SimpleDecision> {
+    let mut bad = SimpleDecision::Pass;
+    for elem in elems.iter() {
+        logs.debug(format!("Testing flow control {} (step {})", elem.name, elem.step));
+        if !flow_match(reqinfo, &tags, elem) {
+            continue;
+        }
+        logs.debug(format!("Checking flow control {} (step {})", elem.name, elem.step));
+        let mkey = build_redis_key(reqinfo, &elem.key, &elem.id, &elem.name);
+        match mkey {
+            Some(key) => match check_flow(storage, &key, elem.step, elem.timeframe, elem.is_last)? {
+                FlowResult::LastOk => {
+                    tags.insert(&elem.name);
+                    return Ok(SimpleDecision::Pass);
                 }
-                logs.debug(format!("Checking flow control {} (step {})", elem.name, elem.step));
-                let mredis_key = build_redis_key(reqinfo, &elem.key, &elem.id, &elem.name);
-                match mredis_key {
-                    Some(redis_key) => match check_flow(&mut cnx, &redis_key, elem.step, elem.timeframe, elem.is_last)? {
-                        FlowResult::LastOk => {
-                            tags.insert(&elem.name);
-                            return Ok(SimpleDecision::Pass);
-                        }
-                        FlowResult::LastBlock => {
-                            tags.insert(&elem.name);
-                            bad = SimpleDecision::Action(
-                                elem.action.clone(),
-                                serde_json::json!({
-                                    "initiator": "flow_check",
-                                    "name": elem.name
-                                }),
-                            );
-                        }
-                        FlowResult::NonLast => {}
-                    },
-                    None => logs.warning(format!("Could not fetch key in flow control {}", elem.name)),
+                FlowResult::LastBlock => {
+                    tags.insert(&elem.name);
+                    bad = SimpleDecision::Action(
+                        elem.action.clone(),
+                        serde_json::json!({
+                            "initiator": "flow_check",
+                            "name": elem.name
+                        }),
+                        DecisionReason::Flow {
+                            id: elem.id.clone(),
+                            name: elem.name.clone(),
+                        },
+                    );
                 }
-            }
-            Ok(bad)
+                FlowResult::NonLast => {}
+            },
+            None => logs.warning(format!("Could not fetch key in flow control {}", elem.name)),
         }
     }
+    Ok(bad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::flow::flow_resolve;
+    use crate::utils::{map_request, RequestMeta};
+
+    fn mk_rinfo(method: &str) -> RequestInfo {
+        let mut headers = HashMap::new();
+        headers.insert("host".to_string(), "www.example.com".to_string());
+        let meta = RequestMeta {
+            authority: None,
+            method: method.to_string(),
+            path: "/login".to_string(),
+            extra: HashMap::new(),
+        };
+        map_request(&mut Logs::default(), "1.2.3.4".to_string(), headers, meta, None).unwrap()
+    }
+
+    #[test]
+    fn in_memory_flow() {
+        let rawflows =
+            serde_json::from_str(&std::fs::read_to_string("../../config/json/flow-control.json").unwrap()).unwrap();
+        let mut logs = Logs::default();
+        let flows = flow_resolve(&mut logs, rawflows, &HashMap::new());
+        let storage = InMemoryFlowStorage::default();
+        let mut check = |method: &str| {
+            let mut tags = Tags::default();
+            tags.insert("all");
+            flow_check(&mut logs, &flows, &mk_rinfo(method), &mut tags, &storage).unwrap()
+        };
+
+        // last step without the first one
+        assert!(matches!(check("POST"), SimpleDecision::Action(_, _, _)));
+        assert!(matches!(check("GET"), SimpleDecision::Pass));
+        assert!(matches!(check("POST"), SimpleDecision::Pass));
+    }
+
+    #[test]
+    fn in_memory_expiry() {
+        let storage = InMemoryFlowStorage::default();
+        assert_eq!(storage.incr("a", 60).unwrap(), 1);
+        assert_eq!(storage.incr("a", 60).unwrap(), 2);
+        storage.set("b", 5, 0).unwrap();
+        assert_eq!(storage.get("a").unwrap(), 2);
+        assert_eq!(storage.get("b").unwrap(), 0);
+    }
 }
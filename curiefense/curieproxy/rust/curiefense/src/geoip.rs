@@ -0,0 +1,96 @@
+/// pluggable geolocation lookups
+///
+/// `find_geoip` asks the registered `GeoIpProvider`, the MaxMind databases being used when none was registered.
+use lazy_static::lazy_static;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::maxmind::MaxMindProvider;
+
+/// the geolocation data of an address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeoData {
+    /// (lat, lon)
+    pub location: Option<(f64, f64)>,
+    /// in kilometers
+    pub accuracy_radius: Option<u16>,
+    pub in_eu: Option<bool>,
+    pub city_name: Option<String>,
+    /// lowercase ISO 3166-1 code
+    pub country_iso: Option<String>,
+    pub country_name: Option<String>,
+    pub continent_name: Option<String>,
+    pub continent_code: Option<String>,
+    pub asn: Option<u32>,
+    pub company: Option<String>,
+    /// ISO 3166-2 code of the largest subdivision, such as `US-CA`
+    pub subdivision: Option<String>,
+    /// threat categories of the address, such as `tor` or `hosting`, each one being tagged as `geo-<category>`
+    pub threat_categories: Vec<String>,
+}
+
+/// a source of geolocation data
+pub trait GeoIpProvider: Send + Sync {
+    fn lookup(&self, ip: IpAddr) -> GeoData;
+}
+
+lazy_static! {
+    static ref PROVIDER: RwLock<Option<Arc<dyn GeoIpProvider>>> = RwLock::new(None);
+}
+
+/// sets the provider used by `find_geoip`, `None` restoring the MaxMind lookups
+pub fn set_geoip_provider(provider: Option<Arc<dyn GeoIpProvider>>) {
+    if let Ok(mut w) = PROVIDER.write() {
+        *w = provider;
+    }
+}
+
+/// looks an address up with the registered provider
+pub fn geoip_lookup(ip: IpAddr) -> GeoData {
+    let provider = PROVIDER.read().ok().and_then(|r| r.clone());
+    match provider {
+        Some(p) => p.lookup(ip),
+        None => MaxMindProvider.lookup(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::find_geoip;
+
+    /// answers for a single address, so that the tests running at the same time still get the MaxMind lookups
+    struct Feed;
+
+    impl GeoIpProvider for Feed {
+        fn lookup(&self, ip: IpAddr) -> GeoData {
+            if ip != "198.51.100.7".parse::<IpAddr>().unwrap() {
+                return MaxMindProvider.lookup(ip);
+            }
+            GeoData {
+                country_iso: Some("nl".to_string()),
+                asn: Some(64500),
+                threat_categories: vec!["tor".to_string(), "anonymizer".to_string()],
+                ..GeoData::default()
+            }
+        }
+    }
+
+    #[test]
+    fn registered_provider() {
+        set_geoip_provider(Some(Arc::new(Feed)));
+        let geoip = find_geoip("198.51.100.7".to_string());
+        set_geoip_provider(None);
+        assert_eq!(geoip.country_iso.as_deref(), Some("nl"));
+        assert_eq!(geoip.asn, Some(64500));
+        assert_eq!(
+            geoip.threat_categories,
+            vec!["tor".to_string(), "anonymizer".to_string()]
+        );
+
+        // the MaxMind databases are missing in tests
+        let geoip = find_geoip("198.51.100.7".to_string());
+        assert_eq!(geoip.country_iso, None);
+        assert!(geoip.threat_categories.is_empty());
+    }
+}
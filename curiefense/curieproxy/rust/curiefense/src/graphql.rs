@@ -0,0 +1,199 @@
+/// GraphQL query analysis
+///
+/// This is not a full GraphQL parser: it only tracks the nesting of selection sets and counts the
+/// selected fields. Fragment spreads are not expanded, so the depth of a query using fragments is
+/// computed on each fragment definition separately.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphQlInfo {
+    /// maximum nesting of selection sets
+    pub depth: usize,
+    /// number of selected fields, aliases excluded
+    pub fields: usize,
+}
+
+impl GraphQlInfo {
+    fn max(self, other: GraphQlInfo) -> GraphQlInfo {
+        GraphQlInfo {
+            depth: self.depth.max(other.depth),
+            fields: self.fields.max(other.fields),
+        }
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn skip_string(chars: &mut Peekable<Chars>) {
+    // the opening quote has already been consumed
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            // block string, ends with three quotes
+            let mut quotes = 0;
+            for c in chars {
+                if c == '"' {
+                    quotes += 1;
+                    if quotes == 3 {
+                        return;
+                    }
+                } else {
+                    quotes = 0;
+                }
+            }
+        }
+        // empty string
+        return;
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return,
+            _ => (),
+        }
+    }
+}
+
+/// computes the depth and field count of a GraphQL document
+pub fn analyze_query(query: &str) -> GraphQlInfo {
+    let mut chars = query.chars().peekable();
+    let mut info = GraphQlInfo::default();
+    let mut depth: usize = 0;
+    let mut parens: usize = 0;
+    // the next name is a directive, a fragment name or a type condition, and not a field
+    let mut skip_name = false;
+    let mut spread = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                for c in &mut chars {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' => skip_string(&mut chars),
+            '(' => parens += 1,
+            ')' => parens = parens.saturating_sub(1),
+            // arguments and variable definitions can contain input objects
+            _ if parens > 0 => (),
+            '{' => {
+                depth += 1;
+                info.depth = info.depth.max(depth);
+            }
+            '}' => depth = depth.saturating_sub(1),
+            '.' => spread = true,
+            '@' => skip_name = true,
+            c if is_name_start(c) => {
+                let mut name = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if !is_name_char(n) {
+                        break;
+                    }
+                    name.push(n);
+                    chars.next();
+                }
+                if spread {
+                    spread = false;
+                    // "... on Type" is an inline fragment, otherwise this is a fragment spread
+                    skip_name = name == "on";
+                    continue;
+                }
+                if skip_name {
+                    skip_name = false;
+                    continue;
+                }
+                if depth == 0 {
+                    continue;
+                }
+                while let Some(&n) = chars.peek() {
+                    if !n.is_whitespace() {
+                        break;
+                    }
+                    chars.next();
+                }
+                // aliases are followed by a colon
+                if chars.peek() != Some(&':') {
+                    info.fields += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+    info
+}
+
+fn json_query(value: &Value) -> Option<GraphQlInfo> {
+    value.get("query").and_then(|q| q.as_str()).map(analyze_query)
+}
+
+/// analyzes a request body, when it contains a GraphQL request
+///
+/// JSON bodies must have a `query` string field, or be an array of such objects for batched queries, in which
+/// case the maximum depth and field count are returned.
+pub fn graphql_info(mcontent_type: Option<&str>, body: &[u8]) -> Option<GraphQlInfo> {
+    let content_type = mcontent_type.unwrap_or_default();
+    if content_type.starts_with("application/graphql") {
+        return Some(analyze_query(&String::from_utf8_lossy(body)));
+    }
+    if !content_type.ends_with("/json") && !content_type.contains("/json;") {
+        return None;
+    }
+    match serde_json::from_slice(body).ok()? {
+        Value::Array(queries) => queries
+            .iter()
+            .filter_map(json_query)
+            .fold(None, |acc, info| Some(acc.map_or(info, |a: GraphQlInfo| a.max(info)))),
+        value => json_query(&value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(depth: usize, fields: usize) -> GraphQlInfo {
+        GraphQlInfo { depth, fields }
+    }
+
+    #[test]
+    fn simple_queries() {
+        assert_eq!(analyze_query("{ me { name } }"), info(2, 2));
+        assert_eq!(
+            analyze_query(
+                r#"query Get($id: ID!, $f: In = {a: 1}) {
+                  user(id: $id, filter: "} {") @include(if: true) {
+                    friendName: name # a { comment
+                    friends { ... on User { id } ...Frag }
+                  }
+                }"#
+            ),
+            info(4, 4)
+        );
+    }
+
+    #[test]
+    fn batched_bodies() {
+        let body = br#"[{"query": "{ a }"}, {"query": "{ a { b { c } } }"}, {"variables": {}}]"#;
+        assert_eq!(graphql_info(Some("application/json"), body), Some(info(3, 3)));
+        assert_eq!(
+            graphql_info(Some("application/graphql"), b"{ a { b } }"),
+            Some(info(2, 2))
+        );
+        assert_eq!(graphql_info(Some("application/json"), br#"{"a": 1}"#), None);
+        assert_eq!(graphql_info(Some("text/plain"), br#"{"query": "{ a }"}"#), None);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::config::raw::{RawAction, RawActionType};
+use crate::config::responsetemplate::{interpolate, Escaping, ResponseTemplate, TemplateContext};
 use crate::logs::Logs;
 use crate::requestfields::RequestField;
 use crate::utils::RequestInfo;
@@ -8,52 +9,82 @@ use serde_json::json;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum SimpleDecision {
     Pass,
-    Action(SimpleAction, serde_json::Value),
+    Action(SimpleAction, serde_json::Value, DecisionReason),
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Decision {
     Pass,
     Action(Action),
+    /// an action caused by a rate limit, with the state of the limit
+    Ratelimit(Action, RateLimit),
+}
+
+/// the limit that caused a decision, from which the `X-RateLimit-*` response headers can be built
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RateLimit {
+    pub limit_id: String,
+    pub limit_name: String,
+    /// effective number of requests in the timeframe, rounded up
+    pub count: u64,
+    pub threshold: u64,
+    /// seconds until the limit is reset, or until the end of the ban
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn headers(&self) -> HashMap<String, String> {
+        [
+            ("X-RateLimit-Limit", self.threshold),
+            ("X-RateLimit-Remaining", self.threshold.saturating_sub(self.count)),
+            ("X-RateLimit-Reset", self.reset),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
 }
 
 impl Decision {
-    pub fn to_json_raw(&self, request_map: serde_json::Value, logs: Logs) -> String {
+    /// the `action` and `response` fields of the serialized decision, that also has a `ratelimit` field for rate limit
+    /// decisions
+    fn json_fields(&self, request_map: serde_json::Value, logs: Logs) -> serde_json::Value {
         let (action_desc, response) = match self {
             Decision::Pass => ("pass", None),
-            Decision::Action(a) => ("custom_response", Some(a)),
+            Decision::Action(a) | Decision::Ratelimit(a, _) => ("custom_response", Some(a)),
         };
-        let j = serde_json::json!({
+        let mut j = serde_json::json!({
             "request_map": request_map,
             "action": action_desc,
             "response": response,
             "logs": logs.logs
         });
+        if let Decision::Ratelimit(_, rl) = self {
+            j["ratelimit"] = serde_json::json!(rl);
+        }
+        j
+    }
+
+    pub fn to_json_raw(&self, request_map: serde_json::Value, logs: Logs) -> String {
+        let j = self.json_fields(request_map, logs);
         serde_json::to_string(&j).unwrap_or_else(|_| "{}".to_string())
     }
 
     pub fn to_json(&self, rinfo: RequestInfo, tags: Tags, logs: Logs) -> String {
         let mut tgs = tags;
-        let (action_desc, response) = match self {
-            Decision::Pass => ("pass", None),
-            Decision::Action(a) => ("custom_response", Some(a)),
-        };
-        if let Decision::Action(a) = &self {
-            if let Some(extra) = &a.extra_tags {
-                for t in extra {
-                    tgs.insert(t);
-                }
+        let mut decision = self.clone();
+        decision.interpolate(rinfo.headers.get_str("x-request-id").unwrap_or_default(), &tgs);
+        if let Some(extra) = self.action().and_then(|a| a.extra_tags.as_ref()) {
+            for t in extra {
+                tgs.insert(t);
             }
         }
         let request_map = rinfo.into_json(tgs);
-        let j = serde_json::json!({
-            "request_map": request_map,
-            "action": action_desc,
-            "response": response,
-            "logs": logs.logs
-        });
+        let j = decision.json_fields(request_map, logs);
         serde_json::to_string(&j).unwrap_or_else(|_| "{}".to_string())
     }
 
@@ -61,7 +92,7 @@ impl Decision {
     pub fn is_blocking(&self) -> bool {
         match self {
             Decision::Pass => false,
-            Decision::Action(a) => a.atype.is_blocking(),
+            Decision::Action(a) | Decision::Ratelimit(a, _) => a.atype.is_blocking(),
         }
     }
 
@@ -69,16 +100,114 @@ impl Decision {
     pub fn is_final(&self) -> bool {
         match self {
             Decision::Pass => false,
-            Decision::Action(a) => a.atype.is_final(),
+            Decision::Action(a) | Decision::Ratelimit(a, _) => a.atype.is_final(),
+        }
+    }
+
+    /// the check that produced the decision, if any
+    pub fn decision_reason(&self) -> Option<&DecisionReason> {
+        match self {
+            Decision::Pass => None,
+            Decision::Action(a) | Decision::Ratelimit(a, _) => Some(&a.decision_reason),
+        }
+    }
+
+    /// the action of the decision, None when it is `Pass`
+    pub fn action(&self) -> Option<&Action> {
+        match self {
+            Decision::Pass => None,
+            Decision::Action(a) | Decision::Ratelimit(a, _) => Some(a),
+        }
+    }
+
+    /// the state of the rate limit that caused the decision, if any
+    pub fn ratelimit(&self) -> Option<&RateLimit> {
+        match self {
+            Decision::Ratelimit(_, rl) => Some(rl),
+            _ => None,
+        }
+    }
+
+    /// the decision without its rate limit metadata, for the callers that only handle generic actions
+    pub fn into_generic(self) -> Decision {
+        match self {
+            Decision::Ratelimit(a, _) => Decision::Action(a),
+            d => d,
+        }
+    }
+
+    /// replaces the placeholders of the response body, the tags including the extra tags of the action
+    pub fn interpolate(&mut self, request_id: &str, tags: &Tags) {
+        if let Decision::Action(a) | Decision::Ratelimit(a, _) = self {
+            if !a.content.contains("${") {
+                return;
+            }
+            let mut all_tags = tags.clone();
+            for t in a.extra_tags.iter().flatten() {
+                all_tags.insert(t);
+            }
+            let ctx = TemplateContext {
+                request_id: request_id.to_string(),
+                tags: all_tags.to_sorted_vec(),
+                matched_rule: a.decision_reason.matched_rule(),
+            };
+            let content_type = a.headers.as_ref().and_then(|h| {
+                h.iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+                    .map(|(_, v)| v.as_str())
+            });
+            a.content = interpolate(&a.content, Escaping::for_content_type(content_type), &ctx);
+        }
+    }
+
+    /// sets the check that produced the decision, when it is an action
+    pub fn with_reason(self, reason: DecisionReason) -> Self {
+        match self {
+            Decision::Pass => Decision::Pass,
+            Decision::Action(a) => Decision::Action(Action {
+                decision_reason: reason,
+                ..a
+            }),
+            Decision::Ratelimit(a, rl) => Decision::Ratelimit(
+                Action {
+                    decision_reason: reason,
+                    ..a
+                },
+                rl,
+            ),
         }
     }
 }
 
-/// a newtype representing tags, to make sure they are tagified when inserted
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Tags(HashSet<String>);
+/// a type representing tags, to make sure they are tagified when inserted
+///
+/// Tags can carry a value, that is kept verbatim. Matching is always performed on the tag itself, and tags are
+/// serialized as a list, without their values.
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    tags: HashSet<String>,
+    values: HashMap<String, String>,
+}
 
-fn tagify(tag: &str) -> String {
+/// the tags are serialized in alphabetical order, so that the output is stable
+impl Serialize for Tags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_sorted_vec().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tags = HashSet::deserialize(deserializer)?;
+        Ok(Tags {
+            tags,
+            values: HashMap::new(),
+        })
+    }
+}
+
+/// normalizes a tag, non alphanumeric characters being replaced by dashes
+pub fn tagify(tag: &str) -> String {
     fn filter_char(c: char) -> char {
         if c.is_ascii_alphanumeric() || c == ':' {
             c
@@ -89,38 +218,71 @@ fn tagify(tag: &str) -> String {
     tag.to_lowercase().chars().map(filter_char).collect()
 }
 
-impl Default for Tags {
-    fn default() -> Self {
-        Tags(HashSet::new())
-    }
-}
-
 impl Tags {
     pub fn insert(&mut self, value: &str) -> bool {
-        self.0.insert(tagify(value))
+        self.tags.insert(tagify(value))
     }
 
     pub fn insert_qualified(&mut self, id: &str, value: &str) -> bool {
         let mut to_insert = id.to_string();
         to_insert.push(':');
         to_insert += &tagify(value);
-        self.0.insert(to_insert)
+        self.tags.insert(to_insert)
+    }
+
+    /// inserts a tag, associated with a value, replacing any previous value
+    pub fn insert_with_value(&mut self, tag: &str, value: &str) -> bool {
+        let tag = tagify(tag);
+        self.values.insert(tag.clone(), value.to_string());
+        self.tags.insert(tag)
+    }
+
+    /// removes a tag, along with its value
+    pub fn remove(&mut self, tag: &str) -> bool {
+        self.values.remove(tag);
+        self.tags.remove(tag)
     }
 
     pub fn extend(&mut self, other: Self) {
-        self.0.extend(other.0)
+        self.tags.extend(other.tags);
+        self.values.extend(other.values)
     }
 
     pub fn from_slice(slice: &[String]) -> Self {
-        Tags(slice.iter().map(|s| tagify(&s)).collect())
+        Tags {
+            tags: slice.iter().map(|s| tagify(&s)).collect(),
+            values: HashMap::new(),
+        }
     }
 
     pub fn contains(&self, s: &str) -> bool {
-        self.0.contains(s)
+        self.tags.contains(s)
+    }
+
+    /// the value associated with a tag, if any
+    pub fn get_value(&self, tag: &str) -> Option<&str> {
+        self.values.get(tag).map(|v| v.as_str())
     }
 
     pub fn as_hash_ref(&self) -> &HashSet<String> {
-        &self.0
+        &self.tags
+    }
+
+    /// iterates over the tags, along with their values
+    pub fn iter_values(&self) -> impl Iterator<Item = (&String, Option<&String>)> {
+        self.tags.iter().map(move |t| (t, self.values.get(t)))
+    }
+
+    /// the tags, in alphabetical order
+    pub fn to_sorted_vec(&self) -> Vec<String> {
+        let mut out: Vec<String> = self.tags.iter().cloned().collect();
+        out.sort();
+        out
+    }
+
+    /// checks that a tag is non empty, already tagified, and that its qualifier and value are not empty
+    pub fn is_valid(tag: &str) -> bool {
+        !tag.is_empty() && tagify(tag) == tag && tag.split(':').all(|part| !part.is_empty())
     }
 }
 
@@ -135,6 +297,92 @@ pub struct Action {
     pub reason: serde_json::value::Value,
     pub content: String,
     pub extra_tags: Option<HashSet<String>>,
+    /// the check that produced this action
+    #[serde(default)]
+    pub decision_reason: DecisionReason,
+}
+
+/// the check, and the entry of this check, that produced an action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(tag = "initiator", rename_all = "snake_case")]
+pub enum DecisionReason {
+    /// actions that are not the result of a check, such as internal errors
+    #[default]
+    Unknown,
+    /// a global filter action, with the tags of the matching global filter
+    GlobalFilter {
+        tags: Vec<String>,
+    },
+    Flow {
+        id: String,
+        name: String,
+    },
+    Limit {
+        id: String,
+        name: String,
+    },
+    /// an ACL deny, with the matching tags
+    Acl {
+        tags: Vec<String>,
+    },
+    ContentFilter {
+        rule_ids: Vec<String>,
+    },
+    /// the challenge verification response
+    Challenge,
+    /// request smuggling indicators, see `smuggling_indicators`
+    Smuggling {
+        indicators: Vec<String>,
+    },
+    /// the evaluation budget of the session, `fields` or `duration`, was exceeded
+    EvalBudget {
+        limit: String,
+    },
+    /// the body is larger than the `max_body_size` limit, in bytes
+    BodyLimit {
+        limit: usize,
+    },
+    /// the response is larger than the `max_response_size` limit of the content filter profile, in bytes
+    ResponseSize {
+        limit: usize,
+    },
+}
+
+impl DecisionReason {
+    /// the entries of the check that produced the action, comma separated, for the `${matched_rule}` placeholder
+    pub fn matched_rule(&self) -> String {
+        self.matched_rules().join(",")
+    }
+
+    /// the entries of the check that produced the action
+    pub fn matched_rules(&self) -> Vec<String> {
+        match self {
+            DecisionReason::Unknown | DecisionReason::Challenge => Vec::new(),
+            DecisionReason::Flow { id, .. } | DecisionReason::Limit { id, .. } => vec![id.clone()],
+            DecisionReason::GlobalFilter { tags } | DecisionReason::Acl { tags } => tags.clone(),
+            DecisionReason::ContentFilter { rule_ids } => rule_ids.clone(),
+            DecisionReason::Smuggling { indicators } => indicators.clone(),
+            DecisionReason::EvalBudget { limit } => vec![limit.clone()],
+            DecisionReason::BodyLimit { limit } | DecisionReason::ResponseSize { limit } => vec![limit.to_string()],
+        }
+    }
+
+    /// the name of the check, as in the serialized `initiator` field
+    pub fn initiator(&self) -> &'static str {
+        match self {
+            DecisionReason::Unknown => "unknown",
+            DecisionReason::GlobalFilter { .. } => "global_filter",
+            DecisionReason::Flow { .. } => "flow",
+            DecisionReason::Limit { .. } => "limit",
+            DecisionReason::Acl { .. } => "acl",
+            DecisionReason::ContentFilter { .. } => "content_filter",
+            DecisionReason::Challenge => "challenge",
+            DecisionReason::Smuggling { .. } => "smuggling",
+            DecisionReason::EvalBudget { .. } => "eval_budget",
+            DecisionReason::BodyLimit { .. } => "body_limit",
+            DecisionReason::ResponseSize { .. } => "response_size",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -154,6 +402,14 @@ pub struct SimpleAction {
     pub atype: SimpleActionT,
     pub status: u32,
     pub reason: String,
+    /// the response of blocking actions, when the action references a template
+    pub template: Option<ResponseTemplate>,
+    /// seconds after which the client can retry, sent as a `Retry-After` header by blocking actions
+    pub retry_after: Option<u64>,
+    /// set by rate limits, the decision then being a `Decision::Ratelimit`
+    pub ratelimit: Option<RateLimit>,
+    /// headers added to the response of blocking actions, such as the `X-RateLimit-*` headers of rate limits
+    pub response_headers: HashMap<String, String>,
 }
 
 impl std::default::Default for SimpleActionT {
@@ -193,6 +449,7 @@ impl std::default::Default for Action {
             reason: serde_json::value::Value::Null,
             content: "curiefense - request denied".to_string(),
             extra_tags: None,
+            decision_reason: DecisionReason::Unknown,
         }
     }
 }
@@ -203,10 +460,18 @@ impl SimpleAction {
             atype: SimpleActionT::default(),
             status: 503,
             reason,
+            template: None,
+            retry_after: None,
+            ratelimit: None,
+            response_headers: HashMap::new(),
         }
     }
 
-    pub fn resolve(rawaction: &RawAction) -> anyhow::Result<SimpleAction> {
+    /// resolves an action, looking its response template up in `templates`
+    pub fn resolve(
+        rawaction: &RawAction,
+        templates: &HashMap<String, ResponseTemplate>,
+    ) -> anyhow::Result<SimpleAction> {
         let atype = match rawaction.type_ {
             RawActionType::Default => SimpleActionT::Default,
             RawActionType::Monitor => SimpleActionT::Monitor,
@@ -216,7 +481,7 @@ impl SimpleAction {
                         .params
                         .action
                         .as_ref()
-                        .map(|x| SimpleAction::resolve(x).ok())
+                        .map(|x| SimpleAction::resolve(x, templates).ok())
                         .flatten()
                         .unwrap_or_else(|| {
                             SimpleAction::from_reason(rawaction.params.reason.clone().unwrap_or_else(|| "?".into()))
@@ -248,18 +513,32 @@ impl SimpleAction {
                     .ok_or_else(|| anyhow::anyhow!("no location for redirect in rule {:?}", rawaction))?,
             ),
         };
+        let template = match &rawaction.params.template {
+            None => None,
+            Some(id) => Some(
+                templates
+                    .get(id)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("Unknown response template {}", id))?,
+            ),
+        };
+        // an explicit status takes precedence over the template status
         let status = if let Some(sstatus) = &rawaction.params.status {
             match sstatus.parse::<u32>() {
                 Ok(s) => s,
                 Err(rr) => return Err(anyhow::anyhow!("Unparseable status: {} -> {}", sstatus, rr)),
             }
         } else {
-            503
+            template.as_ref().map(|t| t.status).unwrap_or(503)
         };
         Ok(SimpleAction {
             atype,
             status,
             reason: rawaction.params.reason.clone().unwrap_or_else(|| "no reason".into()),
+            template,
+            retry_after: None,
+            ratelimit: None,
+            response_headers: HashMap::new(),
         })
     }
 
@@ -297,6 +576,25 @@ impl SimpleAction {
                 action.headers = Some(headers);
             }
         }
+        if let Some(template) = &self.template {
+            if action.atype.is_blocking() {
+                template.apply(&mut action);
+            }
+        }
+        if let Some(secs) = self.retry_after {
+            if action.atype.is_blocking() {
+                action
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .insert("Retry-After".into(), secs.to_string());
+            }
+        }
+        if action.atype.is_blocking() && !self.response_headers.is_empty() {
+            action
+                .headers
+                .get_or_insert_with(HashMap::new)
+                .extend(self.response_headers.clone());
+        }
         Some(action)
     }
 
@@ -306,33 +604,55 @@ impl SimpleAction {
         mgh: &Option<GH>,
         headers: &RequestField,
         reason: serde_json::Value,
+        decision_reason: DecisionReason,
     ) -> Decision {
         let mut action = match self.to_action(is_human) {
             None => match (mgh, headers.get("user-agent")) {
-                (Some(gh), Some(ua)) => return challenge_phase01(gh, ua, Vec::new()),
+                (Some(gh), Some(ua)) => return challenge_phase01(gh, ua, Vec::new()).with_reason(decision_reason),
                 _ => Action::default(),
             },
             Some(a) => a,
         };
         action.reason = reason;
-        Decision::Action(action)
+        action.decision_reason = decision_reason;
+        self.wrap(action)
     }
 
-    pub fn to_decision_no_challenge(&self, reason: serde_json::Value) -> Decision {
+    fn wrap(&self, action: Action) -> Decision {
+        match &self.ratelimit {
+            None => Decision::Action(action),
+            Some(rl) => Decision::Ratelimit(action, rl.clone()),
+        }
+    }
+
+    pub fn to_decision_no_challenge(&self, reason: serde_json::Value, decision_reason: DecisionReason) -> Decision {
         let mut action = match self.to_action(true) {
             None => Action::default(),
             Some(a) => a,
         };
         action.reason = reason;
-        Decision::Action(action)
+        action.decision_reason = decision_reason;
+        self.wrap(action)
     }
 }
 
 impl SimpleDecision {
+    /// converts the decision, challenge actions issuing a challenge page when the client is not known to be human
+    pub fn into_decision<GH: Grasshopper>(self, is_human: bool, mgh: &Option<GH>, headers: &RequestField) -> Decision {
+        match self {
+            SimpleDecision::Pass => Decision::Pass,
+            SimpleDecision::Action(action, reason, decision_reason) => {
+                action.to_decision(is_human, mgh, headers, reason, decision_reason)
+            }
+        }
+    }
+
     pub fn into_decision_no_challenge(self) -> Decision {
         match self {
             SimpleDecision::Pass => Decision::Pass,
-            SimpleDecision::Action(action, reason) => action.to_decision_no_challenge(reason),
+            SimpleDecision::Action(action, reason, decision_reason) => {
+                action.to_decision_no_challenge(reason, decision_reason)
+            }
         }
     }
 }
@@ -345,6 +665,24 @@ pub trait Grasshopper {
     fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String>;
 }
 
+impl<G: Grasshopper + ?Sized> Grasshopper for &G {
+    fn js_app(&self) -> Option<String> {
+        (**self).js_app()
+    }
+    fn js_bio(&self) -> Option<String> {
+        (**self).js_bio()
+    }
+    fn parse_rbzid(&self, rbzid: &str, seed: &str) -> Option<bool> {
+        (**self).parse_rbzid(rbzid, seed)
+    }
+    fn gen_new_seed(&self, seed: &str) -> Option<String> {
+        (**self).gen_new_seed(seed)
+    }
+    fn verify_workproof(&self, workproof: &str, seed: &str) -> Option<String> {
+        (**self).verify_workproof(workproof, seed)
+    }
+}
+
 pub fn gh_fail_decision(reason: &str) -> Decision {
     Decision::Action(Action {
         atype: ActionType::Block,
@@ -355,6 +693,7 @@ pub fn gh_fail_decision(reason: &str) -> Decision {
         status: 500,
         content: "internal_error".to_string(),
         extra_tags: None,
+        decision_reason: DecisionReason::Unknown,
     })
 }
 
@@ -405,6 +744,7 @@ pub fn challenge_phase01<GH: Grasshopper>(gh: &GH, ua: &str, tags: Vec<String>)
         status: 247,
         content,
         extra_tags: Some(["challenge_phase01"].iter().map(|s| s.to_string()).collect()),
+        decision_reason: DecisionReason::Challenge,
     })
 }
 
@@ -440,5 +780,6 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, uri: &str, headers: &RequestF
         status: 248,
         content: "{}".to_string(),
         extra_tags: Some(["challenge_phase02"].iter().map(|s| s.to_string()).collect()),
+        decision_reason: DecisionReason::Challenge,
     }))
 }
@@ -0,0 +1,204 @@
+/// JSON body selectors, that restrict content filter rules to some values of a JSON body
+///
+/// Two syntaxes are supported:
+///  * a subset of JSONPath, made of child and index steps, such as `$.user.role`, `$.items[*].sku` or
+///    `$['a key'][0]`, where `*` matches any key or index,
+///  * JSON Pointer, such as `/user/role` or `/items/0/sku`, where `~1` stands for `/` and `~0` for `~`.
+///
+/// A selector matches the values at the selected location, and all the values below it.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// a step in the path from the root of a JSON document to a value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JsonSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl std::fmt::Display for JsonSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JsonSegment::Key(k) => write!(f, "{}", k),
+            JsonSegment::Index(i) => write!(f, "{}", i),
+        }
+    }
+}
+
+/// the JSON paths of the scalar values of a body, indexed by the name of the argument they are stored in
+///
+/// an argument name can have several paths, as `{"a_b": 1}` and `{"a": {"b": 1}}` are both flattened to `a_b`
+pub type JsonPaths = HashMap<String, Vec<Vec<JsonSegment>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    /// a JSON Pointer reference token, that can be an object key or an array index
+    Token(String),
+    Any,
+}
+
+impl Step {
+    fn matches(&self, segment: &JsonSegment) -> bool {
+        match (self, segment) {
+            (Step::Any, _) => true,
+            (Step::Key(k), JsonSegment::Key(s)) => k == s,
+            (Step::Index(i), JsonSegment::Index(s)) => i == s,
+            (Step::Token(t), JsonSegment::Key(s)) => t == s,
+            (Step::Token(t), JsonSegment::Index(s)) => *t == s.to_string(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonSelector {
+    source: String,
+    steps: Vec<Step>,
+}
+
+impl JsonSelector {
+    pub fn parse(selector: &str) -> Result<Self, String> {
+        let steps = if let Some(path) = selector.strip_prefix('$') {
+            parse_jsonpath(path)?
+        } else if selector.is_empty() || selector.starts_with('/') {
+            parse_pointer(selector)
+        } else {
+            return Err(format!(
+                "invalid JSON selector {:?}, it must start with $ or /",
+                selector
+            ));
+        };
+        Ok(JsonSelector {
+            source: selector.to_string(),
+            steps,
+        })
+    }
+
+    /// the selector, as it was written in the configuration
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// true when the value at `path` is selected
+    pub fn matches(&self, path: &[JsonSegment]) -> bool {
+        self.steps.len() <= path.len() && self.steps.iter().zip(path).all(|(step, segment)| step.matches(segment))
+    }
+}
+
+fn parse_pointer(pointer: &str) -> Vec<Step> {
+    if pointer.is_empty() {
+        return Vec::new();
+    }
+    pointer[1..]
+        .split('/')
+        .map(|token| Step::Token(token.replace("~1", "/").replace("~0", "~")))
+        .collect()
+}
+
+fn parse_jsonpath(path: &str) -> Result<Vec<Step>, String> {
+    let invalid = |msg: &str| format!("invalid JSONPath ${}: {}", path, msg);
+    let mut steps = Vec::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                let mut key = String::new();
+                while let Some(&n) = chars.peek() {
+                    if n == '.' || n == '[' {
+                        break;
+                    }
+                    key.push(n);
+                    chars.next();
+                }
+                steps.push(match key.as_str() {
+                    "" => return Err(invalid("empty key, recursive descent is not supported")),
+                    "*" => Step::Any,
+                    _ => Step::Key(key),
+                });
+            }
+            '[' => {
+                let step = match chars.peek() {
+                    Some('\'') | Some('"') => {
+                        let quote = chars.next();
+                        let mut key = String::new();
+                        loop {
+                            match chars.next() {
+                                None => return Err(invalid("unterminated string")),
+                                Some('\\') => key.extend(chars.next()),
+                                Some(n) if Some(n) == quote => break,
+                                Some(n) => key.push(n),
+                            }
+                        }
+                        Step::Key(key)
+                    }
+                    _ => {
+                        let mut content = String::new();
+                        while let Some(&n) = chars.peek() {
+                            if n == ']' {
+                                break;
+                            }
+                            content.push(n);
+                            chars.next();
+                        }
+                        if content == "*" {
+                            Step::Any
+                        } else {
+                            Step::Index(
+                                content
+                                    .parse()
+                                    .map_err(|_| invalid(&format!("unsupported subscript [{}]", content)))?,
+                            )
+                        }
+                    }
+                };
+                if chars.next() != Some(']') {
+                    return Err(invalid("missing ]"));
+                }
+                steps.push(step);
+            }
+            _ => return Err(invalid(&format!("unexpected character {:?}", c))),
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(segments: &[&str]) -> Vec<JsonSegment> {
+        segments
+            .iter()
+            .map(|s| match s.parse() {
+                Ok(i) => JsonSegment::Index(i),
+                Err(_) => JsonSegment::Key(s.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn selectors() {
+        let sel = |s: &str| JsonSelector::parse(s).unwrap();
+        assert!(sel("$.user.role").matches(&path(&["user", "role"])));
+        assert!(!sel("$.user.role").matches(&path(&["user", "name"])));
+        assert!(!sel("$.user.role").matches(&path(&["user"])));
+        assert!(sel("$.user").matches(&path(&["user", "role"])));
+        assert!(sel("$.items[*].sku").matches(&path(&["items", "3", "sku"])));
+        assert!(!sel("$.items[*].sku").matches(&path(&["items", "sku"])));
+        assert!(sel("$.items[1]").matches(&path(&["items", "1", "sku"])));
+        assert!(!sel("$.items[1]").matches(&path(&["items", "0", "sku"])));
+        assert!(sel("$['a.b'][\"c\"]").matches(&path(&["a.b", "c"])));
+        assert!(sel("$.*.id").matches(&path(&["anything", "id"])));
+        assert!(sel("$").matches(&[]));
+
+        assert!(sel("/items/0/sku").matches(&path(&["items", "0", "sku"])));
+        assert!(sel("/a~1b/c~0d").matches(&path(&["a/b", "c~d"])));
+        assert!(sel("").matches(&path(&["x"])));
+
+        for bad in &["user.role", "$..role", "$.items[?(@.sku)]", "$.a[1", "$['a]"] {
+            assert!(JsonSelector::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+/// learning mode: the shapes of the arguments and headers inspected by the content filter are recorded for each
+/// endpoint, so that `learning_export` can draft allowlist profiles from the observed traffic
+///
+/// Learning is report only, and off by default: when it is off, the content filter checks only pay for an atomic load.
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::config::raw::{RawContentFilterEntryMatch, RawContentFilterProperties};
+use crate::requestfields::RequestField;
+use crate::tagging::path_template;
+use crate::utils::RequestInfo;
+
+/// the type of the values that match none of the classifiers
+pub const FREE_TEXT: &str = "text";
+
+/// the endpoints that are learned, the others being ignored, so that scans can't exhaust the memory
+const MAX_ENDPOINTS: usize = 1000;
+/// the names that are learned for each endpoint and section
+const MAX_FIELDS: usize = 256;
+
+/// infers the type of the values, the classifiers being tried in order, see `set_value_classifiers`
+pub trait ValueClassifier: Send + Sync {
+    /// the name of the type, such as `int`
+    fn name(&self) -> &str;
+    fn matches(&self, value: &str) -> bool;
+    /// a regex matching the whole values of this type, used by the profile drafts
+    fn pattern(&self) -> &str;
+}
+
+/// a classifier for the values that match a regex
+pub struct RegexClassifier {
+    name: String,
+    regex: Regex,
+}
+
+impl RegexClassifier {
+    /// the regex must match whole values, such as `^[0-9]+$`
+    pub fn new(name: &str, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(RegexClassifier {
+            name: name.to_string(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+}
+
+impl ValueClassifier for RegexClassifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+
+    fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+}
+
+/// the `int`, `uuid` and `email` classifiers
+pub fn default_value_classifiers() -> Vec<Arc<dyn ValueClassifier>> {
+    [
+        ("int", r"^-?[0-9]+$"),
+        (
+            "uuid",
+            r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$",
+        ),
+        ("email", r"^[^@\s]+@[^@\s]+\.[^@\s]+$"),
+    ]
+    .iter()
+    .filter_map(|(name, pattern)| RegexClassifier::new(name, pattern).ok())
+    .map(|c| Arc::new(c) as Arc<dyn ValueClassifier>)
+    .collect()
+}
+
+static LEARNING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CLASSIFIERS: RwLock<Arc<Vec<Arc<dyn ValueClassifier>>>> =
+        RwLock::new(Arc::new(default_value_classifiers()));
+    static ref ENDPOINTS: RwLock<BTreeMap<String, EndpointShape>> = RwLock::new(BTreeMap::new());
+}
+
+/// turns learning on or off, the observations are kept until `reset_learning` is called
+pub fn set_learning(enabled: bool) {
+    LEARNING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn learning_enabled() -> bool {
+    LEARNING.load(Ordering::Relaxed)
+}
+
+/// forgets all the observations
+pub fn reset_learning() {
+    if let Ok(mut w) = ENDPOINTS.write() {
+        w.clear();
+    }
+}
+
+/// replaces the classifiers, the values matching none of them being free text
+pub fn set_value_classifiers(classifiers: Vec<Arc<dyn ValueClassifier>>) {
+    if let Ok(mut w) = CLASSIFIERS.write() {
+        *w = Arc::new(classifiers);
+    }
+}
+
+/// the pattern of the classifier of a type
+pub fn type_pattern(name: &str) -> Option<String> {
+    let classifiers = CLASSIFIERS.read().ok()?;
+    classifiers
+        .iter()
+        .find(|c| c.name() == name)
+        .map(|c| c.pattern().to_string())
+}
+
+/// the values seen for a name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FieldShape {
+    pub count: u64,
+    pub types: BTreeSet<String>,
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl FieldShape {
+    fn observe(&mut self, value_type: &str, length: usize) {
+        if self.count == 0 || length < self.min_length {
+            self.min_length = length;
+        }
+        self.max_length = self.max_length.max(length);
+        self.count += 1;
+        if !self.types.contains(value_type) {
+            self.types.insert(value_type.to_string());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EndpointShape {
+    pub requests: u64,
+    pub args: BTreeMap<String, FieldShape>,
+    pub headers: BTreeMap<String, FieldShape>,
+}
+
+fn classify<'a>(classifiers: &'a [Arc<dyn ValueClassifier>], value: &str) -> &'a str {
+    classifiers
+        .iter()
+        .find(|c| c.matches(value))
+        .map(|c| c.name())
+        .unwrap_or(FREE_TEXT)
+}
+
+fn observe_fields(
+    classifiers: &[Arc<dyn ValueClassifier>],
+    fields: &RequestField,
+    shapes: &mut BTreeMap<String, FieldShape>,
+) {
+    for (name, value) in fields.iter() {
+        if !shapes.contains_key(name) && shapes.len() >= MAX_FIELDS {
+            continue;
+        }
+        shapes
+            .entry(name.clone())
+            .or_default()
+            .observe(classify(classifiers, value), value.len());
+    }
+}
+
+/// records the shape of the request, called by the content filter checks when learning is on
+pub(crate) fn observe(rinfo: &RequestInfo) {
+    let classifiers = match CLASSIFIERS.read() {
+        Ok(r) => r.clone(),
+        Err(_) => return,
+    };
+    let endpoint = path_template(&rinfo.rinfo.qinfo.qpath);
+    let mut endpoints = match ENDPOINTS.write() {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+    if !endpoints.contains_key(&endpoint) && endpoints.len() >= MAX_ENDPOINTS {
+        return;
+    }
+    let shape = endpoints.entry(endpoint).or_default();
+    shape.requests += 1;
+    observe_fields(&classifiers, &rinfo.rinfo.qinfo.args, &mut shape.args);
+    observe_fields(&classifiers, &rinfo.headers, &mut shape.headers);
+}
+
+/// the name entries of a profile section, matching the types that were seen, the free text values being unconstrained
+///
+/// The entries do not restrict the values, so that the draft can be reviewed before being enforced.
+fn draft_section(
+    classifiers: &[Arc<dyn ValueClassifier>],
+    shapes: &BTreeMap<String, FieldShape>,
+) -> RawContentFilterProperties {
+    let pattern = |shape: &FieldShape| -> Option<String> {
+        let patterns: Option<Vec<&str>> = shape
+            .types
+            .iter()
+            .map(|t| classifiers.iter().find(|c| c.name() == t).map(|c| c.pattern()))
+            .collect();
+        patterns.map(|ps| ps.iter().map(|p| format!("(?:{})", p)).collect::<Vec<_>>().join("|"))
+    };
+    RawContentFilterProperties {
+        names: shapes
+            .iter()
+            .map(|(name, shape)| RawContentFilterEntryMatch {
+                key: name.clone(),
+                reg: pattern(shape),
+                restrict: false,
+                exclusions: None,
+            })
+            .collect(),
+        regex: Vec::new(),
+        case_sensitive_names: None,
+        case_sensitive_values: None,
+    }
+}
+
+/// the observations, and a draft of the content filter profile sections of each endpoint
+///
+/// ```json
+/// {"endpoints": {"/users/{id}": {"requests": 1, "args": {...}, "headers": {...}}},
+///  "drafts": {"/users/{id}": {"args": {"names": [...], ...}, "headers": {...}, "max_arg_length": 12, ...}}}
+/// ```
+pub fn learning_export() -> serde_json::Value {
+    let classifiers = match CLASSIFIERS.read() {
+        Ok(r) => r.clone(),
+        Err(_) => Arc::new(Vec::new()),
+    };
+    let endpoints = match ENDPOINTS.read() {
+        Ok(r) => r.clone(),
+        Err(_) => BTreeMap::new(),
+    };
+    let max_length = |shapes: &BTreeMap<String, FieldShape>| shapes.values().map(|s| s.max_length).max().unwrap_or(0);
+    let drafts: serde_json::Map<String, serde_json::Value> = endpoints
+        .iter()
+        .map(|(endpoint, shape)| {
+            (
+                endpoint.clone(),
+                serde_json::json!({
+                    "args": draft_section(&classifiers, &shape.args),
+                    "headers": draft_section(&classifiers, &shape.headers),
+                    "max_arg_length": max_length(&shape.args),
+                    "max_args_count": shape.args.len(),
+                    "max_header_length": max_length(&shape.headers),
+                    "max_headers_count": shape.headers.len(),
+                }),
+            )
+        })
+        .collect();
+    serde_json::json!({
+        "endpoints": endpoints,
+        "drafts": drafts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classification() {
+        let classifiers = default_value_classifiers();
+        assert_eq!(classify(&classifiers, "42"), "int");
+        assert_eq!(classify(&classifiers, "-7"), "int");
+        assert_eq!(classify(&classifiers, "123e4567-e89b-12d3-a456-426614174000"), "uuid");
+        assert_eq!(classify(&classifiers, "someone@example.com"), "email");
+        assert_eq!(classify(&classifiers, "hello world"), FREE_TEXT);
+        assert_eq!(classify(&[], "42"), FREE_TEXT);
+    }
+
+    #[test]
+    fn drafts() {
+        let classifiers = default_value_classifiers();
+        let mut shapes = BTreeMap::new();
+        let mut fields = RequestField::default();
+        fields.add("id".to_string(), "12".to_string());
+        fields.add("q".to_string(), "free text".to_string());
+        observe_fields(&classifiers, &fields, &mut shapes);
+        let mut fields = RequestField::default();
+        fields.add("id".to_string(), "someone@example.com".to_string());
+        observe_fields(&classifiers, &fields, &mut shapes);
+
+        let id = &shapes["id"];
+        assert_eq!(id.count, 2);
+        assert_eq!(id.min_length, 2);
+        assert_eq!(id.max_length, 19);
+        let section = draft_section(&classifiers, &shapes);
+        let reg = section.names[0].reg.as_deref().unwrap();
+        let re = Regex::new(reg).unwrap();
+        assert!(re.is_match("12") && re.is_match("a@b.co") && !re.is_match("12 a"));
+        assert!(!section.names[0].restrict);
+        // free text is not constrained
+        assert_eq!(section.names[1].key, "q");
+        assert_eq!(section.names[1].reg, None);
+    }
+}
@@ -1,11 +1,300 @@
 use crate::logs::Logs;
-use redis::RedisResult;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use crate::config::limit::Limit;
 use crate::config::limit::LimitThreshold;
-use crate::interface::{SimpleActionT, SimpleDecision, Tags};
-use crate::redis::redis_conn;
-use crate::utils::{select_string, RequestInfo};
+use crate::config::raw::LimitAlgorithm;
+use crate::interface::{DecisionReason, RateLimit, SimpleActionT, SimpleDecision, Tags};
+use crate::redis::{redis_conn, RedisCnx};
+use crate::utils::{select_string, ExpiringMap, RequestInfo};
+
+/// the tag of the requests denied by a limit that counts error responses
+pub const ERROR_RATE_TAG: &str = "error-rate-abuse";
+
+/// storage for the limit counters and bans
+pub trait LimitStore {
+    /// increments a counter, or adds `pairvalue` to a set when it is set, returning the new counter value, or the
+    /// set cardinal
+    ///
+    /// the key expires after `ttl` seconds when it did not have an expiry
+    fn incr_with_ttl(&mut self, key: &str, ttl: u64, pairvalue: Option<&str>) -> anyhow::Result<i64>;
+    /// returns the value of a counter (or the cardinal of a set, when `paired` is true), along with the number of
+    /// seconds before it expires, without altering it
+    fn get(&mut self, key: &str, paired: bool) -> anyhow::Result<(i64, Option<u64>)>;
+    /// sets a counter, that expires after `ttl` seconds
+    fn set_with_ttl(&mut self, key: &str, value: i64, ttl: u64) -> anyhow::Result<()>;
+    /// adds `delta` to a counter, without going below 0, returning its new value
+    ///
+    /// the key expires after `ttl` seconds, even when it already had an expiry
+    fn add_with_ttl(&mut self, key: &str, delta: i64, ttl: u64) -> anyhow::Result<i64>;
+}
+
+/// the store shared by all proxy instances, with the same data layout as the Lua implementation
+pub struct RedisLimitStore(pub RedisCnx);
+
+impl LimitStore for RedisLimitStore {
+    fn incr_with_ttl(&mut self, key: &str, ttl: u64, pairvalue: Option<&str>) -> anyhow::Result<i64> {
+        let cnx: &mut redis::Connection = &mut self.0;
+        let (mcurrent, mexpire): (Option<i64>, Option<i64>) = match pairvalue {
+            None => redis::pipe().cmd("INCR").arg(key).cmd("TTL").arg(key).query(cnx)?,
+            Some(pv) => redis::pipe()
+                .cmd("SADD")
+                .arg(key)
+                .arg(pv)
+                .ignore()
+                .cmd("SCARD")
+                .arg(key)
+                .cmd("TTL")
+                .arg(key)
+                .query(cnx)?,
+        };
+        let current = mcurrent.unwrap_or(0);
+        let expire = mexpire.unwrap_or(-1);
+
+        if expire < 0 {
+            let _: () = redis::cmd("EXPIRE").arg(key).arg(ttl).query(cnx)?;
+        }
+
+        Ok(current)
+    }
+
+    fn get(&mut self, key: &str, paired: bool) -> anyhow::Result<(i64, Option<u64>)> {
+        let cnx: &mut redis::Connection = &mut self.0;
+        let (mcurrent, ttl): (Option<i64>, i64) = if paired {
+            redis::pipe().cmd("SCARD").arg(key).cmd("TTL").arg(key).query(cnx)?
+        } else {
+            redis::pipe().cmd("GET").arg(key).cmd("TTL").arg(key).query(cnx)?
+        };
+        // negative TTLs mean that the key does not exist, or has no expiry
+        let reset = if ttl < 0 { None } else { Some(ttl as u64) };
+        Ok((mcurrent.unwrap_or(0), reset))
+    }
+
+    fn set_with_ttl(&mut self, key: &str, value: i64, ttl: u64) -> anyhow::Result<()> {
+        redis::pipe()
+            .cmd("SET")
+            .arg(key)
+            .arg(value)
+            .cmd("EXPIRE")
+            .arg(key)
+            .arg(ttl)
+            .query::<()>(&mut *self.0)?;
+        Ok(())
+    }
+
+    fn add_with_ttl(&mut self, key: &str, delta: i64, ttl: u64) -> anyhow::Result<i64> {
+        let cnx: &mut redis::Connection = &mut self.0;
+        let (current,): (i64,) = redis::pipe()
+            .cmd("INCRBY")
+            .arg(key)
+            .arg(delta)
+            .cmd("EXPIRE")
+            .arg(key)
+            .arg(ttl)
+            .ignore()
+            .query(cnx)?;
+        if current < 0 {
+            self.set_with_ttl(key, 0, ttl)?;
+            return Ok(0);
+        }
+        Ok(current)
+    }
+}
+
+#[derive(Debug)]
+enum LocalCounter {
+    Count(i64),
+    Set(HashSet<String>),
+}
+
+impl LocalCounter {
+    fn value(&self) -> i64 {
+        match self {
+            LocalCounter::Count(c) => *c,
+            LocalCounter::Set(s) => s.len() as i64,
+        }
+    }
+}
+
+/// a store local to an evaluation, whose counters are not shared
+#[derive(Debug, Default)]
+pub struct MemoryLimitStore {
+    counters: ExpiringMap<LocalCounter>,
+}
+
+impl LimitStore for MemoryLimitStore {
+    fn incr_with_ttl(&mut self, key: &str, ttl: u64, pairvalue: Option<&str>) -> anyhow::Result<i64> {
+        let (counters, now) = self.counters.access(key);
+        let (counter, _) = counters.entry(key.to_string()).or_insert_with(|| {
+            let initial = match pairvalue {
+                None => LocalCounter::Count(0),
+                Some(_) => LocalCounter::Set(HashSet::new()),
+            };
+            (initial, now + Duration::from_secs(ttl))
+        });
+        match (counter, pairvalue) {
+            (LocalCounter::Set(st), Some(pv)) => {
+                st.insert(pv.to_string());
+            }
+            (LocalCounter::Count(c), None) => *c += 1,
+            // type mismatch, which should not happen as keys depend on the limit id
+            (c, _) => *c = LocalCounter::Count(1),
+        }
+        Ok(counters.get(key).map(|(c, _)| c.value()).unwrap_or(0))
+    }
+
+    fn get(&mut self, key: &str, _paired: bool) -> anyhow::Result<(i64, Option<u64>)> {
+        let (counters, now) = self.counters.access(key);
+        Ok(match counters.get(key) {
+            None => (0, None),
+            Some((counter, expiry)) => {
+                // rounded up, so that the counter is known to be reset after this many seconds
+                let remaining = expiry.duration_since(now);
+                let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                (counter.value(), Some(secs))
+            }
+        })
+    }
+
+    fn set_with_ttl(&mut self, key: &str, value: i64, ttl: u64) -> anyhow::Result<()> {
+        let (counters, now) = self.counters.access(key);
+        counters.insert(
+            key.to_string(),
+            (LocalCounter::Count(value), now + Duration::from_secs(ttl)),
+        );
+        Ok(())
+    }
+
+    fn add_with_ttl(&mut self, key: &str, delta: i64, ttl: u64) -> anyhow::Result<i64> {
+        let (counters, now) = self.counters.access(key);
+        let previous = counters.get(key).map(|(c, _)| c.value()).unwrap_or(0);
+        let current = (previous + delta).max(0);
+        counters.insert(
+            key.to_string(),
+            (LocalCounter::Count(current), now + Duration::from_secs(ttl)),
+        );
+        Ok(current)
+    }
+}
+
+lazy_static! {
+    static ref LOCAL_COUNTERS: Mutex<MemoryLimitStore> = Mutex::new(MemoryLimitStore::default());
+}
+
+/// a store local to the process, used when Redis can't be reached
+///
+/// all instances share the same counters
+pub struct LocalLimitStore;
+
+impl LocalLimitStore {
+    fn with_counters<A, F>(&self, f: F) -> anyhow::Result<A>
+    where
+        F: FnOnce(&mut MemoryLimitStore) -> anyhow::Result<A>,
+    {
+        let mut counters = LOCAL_COUNTERS
+            .lock()
+            .map_err(|rr| anyhow::anyhow!("Could not lock the local limit counters: {}", rr))?;
+        f(&mut counters)
+    }
+}
+
+impl LimitStore for LocalLimitStore {
+    fn incr_with_ttl(&mut self, key: &str, ttl: u64, pairvalue: Option<&str>) -> anyhow::Result<i64> {
+        self.with_counters(|counters| counters.incr_with_ttl(key, ttl, pairvalue))
+    }
+
+    fn get(&mut self, key: &str, paired: bool) -> anyhow::Result<(i64, Option<u64>)> {
+        self.with_counters(|counters| counters.get(key, paired))
+    }
+
+    fn set_with_ttl(&mut self, key: &str, value: i64, ttl: u64) -> anyhow::Result<()> {
+        self.with_counters(|counters| counters.set_with_ttl(key, value, ttl))
+    }
+
+    fn add_with_ttl(&mut self, key: &str, delta: i64, ttl: u64) -> anyhow::Result<i64> {
+        self.with_counters(|counters| counters.add_with_ttl(key, delta, ttl))
+    }
+}
+
+/// the Redis store, that is replaced by the local store for the rest of the check once a Redis command fails
+pub struct FallbackLimitStore {
+    primary: Option<Box<dyn LimitStore>>,
+    /// the error that caused the fallback, until it is logged
+    failure: Option<anyhow::Error>,
+}
+
+impl FallbackLimitStore {
+    fn with_store<A, F>(&mut self, f: F) -> anyhow::Result<A>
+    where
+        F: Fn(&mut dyn LimitStore) -> anyhow::Result<A>,
+    {
+        if let Some(primary) = self.primary.as_mut() {
+            match f(primary.as_mut()) {
+                Ok(a) => return Ok(a),
+                Err(rr) => {
+                    self.failure = Some(rr);
+                    self.primary = None;
+                }
+            }
+        }
+        f(&mut LocalLimitStore)
+    }
+
+    /// true when the local store is used
+    fn degraded(&self) -> bool {
+        self.primary.is_none()
+    }
+
+    /// logs the Redis failure that caused the fallback, if any
+    fn log_failure(&mut self, logs: &mut Logs) {
+        if let Some(rr) = self.failure.take() {
+            logs.warning(format!("Redis command failed {}, using local limit counters", rr));
+        }
+    }
+}
+
+impl LimitStore for FallbackLimitStore {
+    fn incr_with_ttl(&mut self, key: &str, ttl: u64, pairvalue: Option<&str>) -> anyhow::Result<i64> {
+        self.with_store(|store| store.incr_with_ttl(key, ttl, pairvalue))
+    }
+
+    fn get(&mut self, key: &str, paired: bool) -> anyhow::Result<(i64, Option<u64>)> {
+        self.with_store(|store| store.get(key, paired))
+    }
+
+    fn set_with_ttl(&mut self, key: &str, value: i64, ttl: u64) -> anyhow::Result<()> {
+        self.with_store(|store| store.set_with_ttl(key, value, ttl))
+    }
+
+    fn add_with_ttl(&mut self, key: &str, delta: i64, ttl: u64) -> anyhow::Result<i64> {
+        self.with_store(|store| store.add_with_ttl(key, delta, ttl))
+    }
+}
+
+/// returns the Redis store, that falls back to the local store when Redis can't be reached, or fails later on, see
+/// `FallbackLimitStore::degraded`
+fn limit_store(logs: &mut Logs) -> FallbackLimitStore {
+    match redis_conn() {
+        Ok(cnx) => FallbackLimitStore {
+            primary: Some(Box::new(RedisLimitStore(cnx))),
+            failure: None,
+        },
+        Err(rr) => {
+            logs.warning(format!(
+                "Could not connect to the redis server {}, using local limit counters",
+                rr
+            ));
+            FallbackLimitStore {
+                primary: None,
+                failure: None,
+            }
+        }
+    }
+}
 
 fn build_key(security_policy_name: &str, reqinfo: &RequestInfo, limit: &Limit) -> Option<String> {
     let mut key = security_policy_name.to_string() + &limit.id;
@@ -19,94 +308,222 @@ fn get_ban_key(key: &str) -> String {
     format!("{:X}", md5::compute(format!("limit-ban-hash{}", key)))
 }
 
-fn is_banned(cnx: &mut redis::Connection, key: &str) -> bool {
+fn is_banned(store: &mut dyn LimitStore, key: &str) -> bool {
     let ban_key = get_ban_key(&key);
-    let q: redis::RedisResult<Option<u32>> = redis::cmd("GET").arg(&ban_key).query(cnx);
-    q.unwrap_or(None).is_some()
+    store.get(&ban_key, false).map(|(v, _)| v > 0).unwrap_or(false)
+}
+
+/// seconds until the counter of a limit is reset, from the remaining time to live of its key
+///
+/// keys without an expiry are assumed to have been just created, and the value is clamped to the window size
+fn retry_after(reset: Option<u64>, window: u64) -> u64 {
+    reset.unwrap_or(window).min(window)
+}
+
+/// the index of the current window of a sliding limit, and the elapsed fraction of this window
+fn window_position(now: Duration, timeframe: u64) -> (u64, f64) {
+    let timeframe = timeframe.max(1);
+    let window = now.as_secs() / timeframe;
+    let elapsed = now.saturating_sub(Duration::from_secs(window * timeframe));
+    (window, (elapsed.as_secs_f64() / timeframe as f64).min(1.0))
+}
+
+/// the counter of a window of a sliding limit, that lives for two windows, so that it is still available as the
+/// previous window
+fn window_key(key: &str, window: u64) -> String {
+    format!("{}-w{}", key, window)
+}
+
+/// the estimated number of requests over the sliding window ending now
+fn sliding_rate(previous: i64, current: i64, elapsed: f64) -> f64 {
+    previous as f64 * (1.0 - elapsed) + current as f64
+}
+
+/// a request in flight, counted by a concurrency limit until it is released
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConcurrencySlot {
+    pub limit_id: String,
+    key: String,
+    ttl: u64,
+}
+
+/// releases the slots held by a request
+pub fn release_slots(logs: &mut Logs, slots: &[ConcurrencySlot]) {
+    if slots.is_empty() {
+        return;
+    }
+    let mut store = limit_store(logs);
+    release_slots_with_store(logs, &mut store, slots);
+    store.log_failure(logs);
+}
+
+fn release_slots_with_store(logs: &mut Logs, store: &mut dyn LimitStore, slots: &[ConcurrencySlot]) {
+    for slot in slots {
+        match store.add_with_ttl(&slot.key, -1, slot.ttl) {
+            Ok(current) => logs.debug(format!("released limit {}, {} in flight", slot.limit_id, current)),
+            Err(rr) => logs.error(rr),
+        }
+    }
+}
+
+/// counts a request, returning the effective number of requests in the timeframe
+fn count_request(
+    store: &mut dyn LimitStore,
+    limit: &Limit,
+    key: &str,
+    pairvalue: Option<&str>,
+    now: Duration,
+) -> anyhow::Result<f64> {
+    match limit.algorithm {
+        LimitAlgorithm::Fixed => store.incr_with_ttl(key, limit.timeframe, pairvalue).map(|c| c as f64),
+        LimitAlgorithm::Sliding => {
+            let (window, elapsed) = window_position(now, limit.timeframe);
+            let current = store.incr_with_ttl(&window_key(key, window), 2 * limit.timeframe, pairvalue)?;
+            let (previous, _) = store.get(&window_key(key, window.wrapping_sub(1)), limit.pairwith.is_some())?;
+            Ok(sliding_rate(previous, current, elapsed))
+        }
+        LimitAlgorithm::Concurrency => store.add_with_ttl(key, 1, limit.timeframe).map(|c| c as f64),
+    }
+}
+
+/// the value of the counter of the current window, the effective number of requests in the timeframe, and the
+/// number of seconds until the counter is reset
+fn counter_state(
+    store: &mut dyn LimitStore,
+    limit: &Limit,
+    key: &str,
+    now: Duration,
+) -> anyhow::Result<(i64, f64, Option<u64>)> {
+    let paired = limit.pairwith.is_some() && limit.algorithm != LimitAlgorithm::Concurrency;
+    match limit.algorithm {
+        LimitAlgorithm::Fixed | LimitAlgorithm::Concurrency => {
+            let (current, reset) = store.get(key, paired)?;
+            Ok((current, current as f64, reset))
+        }
+        LimitAlgorithm::Sliding => {
+            let (window, elapsed) = window_position(now, limit.timeframe);
+            let (current, _) = store.get(&window_key(key, window), paired)?;
+            let (previous, _) = store.get(&window_key(key, window.wrapping_sub(1)), paired)?;
+            // the previous window stops counting at the end of the current one, rounded up
+            let remaining = Duration::from_secs((window + 1) * limit.timeframe.max(1)).saturating_sub(now);
+            let remaining = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            let reset = if current == 0 && previous == 0 {
+                None
+            } else {
+                Some(remaining)
+            };
+            Ok((current, sliding_rate(previous, current, elapsed), reset))
+        }
+    }
+}
+
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
 }
 
 fn limit_react(
     logs: &mut Logs,
     tags: &mut Tags,
-    cnx: &mut redis::Connection,
+    store: &mut dyn LimitStore,
     limit: &Limit,
     threshold: &LimitThreshold,
     key: String,
+    now: Duration,
 ) -> SimpleDecision {
     tags.insert(&limit.name);
-    let action = if let SimpleActionT::Ban(subaction, duration) = &threshold.action.atype {
+    let (rate, reset) = match counter_state(store, limit, &key, now) {
+        Ok((_, rate, reset)) => (rate, reset),
+        Err(rr) => {
+            logs.error(rr);
+            (0.0, None)
+        }
+    };
+    let (mut action, retry) = if let SimpleActionT::Ban(subaction, duration) = &threshold.action.atype {
         logs.info(format!("Banned key {} for {}s", key, duration));
         let ban_key = get_ban_key(&key);
-        if let Err(rr) = redis::pipe()
-            .cmd("SET")
-            .arg(&ban_key)
-            .arg(1)
-            .cmd("EXPIRE")
-            .arg(&ban_key)
-            .arg(*duration)
-            .query::<()>(cnx)
-        {
+        if let Err(rr) = store.set_with_ttl(&ban_key, 1, *duration) {
             println!("*** Redis error {}", rr);
         }
-        *subaction.clone()
+        (*subaction.clone(), *duration)
     } else {
-        threshold.action.clone()
+        let retry = match limit.algorithm {
+            // slots are released when the requests in flight complete
+            LimitAlgorithm::Concurrency => 1,
+            LimitAlgorithm::Fixed | LimitAlgorithm::Sliding => retry_after(reset, limit.timeframe),
+        };
+        (threshold.action.clone(), retry)
+    };
+    let ratelimit = RateLimit {
+        limit_id: limit.id.clone(),
+        limit_name: limit.name.clone(),
+        count: rate.max(0.0).ceil() as u64,
+        threshold: threshold.limit,
+        reset: retry,
     };
+    action.retry_after = Some(retry);
+    action.response_headers.extend(ratelimit.headers());
+    action.ratelimit = Some(ratelimit);
     SimpleDecision::Action(
         action,
         serde_json::json!({
             "initiator": "limit",
             "limitname": limit.name,
-            "key": key
+            "key": key,
+            "retry_after": retry
         }),
+        DecisionReason::Limit {
+            id: limit.id.clone(),
+            name: limit.name.clone(),
+        },
     )
 }
 
-fn redis_get_limit(
-    cnx: &mut redis::Connection,
-    key: &str,
-    timeframe: u64,
-    pairvalue: Option<String>,
-) -> RedisResult<i64> {
-    let (mcurrent, mexpire): (Option<i64>, Option<i64>) = match &pairvalue {
-        None => redis::pipe().cmd("INCR").arg(key).cmd("TTL").arg(key).query(cnx)?,
-        Some(pv) => redis::pipe()
-            .cmd("SADD")
-            .arg(key)
-            .arg(pv)
-            .ignore()
-            .cmd("SCARD")
-            .arg(key)
-            .cmd("TTL")
-            .arg(key)
-            .query(cnx)?,
-    };
-    let current = mcurrent.unwrap_or(0);
-    let expire = mexpire.unwrap_or(-1);
+/// counter state of a limit, for a given request
+#[derive(Debug, Clone, Serialize)]
+pub struct LimitStatus {
+    pub id: String,
+    pub name: String,
+    /// current value of the counter, of the current window for sliding limits
+    pub current: i64,
+    /// effective number of requests in the timeframe, that is compared to the thresholds: the counter value for
+    /// fixed limits, and the weighted count of the previous and current windows for sliding limits
+    pub rate: f64,
+    pub algorithm: LimitAlgorithm,
+    /// lowest configured threshold, the limit being triggered when the counter goes above it
+    pub threshold: Option<u64>,
+    /// seconds until the counter is reset, unset when the counter does not exist
+    pub reset: Option<u64>,
+    pub banned: bool,
+    /// set when the request carries one of the exclusion tags of the limit, in which case it is neither counted
+    /// nor blocked
+    pub skipped: bool,
+}
 
-    if expire < 0 {
-        let _: () = redis::cmd("EXPIRE").arg(key).arg(timeframe).query(cnx)?;
-    }
+fn limit_excluded(tags: &Tags, elem: &Limit) -> bool {
+    elem.exclude.iter().any(|e| tags.contains(e))
+}
 
-    Ok(current)
+fn limit_included(tags: &Tags, elem: &Limit) -> bool {
+    elem.include.is_empty() || elem.include.iter().any(|e| tags.contains(e))
 }
 
 fn limit_match(tags: &Tags, elem: &Limit) -> bool {
-    if elem.exclude.iter().any(|e| tags.contains(e)) {
-        return false;
-    }
-    if !(elem.include.is_empty() || elem.include.iter().any(|e| tags.contains(e))) {
-        return false;
-    }
-    true
+    !limit_excluded(tags, elem) && limit_included(tags, elem)
 }
 
+/// checks the limits of a request
+///
+/// the slots of the concurrency limits are added to `slots`, and must be released when the request completes. When
+/// `slots` is not given, the concurrency limits are skipped, as nothing would release them.
 pub fn limit_check(
     logs: &mut Logs,
     security_policy_name: &str,
     reqinfo: &RequestInfo,
     limits: &[Limit],
     tags: &mut Tags,
+    slots: Option<&mut Vec<ConcurrencySlot>>,
 ) -> SimpleDecision {
     // early return to avoid redis connection
     if limits.is_empty() {
@@ -115,19 +532,71 @@ pub fn limit_check(
     }
 
     // we connect once for all limit tests
-    let mut redis = match redis_conn() {
-        Ok(c) => c,
-        Err(rr) => {
-            logs.error(format!("Could not connect to the redis server {}", rr));
-            return SimpleDecision::Pass;
-        }
-    };
+    let mut store = limit_store(logs);
+    if store.degraded() {
+        tags.insert("limit-store-degraded");
+    }
+    let decision = limit_check_at(
+        logs,
+        security_policy_name,
+        reqinfo,
+        limits,
+        tags,
+        &mut store,
+        slots,
+        unix_now(),
+    );
+    // Redis can also fail after the connection
+    store.log_failure(logs);
+    if store.degraded() {
+        tags.insert("limit-store-degraded");
+    }
+    decision
+}
 
+/// checks the limits against the given store, without concurrency limits
+pub fn limit_check_with_store(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &mut Tags,
+    store: &mut dyn LimitStore,
+) -> SimpleDecision {
+    limit_check_at(
+        logs,
+        security_policy_name,
+        reqinfo,
+        limits,
+        tags,
+        store,
+        None,
+        unix_now(),
+    )
+}
+
+/// checks the limits, `now` being the time since the unix epoch
+#[allow(clippy::too_many_arguments)]
+fn limit_check_at(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &mut Tags,
+    store: &mut dyn LimitStore,
+    mut slots: Option<&mut Vec<ConcurrencySlot>>,
+    now: Duration,
+) -> SimpleDecision {
     for limit in limits {
         if !limit_match(tags, limit) {
             logs.debug(format!("limit {} excluded", limit.name));
             continue;
         }
+        let concurrency = limit.algorithm == LimitAlgorithm::Concurrency;
+        if concurrency && slots.is_none() {
+            logs.debug(format!("concurrency limit {} skipped", limit.name));
+            continue;
+        }
 
         let key = match build_key(security_policy_name, reqinfo, limit) {
             None => return SimpleDecision::Pass,
@@ -135,31 +604,534 @@ pub fn limit_check(
         };
         logs.debug(format!("limit={:?} key={}", limit, key));
 
-        if is_banned(&mut redis, &key) {
+        let counts_responses = !limit.response_statuses.is_empty();
+        if is_banned(store, &key) {
             logs.debug("is banned!");
             tags.insert(&limit.name);
+            if counts_responses {
+                tags.insert(ERROR_RATE_TAG);
+            }
             let ban_threshold: &LimitThreshold = limit
                 .thresholds
                 .iter()
                 .find(|t| matches!(t.action.atype, SimpleActionT::Ban(_, _)))
                 .unwrap_or(&limit.thresholds[0]);
-            return limit_react(logs, tags, &mut redis, limit, ban_threshold, key);
+            return limit_react(logs, tags, store, limit, ban_threshold, key, now);
+        }
+
+        if counts_responses {
+            // the request is not counted, only its response is, see `limit_count_response`
+            match counter_state(store, limit, &key, now) {
+                Err(rr) => logs.error(rr),
+                Ok((_, rate, _)) => {
+                    if let Some(threshold) = limit.thresholds.iter().find(|t| rate > t.limit as f64) {
+                        tags.insert(ERROR_RATE_TAG);
+                        return limit_react(logs, tags, store, limit, threshold, key, now);
+                    }
+                }
+            }
+            continue;
         }
 
         let pairvalue = limit.pairwith.as_ref().and_then(|sel| select_string(reqinfo, sel));
 
-        match redis_get_limit(&mut redis, &key, limit.timeframe, pairvalue) {
+        match count_request(store, limit, &key, pairvalue.as_deref(), now) {
             Err(rr) => logs.error(rr),
             Ok(current_count) => {
+                let slot = ConcurrencySlot {
+                    limit_id: limit.id.clone(),
+                    key: key.clone(),
+                    ttl: limit.timeframe,
+                };
                 for threshold in &limit.thresholds {
                     // Only one action with highest limit larger than current
                     // counter will be applied, all the rest will be skipped.
-                    if current_count > threshold.limit as i64 {
-                        return limit_react(logs, tags, &mut redis, limit, &threshold, key);
+                    if current_count > threshold.limit as f64 {
+                        let decision = limit_react(logs, tags, store, limit, &threshold, key, now);
+                        // rejected requests do not stay in flight
+                        if concurrency {
+                            release_slots_with_store(logs, store, &[slot]);
+                        }
+                        return decision;
                     }
                 }
+                if let (true, Some(slots)) = (concurrency, slots.as_mut()) {
+                    slots.push(slot);
+                }
             },
         }
     }
     SimpleDecision::Pass
 }
+
+/// counts a response with the limits that count its status, see `Limit::response_statuses`
+pub fn limit_count_response(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &Tags,
+    status: u16,
+) {
+    // early return to avoid redis connection
+    if !limits.iter().any(|l| l.response_statuses.contains(&status)) {
+        return;
+    }
+    let mut store = limit_store(logs);
+    count_response_at(
+        logs,
+        security_policy_name,
+        reqinfo,
+        limits,
+        tags,
+        &mut store,
+        status,
+        unix_now(),
+    );
+    store.log_failure(logs);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn count_response_at(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &Tags,
+    store: &mut dyn LimitStore,
+    status: u16,
+    now: Duration,
+) {
+    for limit in limits.iter().filter(|l| l.response_statuses.contains(&status)) {
+        if !limit_match(tags, limit) {
+            logs.debug(format!("limit {} excluded", limit.name));
+            continue;
+        }
+        let key = match build_key(security_policy_name, reqinfo, limit) {
+            None => continue,
+            Some(k) => k,
+        };
+        let pairvalue = limit.pairwith.as_ref().and_then(|sel| select_string(reqinfo, sel));
+        match count_request(store, limit, &key, pairvalue.as_deref(), now) {
+            Ok(count) => logs.debug(format!(
+                "limit {} counted status {}, rate={}",
+                limit.name, status, count
+            )),
+            Err(rr) => logs.error(rr),
+        }
+    }
+}
+
+/// returns the counter state of all limits that apply to the request, without altering them
+///
+/// limits excluded by a tag of the request are reported as skipped
+pub fn limit_status(
+    logs: &mut Logs,
+    security_policy_name: &str,
+    reqinfo: &RequestInfo,
+    limits: &[Limit],
+    tags: &Tags,
+) -> anyhow::Result<Vec<LimitStatus>> {
+    if limits.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut store = limit_store(logs);
+    let now = unix_now();
+    let mut out = Vec::new();
+    for limit in limits {
+        if !limit_included(tags, limit) {
+            logs.debug(format!("limit {} not included", limit.name));
+            continue;
+        }
+        let threshold = limit.thresholds.last().map(|t| t.limit);
+        if limit_excluded(tags, limit) {
+            logs.debug(format!("limit {} excluded", limit.name));
+            out.push(LimitStatus {
+                id: limit.id.clone(),
+                name: limit.name.clone(),
+                current: 0,
+                rate: 0.0,
+                algorithm: limit.algorithm,
+                threshold,
+                reset: None,
+                banned: false,
+                skipped: true,
+            });
+            continue;
+        }
+        let key = match build_key(security_policy_name, reqinfo, limit) {
+            None => continue,
+            Some(k) => k,
+        };
+        let (current, rate, reset) = counter_state(&mut store, limit, &key, now)?;
+        out.push(LimitStatus {
+            id: limit.id.clone(),
+            name: limit.name.clone(),
+            current,
+            rate,
+            algorithm: limit.algorithm,
+            threshold,
+            reset,
+            banned: is_banned(&mut store, &key),
+            skipped: false,
+        });
+    }
+    store.log_failure(logs);
+    Ok(out)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::raw::RawLimit;
+    use crate::interface::Decision;
+    use crate::utils::{map_request, RequestMeta};
+    use std::collections::HashMap;
+
+    /// the limits of the configuration fixture, `../../config/json/limits.json`
+    pub(crate) fn fixture_limits() -> Vec<Limit> {
+        let rawlimits: Vec<RawLimit> =
+            serde_json::from_str(&std::fs::read_to_string("../../config/json/limits.json").unwrap()).unwrap();
+        Limit::resolve(&mut Logs::default(), rawlimits, &HashMap::new())
+            .into_values()
+            .collect()
+    }
+
+    fn mk_rinfo(ip: &str) -> RequestInfo {
+        let meta = RequestMeta {
+            authority: Some("example.com".to_string()),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            extra: HashMap::new(),
+        };
+        map_request(&mut Logs::default(), ip.to_string(), HashMap::new(), meta, None).unwrap()
+    }
+
+    #[test]
+    fn local_store_limit() {
+        let limits = fixture_limits();
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        let mut check = |rinfo: &RequestInfo| {
+            limit_check_with_store(&mut logs, "local-test", rinfo, &limits, &mut tags, &mut LocalLimitStore)
+        };
+
+        // the limit is 5 requests per minute
+        let rinfo = mk_rinfo("10.0.0.1");
+        for _ in 0..5 {
+            assert!(matches!(check(&rinfo), SimpleDecision::Pass));
+        }
+        assert!(matches!(check(&rinfo), SimpleDecision::Action(_, _, _)));
+        // other keys are not affected
+        assert!(matches!(check(&mk_rinfo("10.0.0.2")), SimpleDecision::Pass));
+
+        let key = build_key("local-test", &rinfo, &limits[0]).unwrap();
+        let (current, reset) = LocalLimitStore.get(&key, false).unwrap();
+        assert_eq!(current, 6);
+        assert!(reset.unwrap() <= 60);
+    }
+
+    #[test]
+    fn excluded_limit() {
+        let limits = fixture_limits();
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        tags.insert("allowlist");
+        let rinfo = mk_rinfo("10.0.1.1");
+        for _ in 0..10 {
+            let decision = limit_check_with_store(
+                &mut logs,
+                "excluded-test",
+                &rinfo,
+                &limits,
+                &mut tags,
+                &mut LocalLimitStore,
+            );
+            assert!(matches!(decision, SimpleDecision::Pass));
+        }
+        let key = build_key("excluded-test", &rinfo, &limits[0]).unwrap();
+        assert_eq!(LocalLimitStore.get(&key, false).unwrap(), (0, None));
+
+        let status = limit_status(&mut logs, "excluded-test", &rinfo, &limits, &tags).unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(status[0].skipped);
+        assert_eq!(status[0].threshold, Some(5));
+    }
+
+    #[test]
+    fn ratelimit_decision() {
+        let limits = fixture_limits();
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        let rinfo = mk_rinfo("10.0.3.1");
+        let mut check = || {
+            limit_check_with_store(
+                &mut logs,
+                "ratelimit-test",
+                &rinfo,
+                &limits,
+                &mut tags,
+                &mut LocalLimitStore,
+            )
+            .into_decision_no_challenge()
+        };
+        for _ in 0..5 {
+            assert!(matches!(check(), Decision::Pass));
+        }
+        let decision = check();
+        let ratelimit = decision.ratelimit().unwrap().clone();
+        assert_eq!(ratelimit.limit_id, "f971e92459e2");
+        assert_eq!(ratelimit.limit_name, "Rate Limit Example Rule 5/60");
+        assert_eq!((ratelimit.count, ratelimit.threshold), (6, 5));
+        assert!(ratelimit.reset > 0 && ratelimit.reset <= 60);
+        assert_eq!(
+            ratelimit.headers().get("X-RateLimit-Remaining").map(|s| s.as_str()),
+            Some("0")
+        );
+        assert!(decision.is_blocking());
+        // the rate limit headers are sent with the response
+        let headers = decision.action().unwrap().headers.clone().unwrap();
+        assert_eq!(headers.get("X-RateLimit-Limit").map(|s| s.as_str()), Some("5"));
+        assert_eq!(headers.get("X-RateLimit-Remaining").map(|s| s.as_str()), Some("0"));
+        assert_eq!(headers.get("X-RateLimit-Reset"), headers.get("Retry-After"));
+
+        // the generic action is the same, without the metadata
+        let action = decision.action().unwrap().clone();
+        match decision.into_generic() {
+            Decision::Action(a) => assert_eq!(a.headers, action.headers),
+            other => panic!("unexpected decision {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(retry_after(Some(12), 60), 12);
+        assert_eq!(retry_after(Some(0), 60), 0);
+        assert_eq!(retry_after(Some(3600), 60), 60);
+        assert_eq!(retry_after(None, 60), 60);
+
+        let limits = fixture_limits();
+        let mut logs = Logs::default();
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        let rinfo = mk_rinfo("10.0.2.1");
+        let key = build_key("retry-test", &rinfo, &limits[0]).unwrap();
+        let mut check = || {
+            let decision = limit_check_with_store(
+                &mut logs,
+                "retry-test",
+                &rinfo,
+                &limits,
+                &mut tags,
+                &mut LocalLimitStore,
+            );
+            decision
+                .into_decision_no_challenge()
+                .action()
+                .map(|a| a.headers.as_ref().unwrap().get("Retry-After").unwrap().clone())
+        };
+
+        // the window just rolled over, so that the counter starts again, for a full window
+        LocalLimitStore.set_with_ttl(&key, 100, 0).unwrap();
+        for _ in 0..5 {
+            assert_eq!(check(), None);
+        }
+        assert_eq!(check().as_deref(), Some("60"));
+
+        // keys that outlive the window are clamped to the window size
+        LocalLimitStore.set_with_ttl(&key, 100, 3600).unwrap();
+        assert_eq!(check().as_deref(), Some("60"));
+    }
+
+    #[test]
+    fn sliding_window_boundary() {
+        let fixed = fixture_limits();
+        let mut sliding = fixed.clone();
+        sliding[0].algorithm = LimitAlgorithm::Sliding;
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        // 5 requests per minute, with a window starting at `start`
+        let start = Duration::from_secs(60 * 1_000_000);
+        let passed = |limits: &[Limit], rinfo: &RequestInfo, at: Duration, count: usize| {
+            (0..count)
+                .filter(|_| {
+                    let decision = limit_check_at(
+                        &mut Logs::default(),
+                        "sliding-test",
+                        rinfo,
+                        limits,
+                        &mut tags.clone(),
+                        &mut LocalLimitStore,
+                        None,
+                        at,
+                    );
+                    matches!(decision, SimpleDecision::Pass)
+                })
+                .count()
+        };
+
+        // with a fixed window, 5 requests at the end of a window, and 5 at the start of the next one, all pass
+        let rinfo = mk_rinfo("10.0.3.1");
+        assert_eq!(passed(&fixed, &rinfo, start + Duration::from_secs(54), 5), 5);
+        let key = build_key("sliding-test", &rinfo, &fixed[0]).unwrap();
+        LocalLimitStore.set_with_ttl(&key, 0, 0).unwrap();
+        assert_eq!(passed(&fixed, &rinfo, start + Duration::from_secs(66), 5), 5);
+
+        // with a sliding window, 90% of the previous window still counts 12 seconds later
+        let rinfo = mk_rinfo("10.0.3.2");
+        assert_eq!(passed(&sliding, &rinfo, start + Duration::from_secs(54), 5), 5);
+        assert_eq!(passed(&sliding, &rinfo, start + Duration::from_secs(66), 5), 0);
+        let key = build_key("sliding-test", &rinfo, &sliding[0]).unwrap();
+        let (current, rate, reset) =
+            counter_state(&mut LocalLimitStore, &sliding[0], &key, start + Duration::from_secs(66)).unwrap();
+        assert_eq!(current, 5);
+        assert!((rate - 9.5).abs() < 1e-9, "{}", rate);
+        assert_eq!(reset, Some(54));
+        // the previous window fades out: at the end of the next window, only 10% of its 5 requests count
+        assert_eq!(passed(&sliding, &rinfo, start + Duration::from_secs(174), 5), 4);
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let mut limits = fixture_limits();
+        limits[0].algorithm = LimitAlgorithm::Concurrency;
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        let rinfo = mk_rinfo("10.0.4.1");
+        let key = build_key("concurrency-test", &rinfo, &limits[0]).unwrap();
+        let mut slots = Vec::new();
+        let check = |slots: Option<&mut Vec<ConcurrencySlot>>| {
+            let decision = limit_check_at(
+                &mut Logs::default(),
+                "concurrency-test",
+                &rinfo,
+                &limits,
+                &mut tags.clone(),
+                &mut LocalLimitStore,
+                slots,
+                unix_now(),
+            );
+            matches!(decision, SimpleDecision::Pass)
+        };
+
+        // at most 5 requests in flight
+        for _ in 0..5 {
+            assert!(check(Some(&mut slots)));
+        }
+        assert!(!check(Some(&mut slots)));
+        assert_eq!(slots.len(), 5);
+        // the rejected request was not counted
+        assert_eq!(LocalLimitStore.get(&key, false).unwrap().0, 5);
+        // concurrency limits are skipped when the slots can't be released
+        assert!(check(None));
+
+        release_slots_with_store(&mut Logs::default(), &mut LocalLimitStore, &slots[..1]);
+        assert!(check(Some(&mut slots)));
+        release_slots_with_store(&mut Logs::default(), &mut LocalLimitStore, &slots);
+        assert_eq!(LocalLimitStore.get(&key, false).unwrap().0, 0);
+        // the gauge does not go below 0
+        assert_eq!(LocalLimitStore.add_with_ttl(&key, -1, 60).unwrap(), 0);
+    }
+
+    #[test]
+    fn error_rate_limit() {
+        let mut limits = fixture_limits();
+        limits[0].response_statuses = [401, 403, 404].iter().copied().collect();
+        limits[0].thresholds[0].limit = 2;
+        let mut tags = Tags::default();
+        tags.insert("blocklist");
+        let rinfo = mk_rinfo("10.0.5.1");
+        let now = unix_now();
+        let check = |rinfo: &RequestInfo, tags: &mut Tags| {
+            let decision = limit_check_at(
+                &mut Logs::default(),
+                "error-rate-test",
+                rinfo,
+                &limits,
+                tags,
+                &mut LocalLimitStore,
+                None,
+                now,
+            );
+            matches!(decision, SimpleDecision::Pass)
+        };
+        let respond = |status: u16| {
+            count_response_at(
+                &mut Logs::default(),
+                "error-rate-test",
+                &rinfo,
+                &limits,
+                &tags,
+                &mut LocalLimitStore,
+                status,
+                now,
+            )
+        };
+
+        // the requests themselves are not counted
+        for _ in 0..5 {
+            assert!(check(&rinfo, &mut tags.clone()));
+        }
+        respond(200);
+        respond(401);
+        respond(404);
+        assert!(check(&rinfo, &mut tags.clone()));
+        respond(403);
+        let mut denied = tags.clone();
+        assert!(!check(&rinfo, &mut denied));
+        assert!(denied.contains(ERROR_RATE_TAG));
+        // other keys are not affected
+        assert!(check(&mk_rinfo("10.0.5.2"), &mut tags.clone()));
+    }
+
+    #[test]
+    fn local_store_sets() {
+        let mut store = LocalLimitStore;
+        assert_eq!(store.incr_with_ttl("local-set-test", 60, Some("a")).unwrap(), 1);
+        assert_eq!(store.incr_with_ttl("local-set-test", 60, Some("b")).unwrap(), 2);
+        assert_eq!(store.incr_with_ttl("local-set-test", 60, Some("a")).unwrap(), 2);
+        store.set_with_ttl("local-expired-test", 1, 0).unwrap();
+        assert_eq!(store.get("local-expired-test", false).unwrap(), (0, None));
+    }
+
+    /// a store whose commands fail, as Redis does when the connection is lost
+    struct FailingStore;
+
+    impl LimitStore for FailingStore {
+        fn incr_with_ttl(&mut self, _key: &str, _ttl: u64, _pairvalue: Option<&str>) -> anyhow::Result<i64> {
+            Err(anyhow::anyhow!("connection reset"))
+        }
+
+        fn get(&mut self, _key: &str, _paired: bool) -> anyhow::Result<(i64, Option<u64>)> {
+            Err(anyhow::anyhow!("connection reset"))
+        }
+
+        fn set_with_ttl(&mut self, _key: &str, _value: i64, _ttl: u64) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("connection reset"))
+        }
+
+        fn add_with_ttl(&mut self, _key: &str, _delta: i64, _ttl: u64) -> anyhow::Result<i64> {
+            Err(anyhow::anyhow!("connection reset"))
+        }
+    }
+
+    #[test]
+    fn fallback_store() {
+        let mut store = FallbackLimitStore {
+            primary: Some(Box::new(FailingStore)),
+            failure: None,
+        };
+        assert!(!store.degraded());
+        assert_eq!(store.incr_with_ttl("fallback-test", 60, None).unwrap(), 1);
+        assert!(store.degraded());
+        // the local store is kept for the next commands
+        assert_eq!(store.incr_with_ttl("fallback-test", 60, None).unwrap(), 2);
+        let mut logs = Logs::default();
+        store.log_failure(&mut logs);
+        assert!(logs.to_stringvec().iter().any(|l| l.contains("connection reset")));
+    }
+}
@@ -13,6 +13,9 @@ pub struct Log {
     pub elapsed_micros: u64,
     pub level: LogLevel,
     pub message: String,
+    /// the part of the configuration the message is about, when loading it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -25,6 +28,17 @@ pub enum LogLevel {
 }
 
 impl LogLevel {
+    /// parses the lowercase level names, as they are serialized
+    pub fn parse(s: &str) -> Option<LogLevel> {
+        match s {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warning" => Some(LogLevel::Warning),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+
     fn short(&self) -> char {
         match self {
             LogLevel::Debug => 'D',
@@ -37,7 +51,11 @@ impl LogLevel {
 
 impl std::fmt::Display for Log {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} {}µs {}", self.level.short(), self.elapsed_micros, self.message)
+        write!(f, "{} {}µs ", self.level.short(), self.elapsed_micros)?;
+        if let Some(c) = &self.component {
+            write!(f, "{}: ", c)?;
+        }
+        write!(f, "{}", self.message)
     }
 }
 
@@ -53,11 +71,21 @@ impl Default for Logs {
 
 impl Logs {
     pub fn log<S: ToString>(&mut self, level: LogLevel, message: S) {
+        self.push(level, None, message.to_string());
+    }
+
+    /// logs a message about a part of the configuration, such as `limits[id].timeframe`
+    pub fn log_at<S: ToString>(&mut self, level: LogLevel, component: String, message: S) {
+        self.push(level, Some(component), message.to_string());
+    }
+
+    fn push(&mut self, level: LogLevel, component: Option<String>, message: String) {
         let now = Instant::now();
         self.logs.push(Log {
             elapsed_micros: now.duration_since(self.start).as_micros() as u64,
-            message: message.to_string(),
+            message,
             level,
+            component,
         })
     }
 
@@ -73,6 +101,12 @@ impl Logs {
     pub fn error<S: ToString>(&mut self, message: S) {
         self.log(LogLevel::Error, message);
     }
+    pub fn warning_at<S: ToString>(&mut self, component: String, message: S) {
+        self.log_at(LogLevel::Warning, component, message);
+    }
+    pub fn error_at<S: ToString>(&mut self, component: String, message: S) {
+        self.log_at(LogLevel::Error, component, message);
+    }
 
     pub fn to_stringvec(&self) -> Vec<String> {
         self.logs.iter().map(|l| l.to_string()).collect()
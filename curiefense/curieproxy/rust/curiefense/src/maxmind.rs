@@ -1,12 +1,15 @@
 use lazy_static::lazy_static;
 use maxminddb::{
-    geoip2::{Asn, City, Country},
+    geoip2::{model, Asn, Country},
     Reader,
 };
+use serde::Deserialize;
 use std::net::IpAddr;
 #[cfg(not(test))]
 use std::ops::Deref;
 
+use crate::geoip::{GeoData, GeoIpProvider};
+
 lazy_static! {
     // as they are lazy, these loads will not be triggered in test mode
     static ref ASN: Result<Reader<Vec<u8>>, maxminddb::MaxMindDBError> =
@@ -17,6 +20,23 @@ lazy_static! {
         Reader::open_readfile("/config/current/config/maxmind/GeoLite2-City.mmdb");
 }
 
+/// the city database fields that are used, including the location accuracy, which is missing from the `maxminddb`
+/// model
+#[derive(Debug, Clone, Deserialize)]
+pub struct City {
+    pub city: Option<model::City>,
+    pub location: Option<Location>,
+    pub subdivisions: Option<Vec<model::Subdivision>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Location {
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// radius around the location, in kilometers
+    pub accuracy_radius: Option<u16>,
+}
+
 /// Retrieves the english name of the country associated with this IP
 #[cfg(not(test))]
 pub fn get_country(addr: IpAddr) -> Result<Country, String> {
@@ -43,16 +63,146 @@ pub fn get_city(addr: IpAddr) -> Result<City, String> {
 }
 
 #[cfg(test)]
-pub fn get_country(_addr: IpAddr) -> Result<Country, String> {
+thread_local! {
+    // number of lookups performed by the current thread, so that tests can check when lookups are skipped
+    static LOOKUPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+pub fn lookup_count() -> usize {
+    LOOKUPS.with(|l| l.get())
+}
+
+#[cfg(test)]
+fn test_lookup<A>() -> Result<A, String> {
+    LOOKUPS.with(|l| l.set(l.get() + 1));
     Err("TEST".into())
 }
 
+#[cfg(test)]
+pub fn get_country(_addr: IpAddr) -> Result<Country, String> {
+    test_lookup()
+}
+
 #[cfg(test)]
 pub fn get_asn(_addr: IpAddr) -> Result<Asn, String> {
-    Err("TEST".into())
+    test_lookup()
 }
 
 #[cfg(test)]
 pub fn get_city(_addr: IpAddr) -> Result<City, String> {
-    Err("TEST".into())
+    test_lookup()
+}
+
+/// the city level data, only available with a city database
+#[derive(Debug, Default, PartialEq)]
+struct CityInfo {
+    name: Option<String>,
+    location: Option<(f64, f64)>,
+    accuracy_radius: Option<u16>,
+    subdivision: Option<String>,
+}
+
+fn city_info(cty: &City, country_iso: Option<&str>) -> CityInfo {
+    let location = cty.location.as_ref();
+    // subdivisions are ordered from the largest to the smallest
+    let subdivision = cty
+        .subdivisions
+        .as_ref()
+        .and_then(|s| s.first())
+        .and_then(|s| s.iso_code.as_ref())
+        .map(|code| match country_iso {
+            Some(ciso) => format!("{}-{}", ciso.to_uppercase(), code.to_uppercase()),
+            None => code.to_uppercase(),
+        });
+    CityInfo {
+        name: cty
+            .city
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .and_then(|mp| mp.get("en"))
+            .map(|s| s.to_lowercase()),
+        location: location.and_then(|l| l.latitude.and_then(|lat| l.longitude.map(|lon| (lat, lon)))),
+        accuracy_radius: location.and_then(|l| l.accuracy_radius),
+        subdivision,
+    }
+}
+
+/// the default geolocation provider, reading the GeoLite2 databases, that has no threat categories
+pub struct MaxMindProvider;
+
+impl GeoIpProvider for MaxMindProvider {
+    fn lookup(&self, ip: IpAddr) -> GeoData {
+        fn cty_info(c: &model::Country) -> (Option<bool>, Option<String>, Option<String>) {
+            (
+                c.is_in_european_union,
+                c.iso_code.as_ref().map(|s| s.to_lowercase()),
+                c.names.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase()),
+            )
+        }
+        fn cont_info(c: &model::Continent) -> (Option<String>, Option<String>) {
+            (
+                c.names.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase()),
+                c.code.clone(),
+            )
+        }
+        let (mcountry_info, mcontinent_info) = match get_country(ip).ok() {
+            None => (None, None),
+            Some(cty) => (
+                cty.country.as_ref().map(cty_info),
+                cty.continent.as_ref().map(cont_info),
+            ),
+        };
+        let (in_eu, country_iso, country_name) = mcountry_info.unwrap_or((None, None, None));
+        let city = match get_city(ip).ok() {
+            None => CityInfo::default(),
+            Some(cty) => city_info(&cty, country_iso.as_deref()),
+        };
+        let (asn, company) = match get_asn(ip).ok() {
+            None => (None, None),
+            Some(iasn) => (iasn.autonomous_system_number, iasn.autonomous_system_organization),
+        };
+        let (continent_name, continent_code) = mcontinent_info.unwrap_or((None, None));
+        GeoData {
+            location: city.location,
+            accuracy_radius: city.accuracy_radius,
+            in_eu,
+            city_name: city.name,
+            country_iso,
+            country_name,
+            continent_name,
+            continent_code,
+            asn,
+            company,
+            subdivision: city.subdivision,
+            threat_categories: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn city_record() {
+        let cty: City = serde_json::from_value(json!({
+            "city": {"names": {"en": "San Francisco"}},
+            "location": {"latitude": 37.7, "longitude": -122.4, "accuracy_radius": 10, "time_zone": "America/Los_Angeles"},
+            "subdivisions": [{"iso_code": "CA", "names": {"en": "California"}}]
+        }))
+        .unwrap();
+        assert_eq!(
+            city_info(&cty, Some("us")),
+            CityInfo {
+                name: Some("san francisco".to_string()),
+                location: Some((37.7, -122.4)),
+                accuracy_radius: Some(10),
+                subdivision: Some("US-CA".to_string()),
+            }
+        );
+        let cty: City = serde_json::from_value(json!({"city": null})).unwrap();
+        assert_eq!(city_info(&cty, Some("us")), CityInfo::default());
+    }
 }
@@ -0,0 +1,257 @@
+/// Prometheus metrics of the session pipeline, rendered in the text exposition format by `render_prometheus`
+///
+/// The decision counters and the stage histograms are fixed arrays of atomics, so that updating them takes no lock.
+/// The number of sessions is read from the session maps, and the content filter hits from the counters of
+/// `contentfilter::content_filter_stats`, when the metrics are rendered.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::contentfilter::content_filter_stats;
+use crate::interface::{ActionType, Decision};
+use crate::session::{session_capacity_stats, Stage};
+
+/// the `action` labels, `pass` being the first one
+const ACTIONS: [&str; 4] = ["pass", "monitor", "block", "alter_headers"];
+
+/// the `reason` labels, the initiators of `DecisionReason`, `none` being used for the requests that passed
+const REASONS: [&str; 12] = [
+    "none",
+    "unknown",
+    "global_filter",
+    "flow",
+    "limit",
+    "acl",
+    "content_filter",
+    "challenge",
+    "smuggling",
+    "eval_budget",
+    "body_limit",
+    "response_size",
+];
+
+/// the stages that are timed, see `SessionTimings`
+const STAGES: [Stage; 5] = [
+    Stage::Tagging,
+    Stage::Limit,
+    Stage::Acl,
+    Stage::ContentFilter,
+    Stage::Flow,
+];
+const STAGE_NAMES: [&str; 5] = ["tagging", "limit", "acl", "content_filter", "flow"];
+
+/// the upper bounds of the histogram buckets, in nanoseconds
+const BUCKETS: [u64; 12] = [
+    10_000,
+    25_000,
+    50_000,
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+];
+
+static DECISIONS: [[AtomicU64; REASONS.len()]; ACTIONS.len()] =
+    [const { [const { AtomicU64::new(0) }; REASONS.len()] }; ACTIONS.len()];
+
+struct Histogram {
+    /// not cumulative, the durations above the last bound are only counted in `count`
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, nanos: u64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| nanos <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static STAGE_DURATIONS: [Histogram; STAGES.len()] = [const { Histogram::new() }; STAGES.len()];
+
+/// counts a decision returned by `session::session_evaluate`
+pub(crate) fn count_decision(decision: &Decision) {
+    let (action, reason) = match decision.action() {
+        None => (0, 0),
+        Some(a) => {
+            let action = match a.atype {
+                ActionType::Monitor => 1,
+                ActionType::Block => 2,
+                ActionType::AlterHeaders => 3,
+            };
+            let initiator = a.decision_reason.initiator();
+            // initiators missing from REASONS are counted as unknown
+            let reason = REASONS.iter().position(|r| *r == initiator).unwrap_or(1);
+            (action, reason)
+        }
+    };
+    DECISIONS[action][reason].fetch_add(1, Ordering::Relaxed);
+}
+
+/// records the duration, in nanoseconds, of a stage, the stages that are not timed being ignored
+pub(crate) fn observe_stage(stage: Stage, nanos: u64) {
+    if let Some(i) = STAGES.iter().position(|s| *s == stage) {
+        STAGE_DURATIONS[i].observe(nanos);
+    }
+}
+
+/// escapes a label value, as required by the exposition format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1e9
+}
+
+/// the metrics, in the Prometheus text exposition format
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP curiefense_sessions_active Number of live sessions.");
+    let _ = writeln!(out, "# TYPE curiefense_sessions_active gauge");
+    let sessions = session_capacity_stats().map(|s| s.sessions).unwrap_or(0);
+    let _ = writeln!(out, "curiefense_sessions_active {}", sessions);
+
+    let _ = writeln!(
+        out,
+        "# HELP curiefense_decisions_total Decisions returned by session_evaluate."
+    );
+    let _ = writeln!(out, "# TYPE curiefense_decisions_total counter");
+    for (action, counters) in ACTIONS.iter().zip(DECISIONS.iter()) {
+        for (reason, counter) in REASONS.iter().zip(counters.iter()) {
+            let count = counter.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(
+                    out,
+                    "curiefense_decisions_total{{action=\"{}\",reason=\"{}\"}} {}",
+                    action, reason, count
+                );
+            }
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP curiefense_stage_duration_seconds Duration of the session pipeline stages."
+    );
+    let _ = writeln!(out, "# TYPE curiefense_stage_duration_seconds histogram");
+    for (stage, histogram) in STAGE_NAMES.iter().zip(STAGE_DURATIONS.iter()) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "curiefense_stage_duration_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                stage,
+                seconds(*bound),
+                cumulative
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "curiefense_stage_duration_seconds_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+            stage, count
+        );
+        let _ = writeln!(
+            out,
+            "curiefense_stage_duration_seconds_sum{{stage=\"{}\"}} {}",
+            stage,
+            seconds(histogram.sum_nanos.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(
+            out,
+            "curiefense_stage_duration_seconds_count{{stage=\"{}\"}} {}",
+            stage, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP curiefense_content_filter_hits_total Matches of each content filter rule."
+    );
+    let _ = writeln!(out, "# TYPE curiefense_content_filter_hits_total counter");
+    let mut hits: Vec<(String, u64)> = content_filter_stats().into_iter().collect();
+    hits.sort();
+    for (rule, count) in hits {
+        let _ = writeln!(
+            out,
+            "curiefense_content_filter_hits_total{{rule=\"{}\"}} {}",
+            escape_label(&rule),
+            count
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{Action, DecisionReason};
+
+    /// the value of a sample of the rendered metrics
+    fn sample(rendered: &str, name: &str) -> f64 {
+        rendered
+            .lines()
+            .find_map(|l| l.strip_prefix(name).and_then(|v| v.strip_prefix(' ')))
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(0.0)
+    }
+
+    #[test]
+    fn decisions() {
+        let name = "curiefense_decisions_total{action=\"block\",reason=\"acl\"}";
+        let before = sample(&render_prometheus(), name);
+        count_decision(&Decision::Action(Action {
+            atype: ActionType::Block,
+            decision_reason: DecisionReason::Acl { tags: Vec::new() },
+            ..Action::default()
+        }));
+        count_decision(&Decision::Pass);
+        let rendered = render_prometheus();
+        assert!(sample(&rendered, name) >= before + 1.0);
+        assert!(sample(&rendered, "curiefense_decisions_total{action=\"pass\",reason=\"none\"}") >= 1.0);
+        assert!(rendered.contains("# TYPE curiefense_sessions_active gauge"));
+    }
+
+    #[test]
+    fn histograms() {
+        let histogram = Histogram::new();
+        histogram.observe(20_000);
+        histogram.observe(20_000);
+        histogram.observe(2_000_000_000);
+        assert_eq!(histogram.buckets[1].load(Ordering::Relaxed), 2);
+        assert_eq!(histogram.count.load(Ordering::Relaxed), 3);
+
+        let name = "curiefense_stage_duration_seconds_count{stage=\"acl\"}";
+        let before = sample(&render_prometheus(), name);
+        observe_stage(Stage::Acl, 1_000);
+        observe_stage(Stage::Evaluate, 1_000);
+        let rendered = render_prometheus();
+        assert!(sample(&rendered, name) >= before + 1.0);
+        assert!(!rendered.contains("stage=\"evaluate\""));
+    }
+
+    #[test]
+    fn label_escaping() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
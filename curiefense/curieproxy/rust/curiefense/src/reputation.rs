@@ -0,0 +1,208 @@
+/// per-address scores of bad behavior, that decay over time
+///
+/// Scores are raised with `report_bad`, usually through `session::session_report_bad`, and halve every half-life of
+/// the `reputation` setting. The scores are only kept in the memory of the process, and the client addresses are
+/// tagged by `tag_request` with the `reputation:` level their score reaches.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// scores below that are forgotten
+const NEGLIGIBLE_SCORE: f64 = 0.01;
+
+/// the negligible scores are removed every time this many new addresses are reported
+const PRUNE_INTERVAL: usize = 1024;
+
+/// the most addresses that are kept, the lowest scores being evicted past it
+const MAX_SCORES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    score: f64,
+    updated: Instant,
+}
+
+struct Scores {
+    entries: HashMap<IpAddr, Entry>,
+    /// new addresses since the last pruning
+    added: usize,
+    max: usize,
+}
+
+impl Scores {
+    fn new(max: usize) -> Self {
+        Scores {
+            entries: HashMap::new(),
+            added: 0,
+            max,
+        }
+    }
+
+    fn report(&mut self, ip: IpAddr, weight: f64, half_life: Duration, now: Instant) -> f64 {
+        if !self.entries.contains_key(&ip) {
+            self.added += 1;
+            if self.added >= PRUNE_INTERVAL || self.entries.len() >= self.max {
+                self.prune(half_life, now);
+            }
+        }
+        let current = self
+            .entries
+            .get(&ip)
+            .map(|entry| entry.at(now, half_life))
+            .unwrap_or(0.0);
+        let score = if weight.is_finite() {
+            (current + weight).max(0.0)
+        } else {
+            current
+        };
+        self.entries.insert(ip, Entry { score, updated: now });
+        score
+    }
+
+    /// removes the negligible scores, and the lowest ones when there are still too many, down to 90% of `max`, so
+    /// that this only happens again after many new addresses
+    fn prune(&mut self, half_life: Duration, now: Instant) {
+        self.added = 0;
+        self.entries
+            .retain(|_, entry| entry.at(now, half_life) >= NEGLIGIBLE_SCORE);
+        if self.entries.len() < self.max {
+            return;
+        }
+        let mut scores: Vec<f64> = self.entries.values().map(|entry| entry.at(now, half_life)).collect();
+        let evicted = scores.len() - (self.max - self.max / 10);
+        let (_, floor, _) = scores.select_nth_unstable_by(evicted, |a, b| a.total_cmp(b));
+        let floor = *floor;
+        self.entries.retain(|_, entry| entry.at(now, half_life) >= floor);
+    }
+}
+
+lazy_static! {
+    static ref SCORES: RwLock<Scores> = RwLock::new(Scores::new(MAX_SCORES));
+}
+
+/// the value of a score, `elapsed` after it was last updated
+pub fn decayed(score: f64, elapsed: Duration, half_life: Duration) -> f64 {
+    if half_life.is_zero() {
+        return 0.0;
+    }
+    score * 0.5_f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+}
+
+impl Entry {
+    fn at(&self, now: Instant, half_life: Duration) -> f64 {
+        decayed(self.score, now.saturating_duration_since(self.updated), half_life)
+    }
+}
+
+/// adds `weight` to the score of an address, and returns the new score
+///
+/// Negative weights lower the score, that never goes below zero. Weights that are not finite numbers are ignored.
+pub fn report_bad(ip: IpAddr, weight: f64, half_life: Duration, now: Instant) -> f64 {
+    match SCORES.write() {
+        Ok(mut scores) => scores.report(ip, weight, half_life, now),
+        Err(_) => 0.0,
+    }
+}
+
+/// the current score of an address, zero for the addresses that were never reported
+pub fn reputation_score(ip: IpAddr, half_life: Duration, now: Instant) -> f64 {
+    SCORES
+        .read()
+        .ok()
+        .and_then(|scores| scores.entries.get(&ip).map(|entry| entry.at(now, half_life)))
+        .unwrap_or(0.0)
+}
+
+/// forgets all the scores
+pub fn reset_reputation() {
+    if let Ok(mut w) = SCORES.write() {
+        *w = Scores::new(MAX_SCORES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn decay() {
+        let hour = Duration::from_secs(3600);
+        assert!(close(decayed(8.0, Duration::ZERO, hour), 8.0));
+        assert!(close(decayed(8.0, hour, hour), 4.0));
+        assert!(close(decayed(8.0, hour * 3, hour), 1.0));
+        assert!(close(decayed(8.0, hour / 2, hour), 8.0 / 2.0_f64.sqrt()));
+        assert!(close(decayed(8.0, hour, Duration::ZERO), 0.0));
+    }
+
+    #[test]
+    fn thresholds() {
+        use crate::config::raw::RawSettings;
+        use crate::config::settings::Settings;
+
+        let default = Settings::default().reputation;
+        assert_eq!(default.level(0.0), None);
+        assert_eq!(default.level(9.9), None);
+        assert_eq!(default.level(10.0), Some("high"));
+
+        let raw: RawSettings = serde_json::from_value(serde_json::json!({
+            "reputation": {"half_life_secs": 600, "thresholds": {"medium": 3, "high": 10, "low": 0}}
+        }))
+        .unwrap();
+        let reputation = Settings::resolve(raw).reputation;
+        assert_eq!(reputation.half_life, Duration::from_secs(600));
+        assert_eq!(reputation.level(0.0), None);
+        assert_eq!(reputation.level(0.5), Some("low"));
+        assert_eq!(reputation.level(3.0), Some("medium"));
+        assert_eq!(reputation.level(25.0), Some("high"));
+        // a reported score falls back to the lower levels as it decays
+        let score = decayed(20.0, Duration::from_secs(1200), reputation.half_life);
+        assert_eq!(reputation.level(score), Some("medium"));
+    }
+
+    #[test]
+    fn reports() {
+        let ip: IpAddr = "192.0.2.91".parse().unwrap();
+        let half_life = Duration::from_secs(60);
+        let start = Instant::now();
+        assert!(close(reputation_score(ip, half_life, start), 0.0));
+        assert!(close(report_bad(ip, 4.0, half_life, start), 4.0));
+        assert!(close(report_bad(ip, 2.0, half_life, start), 6.0));
+        // the score halves before the next report is added
+        let later = start + half_life;
+        assert!(close(reputation_score(ip, half_life, later), 3.0));
+        assert!(close(report_bad(ip, 1.0, half_life, later), 4.0));
+        assert!(close(report_bad(ip, f64::NAN, half_life, later), 4.0));
+        assert!(close(report_bad(ip, -10.0, half_life, later), 0.0));
+    }
+
+    #[test]
+    fn capped() {
+        let half_life = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut scores = Scores::new(100);
+        for i in 0..200u32 {
+            scores.report(IpAddr::from(i.to_be_bytes()), f64::from(i + 1), half_life, now);
+        }
+        assert!(scores.entries.len() <= 100);
+        // the highest scores are kept
+        assert!(scores.entries.contains_key(&IpAddr::from(199u32.to_be_bytes())));
+        assert!(!scores.entries.contains_key(&IpAddr::from(0u32.to_be_bytes())));
+
+        // the negligible scores are removed regularly, even below the cap
+        let mut scores = Scores::new(MAX_SCORES);
+        let old: IpAddr = "192.0.2.1".parse().unwrap();
+        scores.report(old, 1.0, half_life, now);
+        let later = now + half_life * 20;
+        for i in 0..PRUNE_INTERVAL as u32 {
+            scores.report(IpAddr::from((i + 1000).to_be_bytes()), 1.0, half_life, later);
+        }
+        assert!(!scores.entries.contains_key(&old));
+        assert!(scores.entries.len() >= PRUNE_INTERVAL - 1);
+    }
+}
@@ -58,6 +58,53 @@ impl RequestField {
     }
 }
 
+/// a malformed name or value of a request field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldAnomaly {
+    /// a NUL byte, or a line break where none is expected
+    CtrlChar,
+    /// the replacement character, that invalid UTF-8 sequences are decoded to
+    InvalidUtf8,
+}
+
+impl FieldAnomaly {
+    /// the tag of the requests with such an anomaly
+    pub fn tag(&self) -> &'static str {
+        match self {
+            FieldAnomaly::CtrlChar => "ctrl-char",
+            FieldAnomaly::InvalidUtf8 => "invalid-utf8",
+        }
+    }
+}
+
+impl RequestField {
+    /// the malformed entries, sorted by name, CR and LF being only reported when `crlf` is set
+    ///
+    /// the decoded `_base64` copies of the values are skipped, as binary data often decodes to control characters
+    pub fn anomalies(&self, crlf: bool) -> Vec<(FieldAnomaly, &str)> {
+        let ctrl = |s: &str| s.bytes().any(|b| b == 0 || (crlf && (b == b'\r' || b == b'\n')));
+        let mut out = Vec::new();
+        for (name, value) in self.0.iter() {
+            if name
+                .strip_suffix("_base64")
+                .map(|original| self.0.contains_key(original))
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            if ctrl(name) || ctrl(value) {
+                out.push((FieldAnomaly::CtrlChar, name.as_str()));
+            }
+            if name.contains('\u{FFFD}') || value.contains('\u{FFFD}') {
+                out.push((FieldAnomaly::InvalidUtf8, name.as_str()));
+            }
+        }
+        out.sort_by(|a, b| a.1.cmp(b.1));
+        out
+    }
+}
+
 impl FromIterator<(String, String)> for RequestField {
     fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
         let mut out = RequestField::default();
@@ -1,6 +1,152 @@
+/// the response phase: the response of the upstream server is attached to the session with
+/// `session::session_response_init`, and checked with `session::session_response_check`
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::acl::{check_acl, AclDecision, AclResult, BotHuman};
+use crate::acl_block;
+use crate::config::contentfilter::ContentFilterRules;
+use crate::config::hostmap::SecurityPolicy;
+use crate::contentfilter::content_filter_response;
+use crate::interface::{Action, Decision, DecisionReason, Tags};
+use crate::logs::Logs;
+use crate::requestfields::RequestField;
 
 /// parameters of the handle:respond API
 pub struct Response {
-  pub headers: HashMap<String, String>,
-  pub content: String,
-}
\ No newline at end of file
+    pub headers: HashMap<String, String>,
+    pub content: String,
+}
+
+/// the prefix of the tags that describe the response, see `tag_response`
+pub const RESPONSE_TAG_PREFIX: &str = "response-";
+
+/// json representation of the response of the upstream server
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JResponseMap {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: RequestField,
+    /// the response body, only scanned when the content filter profile has `inspect_responses` set
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub body_base64: bool,
+    /// the size of the body, in bytes, when it is not supplied, or truncated, defaults to the `content-length`
+    /// header, or to the size of the body
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+/// the response attached to a session
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseInfo {
+    pub status: u16,
+    /// with lowercased names
+    pub headers: RequestField,
+    pub body: Option<Vec<u8>>,
+    pub size: usize,
+}
+
+impl ResponseInfo {
+    pub fn new(jmap: JResponseMap) -> Self {
+        let headers: RequestField = jmap
+            .headers
+            .iter()
+            .map(|(k, v)| (k.to_lowercase(), v.clone()))
+            .collect();
+        let base64 = jmap.body_base64;
+        let body = jmap.body.map(|b| {
+            if base64 {
+                base64::decode(&b).unwrap_or_else(|_| b.into_bytes())
+            } else {
+                b.into_bytes()
+            }
+        });
+        let size = jmap
+            .size
+            .or_else(|| headers.get("content-length").and_then(|l| l.trim().parse().ok()))
+            .or_else(|| body.as_ref().map(|b| b.len()))
+            .unwrap_or(0);
+        ResponseInfo {
+            status: jmap.status,
+            headers,
+            body,
+            size,
+        }
+    }
+}
+
+/// tags the request with the status of the response, such as `response-status:404` and `response-class:4xx`
+pub fn tag_response(response: &ResponseInfo, tags: &mut Tags) {
+    tags.insert_qualified("response-status", &response.status.to_string());
+    tags.insert_qualified("response-class", &format!("{}xx", response.status / 100));
+}
+
+/// the ACL denial caused by a response tag, with its code, as in `evaluate_detailed`
+///
+/// The denials that only depend on the request tags were already reported by the request checks.
+fn response_acl_denial(result: AclResult) -> Option<(i32, Vec<String>)> {
+    let (code, dtags) = match result {
+        AclResult::Passthrough(dec) if !dec.allowed => (0, dec.tags),
+        AclResult::Match(BotHuman {
+            human:
+                Some(AclDecision {
+                    allowed: false,
+                    tags: dtags,
+                    ..
+                }),
+            ..
+        }) => (5, dtags),
+        _ => return None,
+    };
+    if dtags.iter().any(|t| t.starts_with(RESPONSE_TAG_PREFIX)) {
+        Some((code, dtags))
+    } else {
+        None
+    }
+}
+
+/// checks the response: its size, the ACL entries on the response tags, and the signatures when the content filter
+/// profile inspects the responses
+pub fn response_stage(
+    logs: &mut Logs,
+    hsdb: &Option<ContentFilterRules>,
+    securitypolicy: &SecurityPolicy,
+    response: &ResponseInfo,
+    tags: &mut Tags,
+) -> Decision {
+    let profile = &securitypolicy.content_filter_profile;
+    if let Some(limit) = profile.max_response_size.filter(|l| response.size > *l) {
+        tags.insert("response-too-large");
+        return Decision::Action(Action {
+            block_mode: securitypolicy.content_filter_active,
+            status: 403,
+            content: "response denied".to_string(),
+            reason: serde_json::json!({"initiator": "response_size", "size": response.size, "limit": limit}),
+            extra_tags: Some(std::iter::once("response-too-large".to_string()).collect()),
+            decision_reason: DecisionReason::ResponseSize { limit },
+            ..Action::default()
+        });
+    }
+
+    if let Some((code, dtags)) = response_acl_denial(check_acl(tags, &securitypolicy.acl_profile)) {
+        return acl_block(securitypolicy.acl_active, code, &dtags);
+    }
+
+    if !profile.inspect_responses {
+        return Decision::Pass;
+    }
+    match content_filter_response(hsdb, &response.headers, response.body.as_deref()) {
+        Ok(None) => Decision::Pass,
+        Ok(Some(block)) => {
+            let mut action = block.to_action();
+            action.block_mode = securitypolicy.content_filter_active;
+            Decision::Action(action)
+        }
+        Err(rr) => {
+            logs.error(format!("response content filter: {}", rr));
+            Decision::Pass
+        }
+    }
+}
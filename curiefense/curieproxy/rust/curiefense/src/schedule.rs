@@ -0,0 +1,235 @@
+/// business hours, and the timezones they are evaluated in
+///
+/// Timezones are IANA names, such as `Europe/Paris`, whose rules, including their history, come from the `chrono-tz`
+/// database.
+use chrono::{DateTime, Datelike, Offset, Timelike, Utc};
+use chrono_tz::Tz;
+use std::time::SystemTime;
+
+use crate::config::raw::RawSchedule;
+
+/// the tags added by `Schedule::tags`
+pub const TIME_TAGS: [&str; 3] = ["time:business-hours", "time:after-hours", "time:weekend"];
+
+const WEEKDAYS: [&str; 7] = [
+    "sunday",
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+];
+
+/// a timezone, and the name it was configured with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    pub name: String,
+    tz: Tz,
+}
+
+/// the day of the week and time, in a timezone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 for sunday
+    pub weekday: u32,
+    /// seconds since midnight
+    pub seconds: u32,
+    /// seconds east of UTC
+    pub offset: i64,
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        TimeZone {
+            name: "UTC".to_string(),
+            tz: Tz::UTC,
+        }
+    }
+
+    /// looks an IANA timezone name up
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let tz: Tz = s
+            .parse()
+            .map_err(|rr| anyhow::anyhow!("unknown timezone {}: {}", s, rr))?;
+        Ok(TimeZone {
+            name: s.to_string(),
+            tz,
+        })
+    }
+
+    pub fn local(&self, at: SystemTime) -> LocalTime {
+        let local = DateTime::<Utc>::from(at).with_timezone(&self.tz);
+        LocalTime {
+            weekday: local.weekday().num_days_from_sunday(),
+            seconds: local.num_seconds_from_midnight(),
+            offset: i64::from(local.offset().fix().local_minus_utc()),
+        }
+    }
+}
+
+/// the business hours, the requests being tagged with `time:business-hours` or `time:after-hours`, and
+/// `time:weekend` outside of the business days
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub timezone: TimeZone,
+    /// indexed by weekday, 0 for sunday
+    pub days: [bool; 7],
+    /// seconds since midnight, the end being excluded
+    pub start: u32,
+    pub end: u32,
+}
+
+/// `hh:mm`, in seconds since midnight, 24:00 being accepted as an end
+fn parse_hour(s: &str) -> anyhow::Result<u32> {
+    let invalid = || anyhow::anyhow!("invalid time {}, expected hh:mm", s);
+    let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+    let h: u32 = h.parse().map_err(|_| invalid())?;
+    let m: u32 = m.parse().map_err(|_| invalid())?;
+    if m > 59 || h > 24 || (h == 24 && m > 0) {
+        return Err(invalid());
+    }
+    Ok(h * 3600 + m * 60)
+}
+
+impl Schedule {
+    pub fn resolve(raw: &RawSchedule) -> anyhow::Result<Self> {
+        let timezone = match &raw.timezone {
+            None => TimeZone::utc(),
+            Some(tz) => TimeZone::parse(tz)?,
+        };
+        let mut days = [false, true, true, true, true, true, false];
+        if let Some(rawdays) = &raw.days {
+            days = [false; 7];
+            for day in rawdays {
+                let lday = day.to_lowercase();
+                let idx = WEEKDAYS
+                    .iter()
+                    .position(|d| *d == lday || d[..3] == lday)
+                    .ok_or_else(|| anyhow::anyhow!("invalid day {}", day))?;
+                days[idx] = true;
+            }
+        }
+        let start = parse_hour(raw.start.as_deref().unwrap_or("09:00"))?;
+        let end = parse_hour(raw.end.as_deref().unwrap_or("17:00"))?;
+        if start >= end {
+            return Err(anyhow::anyhow!("the business hours end before they start"));
+        }
+        Ok(Schedule {
+            timezone,
+            days,
+            start,
+            end,
+        })
+    }
+
+    /// the time tags, in the given timezone, or in the schedule timezone
+    pub fn tags(&self, timezone: Option<&TimeZone>, at: SystemTime) -> Vec<&'static str> {
+        let local = timezone.unwrap_or(&self.timezone).local(at);
+        let business_day = self.days[local.weekday as usize];
+        let mut tags = Vec::new();
+        if business_day && local.seconds >= self.start && local.seconds < self.end {
+            tags.push("time:business-hours");
+        } else {
+            tags.push("time:after-hours");
+        }
+        if !business_day {
+            tags.push("time:weekend");
+        }
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(timestamp: i64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp as u64)
+    }
+
+    fn offset_at(tz: &TimeZone, timestamp: i64) -> i64 {
+        tz.local(at(timestamp)).offset
+    }
+
+    #[test]
+    fn dst_boundaries() {
+        let paris = TimeZone::parse("Europe/Paris").unwrap();
+        // 2021-03-28, 02:00 CET becomes 03:00 CEST, at 01:00 UTC
+        assert_eq!(offset_at(&paris, 1616893199), 3600);
+        assert_eq!(offset_at(&paris, 1616893200), 7200);
+        // 2021-10-31, 03:00 CEST becomes 02:00 CET, at 01:00 UTC
+        assert_eq!(offset_at(&paris, 1635641999), 7200);
+        assert_eq!(offset_at(&paris, 1635642000), 3600);
+        let local = paris.local(at(1616893200));
+        assert_eq!((local.weekday, local.seconds), (0, 3 * 3600));
+
+        // 2021-03-14 02:00 EST, and 2021-11-07 02:00 EDT
+        let new_york = TimeZone::parse("America/New_York").unwrap();
+        assert_eq!(offset_at(&new_york, 1615705199), -5 * 3600);
+        assert_eq!(offset_at(&new_york, 1615705200), -4 * 3600);
+        assert_eq!(offset_at(&new_york, 1636264799), -4 * 3600);
+        assert_eq!(offset_at(&new_york, 1636264800), -5 * 3600);
+        // the history of the zone is known: in 2006, DST started on the first sunday of april
+        assert_eq!(offset_at(&new_york, 1142236800), -5 * 3600);
+
+        // DST spans the new year: it ends on 2021-04-04 03:00 AEDT, and starts on 2021-10-03 02:00 AEST
+        let sydney = TimeZone::parse("Australia/Sydney").unwrap();
+        assert_eq!(offset_at(&sydney, 1609459200), 11 * 3600);
+        assert_eq!(offset_at(&sydney, 1617465599), 11 * 3600);
+        assert_eq!(offset_at(&sydney, 1617465600), 10 * 3600);
+        assert_eq!(offset_at(&sydney, 1633190399), 10 * 3600);
+        assert_eq!(offset_at(&sydney, 1633190400), 11 * 3600);
+
+        let kolkata = TimeZone::parse("Asia/Kolkata").unwrap();
+        assert_eq!(offset_at(&kolkata, 1616893200), 5 * 3600 + 1800);
+        assert_eq!(offset_at(&TimeZone::utc(), 1616893200), 0);
+        for invalid in &["", "Mars/Olympus", "CET-1CEST,M3.5.0,M10.5.0/3", "../etc/passwd"] {
+            assert!(TimeZone::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn business_hours() {
+        let raw = RawSchedule {
+            timezone: Some("Europe/Paris".to_string()),
+            days: None,
+            start: Some("09:00".to_string()),
+            end: Some("17:30".to_string()),
+        };
+        let schedule = Schedule::resolve(&raw).unwrap();
+        // friday 2021-03-26 08:30 UTC, 09:30 CET
+        assert_eq!(schedule.tags(None, at(1616747400)), vec!["time:business-hours"]);
+        // monday 2021-03-29 07:30 UTC, 09:30 CEST, that would be 08:30 without DST
+        assert_eq!(schedule.tags(None, at(1617003000)), vec!["time:business-hours"]);
+        assert_eq!(
+            schedule.tags(Some(&TimeZone::parse("Etc/GMT-1").unwrap()), at(1617003000)),
+            vec!["time:after-hours"]
+        );
+        // monday 2021-03-29 15:30 UTC, 17:30 CEST
+        assert_eq!(schedule.tags(None, at(1617031800)), vec!["time:after-hours"]);
+        // sunday 2021-03-28 10:00 UTC
+        assert_eq!(
+            schedule.tags(None, at(1616925600)),
+            vec!["time:after-hours", "time:weekend"]
+        );
+
+        let weekend = Schedule::resolve(&RawSchedule {
+            days: Some(vec!["saturday".to_string(), "sun".to_string()]),
+            ..raw.clone()
+        })
+        .unwrap();
+        assert_eq!(weekend.tags(None, at(1616925600)), vec!["time:business-hours"]);
+        assert!(Schedule::resolve(&RawSchedule {
+            start: Some("18:00".to_string()),
+            ..raw.clone()
+        })
+        .is_err());
+        assert!(Schedule::resolve(&RawSchedule {
+            days: Some(vec!["someday".to_string()]),
+            ..raw
+        })
+        .is_err());
+    }
+}
@@ -1,37 +1,492 @@
-use crate::config::hostmap::{HostMap, SecurityPolicy};
+use crate::config::hostmap::{HostMap, Rollout, SecurityPolicy, ROLLOUT_BUCKETS};
+use crate::config::utils::{first_match, first_match_filtered};
 use crate::config::Config;
 use crate::logs::Logs;
 use crate::utils::RequestInfo;
 
+use serde::Serialize;
+
+/// an entry that was considered while matching a request against the security policies
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyMatchStep {
+    /// regex of the host map, or `__default__`
+    pub host_pattern: String,
+    /// regex of the host map entry, or `__default__`, not set when the host map itself was being matched
+    pub path_pattern: Option<String>,
+    pub matched: bool,
+}
+
+/// number of entries that would have been tried by a linear scan, for tracing purposes
+fn considered(trace_on: bool, idx: Option<usize>) -> usize {
+    match (trace_on, idx) {
+        (false, _) => 0,
+        (true, Some(i)) => i + 1,
+        (true, None) => usize::MAX,
+    }
+}
+
+/// the rollout bucket of a client, derived from a hash of its IP, so that a client always gets the same policy
+pub fn rollout_bucket(ip: &str) -> u32 {
+    let digest = md5::compute(ip);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % ROLLOUT_BUCKETS
+}
+
 /// finds the securitypolicy matching a given request, based on the configuration
 /// there are cases where default values do not exist (even though the UI should prevent that)
 ///
 /// note that the url is matched using the url-decoded path!
 ///
+/// entries that are restricted to a list of methods are only selected for these methods. When none of them match,
+/// the entries without a method restriction are tried.
+///
+/// when the entry has a canary alternative, the clients whose rollout bucket is below its share get the canary policy.
+///
 /// returns the matching security policy, along with the id of the selected host map
 pub fn match_securitypolicy<'a>(ri: &RequestInfo, cfg: &'a Config, logs: &mut Logs) -> Option<(String, &'a SecurityPolicy)> {
-    // find the first matching hostmap, or use the default, if it exists
-    let hostmap: &HostMap = cfg
-        .securitypolicies
-        .iter()
-        .find(|e| e.matcher.is_match(&ri.rinfo.host))
-        .map(|m| &m.inner)
-        .or_else(|| cfg.default.as_ref())?;
+    match_securitypolicy_trace(ri, cfg, logs, None)
+}
+
+/// same as `match_securitypolicy`, but records all the entries that were considered, in order, into `trace`
+pub fn match_securitypolicy_trace<'a>(
+    ri: &RequestInfo,
+    cfg: &'a Config,
+    logs: &mut Logs,
+    mut trace: Option<&mut Vec<PolicyMatchStep>>,
+) -> Option<(String, &'a SecurityPolicy)> {
+    const DEFAULT: &str = "__default__";
+    let trace_on = trace.is_some();
+    let mut record = |host_pattern: &str, path_pattern: Option<&str>, matched: bool| {
+        if let Some(t) = trace.as_mut() {
+            t.push(PolicyMatchStep {
+                host_pattern: host_pattern.to_string(),
+                path_pattern: path_pattern.map(|p| p.to_string()),
+                matched,
+            })
+        }
+    };
+
+    // find the hostmap with the same host name, or the most specific wildcard, or the first matching regex, or use
+    // the default, if it exists
+    let host_idx = match cfg.securitypolicies_index.find(&ri.rinfo.host) {
+        Some(i) => {
+            record(cfg.securitypolicies[i].matcher.as_str(), None, true);
+            Some(i)
+        }
+        None => {
+            let host_idx = first_match(&cfg.securitypolicies, cfg.securitypolicies_set.as_ref(), &ri.rinfo.host);
+            let traced_hosts = considered(trace_on, host_idx);
+            for (i, e) in cfg.securitypolicies.iter().enumerate().take(traced_hosts) {
+                record(e.matcher.as_str(), None, Some(i) == host_idx);
+            }
+            host_idx
+        }
+    };
+    let selected_hostmap = host_idx.map(|i| (&cfg.securitypolicies[i].inner, cfg.securitypolicies[i].matcher.as_str()));
+    let (hostmap, host_pattern): (&HostMap, &str) = match selected_hostmap {
+        Some(x) => x,
+        None => {
+            record(DEFAULT, None, cfg.default.is_some());
+            (cfg.default.as_ref()?, DEFAULT)
+        }
+    };
     logs.debug(format!("Selected hostmap {}", hostmap.name));
+
     // find the first matching securitypolicy, or use the default, if it exists
-    let securitypolicy: &SecurityPolicy = match hostmap
-        .entries
-        .iter()
-        .find(|e| e.matcher.is_match(&ri.rinfo.qinfo.qpath))
-        .map(|m| &m.inner)
-        .or_else(|| hostmap.default.as_ref())
-    {
+    // entries restricted to the request method take precedence over those that apply to all methods
+    let method = ri.rinfo.meta.method.to_uppercase();
+    let qpath = &ri.rinfo.qinfo.qpath;
+    let entries_set = hostmap.entries_set.as_ref();
+    let for_method = |p: &SecurityPolicy| p.methods.as_ref().map(|ms| ms.contains(&method)).unwrap_or(false);
+    let path_idx = first_match_filtered(&hostmap.entries, entries_set, qpath, for_method)
+        .or_else(|| first_match_filtered(&hostmap.entries, entries_set, qpath, |p| p.methods.is_none()));
+    let traced_paths = considered(trace_on, path_idx);
+    for (i, e) in hostmap.entries.iter().enumerate().take(traced_paths) {
+        record(host_pattern, Some(e.matcher.as_str()), Some(i) == path_idx);
+    }
+    let selected_securitypolicy = path_idx.map(|i| &hostmap.entries[i].inner);
+    let securitypolicy: &SecurityPolicy = match selected_securitypolicy {
+        Some(x) => x,
         None => {
-            logs.debug("This hostname has no default entry!");
-            return None;
+            record(host_pattern, Some(DEFAULT), hostmap.default.is_some());
+            match hostmap.default.as_ref() {
+                None => {
+                    logs.debug("This hostname has no default entry!");
+                    return None;
+                }
+                Some(x) => x,
+            }
         }
-        Some(x) => x,
     };
     logs.debug(format!("Selected hostmap entry {}", securitypolicy.name));
+    let securitypolicy = match &securitypolicy.rollout {
+        Rollout::Stable(canary) if rollout_bucket(&ri.rinfo.geoip.ipstr) < canary.buckets => {
+            logs.debug(format!("Selected canary entry {}", canary.policy.name));
+            &canary.policy
+        }
+        _ => securitypolicy,
+    };
     Some((hostmap.name.clone(), securitypolicy))
 }
+
+/// the security policies that apply to the request, in evaluation order: the baseline policies, then the matched one
+///
+/// The matched policy is not repeated when it is also a baseline policy. Without `multiple_policies`, this is the
+/// matched policy alone.
+pub fn match_securitypolicies<'a>(
+    ri: &RequestInfo,
+    cfg: &'a Config,
+    logs: &mut Logs,
+) -> Vec<(String, &'a SecurityPolicy)> {
+    let mut policies: Vec<(String, &SecurityPolicy)> =
+        cfg.baseline_policies.iter().map(|(hn, p)| (hn.clone(), p)).collect();
+    if let Some((hostmap_name, securitypolicy)) = match_securitypolicy(ri, cfg, logs) {
+        if !policies
+            .iter()
+            .any(|(hn, p)| *hn == hostmap_name && p.name == securitypolicy.name)
+        {
+            policies.push((hostmap_name, securitypolicy));
+        }
+    }
+    policies
+}
+
+/// finds a security policy by the names of its host map and entry, without matching the request
+pub fn find_securitypolicy<'a>(cfg: &'a Config, hostmap_name: &str, name: &str) -> Option<&'a SecurityPolicy> {
+    let hostmap = cfg
+        .securitypolicies
+        .iter()
+        .map(|e| &e.inner)
+        .chain(cfg.default.iter())
+        .find(|h| h.name == hostmap_name)?;
+    hostmap
+        .entries
+        .iter()
+        .map(|e| &e.inner)
+        .chain(hostmap.default.iter())
+        .find(|p| p.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::utils::{matching_set, Matching};
+    use crate::testutils::mk_policy;
+    use crate::utils::{map_request, RequestMeta};
+    use regex::Regex;
+    use std::collections::HashMap;
+
+    fn mk_hostmap(id: &str, paths: &[&str]) -> HostMap {
+        let entries: Vec<Matching<SecurityPolicy>> = paths
+            .iter()
+            .map(|p| Matching {
+                matcher: Regex::new(p).unwrap(),
+                inner: mk_policy(p),
+            })
+            .collect();
+        HostMap {
+            id: id.to_string(),
+            name: id.to_string(),
+            entries_set: matching_set(&entries),
+            entries,
+            default: Some(mk_policy("default")),
+        }
+    }
+
+    fn mk_rinfo(host: &str, path: &str) -> RequestInfo {
+        let mut logs = Logs::default();
+        let meta = RequestMeta {
+            authority: Some(host.to_string()),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            extra: HashMap::new(),
+        };
+        map_request(&mut logs, "1.2.3.4".to_string(), HashMap::new(), meta, None).unwrap()
+    }
+
+    #[test]
+    fn set_matches_linear_scan() {
+        // sorted by decreasing length, as when loading the configuration
+        let paths = [
+            "^/api/v1/users",
+            "/static/.*\\.js$",
+            "^/api/v1",
+            "/login",
+            "^/api",
+            "^/$",
+        ];
+        let mut cfg = Config::empty();
+        cfg.securitypolicies = vec![
+            Matching {
+                matcher: Regex::new("^admin\\.").unwrap(),
+                inner: mk_hostmap("admin", &[]),
+            },
+            Matching {
+                matcher: Regex::new("example").unwrap(),
+                inner: mk_hostmap("example", &paths),
+            },
+        ];
+        cfg.securitypolicies_set = matching_set(&cfg.securitypolicies);
+        let mut linear = cfg.clone();
+        linear.securitypolicies_set = None;
+        for e in linear.securitypolicies.iter_mut() {
+            e.inner.entries_set = None;
+        }
+
+        let mut logs = Logs::default();
+        for host in &["admin.example.com", "www.example.com", "unknown"] {
+            for path in &["/", "/api", "/api/v2", "/api/v1/users/12", "/x/login", "/static/a.js"] {
+                let rinfo = mk_rinfo(host, path);
+                let name = |c: &Config| {
+                    match_securitypolicy(&rinfo, c, &mut Logs::default()).map(|(h, p)| (h, p.name.clone()))
+                };
+                assert_eq!(name(&cfg), name(&linear), "{} {}", host, path);
+                let mut trace = Vec::new();
+                let mut linear_trace = Vec::new();
+                match_securitypolicy_trace(&rinfo, &cfg, &mut logs, Some(&mut trace));
+                match_securitypolicy_trace(&rinfo, &linear, &mut logs, Some(&mut linear_trace));
+                assert_eq!(format!("{:?}", trace), format!("{:?}", linear_trace));
+            }
+        }
+        let rinfo = mk_rinfo("www.example.com", "/api/v1/users/12");
+        let (_, policy) = match_securitypolicy(&rinfo, &cfg, &mut logs).unwrap();
+        assert_eq!(policy.name, "^/api/v1/users");
+    }
+
+    #[test]
+    fn find_by_name() {
+        let mut cfg = Config::empty();
+        cfg.securitypolicies = vec![Matching {
+            matcher: Regex::new("example").unwrap(),
+            inner: mk_hostmap("example", &["^/api", "^/$"]),
+        }];
+        cfg.default = Some(mk_hostmap("__default__", &["/login"]));
+        let name = |h: &str, n: &str| find_securitypolicy(&cfg, h, n).map(|p| p.name.clone());
+        assert_eq!(name("example", "^/api"), Some("^/api".to_string()));
+        assert_eq!(name("example", "default"), Some("default".to_string()));
+        assert_eq!(name("__default__", "/login"), Some("/login".to_string()));
+        assert_eq!(name("example", "/login"), None);
+        assert_eq!(name("unknown", "default"), None);
+    }
+
+    #[test]
+    fn trace_shadowed_entry() {
+        let mut cfg = Config::empty();
+        cfg.securitypolicies = vec![
+            Matching {
+                matcher: Regex::new("^other\\.com$").unwrap(),
+                inner: mk_hostmap("other", &[]),
+            },
+            Matching {
+                matcher: Regex::new("^example\\.com$").unwrap(),
+                inner: mk_hostmap("example", &["/", "/api"]),
+            },
+        ];
+        let mut logs = Logs::default();
+        let mut headers = HashMap::new();
+        headers.insert("host".to_string(), "example.com".to_string());
+        let meta = RequestMeta {
+            authority: None,
+            method: "GET".to_string(),
+            path: "/api/v1".to_string(),
+            extra: HashMap::new(),
+        };
+        let mut rinfo = map_request(&mut logs, "1.2.3.4".to_string(), headers, meta, None).unwrap();
+        rinfo.rinfo.host = "example.com".to_string();
+
+        let mut trace = Vec::new();
+        let (hostmap, policy) = match_securitypolicy_trace(&rinfo, &cfg, &mut logs, Some(&mut trace)).unwrap();
+        assert_eq!(hostmap, "example");
+        assert_eq!(policy.name, "/");
+        let steps: Vec<(&str, Option<&str>, bool)> = trace
+            .iter()
+            .map(|s| (s.host_pattern.as_str(), s.path_pattern.as_deref(), s.matched))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                ("^other\\.com$", None, false),
+                ("^example\\.com$", None, true),
+                ("^example\\.com$", Some("/"), true),
+            ]
+        );
+
+        rinfo.rinfo.host = "unknown.com".to_string();
+        trace.clear();
+        assert!(match_securitypolicy_trace(&rinfo, &cfg, &mut logs, Some(&mut trace)).is_none());
+        assert_eq!(trace.len(), 3);
+        assert_eq!(trace[2].host_pattern, "__default__");
+        assert!(!trace[2].matched);
+    }
+
+    #[test]
+    fn method_restricted_entries() {
+        let restricted = |p: &str, name: &str, methods: Option<&[&str]>| Matching {
+            matcher: Regex::new(p).unwrap(),
+            inner: SecurityPolicy {
+                methods: methods.map(|ms| ms.iter().map(|m| m.to_string()).collect()),
+                ..mk_policy(name)
+            },
+        };
+        let mut cfg = Config::empty();
+        let mut hostmap = mk_hostmap("example", &[]);
+        hostmap.entries = vec![
+            restricted("^/orders/", "orders-write", Some(&["POST", "PUT"])),
+            restricted("^/orders", "orders-read", Some(&["GET"])),
+            restricted("^/orders", "orders", None),
+            restricted("^/api", "api-write", Some(&["POST"])),
+        ];
+        hostmap.entries_set = matching_set(&hostmap.entries);
+        cfg.default = Some(hostmap);
+        let mut linear = cfg.clone();
+        linear.default.as_mut().unwrap().entries_set = None;
+
+        let name = |c: &Config, method: &str, path: &str| {
+            let mut rinfo = mk_rinfo("example.com", path);
+            rinfo.rinfo.meta.method = method.to_string();
+            match_securitypolicy(&rinfo, c, &mut Logs::default()).map(|(_, p)| p.name.clone())
+        };
+        for c in &[&cfg, &linear] {
+            assert_eq!(name(c, "GET", "/orders/12").as_deref(), Some("orders-read"));
+            assert_eq!(name(c, "post", "/orders/12").as_deref(), Some("orders-write"));
+            // preflight requests fall through to the entries without a method restriction
+            assert_eq!(name(c, "OPTIONS", "/orders/12").as_deref(), Some("orders"));
+            assert_eq!(name(c, "POST", "/api/x").as_deref(), Some("api-write"));
+            assert_eq!(name(c, "OPTIONS", "/api/x").as_deref(), Some("default"));
+        }
+    }
+
+    #[test]
+    fn canary_rollout() {
+        use crate::config::hostmap::Canary;
+
+        let mut cfg = Config::empty();
+        let mut hostmap = mk_hostmap("example", &[]);
+        let mut canary = mk_policy("default-canary");
+        canary.rollout = Rollout::Canary;
+        let default = hostmap.default.as_mut().unwrap();
+        default.rollout = Rollout::Stable(Box::new(Canary {
+            buckets: ROLLOUT_BUCKETS / 20,
+            policy: canary,
+        }));
+        cfg.default = Some(hostmap);
+
+        let selected_in = |cfg: &Config, ip: &str| {
+            let mut rinfo = mk_rinfo("example.com", "/");
+            rinfo.rinfo.geoip.ipstr = ip.to_string();
+            let (_, policy) = match_securitypolicy(&rinfo, cfg, &mut Logs::default()).unwrap();
+            (policy.name.clone(), policy.rollout.tag())
+        };
+        let selected = |ip: &str| selected_in(&cfg, ip);
+        let ips: Vec<String> = (0..2000).map(|i| format!("10.0.{}.{}", i / 256, i % 256)).collect();
+        let canaries = ips
+            .iter()
+            .filter(|ip| selected(ip) == ("default-canary".to_string(), Some("canary")))
+            .count();
+        // about 5% of the clients
+        assert!((60..140).contains(&canaries), "{}", canaries);
+        for ip in &ips {
+            let expected = if rollout_bucket(ip) < ROLLOUT_BUCKETS / 20 {
+                ("default-canary".to_string(), Some("canary"))
+            } else {
+                ("default".to_string(), Some("stable"))
+            };
+            assert_eq!(selected(ip), expected);
+        }
+        // the bucket only depends on the client IP
+        assert_eq!(rollout_bucket("1.2.3.4"), rollout_bucket("1.2.3.4"));
+
+        if let Rollout::Stable(canary) = &mut cfg.default.as_mut().unwrap().default.as_mut().unwrap().rollout {
+            canary.buckets = 0;
+        }
+        assert!(ips.iter().all(|ip| selected_in(&cfg, ip).0 == "default"));
+    }
+
+    #[test]
+    fn host_keys() {
+        let hostmap = |id: &str, key: &str| {
+            serde_json::json!({
+                "match": key,
+                "id": id,
+                "name": id,
+                "map": [{"match": "/", "name": id, "acl_profile": "__default__", "content_filter_profile": "__default__",
+                         "acl_active": false, "content_filter_active": false, "limit_ids": []}]
+            })
+        };
+        let blob = serde_json::json!({
+            "securitypolicy": [
+                hostmap("__default__", "__default__"),
+                hostmap("regex", "^api-\\d+\\.internal$"),
+                hostmap("wildcard", "*.example.com"),
+                hostmap("exact", "api.example.com"),
+                hostmap("deep", "*.eu.example.com"),
+            ]
+        });
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &blob.to_string()).unwrap();
+        let selected = |host: &str| {
+            let (hostmap, _) = match_securitypolicy(&mk_rinfo(host, "/"), &cfg, &mut Logs::default()).unwrap();
+            hostmap
+        };
+        // exact entries take precedence over wildcards, even when listed after them
+        assert_eq!(selected("api.example.com"), "exact");
+        assert_eq!(selected("API.example.com:8443"), "exact");
+        assert_eq!(selected("www.example.com"), "wildcard");
+        assert_eq!(selected("a.b.example.com"), "wildcard");
+        // the most specific wildcard wins
+        assert_eq!(selected("www.eu.example.com"), "deep");
+        assert_eq!(selected("api-12.internal"), "regex");
+        // the wildcard does not match the domain itself
+        assert_eq!(selected("example.com"), "__default__");
+        assert_eq!(selected("unknown.org"), "__default__");
+        assert_eq!(selected("api-x.internal"), "__default__");
+
+        let mut trace = Vec::new();
+        match_securitypolicy_trace(&mk_rinfo("api.example.com", "/"), &cfg, &mut logs, Some(&mut trace));
+        assert_eq!(trace[0].host_pattern, "api.example.com");
+        assert!(trace[0].matched);
+    }
+
+    #[test]
+    fn baseline_policies() {
+        let hostmap = |id: &str, key: &str| {
+            serde_json::json!({
+                "match": key,
+                "id": id,
+                "name": id,
+                "map": [{"match": "/", "name": id, "acl_profile": "__default__", "content_filter_profile": "__default__",
+                         "acl_active": false, "content_filter_active": false, "limit_ids": []}]
+            })
+        };
+        let blob = |multiple: bool| {
+            serde_json::json!({
+                "securitypolicy": [hostmap("__default__", "__default__"), hostmap("api", "api.example.com")],
+                "settings": {
+                    "multiple_policies": multiple,
+                    "baseline_policies": [{"hostmap": "__default__", "name": "__default__"}, {"hostmap": "x", "name": "y"}]
+                }
+            })
+            .to_string()
+        };
+        let names = |cfg: &Config, host: &str| -> Vec<String> {
+            match_securitypolicies(&mk_rinfo(host, "/"), cfg, &mut Logs::default())
+                .into_iter()
+                .map(|(hostmap, _)| hostmap)
+                .collect()
+        };
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &blob(false)).unwrap();
+        assert!(cfg.baseline_policies.is_empty());
+        assert_eq!(names(&cfg, "api.example.com"), vec!["api"]);
+
+        let mut logs = Logs::default();
+        let (cfg, _) = Config::from_json(&mut logs, &blob(true)).unwrap();
+        assert!(logs
+            .to_stringvec()
+            .iter()
+            .any(|l| l.contains("Unknown security policy x/y")));
+        assert_eq!(names(&cfg, "api.example.com"), vec!["__default__", "api"]);
+        // the matched policy is not evaluated twice
+        assert_eq!(names(&cfg, "www.example.com"), vec!["__default__"]);
+    }
+}
@@ -1,29 +1,329 @@
 /// This module exposes a session based API for the matching system
 use lazy_static::lazy_static;
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{Span, Status, Tracer};
+#[cfg(feature = "otel")]
+use opentelemetry::{global, KeyValue, StringValue, Value};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
 use uuid::Uuid;
 
-use crate::acl::{check_acl, AclResult};
+#[cfg(feature = "async")]
+pub mod nonblocking;
+mod shards;
+
+use crate::accesslog::{
+    tag_values, AccessLogDecision, AccessLogGeo, AccessLogPolicy, AccessLogRecord, AccessLogRequest, AccessLogResponse,
+    AccessLogTimings, ACCESS_LOG_VERSION,
+};
+use crate::acl::{check_acl, explain_acl, AclDecision, AclExplanation, AclResult, BotHuman};
+use crate::anonymous::load_anonymous_networks;
 use crate::config::hostmap::SecurityPolicy;
-use crate::config::{with_config_default_path, Config, CONFIG, HSDB};
-use crate::flow::flow_check;
-use crate::interface::{Decision, Tags};
-use crate::limit::limit_check;
-use crate::logs::Logs;
+use crate::config::contentfilter::ContentFilterRules;
+use crate::config::settings::BudgetExceeded;
+use crate::config::{replace_config, tenant_config, with_config_default_path, Config, TenantId, CONFIG, HSDB};
+use crate::decompress::{inspected_body, DecompressionLimits, InspectedBody};
+use crate::engine::{
+    body_limit, body_limit_stage, content_filter_stage, evaluate_detailed, securitypolicy_stage, tag_stage,
+};
+use crate::flow::flow_check_global;
+use crate::graphql::graphql_info;
+use crate::interface::{Action, Decision, DecisionReason, Grasshopper, SimpleDecision, Tags};
+use crate::jsonpath::JsonPaths;
+use crate::limit::{limit_check, limit_count_response, limit_status, release_slots, ConcurrencySlot, LimitStatus};
+use crate::logs::{LogLevel, Logs};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::reputation::report_bad;
 use crate::requestfields::RequestField;
-use crate::tagging::tag_request;
-use crate::securitypolicy::match_securitypolicy;
-use crate::utils::{find_geoip, QueryInfo, RInfo, RequestInfo, RequestMeta};
-use crate::contentfilter::content_filter_check;
+use crate::response::{response_stage, tag_response, JResponseMap, ResponseInfo};
+use crate::securitypolicy::{find_securitypolicy, PolicyMatchStep};
+use crate::smuggling::{smuggling_action, smuggling_indicators};
+use crate::tagging::tag_time;
+#[cfg(feature = "otel")]
+use crate::telemetry::{parent_context, SpanOutcome, TRACER};
+use crate::utils::url::{parse_structured_params, urlencode_path, DEFAULT_MAX_ARG_DEPTH};
+use crate::utils::{
+    cookie_map, find_geoip, upgrade_protocol, CertVerification, ClientCert, CookieDuplicates, GeoIp, QueryInfo, RInfo,
+    RequestInfo, RequestMeta,
+};
+use crate::contentfilter::{
+    content_filter_matches, content_filter_score, ContentFilterBlock, ContentFilterRuleMatch, ContentFilterStream,
+};
+use crate::{challenge_verified, tag_anomaly_score};
+use crate::body::parse_body;
+use shards::ShardedMap;
 
 // Session stuff, the key is the session id
 lazy_static! {
-    static ref RAW: RwLock<HashMap<Uuid, serde_json::Value>> = RwLock::new(HashMap::new());
-    static ref RINFOS: RwLock<HashMap<Uuid, RequestInfo>> = RwLock::new(HashMap::new());
-    static ref TAGS: RwLock<HashMap<Uuid, Tags>> = RwLock::new(HashMap::new());
-    static ref SECURITYPOLICY: RwLock<HashMap<Uuid, SecurityPolicy>> = RwLock::new(HashMap::new());
+    static ref RAW: ShardedMap<serde_json::Value> = ShardedMap::default();
+    static ref RINFOS: ShardedMap<RequestInfo> = ShardedMap::default();
+    static ref TAGS: ShardedMap<Tags> = ShardedMap::default();
+    /// the matched security policy, along with the name of its host map
+    static ref SECURITYPOLICY: ShardedMap<(String, SecurityPolicy)> = ShardedMap::default();
+    /// the baseline policies that are evaluated before the matched one, along with the names of their host maps, see
+    /// the `multiple_policies` setting
+    static ref BASELINES: ShardedMap<Vec<(String, SecurityPolicy)>> = ShardedMap::default();
+    static ref LOGS: ShardedMap<Vec<LogEntry>> = ShardedMap::default();
+    static ref TIMES: ShardedMap<SessionTimes> = ShardedMap::default();
+    static ref TIMINGS: ShardedMap<SessionTimings> = ShardedMap::default();
+    static ref REASONS: ShardedMap<DecisionReason> = ShardedMap::default();
+    static ref DECISIONS: ShardedMap<Decision> = ShardedMap::default();
+    static ref RESPONSES: ShardedMap<ResponseInfo> = ShardedMap::default();
+    /// the tenant of the session, the sessions without a tenant use the default configuration
+    static ref TENANTS: ShardedMap<TenantId> = ShardedMap::default();
+    /// the sessions of the trusted sources, with the matching source, see `check_trusted`
+    static ref BYPASSED: ShardedMap<String> = ShardedMap::default();
+    /// the slots of the concurrency limits that the session holds, see `session_limit_release`
+    static ref SLOTS: ShardedMap<Vec<ConcurrencySlot>> = ShardedMap::default();
+    /// body streams, opened by the first call to `session_content_filter_feed`
+    static ref STREAMS: Mutex<HashMap<Uuid, ContentFilterStream>> = Mutex::new(HashMap::new());
+}
+
+lazy_static! {
+    static ref CAPACITY: Mutex<SessionCapacity> = Mutex::new(SessionCapacity::default());
+    /// the live sessions ordered by creation time, so that the oldest ones can be evicted
+    static ref AGES: Mutex<BTreeSet<(Instant, Uuid)>> = Mutex::new(BTreeSet::new());
+}
+
+/// the number of sessions in TIMES, see `track_sessions` and `untrack_session`
+static LIVE_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+/// set when there is a `max_sessions` limit, so that creating sessions does not take the CAPACITY lock otherwise
+static LIMITED: AtomicBool = AtomicBool::new(false);
+
+/// errors returned by the session functions
+#[derive(Debug)]
+pub enum SessionError {
+    /// the session id could not be parsed
+    InvalidSessionId(uuid::Error),
+    /// the session id is not known, or the session has been cleaned
+    UnknownSession,
+    /// a lock on one of the session maps, or on the configuration, was poisoned
+    LockPoisoned(String),
+    /// no security policy matched the request
+    NoSecurityPolicy,
+    /// the request map could not be decoded, or encoded
+    DeserializeFailed(serde_json::Error),
+    /// the request map did not have the expected structure
+    InvalidRequestMap(&'static str),
+    /// a tag supplied by the caller is not a valid tag
+    InvalidTag(String),
+    /// a request map could not be decoded during a batch initialization, with its index
+    BatchEntry(usize, Box<SessionError>),
+    /// the session references a tenant whose configuration was never loaded, see `reload_tenant_config`
+    UnknownTenant(String),
+    /// the response was checked before it was attached to the session with `session_response_init`
+    NoResponse,
+    /// the session could not be created without exceeding `max_sessions`, with the limit, see `set_max_sessions`
+    Capacity(usize),
+    /// other errors, such as redis failures
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SessionError::InvalidSessionId(rr) => write!(f, "{}", rr),
+            SessionError::UnknownSession => write!(f, "Unknown session id"),
+            SessionError::LockPoisoned(msg) => write!(f, "{}", msg),
+            SessionError::NoSecurityPolicy => write!(f, "No matching Security Policy"),
+            SessionError::DeserializeFailed(rr) => write!(f, "{}", rr),
+            SessionError::InvalidRequestMap(msg) => write!(f, "{}", msg),
+            SessionError::InvalidTag(tag) => write!(f, "Invalid tag {:?}", tag),
+            SessionError::BatchEntry(index, rr) => write!(f, "request map {}: {}", index, rr),
+            SessionError::UnknownTenant(tenant) => write!(f, "Unknown tenant {:?}", tenant),
+            SessionError::NoResponse => write!(f, "No response attached to the session"),
+            SessionError::Capacity(max) => write!(f, "Too many sessions, the limit is {}", max),
+            SessionError::Other(rr) => write!(f, "{}", rr),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::InvalidSessionId(rr) => Some(rr),
+            SessionError::DeserializeFailed(rr) => Some(rr),
+            SessionError::BatchEntry(_, rr) => Some(rr.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<uuid::Error> for SessionError {
+    fn from(rr: uuid::Error) -> Self {
+        SessionError::InvalidSessionId(rr)
+    }
+}
+
+impl From<serde_json::Error> for SessionError {
+    fn from(rr: serde_json::Error) -> Self {
+        SessionError::DeserializeFailed(rr)
+    }
+}
+
+/// creation time of a session, and how long it is allowed to live
+#[derive(Debug, Clone, Copy)]
+struct SessionTimes {
+    created: Instant,
+    ttl: Option<Duration>,
+}
+
+impl SessionTimes {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.ttl {
+            None => false,
+            Some(ttl) => now.saturating_duration_since(self.created) >= ttl,
+        }
+    }
+}
+
+/// time spent in each pipeline stage of a session, in nanoseconds
+///
+/// When a stage is run several times, durations are added.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SessionTimings {
+    pub tagging: u64,
+    pub limit: u64,
+    pub acl: u64,
+    pub content_filter: u64,
+    pub flow: u64,
+}
+
+/// the session pipeline stages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    SecurityPolicy,
+    Tagging,
+    Limit,
+    Acl,
+    ContentFilter,
+    Flow,
+    /// all the stages run by `session_evaluate`
+    Evaluate,
+    /// decisions set by the caller, see `session_set_decision`
+    Override,
+    /// the checks of the response, see `session_response_check`
+    Response,
+    /// the decoding of the request map, such as the decompression of the body, see `session_init`
+    Init,
+}
+
+impl SessionTimings {
+    /// the stages that are not individually timed have no duration
+    fn at(&mut self, stage: Stage) -> Option<&mut u64> {
+        match stage {
+            Stage::Tagging => Some(&mut self.tagging),
+            Stage::Limit => Some(&mut self.limit),
+            Stage::Acl => Some(&mut self.acl),
+            Stage::ContentFilter => Some(&mut self.content_filter),
+            Stage::Flow => Some(&mut self.flow),
+            Stage::SecurityPolicy | Stage::Evaluate | Stage::Override | Stage::Response | Stage::Init => None,
+        }
+    }
+}
+
+/// a log line of a session, along with the stage that produced it
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    pub stage: Stage,
+    pub message: String,
+    /// time elapsed since the start of the session function that produced it
+    pub elapsed_micros: u64,
+}
+
+/// runs a pipeline stage, and adds its duration to the session timings
+fn timed<F, A>(uuid: Uuid, stage: Stage, f: F) -> A
+where
+    F: FnOnce() -> A,
+{
+    let start = Instant::now();
+    let out = f();
+    add_durations(uuid, &[(stage, start.elapsed().as_nanos() as u64)]);
+    out
+}
+
+/// runs a check in a span of the global tracer when the `otel` feature is enabled, see `telemetry`
+#[cfg(feature = "otel")]
+fn traced<F, A>(uuid: Uuid, name: &'static str, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce() -> Result<A, SessionError>,
+    A: SpanOutcome,
+{
+    let start = SystemTime::now();
+    let out = f();
+    let end = SystemTime::now();
+    let parent = with_request_info(uuid, |rinfo| Ok(parent_context(rinfo.rinfo.traceparent.as_deref())))
+        .unwrap_or_else(|_| parent_context(None));
+    let tracer = global::tracer(TRACER);
+    let mut span = tracer
+        .span_builder(name)
+        .with_start_time(start)
+        .start_with_context(&tracer, &parent);
+    // the attributes are only looked up when the span is recorded, not with the default no-op tracer
+    if span.is_recording() {
+        if let Ok(policy) = with_securitypolicy(uuid, |securitypolicy| Ok(securitypolicy.name.clone())) {
+            span.set_attribute(KeyValue::new("curiefense.policy", policy));
+        }
+        match &out {
+            Ok(outcome) => span.set_attribute(KeyValue::new("curiefense.decision", outcome.outcome())),
+            Err(rr) => {
+                span.set_attribute(KeyValue::new("curiefense.error", rr.to_string()));
+                span.set_status(Status::error(rr.to_string()));
+            }
+        }
+        if let Ok(tags) = with_tags(uuid, |tags| Ok(tags.to_sorted_vec())) {
+            let tags: Vec<StringValue> = tags.into_iter().map(StringValue::from).collect();
+            span.set_attribute(KeyValue::new("curiefense.tags", Value::Array(tags.into())));
+        }
+    }
+    span.end_with_timestamp(end);
+    out
+}
+
+#[cfg(not(feature = "otel"))]
+fn traced<F, A>(_uuid: Uuid, _name: &'static str, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce() -> Result<A, SessionError>,
+{
+    f()
+}
+
+/// adds durations, in nanoseconds, to the session timings
+fn add_durations(uuid: Uuid, durations: &[(Stage, u64)]) {
+    if let Ok(mut w) = TIMINGS.write(&uuid) {
+        if let Some(timings) = w.get_mut(&uuid) {
+            for (stage, elapsed) in durations {
+                if let Some(duration) = timings.at(*stage) {
+                    *duration += elapsed;
+                }
+            }
+        }
+    }
+    #[cfg(feature = "metrics")]
+    for (stage, elapsed) in durations {
+        metrics::observe_stage(*stage, *elapsed);
+    }
+}
+
+/// counts the decisions of `session_evaluate` when the `metrics` feature is enabled
+#[cfg(feature = "metrics")]
+fn counted(decision: Decision) -> Decision {
+    metrics::count_decision(&decision);
+    decision
+}
+
+#[cfg(not(feature = "metrics"))]
+fn counted(decision: Decision) -> Decision {
+    decision
 }
 
 /// json representation of the useful fields in the request map
@@ -33,8 +333,70 @@ pub struct JRequestMap {
     cookies: RequestField,
     args: RequestField,
     attrs: JAttrs,
+    /// when set, the x-forwarded-host header takes precedence over the host header
+    #[serde(default)]
+    prefer_forwarded_host: bool,
+    /// the request body, parsed according to the content-type header
+    #[serde(default)]
+    body: Option<String>,
+    /// bodies larger than this are not parsed, defaults to `DEFAULT_MAX_BODY_SIZE`
+    #[serde(default)]
+    max_body_size: Option<usize>,
+    /// the body is base64 encoded, which is required for binary bodies, such as compressed ones
+    #[serde(default)]
+    body_base64: bool,
+    /// decompressed bodies larger than this are blocked, defaults to `DEFAULT_MAX_DECOMPRESSED_SIZE`
+    #[serde(default)]
+    max_decompressed_size: Option<usize>,
+    /// decompressed bodies that are this many times larger than the compressed body are blocked, defaults to
+    /// `DEFAULT_MAX_DECOMPRESSION_RATIO`
+    #[serde(default)]
+    max_decompression_ratio: Option<usize>,
+    /// nesting depth of the structured query parameters that are expanded, defaults to `DEFAULT_MAX_ARG_DEPTH`, see
+    /// `parse_structured_params`
+    #[serde(default)]
+    max_arg_depth: Option<usize>,
+    /// the headers in the order they were received, as `[name, value]` pairs, including the duplicates that can't
+    /// be represented in `headers`
+    #[serde(default)]
+    header_list: Option<Vec<(String, String)>>,
+    /// the tenant whose configuration is used by the session, the default configuration is used when not set
+    #[serde(default)]
+    tenant: Option<TenantId>,
+    /// how duplicate cookie names are handled when the cookies are parsed from the `cookie` header
+    #[serde(default)]
+    cookie_duplicates: CookieDuplicates,
+    /// number of trusted proxies that append to the x-forwarded-for header, the client IP is taken from this header
+    /// when set, instead of `attrs.ip`
+    #[serde(default)]
+    trusted_hops: usize,
 }
 
+/// default maximum size of the bodies that are parsed, in bytes
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// the size of a base64 encoded body, once decoded
+fn base64_decoded_len(encoded: &str) -> usize {
+    encoded.trim_end_matches('=').len() * 3 / 4
+}
+
+/// when the body can't be base64 decoded, it is inspected as it is
+fn decode_body(body: &str, base64: bool) -> Cow<'_, [u8]> {
+    if base64 {
+        base64::decode(body)
+            .map(Cow::Owned)
+            .unwrap_or_else(|_| Cow::Borrowed(body.as_bytes()))
+    } else {
+        Cow::Borrowed(body.as_bytes())
+    }
+}
+
+/// the tag of the sessions of trusted sources, for the logs only, see `check_trusted`
+const BYPASS_TAG: &str = "trusted-bypass";
+
+/// the anonymous networks list loaded by `init_config`, when it exists
+const DEFAULT_ANONYMOUS_NETWORKS: &str = "/config/current/config/json/anonymous-ips.json";
+
 /// json representation of the useful fields in attrs
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct JAttrs {
@@ -45,293 +407,4056 @@ struct JAttrs {
     authority: Option<String>,
     uri: String,
     tags: HashMap<String, serde_json::Value>,
+    /// geolocation data, when it has already been computed by the caller
+    #[serde(default)]
+    geo: Option<JGeo>,
+    #[serde(default)]
+    tls_fingerprint: Option<String>,
+    /// the W3C trace context of the request, the `traceparent` header is used when it is not set
+    #[serde(default)]
+    traceparent: Option<String>,
+    /// the HTTP version of the request, such as `HTTP/1.1`, `1.1` or `2`
+    #[serde(default)]
+    http_version: Option<String>,
+    /// the protocol negotiated with ALPN, such as `h2`
+    #[serde(default)]
+    alpn: Option<String>,
+    /// the certificate presented by the client with mutual TLS
+    #[serde(default)]
+    client_cert: Option<JClientCert>,
+}
+
+/// json representation of a TLS client certificate
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+struct JClientCert {
+    #[serde(default)]
+    subject: String,
+    #[serde(default)]
+    sans: Vec<String>,
+    #[serde(default)]
+    issuer: String,
+    #[serde(default)]
+    fingerprint: Option<String>,
+    /// the verification status, such as `SUCCESS`, `FAILED:reason` or `NONE`
+    #[serde(default)]
+    verify: Option<String>,
+}
+
+impl JClientCert {
+    fn into_client_cert(self) -> ClientCert {
+        ClientCert {
+            subject: self.subject,
+            sans: self.sans,
+            issuer: self.issuer,
+            fingerprint: self
+                .fingerprint
+                .map(|f| f.trim().to_lowercase())
+                .filter(|f| !f.is_empty()),
+            verification: self
+                .verify
+                .as_deref()
+                .map(CertVerification::parse)
+                .unwrap_or(CertVerification::Unverified),
+        }
+    }
+}
+
+/// json representation of precomputed geolocation data
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+struct JGeo {
+    country: Option<String>,
+    asn: Option<u32>,
+    org: Option<String>,
+    subdivision: Option<String>,
+}
+
+impl JGeo {
+    fn into_geoip(self, ipstr: String) -> GeoIp {
+        GeoIp {
+            ip: ipstr.parse().ok(),
+            ipstr,
+            location: None,
+            accuracy_radius: None,
+            in_eu: None,
+            city_name: None,
+            // find_geoip lowercases the country code, this is done here too for consistency
+            country_iso: self.country.map(|s| s.to_lowercase()),
+            country_name: None,
+            continent_name: None,
+            continent_code: None,
+            asn: self.asn,
+            company: self.org,
+            subdivision: self.subdivision,
+            threat_categories: Vec::new(),
+        }
+    }
+}
+
+/// removes the port part of a host, taking care of IPv6 literals such as `[::1]:443`
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        match host.rfind(':') {
+            // a bare IPv6 address, that can't have a port
+            Some(_) if host.matches(':').count() > 1 => host,
+            Some(idx) => &host[..idx],
+            None => host,
+        }
+    }
+}
+
+/// normalizes an HTTP version, so that `HTTP/1.1` becomes `1.1`, and `HTTP/2.0` becomes `2`, rejecting invalid versions
+fn normalize_http_version(version: &str) -> Option<String> {
+    let trimmed = version.trim();
+    let number = match trimmed.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("http/") => &trimmed[5..],
+        _ => trimmed,
+    };
+    let number = number.strip_suffix(".0").unwrap_or(number);
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    Some(number.to_string())
+}
+
+/// selects the client IP in the x-forwarded-for entries, the last `trusted_hops` ones having been appended by the
+/// trusted proxies, and flags the entries that are not valid IPs
+///
+/// The socket IP is used when there are fewer entries than trusted hops, or when an entry that was appended by a
+/// trusted proxy is not a valid IP.
+fn forwarded_client_ip(socket_ip: &str, entries: &[&str], trusted_hops: usize) -> (String, bool) {
+    if trusted_hops == 0 || entries.len() < trusted_hops {
+        return (socket_ip.to_string(), false);
+    }
+    let is_ip = |entry: &&str| entry.parse::<IpAddr>().is_ok();
+    let (untrusted, trusted) = entries.split_at(entries.len() - trusted_hops);
+    if !trusted.iter().all(is_ip) {
+        return (socket_ip.to_string(), true);
+    }
+    (trusted[0].to_string(), !untrusted.iter().all(is_ip))
 }
 
 impl JRequestMap {
-    pub fn into_request_info(self) -> (RequestInfo, Tags) {
-        let host: String = self
-            .headers
-            .get("host")
-            .cloned()
-            .or_else(|| self.attrs.authority.clone())
-            .unwrap_or_else(|| "unknown".to_string());
+    /// the client IP, and whether the x-forwarded-for header looks forged, see `forwarded_client_ip`
+    fn client_ip(&self) -> (String, bool) {
+        let values: Vec<&str> = match &self.header_list {
+            Some(list) => list
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("x-forwarded-for"))
+                .map(|(_, v)| v.as_str())
+                .collect(),
+            None => self.headers.get_str("x-forwarded-for").into_iter().collect(),
+        };
+        let entries: Vec<&str> = values
+            .iter()
+            .flat_map(|v| v.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|s| !s.is_empty())
+            .collect();
+        forwarded_client_ip(&self.attrs.ip, &entries, self.trusted_hops)
+    }
 
-        // TODO, get geoip data from the encoded request, not from the ip
-        let geoip = find_geoip(self.attrs.ip);
+    /// returns the host, from the first available source:
+    ///  * the first entry of the x-forwarded-host header, when `prefer_forwarded_host` is set,
+    ///  * the host header,
+    ///  * the authority,
+    ///  * "unknown".
+    ///
+    /// The port is always stripped.
+    fn host(&self) -> String {
+        let forwarded = if self.prefer_forwarded_host {
+            self.headers
+                .get_str("x-forwarded-host")
+                .and_then(|h| h.split(|c: char| c == ',' || c.is_whitespace()).find(|s| !s.is_empty()))
+        } else {
+            None
+        };
+        forwarded
+            .or(self.headers.get_str("host"))
+            .or(self.attrs.authority.as_deref())
+            .map(|h| strip_port(h).to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// the request information and the tags of the request map, problems with the body are logged to `logs`
+    pub fn into_request_info(self, logs: &mut Logs) -> (RequestInfo, Tags) {
+        let host = self.host();
+        let (ip, xff_spoofed) = self.client_ip();
+
+        // the maxmind lookups are only performed when the caller did not provide geolocation data
+        let geoip = match self.attrs.geo {
+            Some(geo) => geo.into_geoip(ip),
+            None => find_geoip(ip),
+        };
+        // attrs.path is decoded, it is encoded back so that meta.path matches the raw request path
+        let mut path = urlencode_path(&self.attrs.path);
+        if !self.attrs.query.is_empty() {
+            path.push('?');
+            path.push_str(&self.attrs.query);
+        }
         let meta = RequestMeta {
             authority: self.attrs.authority,
             method: self.attrs.method,
-            path: self.attrs.uri.clone(), // this is wrong, uri should be url-encoded back
+            path,
             extra: HashMap::new(),
         };
+        let mut args = self.args;
+        parse_structured_params(
+            &mut args,
+            &self.attrs.query,
+            self.max_arg_depth.unwrap_or(DEFAULT_MAX_ARG_DEPTH),
+        );
+        let mut tags = Tags::default();
+        for (k, v) in self.attrs.tags {
+            // string values are kept, other values (usually `1`) only mark the tag presence
+            match v {
+                serde_json::Value::String(value) => tags.insert_with_value(&k, &value),
+                _ => tags.insert(&k),
+            };
+        }
+        if xff_spoofed {
+            tags.insert("xff-spoofed");
+        }
+        let mut graphql = None;
+        let mut decompress_bomb = false;
+        let mut decompress_failed = false;
+        let mut json_paths = JsonPaths::new();
+        let mut body_size = None;
+        if let Some(body) = self.body {
+            // the size is checked first, so that large bodies are not decoded
+            let size = if self.body_base64 {
+                base64_decoded_len(&body)
+            } else {
+                body.len()
+            };
+            body_size = Some(size);
+            let limits = DecompressionLimits::new(self.max_decompressed_size, self.max_decompression_ratio);
+            let content_encoding = self.headers.get_str("content-encoding");
+            if size > self.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE) {
+                tags.insert("body-too-large");
+            } else {
+                let decoded = decode_body(&body, self.body_base64);
+                let inspected = inspected_body(logs, content_encoding, &decoded, &limits);
+                decompress_bomb = matches!(inspected, InspectedBody::TooLarge);
+                decompress_failed = matches!(inspected, InspectedBody::Failed(_));
+                if let Some(inspected) = inspected.body() {
+                    let content_type = self.headers.get_str("content-type");
+                    json_paths = add_body_args(&mut args, content_type, inspected);
+                    graphql = graphql_info(content_type, inspected);
+                }
+            }
+        }
         let qinfo = QueryInfo {
             qpath: self.attrs.path,
             query: self.attrs.query,
             uri: Some(self.attrs.uri),
-            args: self.args,
+            args,
+            json_paths,
+        };
+        let mut headers = self.headers;
+        let header_list = match self.header_list {
+            Some(list) => {
+                let list: Vec<(String, String)> = list.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+                merge_duplicate_headers(&mut headers, &list);
+                list
+            }
+            None => {
+                let mut list: Vec<(String, String)> = headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                list.sort();
+                list
+            }
+        };
+        // the cookie headers take precedence over the cookies parsed by the proxy
+        let cookie_headers: Vec<&str> = header_list
+            .iter()
+            .filter(|(k, _)| k == "cookie")
+            .map(|(_, v)| v.as_str())
+            .collect();
+        let cookies = if cookie_headers.is_empty() {
+            self.cookies
+        } else {
+            cookie_map(&cookie_headers, self.cookie_duplicates)
         };
-        let vtags: Vec<String> = self.attrs.tags.into_iter().map(|(k, _)| k).collect();
+        let upgrade_protocol = upgrade_protocol(&headers);
+        let traceparent = self.attrs.traceparent.or_else(|| headers.get("traceparent").cloned());
         (
             RequestInfo {
-                cookies: self.cookies,
-                headers: self.headers,
+                cookies,
+                headers,
+                header_list,
                 rinfo: RInfo {
                     meta,
                     geoip,
                     qinfo,
                     host,
+                    is_upgrade: upgrade_protocol.is_some(),
+                    upgrade_protocol,
+                    graphql,
+                    tls_fingerprint: self.attrs.tls_fingerprint,
+                    decompress_bomb,
+                    decompress_failed,
+                    traceparent,
+                    http_version: self.attrs.http_version.as_deref().and_then(normalize_http_version),
+                    alpn: self
+                        .attrs
+                        .alpn
+                        .map(|p| p.trim().to_lowercase())
+                        .filter(|p| !p.is_empty()),
+                    body_size,
+                    client_cert: self.attrs.client_cert.map(JClientCert::into_client_cert),
                 },
             },
-            Tags::from_slice(&vtags),
+            tags,
         )
     }
 }
 
+/// loads the configuration from the default path, the issues are returned as log lines, see `validate_config` for
+/// their structured form
 pub fn init_config() -> (bool, Vec<String>) {
     let mut logs = Logs::default();
     with_config_default_path(&mut logs, |_, _| {});
+    // the anonymous networks are optional, and can then be reloaded on their own
+    if Path::new(DEFAULT_ANONYMOUS_NETWORKS).exists() {
+        if let Err(rr) = load_anonymous_networks(DEFAULT_ANONYMOUS_NETWORKS) {
+            logs.error(rr);
+        }
+    }
     let is_ok = logs.logs.is_empty();
     (is_ok, logs.to_stringvec())
 }
 
-pub fn clean_session(session_id: &str) -> anyhow::Result<()> {
+/// loads the configuration from a json object, instead of the default path, see `Config::from_json`
+pub fn init_config_from_json(config_blob: &str) -> (bool, Vec<String>) {
+    let mut logs = Logs::default();
+    if let Some((newconfig, newhsdb)) = Config::from_json(&mut logs, config_blob) {
+        replace_config(&mut logs, newconfig, newhsdb);
+    }
+    // the debug message announcing the configuration load is not an error
+    let is_ok = logs.logs.iter().all(|l| l.level < LogLevel::Warning);
+    (is_ok, logs.to_stringvec())
+}
+
+pub fn clean_session(session_id: &str) -> Result<(), SessionError> {
     let uuid: Uuid = session_id.parse()?;
-    if let Ok(mut w) = RINFOS.write() {
+    remove_session(uuid);
+    Ok(())
+}
+
+fn remove_session(uuid: Uuid) {
+    if let Ok(mut w) = RAW.write(&uuid) {
         w.remove(&uuid);
     }
-    if let Ok(mut w) = TAGS.write() {
+    if let Ok(mut w) = RINFOS.write(&uuid) {
         w.remove(&uuid);
     }
-    if let Ok(mut w) = SECURITYPOLICY.write() {
+    if let Ok(mut w) = TAGS.write(&uuid) {
         w.remove(&uuid);
     }
-    Ok(())
+    if let Ok(mut w) = SECURITYPOLICY.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = BASELINES.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = LOGS.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = TIMES.write(&uuid) {
+        if let Some(times) = w.remove(&uuid) {
+            untrack_session(uuid, times.created);
+        }
+    }
+    if let Ok(mut w) = TIMINGS.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = REASONS.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = DECISIONS.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = RESPONSES.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = TENANTS.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = BYPASSED.write(&uuid) {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = STREAMS.lock() {
+        w.remove(&uuid);
+    }
+    release_session_slots(uuid);
 }
 
-pub fn session_serialize_request_map(session_id: &str) -> anyhow::Result<serde_json::Value> {
-    let uuid: Uuid = session_id.parse()?;
-    // get raw request first
-    let raw: serde_json::Value = match RAW.read() {
-        Ok(raws) => match raws.get(&uuid) {
-            Some(v) => v.clone(),
-            None => return Err(anyhow::anyhow!("Could not get RAW {}", uuid)),
-        },
-        Err(rr) => return Err(anyhow::anyhow!("Could not get read lock on RAW {}", rr)),
+/// releases the concurrency slots that the session still holds, when it was not done by `session_limit_release`
+fn release_session_slots(uuid: Uuid) {
+    let slots = match SLOTS.write(&uuid) {
+        Ok(mut w) => w.remove(&uuid),
+        Err(_) => None,
     };
-
-    // get the tags
-    let tags = with_tags(uuid, |tgs| Ok(tgs.clone()))?;
-
-    update_tags(raw, tags)
+    if let Some(slots) = slots {
+        release_slots(&mut Logs::default(), &slots);
+    }
 }
 
-/// update the tags in the JSON-encoded request_map
-pub fn update_tags(rawjson: serde_json::Value, tags: Tags) -> anyhow::Result<serde_json::Value> {
-    let mut raw = rawjson;
-    let tags_map: HashMap<String, u32> = tags.as_hash_ref().iter().map(|k| (k.clone(), 1)).collect();
+/// the number of created sessions between two sweeps of the expired sessions, a power of two
+const GC_INTERVAL: usize = 256;
+/// the longest time between two sweeps, in milliseconds, as long as sessions are created
+const GC_PERIOD_MILLIS: u64 = 1000;
 
-    // update the tags
-    let attrs = raw.get_mut("attrs").ok_or_else(|| anyhow::anyhow!("No attrs field"))?;
-    let attrs_o = attrs
-        .as_object_mut()
-        .ok_or_else(|| anyhow::anyhow!("Attrs was not an object"))?;
-    attrs_o.insert("tags".to_string(), serde_json::to_value(tags_map)?);
+/// the number of sessions created since the process started
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+/// the time of the last sweep, in milliseconds since `GC_EPOCH`
+static LAST_GC: AtomicU64 = AtomicU64::new(0);
 
-    Ok(raw)
+lazy_static! {
+    static ref GC_EPOCH: Instant = Instant::now();
 }
 
-/// initializes a session from a json-encoded request map
-pub fn session_init(encoded_request_map: &str) -> anyhow::Result<String> {
-    let jvalue: serde_json::Value = serde_json::from_str(encoded_request_map)?;
-    let jmap: JRequestMap = serde_json::from_value(jvalue.clone())?;
-    let (rinfo, tags) = jmap.into_request_info();
-
-    let uuid = Uuid::new_v4();
+/// true when the expired sessions should be swept before creating a session, every `GC_INTERVAL` sessions, or when
+/// the last sweep is older than `GC_PERIOD_MILLIS`
+fn gc_due() -> bool {
+    let count = CREATED.fetch_add(1, Ordering::Relaxed);
+    let now = GC_EPOCH.elapsed().as_millis() as u64;
+    let last = LAST_GC.load(Ordering::Relaxed);
+    let due = count & (GC_INTERVAL - 1) == 0
+        || (now.saturating_sub(last) >= GC_PERIOD_MILLIS
+            && LAST_GC
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok());
+    if due {
+        LAST_GC.store(now, Ordering::Relaxed);
+    }
+    due
+}
 
-    let mut raw = RAW
-        .write()
-        .map_err(|rr| anyhow::anyhow!("Could not get RAW write lock {}", rr))?;
-    raw.insert(uuid, jvalue);
-    let mut rinfos = RINFOS
-        .write()
-        .map_err(|rr| anyhow::anyhow!("Could not get RINFOS write lock {}", rr))?;
-    rinfos.insert(uuid, rinfo);
-    let mut wtags = TAGS
-        .write()
-        .map_err(|rr| anyhow::anyhow!("Could not get TAGS write lock {}", rr))?;
-    wtags.insert(uuid, tags);
+/// sweeps the expired sessions when it is due, see `gc_due`
+fn amortized_gc() -> Result<(), SessionError> {
+    if gc_due() {
+        session_gc()?;
+    }
+    Ok(())
+}
 
-    Ok(format!("{}", uuid))
+/// removes all sessions that outlived their TTL, returning the number of removed sessions
+///
+/// This is called regularly when sessions are created, see `gc_due`, but can also be called manually.
+pub fn session_gc() -> Result<usize, SessionError> {
+    let now = Instant::now();
+    let mut expired: Vec<Uuid> = Vec::new();
+    for shard in TIMES.shards() {
+        let times = shard
+            .read()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES read lock {}", rr)))?;
+        expired.extend(
+            times
+                .iter()
+                .filter(|(_, times)| times.is_expired(now))
+                .map(|(uuid, _)| *uuid),
+        );
+    }
+    for uuid in &expired {
+        remove_session(*uuid);
+    }
+    Ok(expired.len())
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct SessionSecurityPolicy {
-    pub name: String,
-    pub acl_profile: String,
-    pub content_filter_profile: String,
-    pub acl_active: bool,
-    pub content_filter_active: bool,
-    pub limit_ids: Vec<String>,
-    pub securitypolicy: String,
+/// the ids of the sessions whose initialization completed, TIMINGS being the last map written by `insert_session`
+fn initialized_sessions() -> Result<HashSet<Uuid>, SessionError> {
+    let mut ids = HashSet::new();
+    session_ids("TIMINGS", &TIMINGS, &mut ids)?;
+    Ok(ids)
 }
 
-/// returns a RawSecurityPolicy object (minus the match field), and updates the internal structure for the security policy
-pub fn session_match_securitypolicy(session_id: &str) -> anyhow::Result<SessionSecurityPolicy> {
-    let mut logs = Logs::default();
-    let uuid: Uuid = session_id.parse()?;
-    // this is done this way in order to release the config lock before writing the tags
-    // this might not be optimal though, perhaps it is faster to keep the locks and avoir copies
-    let (hostmap_name, securitypolicy) = with_config(|cfg| {
-        with_request_info(uuid, |rinfo| match match_securitypolicy(&rinfo, &cfg, &mut logs) {
-            Some((hn, securitypolicy)) => {
-                let mut wsecuritypolicy = SECURITYPOLICY
-                    .write()
-                    .map_err(|rr| anyhow::anyhow!("Could not get TAGS write lock {}", rr))?;
-                wsecuritypolicy.insert(uuid, securitypolicy.clone());
-                Ok((hn, securitypolicy.clone()))
-            }
-            None => Err(anyhow::anyhow!("No matching Security Policy")),
-        })
-    })?;
-    with_tags_mut(uuid, |tags| {
-        tags.insert_qualified("securitypolicy", &hostmap_name);
-        tags.insert_qualified("securitypolicy-entry", &securitypolicy.name);
-        tags.insert_qualified("aclid", &securitypolicy.acl_profile.id);
-        tags.insert_qualified("aclname", &securitypolicy.acl_profile.name);
-        tags.insert_qualified("contentfilterid", &securitypolicy.content_filter_profile.id);
-        tags.insert_qualified("contentfiltername", &securitypolicy.content_filter_profile.name);
-        Ok(())
-    })?;
-    let raw_securitypolicy = SessionSecurityPolicy {
-        name: securitypolicy.name,
-        acl_profile: securitypolicy.acl_profile.id,
-        content_filter_profile: securitypolicy.content_filter_profile.id,
-        acl_active: securitypolicy.acl_active,
-        content_filter_active: securitypolicy.content_filter_active,
-        limit_ids: securitypolicy.limits.into_iter().map(|l| l.id).collect(),
-        securitypolicy: hostmap_name,
-    };
-    Ok(raw_securitypolicy)
+/// removes every session, returning the number of removed sessions
+///
+/// The sessions that are being initialized concurrently are left alone, and survive the cleanup.
+pub fn clean_all_sessions() -> Result<usize, SessionError> {
+    let ids = initialized_sessions()?;
+    for uuid in &ids {
+        remove_session(*uuid);
+    }
+    Ok(ids.len())
 }
 
-pub fn session_tag_request(session_id: &str) -> anyhow::Result<bool> {
-    let uuid: Uuid = session_id.parse()?;
+/// removes the sessions of a tenant, returning the number of removed sessions, as `clean_all_sessions` does
+pub fn clean_tenant_sessions(tenant: &str) -> Result<usize, SessionError> {
+    let initialized = initialized_sessions()?;
+    let mut ids: Vec<Uuid> = Vec::new();
+    for shard in TENANTS.shards() {
+        let tenants = shard
+            .read()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TENANTS read lock {}", rr)))?;
+        ids.extend(
+            tenants
+                .iter()
+                .filter(|(uuid, t)| t.as_str() == tenant && initialized.contains(uuid))
+                .map(|(uuid, _)| *uuid),
+        );
+    }
+    for uuid in &ids {
+        remove_session(*uuid);
+    }
+    Ok(ids.len())
+}
 
-    // TODO: humanity is assumed
-    let new_tags = with_config(|cfg| with_request_info(uuid, |rinfo| Ok(tag_request(true, &cfg, &rinfo))))?;
-    with_tags_mut(uuid, |tgs| {
-        // TODO: the decision is ignored, but this is going to be deprecated
-        tgs.extend(new_tags.0);
-        Ok(())
-    })?;
-    Ok(true)
+/// what happens to new sessions once `max_sessions` is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// the new sessions are refused with `SessionError::Capacity`
+    #[default]
+    Reject,
+    /// the oldest sessions are removed to make room for the new ones
+    EvictOldest,
 }
 
-pub fn session_limit_check(session_id: &str) -> anyhow::Result<Decision> {
-    let uuid: Uuid = session_id.parse()?;
+/// the limit on the number of concurrent sessions, and what it caused so far
+#[derive(Debug, Default)]
+struct SessionCapacity {
+    max_sessions: Option<usize>,
+    eviction: EvictionPolicy,
+    rejected: u64,
+    evicted: u64,
+}
 
-    // copy limits, without keeping a read lock
-    let limits = with_securitypolicy(uuid, |securitypolicy| Ok(securitypolicy.limits.clone()))?;
-    let mut logs = Logs::default();
+impl SessionCapacity {
+    /// the number of sessions that must be evicted before `count` sessions are added to `live` ones
+    fn excess(&self, live: usize, count: usize) -> Result<usize, SessionError> {
+        let max = match self.max_sessions {
+            None => return Ok(0),
+            Some(max) => max,
+        };
+        let excess = (live + count).saturating_sub(max);
+        if excess > 0 && (self.eviction == EvictionPolicy::Reject || count > max) {
+            return Err(SessionError::Capacity(max));
+        }
+        Ok(excess)
+    }
+}
 
-    let sdecision = with_request_info(uuid, |rinfo| {
-        with_securitypolicy(uuid, |securitypolicy| {
-            with_tags_mut(uuid, |mut tags| {
-                Ok(limit_check(&mut logs, &securitypolicy.name, &rinfo, &limits, &mut tags))
-            })
-        })
-    });
-    Ok(sdecision?.into_decision_no_challenge())
+/// sets the maximum number of concurrent sessions, `None` (the default) removing the limit
+///
+/// Sessions that are already live are kept, even when they exceed the new limit.
+pub fn set_max_sessions(max_sessions: Option<usize>, eviction: EvictionPolicy) -> Result<(), SessionError> {
+    let mut capacity = CAPACITY
+        .lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get CAPACITY lock {}", rr)))?;
+    capacity.max_sessions = max_sessions;
+    capacity.eviction = eviction;
+    LIMITED.store(max_sessions.is_some(), Ordering::SeqCst);
+    Ok(())
 }
 
-pub fn session_acl_check(session_id: &str) -> anyhow::Result<AclResult> {
-    let uuid: Uuid = session_id.parse()?;
+/// the session limit, with the counters of the sessions it affected since the process started
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionCapacityStats {
+    pub max_sessions: Option<usize>,
+    pub eviction: EvictionPolicy,
+    /// the number of live sessions
+    pub sessions: usize,
+    /// the number of sessions that were refused
+    pub rejected: u64,
+    /// the number of sessions that were removed to make room for new ones
+    pub evicted: u64,
+}
 
-    with_securitypolicy(uuid, |securitypolicy| {
-        with_tags(uuid, |tags| Ok(check_acl(tags, &securitypolicy.acl_profile)))
+pub fn session_capacity_stats() -> Result<SessionCapacityStats, SessionError> {
+    let capacity = CAPACITY
+        .lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get CAPACITY lock {}", rr)))?;
+    Ok(SessionCapacityStats {
+        max_sessions: capacity.max_sessions,
+        eviction: capacity.eviction,
+        sessions: live_session_count(),
+        rejected: capacity.rejected,
+        evicted: capacity.evicted,
     })
 }
 
-pub fn session_content_filter_check(session_id: &str) -> anyhow::Result<Decision> {
-    let uuid: Uuid = session_id.parse()?;
+/// the number of sessions, every session having its creation time in TIMES
+fn live_session_count() -> usize {
+    LIVE_SESSIONS.load(Ordering::SeqCst)
+}
 
-    let hsdb = HSDB.read().map_err(|rr| anyhow::anyhow!("{}", rr))?;
+/// records sessions that were inserted in TIMES
+fn track_sessions(uuids: &[Uuid], created: Instant) -> Result<(), SessionError> {
+    LIVE_SESSIONS.fetch_add(uuids.len(), Ordering::SeqCst);
+    AGES.lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get AGES lock {}", rr)))?
+        .extend(uuids.iter().map(|uuid| (created, *uuid)));
+    Ok(())
+}
 
-    with_request_info(uuid, |rinfo| {
-        with_securitypolicy(uuid, |securitypolicy| {
-            Ok(match content_filter_check(rinfo, &securitypolicy.content_filter_profile, hsdb) {
-                Ok(()) => Decision::Pass,
-                Err(rr) => Decision::Action(rr.to_action()),
-            })
-        })
-    })
+/// forgets a session that was removed from TIMES
+fn untrack_session(uuid: Uuid, created: Instant) {
+    LIVE_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    if let Ok(mut ages) = AGES.lock() {
+        ages.remove(&(created, uuid));
+    }
 }
 
-pub fn session_flow_check(session_id: &str) -> anyhow::Result<Decision> {
-    let uuid: Uuid = session_id.parse()?;
-    let mut logs = Logs::default();
+/// the ids of the `count` oldest sessions
+fn oldest_sessions(count: usize) -> Result<Vec<Uuid>, SessionError> {
+    let ages = AGES
+        .lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get AGES lock {}", rr)))?;
+    Ok(ages.iter().take(count).map(|(_, uuid)| *uuid).collect())
+}
 
-    let sdecision = with_config(|cfg| {
-        with_request_info(uuid, |rinfo| {
-            with_tags_mut(uuid, |tags| flow_check(&mut logs, &cfg.flows, rinfo, tags))
-        })
-    });
-    Ok(sdecision?.into_decision_no_challenge())
+/// makes room for `count` new sessions, evicting the oldest ones when needed
+///
+/// When there is a limit, the returned guard must be held while the sessions are inserted, so that concurrent
+/// initializations can't exceed it.
+fn reserve_sessions(count: usize) -> Result<Option<MutexGuard<'static, SessionCapacity>>, SessionError> {
+    if !LIMITED.load(Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let mut capacity = CAPACITY
+        .lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get CAPACITY lock {}", rr)))?;
+    if capacity.max_sessions.is_none() {
+        return Ok(None);
+    }
+    // the expired sessions are removed before live ones are evicted or rejected
+    if capacity.excess(live_session_count(), count).map_or(true, |excess| excess > 0) {
+        session_gc()?;
+    }
+    let excess = match capacity.excess(live_session_count(), count) {
+        Ok(excess) => excess,
+        Err(rr) => {
+            capacity.rejected += count as u64;
+            return Err(rr);
+        }
+    };
+    if excess > 0 {
+        let evicted = oldest_sessions(excess)?;
+        for uuid in &evicted {
+            remove_session(*uuid);
+        }
+        capacity.evicted += evicted.len() as u64;
+    }
+    Ok(Some(capacity))
 }
-// HELPERS
 
-fn with_config<F, A>(f: F) -> anyhow::Result<A>
-where
-    F: FnOnce(&Config) -> anyhow::Result<A>,
-{
-    match CONFIG.read() {
-        Ok(cfg) => f(&cfg),
-        Err(rr) => Err(anyhow::anyhow!("Could not get configuration read lock {}", rr)),
+/// adds the session ids held by a map to `out`
+fn session_ids<V>(name: &str, map: &ShardedMap<V>, out: &mut HashSet<Uuid>) -> Result<(), SessionError> {
+    for shard in map.shards() {
+        let entries = shard
+            .read()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get {} read lock {}", name, rr)))?;
+        out.extend(entries.keys());
     }
+    Ok(())
 }
 
-fn with_request_info<F, A>(uuid: Uuid, f: F) -> anyhow::Result<A>
-where
-    F: FnOnce(&RequestInfo) -> anyhow::Result<A>,
-{
-    let infos = RINFOS
-        .read()
-        .map_err(|rr| anyhow::anyhow!("Could not get RINFOS read lock {}", rr))?;
-    let rinfo = infos.get(&uuid).ok_or_else(|| anyhow::anyhow!("Unknown session id"))?;
-    f(rinfo)
+/// the ids of the sessions that are held by any of the session maps, sorted
+///
+/// A session that was only partially removed is still listed, so that leaks can be spotted.
+pub fn active_sessions() -> Result<Vec<String>, SessionError> {
+    let mut ids = HashSet::new();
+    session_ids("RAW", &RAW, &mut ids)?;
+    session_ids("RINFOS", &RINFOS, &mut ids)?;
+    session_ids("TAGS", &TAGS, &mut ids)?;
+    session_ids("SECURITYPOLICY", &SECURITYPOLICY, &mut ids)?;
+    session_ids("BASELINES", &BASELINES, &mut ids)?;
+    session_ids("LOGS", &LOGS, &mut ids)?;
+    session_ids("TIMES", &TIMES, &mut ids)?;
+    session_ids("TIMINGS", &TIMINGS, &mut ids)?;
+    session_ids("REASONS", &REASONS, &mut ids)?;
+    session_ids("DECISIONS", &DECISIONS, &mut ids)?;
+    session_ids("RESPONSES", &RESPONSES, &mut ids)?;
+    session_ids("TENANTS", &TENANTS, &mut ids)?;
+    session_ids("BYPASSED", &BYPASSED, &mut ids)?;
+    session_ids("SLOTS", &SLOTS, &mut ids)?;
+    ids.extend(
+        STREAMS
+            .lock()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get STREAMS lock {}", rr)))?
+            .keys(),
+    );
+    let mut out: Vec<String> = ids.into_iter().map(|uuid| uuid.to_string()).collect();
+    out.sort();
+    Ok(out)
 }
 
-fn with_securitypolicy<F, A>(uuid: Uuid, f: F) -> anyhow::Result<A>
-where
-    F: FnOnce(&SecurityPolicy) -> anyhow::Result<A>,
-{
-    let maps = SECURITYPOLICY
-        .read()
-        .map_err(|rr| anyhow::anyhow!("Could not get SECURITYPOLICY read lock {}", rr))?;
-    let umap = maps.get(&uuid).ok_or_else(|| anyhow::anyhow!("Unknown session id"))?;
-    f(umap)
+/// the number of sessions returned by `active_sessions`
+pub fn active_session_count() -> Result<usize, SessionError> {
+    active_sessions().map(|ids| ids.len())
 }
 
-fn with_tags<F, A>(uuid: Uuid, f: F) -> anyhow::Result<A>
-where
-    F: FnOnce(&Tags) -> anyhow::Result<A>,
-{
-    let tags = TAGS
-        .read()
-        .map_err(|rr| anyhow::anyhow!("Could not get TAGS read lock {}", rr))?;
-    let tag = tags.get(&uuid).ok_or_else(|| anyhow::anyhow!("Unknown session id"))?;
-    f(tag)
+/// how long ago the oldest live session was created, `None` when there is no session
+pub fn oldest_session_age() -> Result<Option<Duration>, SessionError> {
+    let now = Instant::now();
+    let mut oldest: Option<Instant> = None;
+    for shard in TIMES.shards() {
+        let times = shard
+            .read()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES read lock {}", rr)))?;
+        for t in times.values() {
+            oldest = Some(oldest.map_or(t.created, |o| o.min(t.created)));
+        }
+    }
+    Ok(oldest.map(|created| now.saturating_duration_since(created)))
 }
 
-fn with_tags_mut<F, A>(uuid: Uuid, f: F) -> anyhow::Result<A>
-where
-    F: FnOnce(&mut Tags) -> anyhow::Result<A>,
-{
-    let mut tags = TAGS
-        .write()
-        .map_err(|rr| anyhow::anyhow!("Could not get TAGS read lock {}", rr))?;
-    let tag = tags
-        .get_mut(&uuid)
-        .ok_or_else(|| anyhow::anyhow!("Unknown session id"))?;
-    f(tag)
+/// replaces the placeholders of the response body of a decision, with the session id and tags
+pub fn session_interpolate_decision(session_id: &str, decision: Decision) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let mut decision = decision;
+    with_tags(uuid, |tags| {
+        decision.interpolate(session_id, tags);
+        Ok(())
+    })?;
+    Ok(decision)
+}
+
+pub fn session_serialize_request_map(session_id: &str) -> Result<serde_json::Value, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    // get raw request first
+    let raw: serde_json::Value = match RAW.read(&uuid) {
+        Ok(raws) => match raws.get(&uuid) {
+            Some(v) => v.clone(),
+            None => return Err(SessionError::UnknownSession),
+        },
+        Err(rr) => {
+            return Err(SessionError::LockPoisoned(format!(
+                "Could not get read lock on RAW {}",
+                rr
+            )))
+        }
+    };
+
+    // get the tags
+    let tags = with_tags(uuid, |tgs| Ok(tgs.clone()))?;
+
+    let mut out = update_tags(raw, tags)?;
+    let reason = REASONS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on REASONS {}", rr)))?
+        .get(&uuid)
+        .cloned();
+    if let (Some(reason), Some(obj)) = (reason, out.as_object_mut()) {
+        obj.insert("decision_reason".to_string(), serde_json::to_value(reason)?);
+    }
+    Ok(out)
+}
+
+/// stores the reason of an action, so that it is part of the serialized request map
+///
+/// The action also becomes the running decision of the session, unless it already has one. Blocking actions
+/// replace the other actions.
+fn record_decision(uuid: Uuid, decision: Decision) -> Result<Decision, SessionError> {
+    if let Some(reason) = decision.decision_reason() {
+        let mut wreasons = REASONS
+            .write(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get REASONS write lock {}", rr)))?;
+        wreasons.insert(uuid, reason.clone());
+    }
+    if decision.action().is_some() {
+        let mut wdecisions = DECISIONS
+            .write(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get DECISIONS write lock {}", rr)))?;
+        let replace = match wdecisions.get(&uuid) {
+            None => true,
+            Some(current) => decision.is_blocking() && !current.is_blocking(),
+        };
+        if replace {
+            wdecisions.insert(uuid, decision.clone());
+        }
+    }
+    Ok(decision)
+}
+
+/// the decision of a check that is not run, because the session is bypassed or exceeded its evaluation budget
+fn skipped_decision(uuid: Uuid) -> Result<Option<Decision>, SessionError> {
+    if is_bypassed(uuid)? {
+        return Ok(Some(Decision::Pass));
+    }
+    budget_decision(uuid)
+}
+
+/// the decision of a check once the session exceeded the `eval_budget` setting, None when it did not
+///
+/// The check is then skipped, and the request is tagged with `eval-budget-exceeded`. The duration is measured from
+/// the creation of the session.
+fn budget_decision(uuid: Uuid) -> Result<Option<Decision>, SessionError> {
+    let budget = match with_config(uuid, |cfg| Ok(cfg.settings.eval_budget.clone()))? {
+        None => return Ok(None),
+        Some(budget) => budget,
+    };
+    let fields = with_request_info(uuid, |rinfo| Ok(rinfo.field_count()))?;
+    let elapsed = TIMES
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES read lock {}", rr)))?
+        .get(&uuid)
+        .map(|times| times.created.elapsed())
+        .unwrap_or_default();
+    let exceeded = match budget.exceeded(fields, elapsed) {
+        None => return Ok(None),
+        Some(exceeded) => exceeded,
+    };
+    if with_tags_mut(uuid, |tags| Ok(tags.insert("eval-budget-exceeded")))? {
+        let mut logs = Logs::default();
+        logs.warning(match exceeded {
+            BudgetExceeded::Fields(n) => format!("Evaluation budget exceeded, {} fields", n),
+            BudgetExceeded::Duration(d) => format!("Evaluation budget exceeded, after {}ms", d.as_millis()),
+        });
+        append_logs(uuid, Stage::Evaluate, logs)?;
+    }
+    let decision = if budget.block {
+        Decision::Action(budget_action(exceeded))
+    } else {
+        Decision::Pass
+    };
+    record_decision(uuid, decision).map(Some)
+}
+
+fn budget_action(exceeded: BudgetExceeded) -> Action {
+    let (limit, reason) = match exceeded {
+        BudgetExceeded::Fields(n) => ("fields", serde_json::json!({"initiator": "eval_budget", "fields": n})),
+        BudgetExceeded::Duration(d) => (
+            "duration",
+            serde_json::json!({"initiator": "eval_budget", "duration_ms": d.as_millis() as u64}),
+        ),
+    };
+    Action {
+        reason,
+        extra_tags: Some(std::iter::once("eval-budget-exceeded".to_string()).collect()),
+        decision_reason: DecisionReason::EvalBudget {
+            limit: limit.to_string(),
+        },
+        ..Action::default()
+    }
+}
+
+/// the running decision of the session, made of the actions returned by the checks so far, see `record_decision`
+pub fn session_current_decision(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    with_request_info(uuid, |_| Ok(()))?;
+    let decisions = DECISIONS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get DECISIONS read lock {}", rr)))?;
+    Ok(decisions.get(&uuid).cloned().unwrap_or(Decision::Pass))
+}
+
+fn describe_decision(decision: &Decision) -> String {
+    match decision {
+        Decision::Pass => "pass".to_string(),
+        Decision::Action(a) => format!("{:?} action, reason {:?}", a.atype, a.decision_reason),
+        Decision::Ratelimit(a, rl) => format!("{:?} action, rate limit {}", a.atype, rl.limit_id),
+    }
+}
+
+/// replaces the running decision of the session, returning the previous one
+///
+/// The tags are kept. The reason of the new decision replaces the stored reason, which is removed when the new
+/// decision is `Pass`. The override is logged, with the `override` stage.
+pub fn session_set_decision(session_id: &str, decision: Decision) -> Result<Decision, SessionError> {
+    let previous = session_current_decision(session_id)?;
+    let uuid: Uuid = session_id.parse()?;
+    let mut wdecisions = DECISIONS
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get DECISIONS write lock {}", rr)))?;
+    let mut wreasons = REASONS
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get REASONS write lock {}", rr)))?;
+    let mut logs = Logs::default();
+    logs.info(format!(
+        "decision overridden by the caller, from {} to {}",
+        describe_decision(&previous),
+        describe_decision(&decision)
+    ));
+    match decision {
+        Decision::Pass => {
+            wdecisions.remove(&uuid);
+            wreasons.remove(&uuid);
+        }
+        Decision::Action(ref action) | Decision::Ratelimit(ref action, _) => {
+            wreasons.insert(uuid, action.decision_reason.clone());
+            wdecisions.insert(uuid, decision);
+        }
+    }
+    drop(wreasons);
+    drop(wdecisions);
+    append_logs(uuid, Stage::Override, logs)?;
+    Ok(previous)
+}
+
+/// update the tags in the JSON-encoded request_map
+pub fn update_tags(rawjson: serde_json::Value, tags: Tags) -> Result<serde_json::Value, SessionError> {
+    let mut raw = rawjson;
+    // sorted, so that the serialized request map does not depend on the hash map order
+    let tags_map: BTreeMap<String, serde_json::Value> = tags
+        .iter_values()
+        .map(|(k, v)| {
+            (
+                k.clone(),
+                v.map_or_else(|| serde_json::json!(1), |v| serde_json::json!(v)),
+            )
+        })
+        .collect();
+
+    // update the tags
+    let attrs = raw
+        .get_mut("attrs")
+        .ok_or(SessionError::InvalidRequestMap("No attrs field"))?;
+    let attrs_o = attrs
+        .as_object_mut()
+        .ok_or(SessionError::InvalidRequestMap("Attrs was not an object"))?;
+    attrs_o.insert("tags".to_string(), serde_json::to_value(tags_map)?);
+
+    Ok(raw)
+}
+
+/// initializes a session from a json-encoded request map
+///
+/// The session is removed by calling `clean_session` or `clean_all_sessions`, or evicted to make room for a new one
+/// when `set_max_sessions` was called with `EvictionPolicy::EvictOldest`. It has no TTL, so that `session_gc` does
+/// not remove it, see `session_init_with_ttl`. When the request map has a `tenant` field, the session uses the
+/// configuration of this tenant, and `UnknownTenant` is returned when it was never loaded. `Capacity` is returned
+/// when the session would exceed the limit set with `set_max_sessions`, with `EvictionPolicy::Reject`.
+pub fn session_init(encoded_request_map: &str) -> Result<String, SessionError> {
+    init_session(encoded_request_map, None)
+}
+
+/// initializes a session from a json-encoded request map, that is automatically removed after `ttl`
+pub fn session_init_with_ttl(encoded_request_map: &str, ttl: Duration) -> Result<String, SessionError> {
+    init_session(encoded_request_map, Some(ttl))
+}
+
+fn init_session(encoded_request_map: &str, ttl: Option<Duration>) -> Result<String, SessionError> {
+    // lazily sweep expired sessions, before any write lock is taken
+    amortized_gc()?;
+
+    let decoded = decode_request_map(encoded_request_map)?;
+    let mut uuids = insert_sessions(vec![decoded], ttl)?;
+    uuids.pop().ok_or(SessionError::UnknownSession)
+}
+
+/// initializes sessions from a list of json-encoded request maps, taking the write locks of the session maps only once
+///
+/// All the request maps are decoded before the sessions are inserted, each shard being locked once for the whole batch.
+///
+/// The returned vector has one entry per request map, in the same order. When `allow_partial` is not set,
+/// a request map that can't be decoded aborts the whole batch, and its index is reported in the error.
+/// Otherwise, the valid request maps are inserted, and decoding errors are reported in place.
+pub fn session_init_batch(
+    maps: &[&str],
+    allow_partial: bool,
+) -> Result<Vec<Result<String, SessionError>>, SessionError> {
+    amortized_gc()?;
+
+    let mut decoded = Vec::new();
+    let mut failures = Vec::new();
+    for (index, encoded_request_map) in maps.iter().enumerate() {
+        match decode_request_map(encoded_request_map) {
+            Ok(d) => decoded.push(d),
+            Err(rr) if allow_partial => failures.push((index, rr)),
+            Err(rr) => return Err(SessionError::BatchEntry(index, Box::new(rr))),
+        }
+    }
+
+    // rebuild the results in the original order
+    let mut uuids = insert_sessions(decoded, None)?.into_iter();
+    let mut failures = failures.into_iter().peekable();
+    let mut out = Vec::with_capacity(maps.len());
+    for index in 0..maps.len() {
+        match failures.peek() {
+            Some((findex, _)) if *findex == index => {
+                if let Some((_, rr)) = failures.next() {
+                    out.push(Err(rr));
+                }
+            }
+            _ => out.push(uuids.next().ok_or(SessionError::UnknownSession)),
+        }
+    }
+    Ok(out)
+}
+
+/// reference to the security policy of a snapshotted session, see `session_snapshot`
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotSecurityPolicy {
+    hostmap: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionSnapshot {
+    raw: serde_json::Value,
+    rinfo: RequestInfo,
+    /// tags, with their values
+    tags: BTreeMap<String, Option<String>>,
+    securitypolicy: Option<SnapshotSecurityPolicy>,
+    /// the baseline policies of the session, see `BASELINES`
+    #[serde(default)]
+    baselines: Vec<SnapshotSecurityPolicy>,
+    #[serde(default)]
+    tenant: Option<TenantId>,
+}
+
+/// serializes the state of a session: the request map, the request information, the tags, and the matched
+/// security policy and baseline policies, if any
+///
+/// The security policies are stored by reference, using the names of the host map and of its entry.
+pub fn session_snapshot(session_id: &str) -> Result<String, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let raw = RAW
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on RAW {}", rr)))?
+        .get(&uuid)
+        .cloned()
+        .ok_or(SessionError::UnknownSession)?;
+    let rinfo = with_request_info(uuid, |rinfo| Ok(rinfo.clone()))?;
+    let tags = with_tags(uuid, |tags| {
+        Ok(tags.iter_values().map(|(k, v)| (k.clone(), v.cloned())).collect())
+    })?;
+    let securitypolicy = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?
+        .get(&uuid)
+        .map(|(hostmap, securitypolicy)| SnapshotSecurityPolicy {
+            hostmap: hostmap.clone(),
+            name: securitypolicy.name.clone(),
+        });
+    let baselines = session_baselines(uuid)?
+        .iter()
+        .map(|(hostmap, baseline)| SnapshotSecurityPolicy {
+            hostmap: hostmap.clone(),
+            name: baseline.name.clone(),
+        })
+        .collect();
+    let snapshot = SessionSnapshot {
+        raw,
+        rinfo,
+        tags,
+        securitypolicy,
+        baselines,
+        tenant: session_tenant(uuid)?,
+    };
+    // going through a JSON value sorts the object keys, so that the blob is stable
+    Ok(serde_json::to_value(&snapshot)?.to_string())
+}
+
+/// creates a new session from a blob returned by `session_snapshot`, returning its id
+///
+/// The security policies are looked up in the current configuration of the session tenant, and `NoSecurityPolicy` is
+/// returned when one of them does not exist anymore. The request is not matched again.
+pub fn session_restore(blob: &str) -> Result<String, SessionError> {
+    let snapshot: SessionSnapshot = serde_json::from_str(blob)?;
+    check_tenant(snapshot.tenant.as_deref())?;
+    let (securitypolicy, baselines) = with_tenant(snapshot.tenant.as_deref(), |config, _| {
+        let cfg = read_config(config)?;
+        let find = |sp: &SnapshotSecurityPolicy| {
+            find_securitypolicy(&cfg, &sp.hostmap, &sp.name)
+                .map(|p| (sp.hostmap.clone(), p.clone()))
+                .ok_or(SessionError::NoSecurityPolicy)
+        };
+        let securitypolicy = snapshot.securitypolicy.as_ref().map(find).transpose()?;
+        let baselines = snapshot.baselines.iter().map(find).collect::<Result<Vec<_>, _>>()?;
+        Ok((securitypolicy, baselines))
+    })?;
+    let mut tags = Tags::default();
+    for (tag, value) in snapshot.tags.iter() {
+        match value {
+            None => tags.insert(tag),
+            Some(v) => tags.insert_with_value(tag, v),
+        };
+    }
+
+    amortized_gc()?;
+    let restored = DecodedSession {
+        raw: snapshot.raw,
+        rinfo: snapshot.rinfo,
+        tags,
+        tenant: snapshot.tenant,
+        logs: Logs::default(),
+    };
+    let mut uuids = insert_sessions(vec![restored], None)?;
+    let session_id = uuids.pop().ok_or(SessionError::UnknownSession)?;
+    if let Some((hostmap_name, sp)) = securitypolicy {
+        store_securitypolicy(session_id.parse()?, hostmap_name, sp, baselines)?;
+    }
+    Ok(session_id)
+}
+
+/// creates an independent copy of a session, returning its id
+///
+/// The request map, request information, tags, tenant, and matched security policy and baseline policies are copied,
+/// so that the copy can be
+/// checked, tagged or matched against another policy without changing the original session. The decisions, logs and
+/// timings of the original session are not copied, and the copy expires with the same time to live.
+pub fn session_clone(session_id: &str) -> Result<String, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let raw = RAW
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on RAW {}", rr)))?
+        .get(&uuid)
+        .cloned()
+        .ok_or(SessionError::UnknownSession)?;
+    let rinfo = with_request_info(uuid, |rinfo| Ok(rinfo.clone()))?;
+    let tags = with_tags(uuid, |tags| Ok(tags.clone()))?;
+    let securitypolicy = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?
+        .get(&uuid)
+        .cloned();
+    let baselines = session_baselines(uuid)?;
+    let ttl = TIMES
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES read lock {}", rr)))?
+        .get(&uuid)
+        .and_then(|times| times.ttl);
+    let cloned = DecodedSession {
+        raw,
+        rinfo,
+        tags,
+        tenant: session_tenant(uuid)?,
+        logs: Logs::default(),
+    };
+
+    amortized_gc()?;
+    let mut uuids = insert_sessions(vec![cloned], ttl)?;
+    let clone_id = uuids.pop().ok_or(SessionError::UnknownSession)?;
+    if let Some((hostmap_name, sp)) = securitypolicy {
+        store_securitypolicy(clone_id.parse()?, hostmap_name, sp, baselines)?;
+    }
+    Ok(clone_id)
+}
+
+/// parses the body, adding the resulting values to the arguments, with the `body:` prefix, and returning their JSON
+/// paths
+///
+/// when the body can't be parsed, it is stored in the `body:RAW_BODY` argument
+fn add_body_args(args: &mut RequestField, mcontent_type: Option<&str>, body: &[u8]) -> JsonPaths {
+    let mut body_args = RequestField::default();
+    // the body parsing logs are only useful for debugging
+    let mut logs = Logs::default();
+    let paths = match parse_body(&mut logs, &mut body_args, mcontent_type, body) {
+        Ok(paths) => paths,
+        Err(_) => {
+            body_args = RequestField::default();
+            body_args.add("RAW_BODY".to_string(), String::from_utf8_lossy(body).to_string());
+            JsonPaths::new()
+        }
+    };
+    for (k, v) in body_args.iter() {
+        args.add(format!("body:{}", k), v.clone());
+    }
+    paths.into_iter().map(|(k, v)| (format!("body:{}", k), v)).collect()
+}
+
+/// the values of the headers that appear several times in the ordered list are all added to the header map, so that
+/// the content filter inspects each of them
+fn merge_duplicate_headers(headers: &mut RequestField, list: &[(String, String)]) {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (name, _) in list {
+        *counts.entry(name.as_str()).or_default() += 1;
+    }
+    counts.retain(|name, count| *count > 1 && *name != "cookie");
+    for name in counts.keys() {
+        headers.0.remove(*name);
+        headers.0.remove(&format!("{}_base64", name));
+        for (_, value) in list.iter().filter(|(k, _)| k == name) {
+            headers.add(name.to_string(), value.clone());
+        }
+    }
+}
+
+/// the state of a new session
+struct DecodedSession {
+    raw: serde_json::Value,
+    rinfo: RequestInfo,
+    tags: Tags,
+    tenant: Option<TenantId>,
+    /// the logs of the decoding, kept with the session logs
+    logs: Logs,
+}
+
+fn decode_request_map(encoded_request_map: &str) -> Result<DecodedSession, SessionError> {
+    let jvalue: serde_json::Value = serde_json::from_str(encoded_request_map)?;
+    let jmap: JRequestMap = serde_json::from_value(jvalue.clone())?;
+    let tenant = jmap.tenant.clone();
+    check_tenant(tenant.as_deref())?;
+    let mut logs = Logs::default();
+    let (rinfo, tags) = jmap.into_request_info(&mut logs);
+    Ok(DecodedSession {
+        raw: jvalue,
+        rinfo,
+        tags,
+        tenant,
+        logs,
+    })
+}
+
+/// fails with `UnknownTenant` when the tenant configuration is not loaded
+fn check_tenant(tenant: Option<&str>) -> Result<(), SessionError> {
+    with_tenant(tenant, |_, _| Ok(()))
+}
+
+/// inserts decoded request maps in the session maps, returning the session ids
+fn insert_sessions(decoded: Vec<DecodedSession>, ttl: Option<Duration>) -> Result<Vec<String>, SessionError> {
+    let _capacity = reserve_sessions(decoded.len())?;
+    let times = SessionTimes {
+        created: Instant::now(),
+        ttl,
+    };
+    let mut sessions: Vec<(Uuid, DecodedSession)> = decoded.into_iter().map(|d| (Uuid::new_v4(), d)).collect();
+    let logs: Vec<(Uuid, Logs)> = sessions
+        .iter_mut()
+        .map(|(uuid, d)| (*uuid, std::mem::take(&mut d.logs)))
+        .collect();
+    insert_session_batch(sessions, times)?;
+    let mut uuids = Vec::with_capacity(logs.len());
+    for (uuid, logs) in logs {
+        append_logs(uuid, Stage::Init, logs)?;
+        check_trusted(uuid)?;
+        uuids.push(uuid);
+    }
+    Ok(uuids.iter().map(|uuid| format!("{}", uuid)).collect())
+}
+
+/// marks the sessions of the trusted sources of the settings as bypassed, so that their checks are skipped
+///
+/// They are also tagged `trusted-bypass`, a tag that the request map can't set.
+fn check_trusted(uuid: Uuid) -> Result<(), SessionError> {
+    let source = with_config(uuid, |cfg| match &cfg.settings.trusted {
+        None => Ok(None),
+        Some(trusted) => with_request_info(uuid, |rinfo| with_tags(uuid, |tags| Ok(trusted.matching(rinfo, tags)))),
+    })?;
+    let mut logs = Logs::default();
+    match source {
+        Some(source) => {
+            with_tags_mut(uuid, |tags| Ok(tags.insert(BYPASS_TAG)))?;
+            logs.info(format!("trusted source, {}, the checks are skipped", source));
+            BYPASSED
+                .write(&uuid)
+                .map_err(|rr| SessionError::LockPoisoned(format!("Could not get BYPASSED write lock {}", rr)))?
+                .insert(uuid, source.to_string());
+        }
+        None => {
+            if with_tags_mut(uuid, |tags| Ok(tags.remove(BYPASS_TAG)))? {
+                logs.warning(format!("the {} tag of the request map is ignored", BYPASS_TAG));
+            }
+        }
+    }
+    append_logs(uuid, Stage::Tagging, logs)
+}
+
+/// true when the session comes from a trusted source, in which case the checks always pass, and do not have to be
+/// called at all
+pub fn session_is_bypassed(session_id: &str) -> Result<bool, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    is_bypassed(uuid)
+}
+
+fn is_bypassed(uuid: Uuid) -> Result<bool, SessionError> {
+    let bypassed = BYPASSED
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get BYPASSED read lock {}", rr)))?;
+    Ok(bypassed.contains_key(&uuid))
+}
+
+/// inserts sessions, taking the write lock of each shard of each map only once for the whole batch
+fn insert_session_batch(sessions: Vec<(Uuid, DecodedSession)>, times: SessionTimes) -> Result<(), SessionError> {
+    let mut raws = Vec::with_capacity(sessions.len());
+    let mut rinfos = Vec::with_capacity(sessions.len());
+    let mut tags = Vec::with_capacity(sessions.len());
+    let mut tenants = Vec::new();
+    let mut stimes = Vec::with_capacity(sessions.len());
+    let mut timings = Vec::with_capacity(sessions.len());
+    let mut uuids = Vec::with_capacity(sessions.len());
+    for (uuid, session) in sessions {
+        raws.push((uuid, session.raw));
+        rinfos.push((uuid, session.rinfo));
+        tags.push((uuid, session.tags));
+        if let Some(tenant) = session.tenant {
+            tenants.push((uuid, tenant));
+        }
+        stimes.push((uuid, times));
+        uuids.push(uuid);
+        timings.push((uuid, SessionTimings::default()));
+    }
+    RAW.insert_batch(raws)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RAW write lock {}", rr)))?;
+    RINFOS
+        .insert_batch(rinfos)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RINFOS write lock {}", rr)))?;
+    TAGS.insert_batch(tags)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS write lock {}", rr)))?;
+    TENANTS
+        .insert_batch(tenants)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TENANTS write lock {}", rr)))?;
+    TIMES
+        .insert_batch(stimes)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES write lock {}", rr)))?;
+    track_sessions(&uuids, times.created)?;
+    // TIMINGS is written last, see `initialized_sessions`
+    TIMINGS
+        .insert_batch(timings)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMINGS write lock {}", rr)))?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SessionSecurityPolicy {
+    pub name: String,
+    pub acl_profile: String,
+    pub content_filter_profile: String,
+    pub acl_active: bool,
+    pub content_filter_active: bool,
+    pub limit_ids: Vec<String>,
+    pub securitypolicy: String,
+}
+
+impl SessionSecurityPolicy {
+    fn new(hostmap_name: String, securitypolicy: &SecurityPolicy) -> Self {
+        SessionSecurityPolicy {
+            name: securitypolicy.name.clone(),
+            acl_profile: securitypolicy.acl_profile.id.clone(),
+            content_filter_profile: securitypolicy.content_filter_profile.id.clone(),
+            acl_active: securitypolicy.acl_active,
+            content_filter_active: securitypolicy.content_filter_active,
+            limit_ids: securitypolicy.limits.iter().map(|l| l.id.clone()).collect(),
+            securitypolicy: hostmap_name,
+        }
+    }
+}
+
+/// returns a RawSecurityPolicy object (minus the match field), and updates the internal structure for the security policy
+pub fn session_match_securitypolicy(session_id: &str) -> Result<SessionSecurityPolicy, SessionError> {
+    let mut logs = Logs::default();
+    let uuid: Uuid = session_id.parse()?;
+    let out = match_securitypolicy_uuid(&mut logs, uuid, None);
+    append_logs(uuid, Stage::SecurityPolicy, logs)?;
+    out
+}
+
+/// the matched security policy, along with the host map and host map entries that were considered, in order
+#[derive(Debug, Serialize, Clone)]
+pub struct SessionSecurityPolicyTrace {
+    pub securitypolicy: SessionSecurityPolicy,
+    pub trace: Vec<PolicyMatchStep>,
+}
+
+/// same as `session_match_securitypolicy`, but also returns the security policy entries that were evaluated
+pub fn session_match_securitypolicy_verbose(session_id: &str) -> Result<SessionSecurityPolicyTrace, SessionError> {
+    let mut logs = Logs::default();
+    let uuid: Uuid = session_id.parse()?;
+    let mut trace = Vec::new();
+    let securitypolicy = match_securitypolicy_uuid(&mut logs, uuid, Some(&mut trace));
+    append_logs(uuid, Stage::SecurityPolicy, logs)?;
+    Ok(SessionSecurityPolicyTrace {
+        securitypolicy: securitypolicy?,
+        trace,
+    })
+}
+
+fn match_securitypolicy_uuid(
+    logs: &mut Logs,
+    uuid: Uuid,
+    trace: Option<&mut Vec<PolicyMatchStep>>,
+) -> Result<SessionSecurityPolicy, SessionError> {
+    let (hostmap_name, securitypolicy, baselines) = with_config(uuid, |cfg| {
+        with_request_info(uuid, |rinfo| {
+            with_tags_mut(uuid, |tags| match securitypolicy_stage(logs, cfg, rinfo, tags, trace) {
+                Some((hn, securitypolicy)) => {
+                    let baselines = baseline_policies(cfg, &hn, &securitypolicy.name);
+                    Ok((hn, securitypolicy.clone(), baselines))
+                }
+                None => Err(SessionError::NoSecurityPolicy),
+            })
+        })
+    })?;
+    let raw_securitypolicy = SessionSecurityPolicy::new(hostmap_name.clone(), &securitypolicy);
+    store_securitypolicy(uuid, hostmap_name, securitypolicy, baselines)?;
+    Ok(raw_securitypolicy)
+}
+
+/// the baseline policies of the configuration, without the matched policy
+fn baseline_policies(cfg: &Config, hostmap_name: &str, name: &str) -> Vec<(String, SecurityPolicy)> {
+    cfg.baseline_policies
+        .iter()
+        .filter(|(bhn, baseline)| !(bhn == hostmap_name && baseline.name == name))
+        .cloned()
+        .collect()
+}
+
+fn session_baselines(uuid: Uuid) -> Result<Vec<(String, SecurityPolicy)>, SessionError> {
+    let baselines = BASELINES
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get BASELINES read lock {}", rr)))?;
+    Ok(baselines.get(&uuid).cloned().unwrap_or_default())
+}
+
+/// runs `f` on the baseline policies of the session, then on its security policy, returning the policy names along
+/// with the results
+fn for_each_policy<F, A>(uuid: Uuid, mut f: F) -> Result<Vec<(String, A)>, SessionError>
+where
+    F: FnMut(&SecurityPolicy) -> Result<A, SessionError>,
+{
+    let mut results = Vec::new();
+    {
+        let baselines = BASELINES
+            .read(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get BASELINES read lock {}", rr)))?;
+        for (_, baseline) in baselines.get(&uuid).into_iter().flatten() {
+            results.push((baseline.name.clone(), f(baseline)?));
+        }
+    }
+    results.push(with_securitypolicy(uuid, |securitypolicy| {
+        Ok((securitypolicy.name.clone(), f(securitypolicy)?))
+    })?);
+    Ok(results)
+}
+
+/// keeps the most restrictive result, according to `rank`, the later policies winning ties
+///
+/// When several policies were evaluated and the result is not the least restrictive, the request is tagged with
+/// `<qualifier>:<policy name>`, and the policy is logged.
+fn most_restrictive<A, R>(
+    logs: &mut Logs,
+    uuid: Uuid,
+    qualifier: &str,
+    results: Vec<(String, A)>,
+    rank: R,
+) -> Result<A, SessionError>
+where
+    R: Fn(&A) -> u8,
+{
+    let several = results.len() > 1;
+    let mut best: Option<(String, A)> = None;
+    for (name, result) in results {
+        if best.as_ref().map(|(_, b)| rank(&result) >= rank(b)).unwrap_or(true) {
+            best = Some((name, result));
+        }
+    }
+    let (name, result) = best.ok_or(SessionError::NoSecurityPolicy)?;
+    if several && rank(&result) > 0 {
+        logs.info(format!("{} decision taken by security policy {}", qualifier, name));
+        with_tags_mut(uuid, |tags| {
+            tags.insert_qualified(qualifier, &name);
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+/// Pass, then monitor, then the other final actions, then blocks
+fn decision_rank(decision: &Decision) -> u8 {
+    match decision {
+        Decision::Pass => 0,
+        Decision::Action(a) | Decision::Ratelimit(a, _) if a.atype.is_blocking() => 3,
+        Decision::Action(a) | Decision::Ratelimit(a, _) if a.atype.is_final() => 2,
+        Decision::Action(_) | Decision::Ratelimit(_, _) => 1,
+    }
+}
+
+/// passthrough, then allowed, then bot deny, then human deny, then force deny
+fn acl_rank(result: &AclResult) -> u8 {
+    match result {
+        AclResult::Passthrough(dec) if dec.allowed => 0,
+        AclResult::Passthrough(_) => 4,
+        AclResult::Match(bh) => {
+            let denied = |d: &Option<AclDecision>| d.as_ref().map(|d| !d.allowed).unwrap_or(false);
+            if denied(&bh.human) {
+                3
+            } else if denied(&bh.bot) {
+                2
+            } else {
+                1
+            }
+        }
+    }
+}
+
+/// stores the matched security policy, replacing the baseline policies of any earlier match
+fn store_securitypolicy(
+    uuid: Uuid,
+    hostmap_name: String,
+    securitypolicy: SecurityPolicy,
+    baselines: Vec<(String, SecurityPolicy)>,
+) -> Result<(), SessionError> {
+    SECURITYPOLICY
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY write lock {}", rr)))?
+        .insert(uuid, (hostmap_name, securitypolicy));
+    let mut wbaselines = BASELINES
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get BASELINES write lock {}", rr)))?;
+    if baselines.is_empty() {
+        wbaselines.remove(&uuid);
+    } else {
+        wbaselines.insert(uuid, baselines);
+    }
+    Ok(())
+}
+
+/// tags the request, the client being assumed to be human
+pub fn session_tag_request(session_id: &str) -> Result<bool, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    // TODO: the decision is ignored, but this is going to be deprecated
+    tag_request_uuid(uuid, None)?;
+    Ok(true)
+}
+
+/// same as `session_tag_request`, with the verdict of an external bot detection, that is added as a `human` or
+/// `bot` tag
+///
+/// global filter challenges are only skipped for humans
+pub fn session_tag_request_with_humanity(session_id: &str, is_human: bool) -> Result<bool, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    tag_request_uuid(uuid, Some(is_human))?;
+    Ok(true)
+}
+
+/// tags the request, and returns the global filter decision
+///
+/// the client is assumed to be human, without a `human` tag, when its humanity is not known
+///
+/// when the security policy was already matched, the `time:` tags are those of its timezone
+fn tag_request_uuid(uuid: Uuid, humanity: Option<bool>) -> Result<SimpleDecision, SessionError> {
+    if is_bypassed(uuid)? {
+        return Ok(SimpleDecision::Pass);
+    }
+    let timezone = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?
+        .get(&uuid)
+        .and_then(|(_, securitypolicy)| securitypolicy.timezone.clone());
+    timed(uuid, Stage::Tagging, || {
+        with_config(uuid, |cfg| {
+            with_request_info(uuid, |rinfo| {
+                with_tags_mut(uuid, |tags| {
+                    let decision = tag_stage(cfg, rinfo, tags, humanity);
+                    if timezone.is_some() {
+                        tag_time(cfg, timezone.as_ref(), SystemTime::now(), tags);
+                    }
+                    Ok(decision)
+                })
+            })
+        })
+    })
+}
+
+/// adds caller-supplied tags to the session, so that they are taken into account by the following checks
+/// all tags are validated before any of them is added
+pub fn session_add_tags(session_id: &str, new_tags: &[&str]) -> Result<(), SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    if let Some(bad) = new_tags.iter().find(|t| !Tags::is_valid(t)) {
+        return Err(SessionError::InvalidTag(bad.to_string()));
+    }
+    with_tags_mut(uuid, |tags| {
+        for tag in new_tags {
+            tags.insert(tag);
+        }
+        Ok(())
+    })
+}
+
+/// adds `weight` to the reputation score of the client address of the session, such as after a failed login, and
+/// returns the new score
+///
+/// The following requests of the address are tagged with the `reputation:` level of the score, see the `reputation`
+/// setting. None is returned when the session has no valid client address.
+pub fn session_report_bad(session_id: &str, weight: f64) -> Result<Option<f64>, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let ip = with_request_info(uuid, |rinfo| Ok(rinfo.rinfo.geoip.ip))?;
+    let half_life = with_config(uuid, |cfg| Ok(cfg.settings.reputation.half_life))?;
+    Ok(ip.map(|ip| report_bad(ip, weight, half_life, Instant::now())))
+}
+
+pub fn session_limit_check(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.limit", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let mut logs = Logs::default();
+        let decision = limit_check_uuid(&mut logs, uuid);
+        append_logs(uuid, Stage::Limit, logs)?;
+        record_decision(uuid, decision?.into_decision_no_challenge())
+    })
+}
+
+/// same as `session_limit_check`, but challenge actions are turned into challenge pages, see `challenge_decision`
+pub fn session_limit_check_with_challenge<GH: Grasshopper>(
+    session_id: &str,
+    mgh: Option<GH>,
+) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.limit", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let mut logs = Logs::default();
+        let decision = limit_check_uuid(&mut logs, uuid).and_then(|d| challenge_decision(&mut logs, uuid, d, mgh));
+        append_logs(uuid, Stage::Limit, logs)?;
+        record_decision(uuid, decision?)
+    })
+}
+
+/// converts the decision, issuing a challenge when the action is a challenge and the client is not known to be human
+///
+/// The client is human when its `rbzid` cookie is verified by the grasshopper, in which case the request is only
+/// monitored. Without grasshopper or user agent, the challenge can't be rendered, and the request is blocked.
+fn challenge_decision<GH: Grasshopper>(
+    logs: &mut Logs,
+    uuid: Uuid,
+    decision: SimpleDecision,
+    mgh: Option<GH>,
+) -> Result<Decision, SessionError> {
+    with_request_info(uuid, |rinfo| {
+        let is_human = match &mgh {
+            Some(gh) => challenge_verified(gh, rinfo, logs),
+            None => false,
+        };
+        Ok(decision.into_decision(is_human, &mgh, &rinfo.headers))
+    })
+}
+
+fn limit_check_uuid(logs: &mut Logs, uuid: Uuid) -> Result<SimpleDecision, SessionError> {
+    timed(uuid, Stage::Limit, || {
+        // copy limits, without keeping a read lock
+        let policies = for_each_policy(uuid, |securitypolicy| Ok(securitypolicy.limits.clone()))?;
+
+        let mut slots = Vec::new();
+        let decisions: Result<Vec<(String, SimpleDecision)>, SessionError> = policies
+            .into_iter()
+            .map(|(name, limits)| {
+                let decision = with_request_info(uuid, |rinfo| {
+                    with_tags_mut(uuid, |mut tags| {
+                        Ok(limit_check(logs, &name, &rinfo, &limits, &mut tags, Some(&mut slots)))
+                    })
+                })?;
+                Ok((name, decision))
+            })
+            .collect();
+        let decision = decisions.and_then(|decisions| {
+            most_restrictive(logs, uuid, "limit-policy", decisions, |d: &SimpleDecision| {
+                decision_rank(&d.clone().into_decision_no_challenge())
+            })
+        });
+        if !slots.is_empty() {
+            SLOTS
+                .write(&uuid)
+                .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SLOTS write lock {}", rr)))?
+                .entry(uuid)
+                .or_default()
+                .extend(slots);
+        }
+        decision
+    })
+}
+
+/// releases the slots of a concurrency limit held by the session, returning false when it held none
+///
+/// This should be called when the response is sent. The slots that are not released are released when the session is
+/// cleaned, or expires.
+pub fn session_limit_release(session_id: &str, limit_id: &str) -> Result<bool, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    with_request_info(uuid, |_| Ok(()))?;
+    let released: Vec<ConcurrencySlot> = {
+        let mut wslots = SLOTS
+            .write(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SLOTS write lock {}", rr)))?;
+        match wslots.get_mut(&uuid) {
+            None => Vec::new(),
+            Some(slots) => {
+                let (released, kept) = slots.drain(..).partition(|slot| slot.limit_id == limit_id);
+                *slots = kept;
+                released
+            }
+        }
+    };
+    let mut logs = Logs::default();
+    release_slots(&mut logs, &released);
+    append_logs(uuid, Stage::Limit, logs)?;
+    Ok(!released.is_empty())
+}
+
+/// returns the counter state of the limits applying to the session, without incrementing them
+pub fn session_limit_status(session_id: &str) -> Result<Vec<LimitStatus>, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let mut logs = Logs::default();
+    let status = with_request_info(uuid, |rinfo| {
+        with_securitypolicy(uuid, |securitypolicy| {
+            with_tags(uuid, |tags| {
+                limit_status(&mut logs, &securitypolicy.name, rinfo, &securitypolicy.limits, tags)
+                    .map_err(SessionError::Other)
+            })
+        })
+    });
+    append_logs(uuid, Stage::Limit, logs)?;
+    status
+}
+
+pub fn session_acl_check(session_id: &str) -> Result<AclResult, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.acl", || acl_check_uuid(uuid))
+}
+
+/// lists the tags that matched each stage of the ACL profile, along with the ACL result
+pub fn session_acl_explain(session_id: &str) -> Result<AclExplanation, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    with_securitypolicy(uuid, |securitypolicy| {
+        with_tags(uuid, |tags| Ok(explain_acl(tags, &securitypolicy.acl_profile)))
+    })
+}
+
+fn acl_check_uuid(uuid: Uuid) -> Result<AclResult, SessionError> {
+    if is_bypassed(uuid)? {
+        return Ok(AclResult::Match(BotHuman { bot: None, human: None }));
+    }
+    timed(uuid, Stage::Acl, || {
+        let results = for_each_policy(uuid, |securitypolicy| {
+            with_tags(uuid, |tags| Ok(check_acl(tags, &securitypolicy.acl_profile)))
+        })?;
+        let mut logs = Logs::default();
+        let result = most_restrictive(&mut logs, uuid, "acl-policy", results, acl_rank);
+        append_logs(uuid, Stage::Acl, logs)?;
+        result
+    })
+}
+
+/// looks for request smuggling indicators in the headers, in report only mode, see `session_smuggling_check_mode`
+pub fn session_smuggling_check(session_id: &str) -> Result<Decision, SessionError> {
+    session_smuggling_check_mode(session_id, true)
+}
+
+/// tags suspicious requests with `smuggling-suspected`, and with the `smuggling:` qualified indicators
+///
+/// The returned action is a monitor action in report only mode, and a block action otherwise.
+pub fn session_smuggling_check_mode(session_id: &str, report_only: bool) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.smuggling", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let indicators = with_request_info(uuid, |rinfo| Ok(smuggling_indicators(rinfo)))?;
+        if indicators.is_empty() {
+            return record_decision(uuid, Decision::Pass);
+        }
+        with_tags_mut(uuid, |tags| {
+            tags.insert("smuggling-suspected");
+            for indicator in &indicators {
+                tags.insert_qualified("smuggling", indicator.name());
+            }
+            Ok(())
+        })?;
+        record_decision(uuid, Decision::Action(smuggling_action(&indicators, !report_only)))
+    })
+}
+
+/// blocks the requests whose body is larger than the `max_body_size` limit of the matched security policy, or of the
+/// global settings, with a 413 action
+pub fn session_body_limit_check(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let body_size = with_request_info(uuid, |rinfo| Ok(rinfo.rinfo.body_size.unwrap_or(0)))?;
+    if is_bypassed(uuid)? {
+        return Ok(Decision::Pass);
+    }
+    let limit = session_body_limit(uuid)?;
+    let decision = with_tags_mut(uuid, |tags| Ok(body_limit_stage(limit, body_size, tags)))?;
+    record_decision(uuid, decision.unwrap_or(Decision::Pass))
+}
+
+/// the body size limit of the session, the global one when no security policy was matched
+fn session_body_limit(uuid: Uuid) -> Result<Option<usize>, SessionError> {
+    let policies = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?;
+    with_config(uuid, |cfg| {
+        Ok(match policies.get(&uuid) {
+            Some((_, securitypolicy)) => body_limit(cfg, securitypolicy),
+            None => cfg.settings.max_body_size,
+        })
+    })
+}
+
+pub fn session_content_filter_check(session_id: &str) -> Result<Decision, SessionError> {
+    session_content_filter_check_mode(session_id, false)
+}
+
+/// runs the content filter checks, never blocking when `report_only` is set
+///
+/// In report only mode, the request is tagged with the `cf-rule:` qualified ids of the rules that matched, and the
+/// action that would have been taken is stored in the session logs (see `session_logs`).
+pub fn session_content_filter_check_mode(session_id: &str, report_only: bool) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.content_filter", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let decision = match content_filter_check_uuid(uuid)? {
+            Ok(()) => Decision::Pass,
+            Err(rr) if report_only => {
+                let action = rr.to_action();
+                with_tags_mut(uuid, |tags| {
+                    for id in rr.rule_ids() {
+                        tags.insert_qualified("cf-rule", &id);
+                    }
+                    Ok(())
+                })?;
+                let mut logs = Logs::default();
+                logs.info(format!(
+                    "Content Filter report only mode, would have returned {}",
+                    serde_json::to_string(&action)?
+                ));
+                append_logs(uuid, Stage::ContentFilter, logs)?;
+                Decision::Pass
+            }
+            Err(rr) => Decision::Action(rr.to_action()),
+        };
+        record_decision(uuid, decision)
+    })
+}
+
+fn content_filter_check_uuid(uuid: Uuid) -> Result<Result<(), ContentFilterBlock>, SessionError> {
+    timed(uuid, Stage::ContentFilter, || {
+        let mut logs = Logs::default();
+        let results = with_hsdb(uuid, |hsdb| {
+            with_request_info(uuid, |rinfo| {
+                for_each_policy(uuid, |securitypolicy| {
+                    with_tags_mut(uuid, |tags| {
+                        Ok(content_filter_stage(&mut logs, hsdb, rinfo, securitypolicy, tags))
+                    })
+                })
+            })
+        })?;
+        let result = most_restrictive(
+            &mut logs,
+            uuid,
+            "cf-policy",
+            results,
+            |r: &Result<(), ContentFilterBlock>| u8::from(r.is_err()),
+        );
+        append_logs(uuid, Stage::ContentFilter, logs)?;
+        result
+    })
+}
+
+/// returns the anomaly score of the request, the sum of the scores of all the matching signatures, and tags the
+/// request with `anomaly-score:<n>`
+///
+/// Rules without a score are worth the blocking threshold of the profile, or nothing when it has none.
+pub fn session_content_filter_score(session_id: &str) -> Result<u32, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+
+    let mut logs = Logs::default();
+    let score = timed(uuid, Stage::ContentFilter, || {
+        with_hsdb(uuid, |hsdb| {
+            with_request_info(uuid, |rinfo| {
+                with_securitypolicy(uuid, |securitypolicy| {
+                    Ok(content_filter_score(
+                        &mut logs,
+                        rinfo,
+                        &securitypolicy.content_filter_profile,
+                        hsdb,
+                    ))
+                })
+            })
+        })
+    })?;
+    append_logs(uuid, Stage::ContentFilter, logs)?;
+    with_tags_mut(uuid, |tags| {
+        tag_anomaly_score(tags, Some(score));
+        Ok(())
+    })?;
+    Ok(score)
+}
+
+/// returns all the content filter rules matching the request, instead of stopping at the first match
+pub fn session_content_filter_matches(session_id: &str) -> Result<Vec<ContentFilterRuleMatch>, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+
+    let mut logs = Logs::default();
+    let matches = timed(uuid, Stage::ContentFilter, || {
+        with_hsdb(uuid, |hsdb| {
+            with_request_info(uuid, |rinfo| {
+                with_securitypolicy(uuid, |securitypolicy| {
+                    Ok(content_filter_matches(
+                        &mut logs,
+                        rinfo,
+                        &securitypolicy.content_filter_profile,
+                        hsdb,
+                    ))
+                })
+            })
+        })
+    })?;
+    append_logs(uuid, Stage::ContentFilter, logs)?;
+    Ok(matches)
+}
+
+/// scans the next chunk of the request body with the content filter signatures
+///
+/// Returns a decision as soon as a signature matched, so that the rest of the body does not have to be read. Bodies
+/// that exceed the `max_body_size` limit are blocked as soon as the chunk that crosses it is fed. The stream is built
+/// from the content filter profile of the matched security policy, and nothing is scanned when the content filter is
+/// not active, for preflight requests that the policy does not inspect, or when the checks are skipped (see
+/// `skipped_decision`).
+pub fn session_content_filter_feed(session_id: &str, chunk: &[u8]) -> Result<Option<Decision>, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    if skipped_decision(uuid)?.is_some() {
+        return Ok(None);
+    }
+    let (decision, logs) = timed(uuid, Stage::ContentFilter, || -> Result<_, SessionError> {
+        let inspected = with_request_info(uuid, |rinfo| {
+            with_securitypolicy(uuid, |sp| {
+                Ok(sp.content_filter_active && (!rinfo.is_preflight() || sp.inspect_preflight))
+            })
+        })?;
+        if !inspected {
+            return Ok((None, Logs::default()));
+        }
+        let limit = session_body_limit(uuid)?;
+        // the stream is taken out of the map while it scans, so that the other sessions are not blocked
+        let stream = STREAMS
+            .lock()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get STREAMS lock {}", rr)))?
+            .remove(&uuid);
+        let scanned = stream.as_ref().map(|s| s.scanned()).unwrap_or(0) + chunk.len();
+        if limit.map(|l| scanned > l).unwrap_or(false) {
+            let logs = stream.map(|mut s| s.take_logs()).unwrap_or_default();
+            let decision = with_tags_mut(uuid, |tags| Ok(body_limit_stage(limit, scanned, tags)))?;
+            return Ok((decision, logs));
+        }
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => with_hsdb(uuid, |hsdb| {
+                let rules = hsdb
+                    .as_ref()
+                    .ok_or_else(|| SessionError::Other(anyhow::anyhow!("Hyperscan database not loaded")))?;
+                with_securitypolicy(uuid, |sp| {
+                    ContentFilterStream::new(rules, &sp.content_filter_profile).map_err(SessionError::Other)
+                })
+            })?,
+        };
+        let decision = stream.feed(chunk);
+        let logs = stream.take_logs();
+        let mut streams = STREAMS
+            .lock()
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get STREAMS lock {}", rr)))?;
+        // the stream is dropped when the session was cleaned while it scanned, as RINFOS is cleared before STREAMS
+        if with_request_info(uuid, |_| Ok(())).is_ok() {
+            streams.insert(uuid, stream);
+        }
+        Ok((decision, logs))
+    })?;
+    append_logs(uuid, Stage::ContentFilter, logs)?;
+    match decision {
+        None => Ok(None),
+        Some(d) => record_decision(uuid, d).map(Some),
+    }
+}
+
+/// closes the body stream, returning the decision for the whole body
+///
+/// Returns Pass when no chunk has been fed.
+pub fn session_content_filter_finish(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let stream = STREAMS
+        .lock()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get STREAMS lock {}", rr)))?
+        .remove(&uuid);
+    let decision = match stream {
+        None => Decision::Pass,
+        Some(s) => {
+            let (decision, logs) = timed(uuid, Stage::ContentFilter, || s.finish());
+            append_logs(uuid, Stage::ContentFilter, logs)?;
+            decision
+        }
+    };
+    record_decision(uuid, decision)
+}
+
+pub fn session_flow_check(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.flow", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let mut logs = Logs::default();
+        let decision = flow_check_uuid(&mut logs, uuid);
+        append_logs(uuid, Stage::Flow, logs)?;
+        record_decision(uuid, decision?.into_decision_no_challenge())
+    })
+}
+
+/// same as `session_flow_check`, but challenge actions are turned into challenge pages, see `challenge_decision`
+pub fn session_flow_check_with_challenge<GH: Grasshopper>(
+    session_id: &str,
+    mgh: Option<GH>,
+) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.flow", || {
+        if let Some(decision) = skipped_decision(uuid)? {
+            return Ok(decision);
+        }
+        let mut logs = Logs::default();
+        let decision = flow_check_uuid(&mut logs, uuid).and_then(|d| challenge_decision(&mut logs, uuid, d, mgh));
+        append_logs(uuid, Stage::Flow, logs)?;
+        record_decision(uuid, decision?)
+    })
+}
+
+fn flow_check_uuid(logs: &mut Logs, uuid: Uuid) -> Result<SimpleDecision, SessionError> {
+    timed(uuid, Stage::Flow, || {
+        with_config(uuid, |cfg| {
+            with_request_info(uuid, |rinfo| {
+                with_tags_mut(uuid, |tags| {
+                    flow_check_global(logs, &cfg.flows, rinfo, tags).map_err(SessionError::Other)
+                })
+            })
+        })
+    })
+}
+
+/// attaches the response of the upstream server to the session, tagging the request with its status
+///
+/// A response that was already attached is replaced.
+pub fn session_response_init(session_id: &str, encoded_response_map: &str) -> Result<(), SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let jmap: JResponseMap = serde_json::from_str(encoded_response_map)?;
+    let response = ResponseInfo::new(jmap);
+    with_tags_mut(uuid, |tags| {
+        tag_response(&response, tags);
+        Ok(())
+    })?;
+    RESPONSES
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RESPONSES write lock {}", rr)))?
+        .insert(uuid, response);
+    Ok(())
+}
+
+/// checks the response attached with `session_response_init`, see `response_stage`
+///
+/// The decision is recorded as the decisions of the request checks, and can still replace the response.
+pub fn session_response_check(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    traced(uuid, "curiefense.response", || {
+        if is_bypassed(uuid)? {
+            return Ok(Decision::Pass);
+        }
+        let response = RESPONSES
+            .read(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RESPONSES read lock {}", rr)))?
+            .get(&uuid)
+            .cloned()
+            .ok_or(SessionError::NoResponse)?;
+        let mut logs = Logs::default();
+        let decision = with_hsdb(uuid, |hsdb| {
+            with_securitypolicy(uuid, |securitypolicy| {
+                with_tags_mut(uuid, |tags| {
+                    Ok(response_stage(&mut logs, hsdb, securitypolicy, &response, tags))
+                })
+            })
+        });
+        append_logs(uuid, Stage::Response, logs)?;
+        session_count_response(session_id, response.status)?;
+        record_decision(uuid, decision?)
+    })
+}
+
+/// counts a response status with the limits of the security policies that count it, see `Limit::response_statuses`
+///
+/// Called by `session_response_check`, it can also be called on its own when the responses are not checked.
+pub fn session_count_response(session_id: &str, status: u16) -> Result<(), SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    if is_bypassed(uuid)? {
+        return Ok(());
+    }
+    let mut logs = Logs::default();
+    let counted = count_response_uuid(&mut logs, uuid, status);
+    append_logs(uuid, Stage::Response, logs)?;
+    counted
+}
+
+/// counts the response with the limits of the security policies that count its status, see `limit_count_response`
+fn count_response_uuid(logs: &mut Logs, uuid: Uuid, status: u16) -> Result<(), SessionError> {
+    let policies = for_each_policy(uuid, |securitypolicy| Ok(securitypolicy.limits.clone()))?;
+    for (name, limits) in policies {
+        with_request_info(uuid, |rinfo| {
+            with_tags(uuid, |tags| {
+                limit_count_response(logs, &name, rinfo, &limits, tags, status);
+                Ok(())
+            })
+        })?;
+    }
+    Ok(())
+}
+
+/// runs all the checks on a session, in the same order as `inspect_generic_request_map`
+///
+/// The evaluation stops at the first final decision. Logs are added to the session logs, with the `evaluate` stage,
+/// and can be retrieved with `session_logs`. As with the other session functions, the requester is assumed to be human.
+pub fn session_evaluate(session_id: &str) -> Result<Decision, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    // fails early on unknown sessions, so that no logs are stored for them
+    with_request_info(uuid, |_| Ok(()))?;
+    if let Some(decision) = skipped_decision(uuid)? {
+        return Ok(counted(decision));
+    }
+    let mut logs = Logs::default();
+    let decision = evaluate_uuid(&mut logs, uuid);
+    if let Err(rr) = &decision {
+        logs.error(rr);
+    }
+    append_logs(uuid, Stage::Evaluate, logs)?;
+    record_decision(uuid, decision?).map(counted)
+}
+
+/// runs `evaluate_detailed` on a copy of the session tags, and stores its results in the session
+fn evaluate_uuid(logs: &mut Logs, uuid: Uuid) -> Result<Decision, SessionError> {
+    let mut tags = with_tags(uuid, |tags| Ok(tags.clone()))?;
+    let (evaluation, baselines) = with_hsdb(uuid, |hsdb| {
+        with_config(uuid, |cfg| {
+            with_request_info(uuid, |rinfo| {
+                let evaluation = evaluate_detailed(logs, cfg, hsdb, rinfo, &mut tags, None);
+                let baselines = evaluation
+                    .securitypolicy
+                    .as_ref()
+                    .map(|(hn, sp)| baseline_policies(cfg, hn, &sp.name))
+                    .unwrap_or_default();
+                Ok((evaluation, baselines))
+            })
+        })
+    })?;
+    with_tags_mut(uuid, |stags| {
+        *stags = tags;
+        Ok(())
+    })?;
+    if let Some((hostmap_name, securitypolicy)) = evaluation.securitypolicy {
+        store_securitypolicy(uuid, hostmap_name, securitypolicy, baselines)?;
+    }
+    add_durations(uuid, &evaluation.durations);
+    Ok(evaluation.decision)
+}
+
+/// adds the logs produced by a stage to the session logs
+fn append_logs(uuid: Uuid, stage: Stage, logs: Logs) -> Result<(), SessionError> {
+    if logs.logs.is_empty() {
+        return Ok(());
+    }
+    let mut wlogs = LOGS
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get LOGS write lock {}", rr)))?;
+    let entries = logs.logs.into_iter().map(|l| LogEntry {
+        level: l.level,
+        stage,
+        message: l.message,
+        elapsed_micros: l.elapsed_micros,
+    });
+    wlogs.entry(uuid).or_default().extend(entries);
+    Ok(())
+}
+
+/// returns the logs accumulated by all the session functions, in order, keeping those at or above `min_level`
+pub fn session_logs(session_id: &str, min_level: LogLevel) -> Result<Vec<LogEntry>, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let logs = LOGS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get LOGS read lock {}", rr)))?;
+    Ok(logs
+        .get(&uuid)
+        .map(|entries| entries.iter().filter(|e| e.level >= min_level).cloned().collect())
+        .unwrap_or_default())
+}
+
+/// returns the time spent in each pipeline stage of the session
+pub fn session_timings(session_id: &str) -> Result<SessionTimings, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let timings = TIMINGS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMINGS read lock {}", rr)))?;
+    timings.get(&uuid).cloned().ok_or(SessionError::UnknownSession)
+}
+
+/// the pipeline stages that have run on a session
+#[derive(Debug, Clone, Serialize)]
+pub struct PeekStages {
+    pub security_policy: bool,
+    pub tagging: bool,
+    pub limit: bool,
+    pub acl: bool,
+    pub content_filter: bool,
+    pub flow: bool,
+}
+
+/// the internal state of a session, see `session_peek`
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionPeek {
+    pub request_info: RequestInfo,
+    pub securitypolicy: Option<SessionSecurityPolicy>,
+    /// tags, with their values
+    pub tags: BTreeMap<String, Option<String>>,
+    pub logs: Vec<LogEntry>,
+    /// the running decision, `pass` or `custom_response`, as with the check functions
+    pub action: &'static str,
+    pub response: Option<Action>,
+    pub decision_reason: Option<DecisionReason>,
+    pub stages: PeekStages,
+    pub timings: SessionTimings,
+}
+
+/// returns the internal state of a session, for debugging purposes, without altering it
+///
+/// A stage has run when the session has spent time in it, or, for the security policy stage, when a security policy
+/// was matched.
+pub fn session_peek(session_id: &str) -> Result<serde_json::Value, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let request_info = with_request_info(uuid, |rinfo| Ok(rinfo.clone()))?;
+    let tags = with_tags(uuid, |tags| {
+        Ok(tags.iter_values().map(|(k, v)| (k.clone(), v.cloned())).collect())
+    })?;
+    let securitypolicy = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?
+        .get(&uuid)
+        .map(|(hostmap, securitypolicy)| SessionSecurityPolicy::new(hostmap.clone(), securitypolicy));
+    let decision_reason = REASONS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on REASONS {}", rr)))?
+        .get(&uuid)
+        .cloned();
+    let (action, response) = match session_current_decision(session_id)? {
+        Decision::Pass => ("pass", None),
+        Decision::Action(a) | Decision::Ratelimit(a, _) => ("custom_response", Some(a)),
+    };
+    let timings = session_timings(session_id)?;
+    let stages = PeekStages {
+        security_policy: securitypolicy.is_some(),
+        tagging: timings.tagging > 0,
+        limit: timings.limit > 0,
+        acl: timings.acl > 0,
+        content_filter: timings.content_filter > 0,
+        flow: timings.flow > 0,
+    };
+    let peek = SessionPeek {
+        request_info,
+        securitypolicy,
+        tags,
+        logs: session_logs(session_id, LogLevel::Debug)?,
+        action,
+        response,
+        decision_reason,
+        stages,
+        timings,
+    };
+    Ok(serde_json::to_value(&peek)?)
+}
+
+/// builds the access log record of a session, see `AccessLogRecord`
+///
+/// The decision is the running decision of the session, so that the record should be built once all the checks ran.
+pub fn session_access_log(session_id: &str) -> Result<AccessLogRecord, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let (request, geo) = with_request_info(uuid, |rinfo| {
+        Ok((AccessLogRequest::new(rinfo), AccessLogGeo::new(&rinfo.rinfo.geoip)))
+    })?;
+    let (tags, tag_values) = with_tags(uuid, |tags| Ok((tags.to_sorted_vec(), tag_values(tags))))?;
+    let securitypolicy = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?
+        .get(&uuid)
+        .map(|(hostmap, securitypolicy)| AccessLogPolicy {
+            hostmap: hostmap.clone(),
+            name: securitypolicy.name.clone(),
+            acl_profile: securitypolicy.acl_profile.id.clone(),
+            content_filter_profile: securitypolicy.content_filter_profile.id.clone(),
+        });
+    let (decision, response) = AccessLogDecision::new(&session_current_decision(session_id)?);
+    // when curiefense did not answer, the response of the upstream server is logged, if it was attached
+    let response = match response {
+        Some(r) => Some(r),
+        None => RESPONSES
+            .read(&uuid)
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RESPONSES read lock {}", rr)))?
+            .get(&uuid)
+            .map(|r| AccessLogResponse {
+                status: u32::from(r.status),
+                headers: r.headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            }),
+    };
+    let stages = session_timings(session_id)?;
+    let total = TIMES
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES read lock {}", rr)))?
+        .get(&uuid)
+        .map(|times| times.created.elapsed().as_micros() as u64)
+        .unwrap_or_default();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    Ok(AccessLogRecord {
+        version: ACCESS_LOG_VERSION,
+        session_id: session_id.to_string(),
+        tenant: session_tenant(uuid)?,
+        timestamp,
+        request,
+        response,
+        decision,
+        securitypolicy,
+        tags,
+        tag_values,
+        geo,
+        timings: AccessLogTimings {
+            tagging: stages.tagging / 1000,
+            limit: stages.limit / 1000,
+            acl: stages.acl / 1000,
+            content_filter: stages.content_filter / 1000,
+            flow: stages.flow / 1000,
+            total,
+        },
+    })
+}
+
+// HELPERS
+
+fn session_tenant(uuid: Uuid) -> Result<Option<TenantId>, SessionError> {
+    let tenants = TENANTS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TENANTS read lock {}", rr)))?;
+    Ok(tenants.get(&uuid).cloned())
+}
+
+/// runs `f` on the configuration and content filter database of a tenant, or on the default ones
+fn with_tenant<F, A>(tenant: Option<&str>, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&RwLock<Config>, &RwLock<Option<ContentFilterRules>>) -> Result<A, SessionError>,
+{
+    match tenant {
+        None => f(&CONFIG, &HSDB),
+        Some(t) => {
+            let tcfg = tenant_config(t).ok_or_else(|| SessionError::UnknownTenant(t.to_string()))?;
+            f(&tcfg.config, &tcfg.hsdb)
+        }
+    }
+}
+
+fn read_config(config: &RwLock<Config>) -> Result<RwLockReadGuard<'_, Config>, SessionError> {
+    config
+        .read()
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get configuration read lock {}", rr)))
+}
+
+/// runs `f` on the configuration of the session tenant
+fn with_config<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&Config) -> Result<A, SessionError>,
+{
+    let tenant = session_tenant(uuid)?;
+    with_tenant(tenant.as_deref(), |config, _| f(&*read_config(config)?))
+}
+
+/// runs `f` on the content filter database of the session tenant
+fn with_hsdb<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&Option<ContentFilterRules>) -> Result<A, SessionError>,
+{
+    let tenant = session_tenant(uuid)?;
+    with_tenant(tenant.as_deref(), |_, hsdb| match hsdb.read() {
+        Ok(db) => f(&db),
+        Err(rr) => Err(SessionError::LockPoisoned(format!("{}", rr))),
+    })
+}
+
+fn with_request_info<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&RequestInfo) -> Result<A, SessionError>,
+{
+    let infos = RINFOS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RINFOS read lock {}", rr)))?;
+    let rinfo = infos.get(&uuid).ok_or(SessionError::UnknownSession)?;
+    f(rinfo)
+}
+
+fn with_securitypolicy<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&SecurityPolicy) -> Result<A, SessionError>,
+{
+    let maps = SECURITYPOLICY
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get SECURITYPOLICY read lock {}", rr)))?;
+    let (_, umap) = maps.get(&uuid).ok_or(SessionError::UnknownSession)?;
+    f(umap)
+}
+
+fn with_tags<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&Tags) -> Result<A, SessionError>,
+{
+    let tags = TAGS
+        .read(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS read lock {}", rr)))?;
+    let tag = tags.get(&uuid).ok_or(SessionError::UnknownSession)?;
+    f(tag)
+}
+
+fn with_tags_mut<F, A>(uuid: Uuid, f: F) -> Result<A, SessionError>
+where
+    F: FnOnce(&mut Tags) -> Result<A, SessionError>,
+{
+    let mut tags = TAGS
+        .write(&uuid)
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS read lock {}", rr)))?;
+    let tag = tags.get_mut(&uuid).ok_or(SessionError::UnknownSession)?;
+    f(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contentfilter::content_filter_check;
+    use crate::interface::ActionType;
+    use crate::tagging::tag_request;
+    use crate::testutils::{content_filter_rule, content_filter_rules, mk_jmap, mk_policy, request_map};
+
+    #[test]
+    fn structured_query_args() {
+        let query = "filter[status][]=open&filter[status][]=closed&q=%7B%22a%22%3A%5B%22x%22%5D%7D";
+        let mut jmap = mk_jmap(&[], None, false);
+        jmap.attrs.query = query.to_string();
+        // the args parsed by the proxy are kept
+        jmap.args.add("filter[status][]".to_string(), "open".to_string());
+        let (rinfo, _) = jmap.clone().into_request_info(&mut Logs::default());
+        let args = &rinfo.rinfo.qinfo.args;
+        assert_eq!(args.get_str("filter.status.0"), Some("open"));
+        assert_eq!(args.get_str("filter.status.1"), Some("closed"));
+        assert_eq!(args.get_str("q.a.0"), Some("x"));
+        assert_eq!(args.get_str("filter[status][]"), Some("open"));
+
+        jmap.max_arg_depth = Some(0);
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.rinfo.qinfo.args.get_str("filter.status.0"), None);
+    }
+
+    #[test]
+    fn eval_budget() {
+        use crate::config::settings::EvalBudget;
+
+        let mut cfg = Config::empty();
+        cfg.settings.eval_budget = Some(EvalBudget {
+            max_fields: Some(20),
+            max_duration: None,
+            block: true,
+        });
+        crate::config::TENANT_CONFIGS.write().unwrap().insert(
+            "budget-tenant".to_string(),
+            std::sync::Arc::new(crate::config::TenantConfig {
+                config: RwLock::new(cfg),
+                hsdb: RwLock::new(None),
+            }),
+        );
+        let mut jmap = mk_jmap(&[("host", "www.example.com")], None, false);
+        jmap.tenant = Some("budget-tenant".to_string());
+        let small = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+        for i in 0..100 {
+            jmap.args.add(format!("a{}", i), "x".to_string());
+        }
+        let large = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+
+        assert!(matches!(session_flow_check(&small).unwrap(), Decision::Pass));
+        assert!(!with_tags(small.parse().unwrap(), |tags| Ok(tags.contains("eval-budget-exceeded"))).unwrap());
+        match session_flow_check(&large).unwrap() {
+            Decision::Action(action) => {
+                assert!(action.block_mode);
+                assert_eq!(
+                    action.decision_reason,
+                    DecisionReason::EvalBudget {
+                        limit: "fields".to_string()
+                    }
+                );
+                assert_eq!(action.reason["fields"], 101);
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert!(with_tags(large.parse().unwrap(), |tags| Ok(tags.contains("eval-budget-exceeded"))).unwrap());
+        // the following checks are skipped, without failing on the missing security policy
+        assert!(session_content_filter_check(&large).unwrap().is_blocking());
+        let logs = session_logs(&large, LogLevel::Warning).unwrap();
+        assert_eq!(
+            logs.iter()
+                .filter(|l| l.message.starts_with("Evaluation budget"))
+                .count(),
+            1
+        );
+
+        let tcfg = crate::config::tenant_config("budget-tenant").unwrap();
+        if let Some(budget) = tcfg.config.write().unwrap().settings.eval_budget.as_mut() {
+            budget.block = false;
+        }
+        assert!(matches!(session_content_filter_check(&large).unwrap(), Decision::Pass));
+
+        crate::config::TENANT_CONFIGS.write().unwrap().remove("budget-tenant");
+        clean_session(&small).unwrap();
+        clean_session(&large).unwrap();
+    }
+
+    #[test]
+    fn trusted_bypass() {
+        use crate::config::raw::RawTrustedSources;
+        use crate::config::settings::{EvalBudget, TrustedSources};
+
+        let mut cfg = Config::empty();
+        let mut logs = Logs::default();
+        let raw: RawTrustedSources = serde_json::from_value(serde_json::json!({
+            "networks": ["10.0.0.0/8", "not-a-network"],
+            "tags": ["healthcheck"],
+            "headers": [{"name": "X-Mesh-Secret", "secret": "s3cret"}]
+        }))
+        .unwrap();
+        cfg.settings.trusted = Some(TrustedSources::resolve(&mut logs, &raw));
+        assert_eq!(logs.logs.len(), 1);
+        // every other request exceeds the budget, and is blocked
+        cfg.settings.eval_budget = Some(EvalBudget {
+            max_fields: Some(0),
+            max_duration: None,
+            block: true,
+        });
+        crate::config::TENANT_CONFIGS.write().unwrap().insert(
+            "trusted-tenant".to_string(),
+            std::sync::Arc::new(crate::config::TenantConfig {
+                config: RwLock::new(cfg),
+                hsdb: RwLock::new(None),
+            }),
+        );
+        let init = |headers: &[(&str, &str)], ip: &str, tag: Option<&str>| {
+            let mut jmap = mk_jmap(headers, None, false);
+            jmap.tenant = Some("trusted-tenant".to_string());
+            jmap.attrs.ip = ip.to_string();
+            if let Some(tag) = tag {
+                jmap.attrs.tags.insert(tag.to_string(), serde_json::json!(1));
+            }
+            session_init(&serde_json::to_string(&jmap).unwrap()).unwrap()
+        };
+        let trusted = vec![
+            init(&[("host", "a")], "10.1.2.3", None),
+            init(&[("host", "a")], "192.0.2.1", Some("healthcheck")),
+            init(&[("host", "a"), ("x-mesh-secret", "s3cret")], "192.0.2.1", None),
+        ];
+        for session in &trusted {
+            assert!(session_is_bypassed(session).unwrap());
+            assert!(session_tag_request(session).unwrap());
+            assert!(matches!(session_flow_check(session).unwrap(), Decision::Pass));
+            assert!(matches!(session_content_filter_check(session).unwrap(), Decision::Pass));
+            assert!(matches!(session_evaluate(session).unwrap(), Decision::Pass));
+            assert!(matches!(
+                session_acl_check(session).unwrap(),
+                AclResult::Match(BotHuman { bot: None, human: None })
+            ));
+            assert!(!with_tags(session.parse().unwrap(), |tags| Ok(
+                tags.contains("eval-budget-exceeded")
+            ))
+            .unwrap());
+        }
+        let logs = session_logs(&trusted[2], LogLevel::Info).unwrap();
+        assert!(logs
+            .iter()
+            .any(|l| l.message == "trusted source, header x-mesh-secret, the checks are skipped"));
+
+        let untrusted = init(&[("host", "a"), ("x-mesh-secret", "s3cre")], "192.0.2.1", None);
+        assert!(!session_is_bypassed(&untrusted).unwrap());
+        assert!(session_flow_check(&untrusted).unwrap().is_blocking());
+        // the tag can't be forged
+        let forged = init(&[("host", "a")], "192.0.2.1", Some(BYPASS_TAG));
+        assert!(!session_is_bypassed(&forged).unwrap());
+        assert!(!with_tags(forged.parse().unwrap(), |tags| Ok(tags.contains(BYPASS_TAG))).unwrap());
+        assert!(session_logs(&forged, LogLevel::Warning)
+            .unwrap()
+            .iter()
+            .any(|l| l.message.contains("is ignored")));
+        session_add_tags(&forged, &[BYPASS_TAG]).unwrap();
+        assert!(session_flow_check(&forged).unwrap().is_blocking());
+
+        crate::config::TENANT_CONFIGS.write().unwrap().remove("trusted-tenant");
+        for session in trusted.iter().chain([&untrusted, &forged]) {
+            clean_session(session).unwrap();
+        }
+    }
+
+    #[test]
+    fn body_limit() {
+        let mut cfg = Config::empty();
+        cfg.settings.max_body_size = Some(16);
+        crate::config::TENANT_CONFIGS.write().unwrap().insert(
+            "body-limit-tenant".to_string(),
+            std::sync::Arc::new(crate::config::TenantConfig {
+                config: RwLock::new(cfg),
+                hsdb: RwLock::new(None),
+            }),
+        );
+        let mut jmap = mk_jmap(&[("content-type", "application/json")], None, false);
+        jmap.tenant = Some("body-limit-tenant".to_string());
+        let small = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+        jmap.body = Some(base64::encode(br#"{"name": "a large value"}"#));
+        jmap.body_base64 = true;
+        let large = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+        let body_size = with_request_info(large.parse().unwrap(), |rinfo| Ok(rinfo.rinfo.body_size)).unwrap();
+        assert_eq!(body_size, Some(25));
+
+        assert!(matches!(session_body_limit_check(&small).unwrap(), Decision::Pass));
+        match session_body_limit_check(&large).unwrap() {
+            Decision::Action(action) => {
+                assert_eq!(action.status, 413);
+                assert_eq!(action.decision_reason, DecisionReason::BodyLimit { limit: 16 });
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert!(with_tags(large.parse().unwrap(), |tags| Ok(tags.contains("body-limit"))).unwrap());
+
+        // the security policy limit takes precedence
+        let uuid: Uuid = large.parse().unwrap();
+        let securitypolicy = SecurityPolicy {
+            max_body_size: Some(1024),
+            ..mk_policy("uploads")
+        };
+        let streamed = mk_policy("uploads");
+        SECURITYPOLICY
+            .write(&uuid)
+            .unwrap()
+            .insert(uuid, ("uploads".to_string(), securitypolicy));
+        assert!(matches!(session_body_limit_check(&large).unwrap(), Decision::Pass));
+        let small_uuid: Uuid = small.parse().unwrap();
+        SECURITYPOLICY
+            .write(&small_uuid)
+            .unwrap()
+            .insert(small_uuid, ("uploads".to_string(), streamed));
+
+        // streamed bodies are blocked by the chunk that crosses the limit, without being scanned
+        let feed = |chunk: &[u8]| session_content_filter_feed(&small, chunk);
+        assert!(matches!(feed(b"0123456789abcdefg"), Ok(Some(Decision::Action(a))) if a.status == 413));
+        assert!(STREAMS.lock().unwrap().get(&small.parse().unwrap()).is_none());
+
+        crate::config::TENANT_CONFIGS
+            .write()
+            .unwrap()
+            .remove("body-limit-tenant");
+        clean_session(&small).unwrap();
+        clean_session(&large).unwrap();
+    }
+
+    #[test]
+    fn content_filter_stream_policy() {
+        let rules = content_filter_rules(vec![content_filter_rule("100001", "select.*from")]);
+        crate::config::TENANT_CONFIGS.write().unwrap().insert(
+            "stream-policy-tenant".to_string(),
+            std::sync::Arc::new(crate::config::TenantConfig {
+                config: RwLock::new(Config::empty()),
+                hsdb: RwLock::new(Some(rules)),
+            }),
+        );
+        let init = |headers: &[(&str, &str)], method: &str, content_filter_active: bool| {
+            let mut jmap = mk_jmap(headers, None, false);
+            jmap.tenant = Some("stream-policy-tenant".to_string());
+            jmap.attrs.method = method.to_string();
+            let session_id = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+            let uuid: Uuid = session_id.parse().unwrap();
+            let securitypolicy = SecurityPolicy {
+                content_filter_active,
+                ..mk_policy("streamed")
+            };
+            SECURITYPOLICY
+                .write(&uuid)
+                .unwrap()
+                .insert(uuid, ("streamed".to_string(), securitypolicy));
+            session_id
+        };
+        let body = b"id=1 union select password from users";
+
+        let active = init(&[], "POST", true);
+        assert!(matches!(
+            session_content_filter_feed(&active, body),
+            Ok(Some(Decision::Action(_)))
+        ));
+        // the body is not scanned when the content filter is not active
+        let inactive = init(&[], "POST", false);
+        assert!(matches!(session_content_filter_feed(&inactive, body), Ok(None)));
+        assert!(STREAMS.lock().unwrap().get(&inactive.parse().unwrap()).is_none());
+        assert!(matches!(
+            session_content_filter_finish(&inactive).unwrap(),
+            Decision::Pass
+        ));
+        // nor for the preflight requests, unless the policy inspects them
+        let preflight = init(&[("access-control-request-method", "POST")], "OPTIONS", true);
+        assert!(matches!(session_content_filter_feed(&preflight, body), Ok(None)));
+
+        crate::config::TENANT_CONFIGS
+            .write()
+            .unwrap()
+            .remove("stream-policy-tenant");
+        for session in [&active, &inactive, &preflight] {
+            clean_session(session).unwrap();
+        }
+    }
+
+    #[test]
+    fn cookie_header() {
+        let headers = [("cookie", "sid=\"a=b;c\"; lang=en; sid=second")];
+        let (rinfo, _) = mk_jmap(&headers, None, false).into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.cookies.get_str("sid"), Some("second"));
+        assert_eq!(rinfo.cookies.get_str("lang"), Some("en"));
+        assert_eq!(rinfo.cookies.get_str("c\""), None);
+
+        let mut jmap = mk_jmap(&headers, None, false);
+        jmap.cookie_duplicates = CookieDuplicates::All;
+        // with HTTP/2, cookies can be split across several headers
+        let list = [("Cookie", "sid=\"a=b;c\"; lang=en"), ("cookie", "sid=second")];
+        jmap.header_list = Some(list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.cookies.get_str("sid"), Some("a=b;c second"));
+
+        // without a cookie header, the cookies of the request map are kept
+        let mut jmap = mk_jmap(&[], None, false);
+        jmap.cookies.add("rbzid".to_string(), "x".to_string());
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.cookies.get_str("rbzid"), Some("x"));
+    }
+
+    #[test]
+    fn trusted_hops() {
+        let client_ip = |xff: &[&str], trusted_hops: usize| {
+            let mut jmap = mk_jmap(&[], None, false);
+            jmap.header_list = Some(
+                xff.iter()
+                    .map(|v| ("X-Forwarded-For".to_string(), v.to_string()))
+                    .collect(),
+            );
+            jmap.trusted_hops = trusted_hops;
+            let (rinfo, tags) = jmap.into_request_info(&mut Logs::default());
+            (rinfo.rinfo.geoip.ipstr, tags.contains("xff-spoofed"))
+        };
+        // the header is ignored by default
+        assert_eq!(client_ip(&["1.1.1.1"], 0), ("127.0.0.1".to_string(), false));
+        assert_eq!(client_ip(&["1.1.1.1, 2.2.2.2"], 1), ("2.2.2.2".to_string(), false));
+        assert_eq!(client_ip(&["1.1.1.1, 2.2.2.2"], 2), ("1.1.1.1".to_string(), false));
+        // the entries of all the headers are used, in order
+        assert_eq!(
+            client_ip(&["1.1.1.1", "2001:db8::1, 3.3.3.3"], 2),
+            ("2001:db8::1".to_string(), false)
+        );
+        // the entries prepended by the client are not trusted
+        assert_eq!(client_ip(&["6.6.6.6, 2.2.2.2"], 1), ("2.2.2.2".to_string(), false));
+        assert_eq!(client_ip(&["<script>, 2.2.2.2"], 1), ("2.2.2.2".to_string(), true));
+        // malformed entries from the trusted proxies, or missing hops
+        assert_eq!(client_ip(&["1.1.1.1, not-an-ip"], 1), ("127.0.0.1".to_string(), true));
+        assert_eq!(
+            client_ip(&["1.1.1.1, 1.1.1.1:8080"], 1),
+            ("127.0.0.1".to_string(), true)
+        );
+        assert_eq!(client_ip(&["1.1.1.1"], 2), ("127.0.0.1".to_string(), false));
+        assert_eq!(client_ip(&[], 1), ("127.0.0.1".to_string(), false));
+    }
+
+    #[test]
+    fn ordered_headers() {
+        let headers = [("host", "www.example.com"), ("x-forwarded-for", "1.1.1.1")];
+        let mut jmap = mk_jmap(&headers, None, false);
+        let list = [
+            ("Host", "www.example.com"),
+            ("X-Forwarded-For", "1.1.1.1"),
+            ("Accept", "*/*"),
+            ("x-forwarded-for", "2.2.2.2"),
+        ];
+        jmap.header_list = Some(list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        let names: Vec<&str> = rinfo.header_list.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, vec!["host", "x-forwarded-for", "accept", "x-forwarded-for"]);
+        let hops: Vec<&str> = rinfo.header_values("x-forwarded-for").collect();
+        assert_eq!(hops, vec!["1.1.1.1", "2.2.2.2"]);
+        // the content filter sees both hops
+        assert_eq!(rinfo.headers.get_str("x-forwarded-for"), Some("1.1.1.1 2.2.2.2"));
+        assert_eq!(rinfo.headers.get_str("host"), Some("www.example.com"));
+
+        // without an ordered list, the header map is used
+        let (rinfo, _) = mk_jmap(&[("b", "2"), ("a", "1")], None, false).into_request_info(&mut Logs::default());
+        let names: Vec<&str> = rinfo.header_list.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn body_args() {
+        let mut jmap = mk_jmap(&[("content-type", "application/json")], None, false);
+        jmap.body = Some(r#"{"user": {"name": "1' or '1'='1"}, "ids": [1, 2]}"#.to_string());
+        let (rinfo, tags) = jmap.clone().into_request_info(&mut Logs::default());
+        let args = &rinfo.rinfo.qinfo.args;
+        assert_eq!(args.get_str("body:user_name"), Some("1' or '1'='1"));
+        assert_eq!(args.get_str("body:ids_1"), Some("2"));
+        assert!(!tags.contains("body-too-large"));
+
+        let block = content_filter_check(
+            &rinfo,
+            &crate::config::contentfilter::ContentFilterProfile::default(),
+            &HSDB.read().unwrap(),
+        )
+        .unwrap_err();
+        let matches = block.rule_matches();
+        assert_eq!(matches[0].rule_id, "libinjection-sqli");
+        assert_eq!(matches[0].name, "body:user_name");
+
+        jmap.max_body_size = Some(10);
+        let (rinfo, tags) = jmap.into_request_info(&mut Logs::default());
+        assert!(tags.contains("body-too-large"));
+        assert!(rinfo.rinfo.qinfo.args.get("body:user_name").is_none());
+    }
+
+    #[test]
+    fn compressed_body() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let gzip = |data: &[u8]| {
+            let mut e = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            e.write_all(data).unwrap();
+            base64::encode(e.finish().unwrap())
+        };
+        let headers = [("content-type", "application/json"), ("content-encoding", "gzip")];
+        let mut jmap = mk_jmap(&headers, None, false);
+        jmap.body_base64 = true;
+        jmap.body = Some(gzip(br#"{"user": "1' or '1'='1"}"#));
+        let (rinfo, _) = jmap.clone().into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.rinfo.qinfo.args.get_str("body:user"), Some("1' or '1'='1"));
+        assert!(!rinfo.rinfo.decompress_bomb);
+
+        let profile = crate::config::contentfilter::ContentFilterProfile::default();
+        jmap.body = Some(gzip(&[b' '; 100_000]));
+        jmap.max_decompressed_size = Some(50_000);
+        let mut logs = Logs::default();
+        let (rinfo, _) = jmap.clone().into_request_info(&mut logs);
+        assert!(logs.to_stringvec().iter().any(|l| l.contains("exceeds the limits")));
+        assert!(rinfo.rinfo.decompress_bomb);
+        assert!(rinfo.rinfo.qinfo.args.get("body:RAW_BODY").is_none());
+        let (tags, _) = tag_request(true, &Config::empty(), &rinfo);
+        assert!(tags.contains("decompress-bomb"));
+        let block = content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()).unwrap_err();
+        assert_eq!(block.rule_ids(), vec!["decompress-bomb"]);
+        assert!(block.to_action().extra_tags.unwrap().contains("decompress-bomb"));
+        // the session keeps the logs of the body decoding
+        let session_id = session_init(&serde_json::to_string(&jmap).unwrap()).unwrap();
+        let logs = session_logs(&session_id, LogLevel::Warning).unwrap();
+        assert!(logs
+            .iter()
+            .any(|l| l.stage == Stage::Init && l.message.contains("exceeds the limits")));
+        clean_session(&session_id).unwrap();
+
+        // malformed bodies are inspected as they are, and blocked
+        let mut jmap = mk_jmap(&headers, None, false);
+        jmap.body = Some(r#"{"user": "1' or '1'='1"}"#.to_string());
+        let (rinfo, _) = jmap.clone().into_request_info(&mut Logs::default());
+        assert!(!rinfo.rinfo.decompress_bomb);
+        assert!(rinfo.rinfo.decompress_failed);
+        assert_eq!(rinfo.rinfo.qinfo.args.get_str("body:user"), Some("1' or '1'='1"));
+        let (tags, _) = tag_request(true, &Config::empty(), &rinfo);
+        assert!(tags.contains("decompress-failed"));
+        let block = content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()).unwrap_err();
+        assert_eq!(block.rule_ids(), vec!["decompress-failed"]);
+        assert!(block.to_action().extra_tags.unwrap().contains("decompress-failed"));
+
+        // as are unsupported codings
+        let mut jmap = mk_jmap(&[("content-encoding", "gzip, compress")], None, false);
+        jmap.body = Some(gzip(br#"{"user": "admin"}"#));
+        jmap.body_base64 = true;
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        assert!(rinfo.rinfo.decompress_failed);
+    }
+
+    #[test]
+    fn graphql_depth() {
+        let mut jmap = mk_jmap(&[("content-type", "application/json")], None, false);
+        jmap.body = Some(r#"{"query": "{ user { friends { name } } }"}"#.to_string());
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        let info = rinfo.rinfo.graphql.unwrap();
+        assert_eq!((info.depth, info.fields), (3, 3));
+
+        let sel = crate::config::utils::decode_request_selector_condition(
+            crate::config::utils::SelectorType::Attrs,
+            "graphql_depth",
+            "^3$",
+        )
+        .unwrap();
+        assert!(crate::utils::check_selector_cond(&rinfo, &Tags::default(), &sel));
+
+        let profile = crate::config::contentfilter::ContentFilterProfile {
+            graphql_max_depth: Some(2),
+            ..Default::default()
+        };
+        let block = content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()).unwrap_err();
+        assert_eq!(block.rule_ids(), vec!["graphql-too-deep".to_string()]);
+    }
+
+    #[test]
+    fn path_encoding() {
+        for (path, query, expected) in &[
+            ("/a%2Fb", "", "/a%252Fb"),
+            ("/a b", "x=y", "/a%20b?x=y"),
+            ("/é/ü", "", "/%C3%A9/%C3%BC"),
+        ] {
+            let mut jmap = mk_jmap(&[], None, false);
+            jmap.attrs.path = path.to_string();
+            jmap.attrs.query = query.to_string();
+            let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+            assert_eq!(&rinfo.rinfo.meta.path, expected);
+            assert_eq!(&rinfo.rinfo.qinfo.qpath, path);
+        }
+    }
+
+    #[test]
+    fn precomputed_geoip() {
+        let mut jmap = mk_jmap(&[], None, false);
+        jmap.attrs.geo = Some(JGeo {
+            country: Some("US".to_string()),
+            asn: Some(13335),
+            org: Some("Cloudflare".to_string()),
+            subdivision: Some("US-CA".to_string()),
+        });
+        let before = crate::maxmind::lookup_count();
+        let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+        assert_eq!(crate::maxmind::lookup_count(), before);
+        assert_eq!(rinfo.rinfo.geoip.country_iso.as_deref(), Some("us"));
+        assert_eq!(rinfo.rinfo.geoip.asn, Some(13335));
+        assert_eq!(rinfo.rinfo.geoip.company.as_deref(), Some("Cloudflare"));
+        assert_eq!(rinfo.rinfo.geoip.subdivision.as_deref(), Some("US-CA"));
+        assert_eq!(rinfo.rinfo.geoip.ip, Some("127.0.0.1".parse().unwrap()));
+
+        // without geo data, the lookups are performed
+        let (rinfo, _) = mk_jmap(&[], None, false).into_request_info(&mut Logs::default());
+        assert!(crate::maxmind::lookup_count() > before);
+        assert_eq!(rinfo.rinfo.geoip.country_iso, None);
+    }
+
+    #[test]
+    fn host_port_stripping() {
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("example.com:8443"), "example.com");
+        assert_eq!(strip_port("1.2.3.4:80"), "1.2.3.4");
+        assert_eq!(strip_port("[::1]:443"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("::1"), "::1");
+    }
+
+    #[test]
+    fn host_precedence() {
+        let fwd = [
+            ("host", "internal:8080"),
+            ("x-forwarded-host", "example.com:443, proxy.local"),
+        ];
+        assert_eq!(mk_jmap(&fwd, None, false).host(), "internal");
+        assert_eq!(mk_jmap(&fwd, None, true).host(), "example.com");
+        assert_eq!(mk_jmap(&[("host", "internal")], None, true).host(), "internal");
+        assert_eq!(mk_jmap(&[], Some("[::1]:443"), false).host(), "[::1]");
+        assert_eq!(mk_jmap(&[], None, true).host(), "unknown");
+        let (rinfo, _) = mk_jmap(&[], Some("[2001:db8::1]:8443"), false).into_request_info(&mut Logs::default());
+        assert_eq!(rinfo.rinfo.host, "[2001:db8::1]");
+    }
+
+    fn mk_request_map() -> String {
+        let mut jvalue = request_map(&[("host", "www.example.com"), ("user-agent", "curl/7.68.0")]);
+        jvalue["args"] = serde_json::json!({"a": "b"});
+        jvalue["attrs"]["path"] = serde_json::json!("/test/");
+        jvalue["attrs"]["query"] = serde_json::json!("a=b");
+        jvalue["attrs"]["uri"] = serde_json::json!("/test/?a=b");
+        jvalue.to_string()
+    }
+
+    #[test]
+    fn tls_fingerprint_attr() {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["attrs"]["tls_fingerprint"] = serde_json::json!("e7d705a3286e19ea42f587b344ee6865");
+        let session_id = session_init(&jvalue.to_string()).unwrap();
+        let uuid: Uuid = session_id.parse().unwrap();
+        let fingerprint = with_request_info(uuid, |rinfo| Ok(rinfo.rinfo.tls_fingerprint.clone())).unwrap();
+        assert_eq!(fingerprint.as_deref(), Some("e7d705a3286e19ea42f587b344ee6865"));
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn protocol_attrs() {
+        let tags_of = |http_version: Option<&str>, alpn: Option<&str>| {
+            let mut jmap = mk_jmap(&[], None, false);
+            jmap.attrs.http_version = http_version.map(|s| s.to_string());
+            jmap.attrs.alpn = alpn.map(|s| s.to_string());
+            let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+            let (tags, _) = tag_request(true, &Config::empty(), &rinfo);
+            tags.to_sorted_vec()
+                .into_iter()
+                .filter(|t| t.starts_with("httpver:") || t.starts_with("alpn:"))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(tags_of(Some("HTTP/2.0"), Some("H2")), vec!["alpn:h2", "httpver:2"]);
+        assert_eq!(tags_of(Some("3"), Some("h3")), vec!["alpn:h3", "httpver:3"]);
+        assert_eq!(
+            tags_of(Some("HTTP/1.1"), Some("http/1.1")),
+            vec!["alpn:http-1-1", "httpver:1-1"]
+        );
+        // older integrations do not send these fields
+        assert!(tags_of(None, None).is_empty());
+        assert!(tags_of(Some("HTTP/x"), Some(" ")).is_empty());
+
+        let jmap: JRequestMap = serde_json::from_str(&mk_request_map()).unwrap();
+        assert_eq!(jmap.attrs.http_version, None);
+    }
+
+    #[test]
+    fn clone_session() {
+        let session_id = mk_session(&[]);
+        session_add_tags(&session_id, &["original"]).unwrap();
+        let clone_id = session_clone(&session_id).unwrap();
+        assert_ne!(clone_id, session_id);
+        assert_eq!(
+            session_serialize_request_map(&clone_id).unwrap(),
+            session_serialize_request_map(&session_id).unwrap()
+        );
+
+        // tags and policy changes on the clone do not leak into the original
+        session_add_tags(&clone_id, &["forked"]).unwrap();
+        let clone_uuid: Uuid = clone_id.parse().unwrap();
+        if let Some((_, sp)) = SECURITYPOLICY.write(&clone_uuid).unwrap().get_mut(&clone_uuid) {
+            sp.name = "alternate".to_string();
+            sp.acl_profile.deny.insert("forked".to_string());
+        }
+        assert!(with_tags(session_id.parse().unwrap(), |tags| Ok(!tags.contains("forked"))).unwrap());
+        assert_eq!(
+            with_securitypolicy(session_id.parse().unwrap(), |sp| Ok(sp.name.clone())).unwrap(),
+            "test"
+        );
+        let denied = |result: AclResult| match result {
+            AclResult::Match(bh) => bh.human.map(|d| !d.allowed).unwrap_or(false),
+            AclResult::Passthrough(_) => false,
+        };
+        assert!(denied(session_acl_check(&clone_id).unwrap()));
+        assert!(!denied(session_acl_check(&session_id).unwrap()));
+
+        // the clone outlives the original
+        clean_session(&session_id).unwrap();
+        assert!(session_serialize_request_map(&clone_id).is_ok());
+        clean_session(&clone_id).unwrap();
+        assert!(matches!(session_clone(&clone_id), Err(SessionError::UnknownSession)));
+    }
+
+    #[test]
+    fn concurrency_release() {
+        use crate::config::raw::LimitAlgorithm;
+        use crate::limit::tests::fixture_limits;
+
+        let mut limit = fixture_limits().remove(0);
+        limit.algorithm = LimitAlgorithm::Concurrency;
+        limit.thresholds[0].limit = 1;
+        let limit_id = limit.id.clone();
+        let mk_limited = || {
+            let session_id = mk_session(&[]);
+            let uuid: Uuid = session_id.parse().unwrap();
+            if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+                sp.name = format!("concurrency-release-{}", std::process::id());
+                sp.limits = vec![limit.clone()];
+            }
+            session_add_tags(&session_id, &["blocklist"]).unwrap();
+            session_id
+        };
+        let passes = |session_id: &str| matches!(session_limit_check(session_id).unwrap(), Decision::Pass);
+
+        let first = mk_limited();
+        let second = mk_limited();
+        assert!(passes(&first));
+        assert!(!passes(&second));
+        // the rejection carries the state of the limit
+        let ratelimit = session_limit_check(&second).unwrap().ratelimit().cloned().unwrap();
+        assert_eq!(
+            (ratelimit.limit_id.as_str(), ratelimit.threshold),
+            (limit_id.as_str(), 1)
+        );
+        assert!(session_limit_release(&first, &limit_id).unwrap());
+        assert!(!session_limit_release(&first, &limit_id).unwrap());
+        let third = mk_limited();
+        assert!(passes(&third));
+
+        // cleaning a session releases the slots it still holds
+        clean_session(&third).unwrap();
+        let fourth = mk_limited();
+        assert!(passes(&fourth));
+        for session_id in &[first, second, fourth] {
+            clean_session(session_id).unwrap();
+        }
+        assert!(matches!(
+            session_limit_release(&third, &limit_id),
+            Err(SessionError::UnknownSession)
+        ));
+    }
+
+    #[test]
+    fn error_rate_limit() {
+        use crate::limit::tests::fixture_limits;
+        use crate::limit::ERROR_RATE_TAG;
+
+        let mut limit = fixture_limits().remove(0);
+        limit.response_statuses = [401, 403, 404].iter().copied().collect();
+        limit.thresholds[0].limit = 1;
+        let mk_limited = || {
+            let session_id = mk_session(&[]);
+            let uuid: Uuid = session_id.parse().unwrap();
+            if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+                sp.name = format!("error-rate-{}", std::process::id());
+                sp.limits = vec![limit.clone()];
+            }
+            session_add_tags(&session_id, &["blocklist"]).unwrap();
+            session_id
+        };
+        let respond = |status: u16| {
+            let session_id = mk_limited();
+            assert!(matches!(session_limit_check(&session_id).unwrap(), Decision::Pass));
+            let response = serde_json::json!({ "status": status });
+            session_response_init(&session_id, &response.to_string()).unwrap();
+            session_response_check(&session_id).unwrap();
+            clean_session(&session_id).unwrap();
+        };
+
+        respond(200);
+        respond(404);
+        respond(401);
+        let session_id = mk_limited();
+        assert!(session_limit_check(&session_id).unwrap().is_blocking());
+        let uuid: Uuid = session_id.parse().unwrap();
+        assert!(with_tags(uuid, |tags| Ok(tags.contains(ERROR_RATE_TAG))).unwrap());
+        clean_session(&session_id).unwrap();
+
+        // the responses can be counted without being checked
+        let mut counted = limit.clone();
+        counted.id = format!("{}-counted", limit.id);
+        let session_id = mk_session(&[]);
+        let uuid: Uuid = session_id.parse().unwrap();
+        if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+            sp.name = format!("error-count-{}", std::process::id());
+            sp.limits = vec![counted];
+        }
+        session_add_tags(&session_id, &["blocklist"]).unwrap();
+        assert!(matches!(session_limit_check(&session_id).unwrap(), Decision::Pass));
+        session_count_response(&session_id, 403).unwrap();
+        session_count_response(&session_id, 403).unwrap();
+        assert!(session_limit_check(&session_id).unwrap().is_blocking());
+        assert!(matches!(
+            session_count_response(&Uuid::new_v4().to_string(), 403),
+            Err(SessionError::UnknownSession)
+        ));
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn multiple_policies() {
+        use crate::config::raw::AclProfile;
+
+        let session_id = mk_session(&[]);
+        let uuid: Uuid = session_id.parse().unwrap();
+        session_add_tags(&session_id, &["blocklist"]).unwrap();
+        // a single policy, that does not deny the request
+        match session_acl_check(&session_id).unwrap() {
+            AclResult::Match(bh) => assert!(bh.human.is_none()),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut baseline = SECURITYPOLICY.read(&uuid).unwrap().get(&uuid).unwrap().1.clone();
+        baseline.name = "baseline".to_string();
+        baseline.acl_profile = AclProfile {
+            deny: std::iter::once("blocklist".to_string()).collect(),
+            ..AclProfile::default()
+        };
+        let hostmap = SECURITYPOLICY.read(&uuid).unwrap().get(&uuid).unwrap().0.clone();
+        BASELINES
+            .write(&uuid)
+            .unwrap()
+            .insert(uuid, vec![(hostmap.clone(), baseline)]);
+        if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+            sp.acl_profile.passthrough = std::iter::once("blocklist".to_string()).collect();
+        }
+        // the baseline deny is more restrictive than the passthrough of the matched policy
+        match session_acl_check(&session_id).unwrap() {
+            AclResult::Match(bh) => assert!(!bh.human.unwrap().allowed),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let tags = with_tags(uuid, |tags| Ok(tags.clone())).unwrap();
+        assert!(tags.contains("acl-policy:baseline"));
+        let logs = session_logs(&session_id, LogLevel::Debug).unwrap();
+        assert!(logs
+            .iter()
+            .any(|l| l.stage == Stage::Acl && l.message == "acl-policy decision taken by security policy baseline"));
+        assert!(matches!(
+            session_content_filter_check(&session_id).unwrap(),
+            Decision::Pass
+        ));
+        assert!(!with_tags(uuid, |tags| Ok(tags.contains("cf-policy:baseline"))).unwrap());
+
+        // the clones are checked against the same policies
+        let clone_id = session_clone(&session_id).unwrap();
+        match session_acl_check(&clone_id).unwrap() {
+            AclResult::Match(bh) => assert!(!bh.human.unwrap().allowed),
+            other => panic!("unexpected result {:?}", other),
+        }
+        clean_session(&clone_id).unwrap();
+        // the snapshots reference the baseline policies, as they do for the matched policy
+        let snapshot: serde_json::Value = serde_json::from_str(&session_snapshot(&session_id).unwrap()).unwrap();
+        assert_eq!(
+            snapshot["baselines"],
+            serde_json::json!([{"hostmap": hostmap, "name": "baseline"}])
+        );
+
+        clean_session(&session_id).unwrap();
+        assert!(BASELINES.read(&uuid).unwrap().get(&uuid).is_none());
+    }
+
+    #[test]
+    fn peek() {
+        let session_id = session_init(&mk_request_map()).unwrap();
+        let peeked = session_peek(&session_id).unwrap();
+        assert!(peeked["securitypolicy"].is_null());
+        assert_eq!(peeked["action"], "pass");
+        assert!(peeked["stages"].as_object().unwrap().values().all(|ran| ran == false));
+        clean_session(&session_id).unwrap();
+
+        let session_id = mk_session(&[]);
+        session_add_tags(&session_id, &["peeked"]).unwrap();
+        session_acl_check(&session_id).unwrap();
+        let before = session_serialize_request_map(&session_id).unwrap();
+        let peeked = session_peek(&session_id).unwrap();
+        assert_eq!(peeked["securitypolicy"]["name"], "test");
+        assert_eq!(peeked["tags"]["peeked"], serde_json::Value::Null);
+        assert!(peeked["tags"].as_object().unwrap().contains_key("peeked"));
+        assert_eq!(peeked["stages"]["security_policy"], true);
+        assert_eq!(peeked["stages"]["acl"], true);
+        assert_eq!(peeked["stages"]["content_filter"], false);
+        assert!(peeked["request_info"]["rinfo"].is_object());
+        assert!(peeked["logs"].is_array());
+        // peeking does not alter the session
+        assert_eq!(session_peek(&session_id).unwrap(), peeked);
+        assert_eq!(session_serialize_request_map(&session_id).unwrap(), before);
+        clean_session(&session_id).unwrap();
+        assert!(matches!(session_peek(&session_id), Err(SessionError::UnknownSession)));
+    }
+
+    #[test]
+    fn access_log() {
+        let session_id = mk_session(&[("q", "1")]);
+        session_add_tags(&session_id, &["logged"]).unwrap();
+        let record = session_access_log(&session_id).unwrap();
+        assert_eq!(record.version, ACCESS_LOG_VERSION);
+        assert_eq!(record.decision.action, "pass");
+        assert_eq!(record.response, None);
+        assert_eq!(record.securitypolicy.as_ref().unwrap().hostmap, "test");
+        assert!(record.tags.contains(&"logged".to_string()));
+        assert_eq!(record.request.args.get("q").map(|s| s.as_str()), Some("1"));
+
+        session_set_decision(
+            &session_id,
+            Decision::Action(Action {
+                atype: ActionType::Block,
+                status: 403,
+                decision_reason: DecisionReason::ContentFilter {
+                    rule_ids: vec!["100".to_string(), "101".to_string()],
+                },
+                ..Action::default()
+            }),
+        )
+        .unwrap();
+        let record = session_access_log(&session_id).unwrap();
+        assert_eq!(record.response.as_ref().map(|r| r.status), Some(403));
+        let flat = record.to_key_values();
+        assert_eq!(flat["version"], "1");
+        assert_eq!(flat["decision.action"], "block");
+        assert_eq!(flat["decision.initiator"], "content_filter");
+        assert_eq!(flat["decision.matched_rules"], "100,101");
+        assert_eq!(flat["request.args.q"], "1");
+        assert_eq!(flat["response.status"], "403");
+        assert!(!flat.contains_key("tenant"));
+        assert_eq!(serde_json::to_value(&record).unwrap()["decision"]["blocking"], true);
+
+        clean_session(&session_id).unwrap();
+        assert!(matches!(
+            session_access_log(&session_id),
+            Err(SessionError::UnknownSession)
+        ));
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let session_id = session_init(&mk_request_map()).unwrap();
+        session_add_tags(&session_id, &["snapshotted"]).unwrap();
+        with_tags_mut(session_id.parse().unwrap(), |tags| {
+            tags.insert_with_value("label", "value");
+            Ok(())
+        })
+        .unwrap();
+        let blob = session_snapshot(&session_id).unwrap();
+        let restored = session_restore(&blob).unwrap();
+        assert_ne!(restored, session_id);
+        assert_eq!(
+            session_serialize_request_map(&restored).unwrap(),
+            session_serialize_request_map(&session_id).unwrap()
+        );
+        let rinfo = with_request_info(restored.parse().unwrap(), |rinfo| Ok(rinfo.clone())).unwrap();
+        assert_eq!(rinfo.rinfo.qinfo.args.get_str("a"), Some("b"));
+        assert_eq!(session_snapshot(&restored).unwrap(), blob);
+        assert!(matches!(
+            session_acl_check(&restored),
+            Err(SessionError::UnknownSession)
+        ));
+        clean_session(&restored).unwrap();
+        clean_session(&session_id).unwrap();
+
+        // the security policy is not part of the loaded configuration
+        let session_id = mk_session(&[]);
+        let blob = session_snapshot(&session_id).unwrap();
+        assert!(matches!(session_restore(&blob), Err(SessionError::NoSecurityPolicy)));
+        clean_session(&session_id).unwrap();
+        assert!(matches!(session_restore("{}"), Err(SessionError::DeserializeFailed(_))));
+    }
+
+    #[test]
+    fn tenants() {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["tenant"] = serde_json::json!("session-tenant");
+        assert!(matches!(
+            session_init(&jvalue.to_string()),
+            Err(SessionError::UnknownTenant(t)) if t == "session-tenant"
+        ));
+
+        let mut cfg = Config::empty();
+        cfg.default = Some(crate::config::hostmap::HostMap {
+            id: "__default__".to_string(),
+            name: "tenant hostmap".to_string(),
+            entries: Vec::new(),
+            entries_set: None,
+            default: Some(mk_policy("tenant default")),
+        });
+        crate::config::TENANT_CONFIGS.write().unwrap().insert(
+            "session-tenant".to_string(),
+            std::sync::Arc::new(crate::config::TenantConfig {
+                config: RwLock::new(cfg),
+                hsdb: RwLock::new(None),
+            }),
+        );
+        let session_id = session_init(&jvalue.to_string()).unwrap();
+        let securitypolicy = session_match_securitypolicy(&session_id).unwrap();
+        assert_eq!(securitypolicy.name, "tenant default");
+        // the default configuration is empty
+        let default_session = session_init(&mk_request_map()).unwrap();
+        assert!(matches!(
+            session_match_securitypolicy(&default_session),
+            Err(SessionError::NoSecurityPolicy)
+        ));
+
+        // snapshots keep the tenant
+        let restored = session_restore(&session_snapshot(&session_id).unwrap()).unwrap();
+        assert_eq!(
+            session_tenant(restored.parse().unwrap()).unwrap().as_deref(),
+            Some("session-tenant")
+        );
+
+        // sessions whose tenant disappeared fail clearly
+        assert!(crate::config::remove_tenant_config("session-tenant").unwrap());
+        assert!(matches!(
+            session_match_securitypolicy(&session_id),
+            Err(SessionError::UnknownTenant(_))
+        ));
+        for s in &[session_id, default_session, restored] {
+            clean_session(s).unwrap();
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        assert!(matches!(
+            session_evaluate("foo"),
+            Err(SessionError::InvalidSessionId(_))
+        ));
+        assert!(matches!(session_init("{}"), Err(SessionError::DeserializeFailed(_))));
+        let err = session_acl_check(&Uuid::new_v4().to_string()).unwrap_err();
+        assert!(matches!(err, SessionError::UnknownSession));
+        assert_eq!(err.to_string(), "Unknown session id");
+    }
+
+    #[test]
+    fn evaluate_unknown_session() {
+        let uuid = Uuid::new_v4().to_string();
+        assert!(session_evaluate(&uuid).is_err());
+        assert!(session_logs(&uuid, LogLevel::Debug).unwrap().is_empty());
+    }
+
+    #[test]
+    fn evaluate_keeps_logs() {
+        let session_id = session_init(&mk_request_map()).unwrap();
+        // the default configuration is empty, there is no matching security policy
+        let decision = session_evaluate(&session_id).unwrap();
+        assert!(matches!(decision, Decision::Pass));
+        assert!(!session_logs(&session_id, LogLevel::Debug).unwrap().is_empty());
+        clean_session(&session_id).unwrap();
+        assert!(session_logs(&session_id, LogLevel::Debug).unwrap().is_empty());
+    }
+
+    /// creates a session with a default security policy, bypassing the configuration
+    fn mk_session(args: &[(&str, &str)]) -> String {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["args"] = serde_json::json!(args.iter().cloned().collect::<HashMap<_, _>>());
+        mk_session_from(&jvalue)
+    }
+
+    /// a session with the test security policy
+    fn mk_session_from(jvalue: &serde_json::Value) -> String {
+        let session_id = session_init(&jvalue.to_string()).unwrap();
+        let uuid: Uuid = session_id.parse().unwrap();
+        SECURITYPOLICY
+            .write(&uuid)
+            .unwrap()
+            .insert(uuid, ("test".to_string(), mk_policy("test")));
+        session_id
+    }
+
+    struct TestGrasshopper;
+
+    impl Grasshopper for TestGrasshopper {
+        fn js_app(&self) -> Option<String> {
+            Some("challenge();".to_string())
+        }
+        fn js_bio(&self) -> Option<String> {
+            None
+        }
+        fn parse_rbzid(&self, rbzid: &str, _seed: &str) -> Option<bool> {
+            Some(rbzid == "valid")
+        }
+        fn gen_new_seed(&self, _seed: &str) -> Option<String> {
+            Some("seed".to_string())
+        }
+        fn verify_workproof(&self, _workproof: &str, _seed: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn challenge_decisions() {
+        use crate::interface::{ActionType, SimpleAction, SimpleActionT};
+
+        let challenge = || {
+            SimpleDecision::Action(
+                SimpleAction {
+                    atype: SimpleActionT::Challenge,
+                    status: 503,
+                    reason: "limit".to_string(),
+                    template: None,
+                    retry_after: None,
+                    ratelimit: None,
+                    response_headers: HashMap::new(),
+                },
+                serde_json::json!({"initiator": "limit"}),
+                DecisionReason::Unknown,
+            )
+        };
+        let bot = mk_session(&[]);
+        let uuid: Uuid = bot.parse().unwrap();
+        let mut logs = Logs::default();
+        match challenge_decision(&mut logs, uuid, challenge(), Some(TestGrasshopper)).unwrap() {
+            Decision::Action(a) => {
+                assert_eq!(a.status, 247);
+                assert_eq!(
+                    a.extra_tags,
+                    Some(["challenge_phase01".to_string()].iter().cloned().collect())
+                );
+            }
+            _ => panic!("challenge expected"),
+        }
+        // the challenge can't be rendered
+        match challenge_decision::<TestGrasshopper>(&mut logs, uuid, challenge(), None).unwrap() {
+            Decision::Action(a) => assert_eq!((a.atype, a.status), (ActionType::Block, 503)),
+            _ => panic!("block expected"),
+        }
+        assert!(matches!(
+            challenge_decision(&mut logs, uuid, SimpleDecision::Pass, Some(TestGrasshopper)).unwrap(),
+            Decision::Pass
+        ));
+
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["cookies"] = serde_json::json!({"rbzid": "valid"});
+        let human: Uuid = session_init(&jvalue.to_string()).unwrap().parse().unwrap();
+        match challenge_decision(&mut logs, human, challenge(), Some(TestGrasshopper)).unwrap() {
+            Decision::Action(a) => assert_eq!(a.atype, ActionType::Monitor),
+            _ => panic!("monitor expected"),
+        }
+    }
+
+    #[test]
+    fn decision_override() {
+        let session_id = mk_session(&[("q", "1' or '1'='1")]);
+        let uuid: Uuid = session_id.parse().unwrap();
+        let smuggled = [("content-length", "4"), ("transfer-encoding", "chunked")];
+        RINFOS.write(&uuid).unwrap().get_mut(&uuid).unwrap().header_list =
+            smuggled.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert!(matches!(session_current_decision(&session_id).unwrap(), Decision::Pass));
+
+        // the monitor action is replaced by the block action, but not the other way around
+        session_smuggling_check(&session_id).unwrap();
+        assert!(!session_current_decision(&session_id).unwrap().is_blocking());
+        assert!(session_content_filter_check(&session_id).unwrap().is_blocking());
+        session_smuggling_check(&session_id).unwrap();
+        assert!(session_current_decision(&session_id).unwrap().is_blocking());
+
+        let previous = session_set_decision(&session_id, Decision::Pass).unwrap();
+        assert!(previous.is_blocking());
+        assert!(matches!(session_current_decision(&session_id).unwrap(), Decision::Pass));
+        assert!(with_tags(uuid, |tags| Ok(tags.contains("smuggling-suspected"))).unwrap());
+        let raw = session_serialize_request_map(&session_id).unwrap();
+        assert!(raw.get("decision_reason").is_none());
+        let logs = session_logs(&session_id, LogLevel::Info).unwrap();
+        assert!(logs.iter().any(|l| l.stage == Stage::Override));
+
+        clean_session(&session_id).unwrap();
+        assert!(session_current_decision(&session_id).is_err());
+        assert!(session_set_decision(&session_id, Decision::Pass).is_err());
+    }
+
+    #[test]
+    fn content_filter_report_only() {
+        let session_id = mk_session(&[("q", "1' or '1'='1")]);
+        assert!(session_content_filter_check(&session_id).unwrap().is_blocking());
+        assert!(with_tags(session_id.parse().unwrap(), |tags| Ok(
+            !tags.contains("cf-rule:libinjection-sqli")
+        ))
+        .unwrap());
+
+        let decision = session_content_filter_check_mode(&session_id, true).unwrap();
+        assert!(matches!(decision, Decision::Pass));
+        assert!(with_tags(session_id.parse().unwrap(), |tags| Ok(
+            tags.contains("cf-rule:libinjection-sqli")
+        ))
+        .unwrap());
+        let logs = session_logs(&session_id, LogLevel::Debug).unwrap();
+        assert!(logs.iter().any(|l| l.message.contains("would have returned")));
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn logs_levels_and_stages() {
+        let session_id = mk_session(&[("q", "1' or '1'='1")]);
+        session_content_filter_check_mode(&session_id, true).unwrap();
+        session_evaluate(&session_id).unwrap();
+        let logs = session_logs(&session_id, LogLevel::Debug).unwrap();
+        // the logs of both calls are kept, in order
+        let report = logs
+            .iter()
+            .position(|l| l.message.contains("would have returned"))
+            .unwrap();
+        assert_eq!(logs[report].stage, Stage::ContentFilter);
+        assert_eq!(logs[report].level, LogLevel::Info);
+        assert!(logs.len() > report + 1);
+        assert!(logs[report + 1..].iter().all(|l| l.stage == Stage::Evaluate));
+
+        let infos = session_logs(&session_id, LogLevel::Info).unwrap();
+        assert!(infos.iter().all(|l| l.level >= LogLevel::Info));
+        assert!(infos.iter().any(|l| l.stage == Stage::ContentFilter));
+        assert!(session_logs(&session_id, LogLevel::Error).unwrap().is_empty());
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn args_limits() {
+        let (mut rinfo, _) = mk_jmap(&[], None, false).into_request_info(&mut Logs::default());
+        for i in 0..4 {
+            rinfo.rinfo.qinfo.args.add(format!("a{}", i), "x".repeat(10));
+        }
+        let check = |profile: &crate::config::contentfilter::ContentFilterProfile| {
+            content_filter_check(&rinfo, profile, &HSDB.read().unwrap())
+                .unwrap_err()
+                .to_action()
+                .extra_tags
+                .unwrap()
+        };
+
+        let mut profile = crate::config::contentfilter::ContentFilterProfile::default();
+        profile.sections.args.max_count = 3;
+        assert!(check(&profile).contains("too-many-args"));
+
+        let mut profile = crate::config::contentfilter::ContentFilterProfile::default();
+        profile.sections.args.max_length = 5;
+        assert!(check(&profile).contains("arg-too-long"));
+
+        let profile = crate::config::contentfilter::ContentFilterProfile {
+            max_total_args_length: Some(20),
+            ..Default::default()
+        };
+        assert!(check(&profile).contains("arg-too-long"));
+    }
+
+    #[test]
+    fn learning_mode() {
+        use crate::config::contentfilter::ContentFilterProfile;
+        use crate::learning::{learning_export, set_learning};
+
+        let profile = ContentFilterProfile::default();
+        let check = |path: &str, args: &[(&str, &str)]| {
+            let mut jmap = mk_jmap(&[("user-agent", "learner")], None, false);
+            jmap.attrs.path = path.to_string();
+            jmap.attrs.uri = path.to_string();
+            for (k, v) in args {
+                jmap.args.add(k.to_string(), v.to_string());
+            }
+            let (rinfo, _) = jmap.into_request_info(&mut Logs::default());
+            content_filter_check(&rinfo, &profile, &HSDB.read().unwrap()).unwrap();
+        };
+        check("/learning/1", &[("page", "2")]);
+        set_learning(true);
+        check("/learning/2", &[("page", "3"), ("q", "some text")]);
+        check("/learning/345", &[("page", "10")]);
+        set_learning(false);
+        check("/learning/6", &[("page", "not a number")]);
+
+        let export = learning_export();
+        let endpoint = &export["endpoints"]["/learning/{id}"];
+        assert_eq!(endpoint["requests"], 2);
+        assert_eq!(
+            endpoint["args"]["page"],
+            serde_json::json!({"count": 2, "types": ["int"], "min_length": 1, "max_length": 2})
+        );
+        assert_eq!(endpoint["args"]["q"]["types"], serde_json::json!(["text"]));
+        assert_eq!(endpoint["headers"]["user-agent"]["count"], 2);
+        let draft = &export["drafts"]["/learning/{id}"];
+        assert_eq!(draft["max_args_count"], 2);
+        assert_eq!(
+            draft["args"]["names"][0],
+            serde_json::json!({"key": "page", "reg": "(?:^-?[0-9]+$)", "restrict": false, "exclusions": null})
+        );
+    }
+
+    #[test]
+    fn reputation_tags() {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["attrs"]["ip"] = serde_json::json!("198.51.100.91");
+        let first = session_init(&jvalue.to_string()).unwrap();
+        assert_eq!(session_report_bad(&first, 6.0).unwrap(), Some(6.0));
+        clean_session(&first).unwrap();
+
+        let reputation = |jvalue: &serde_json::Value| {
+            let session_id = session_init(&jvalue.to_string()).unwrap();
+            session_tag_request(&session_id).unwrap();
+            let uuid: Uuid = session_id.parse().unwrap();
+            let high = with_tags(uuid, |tags| Ok(tags.contains("reputation:high"))).unwrap();
+            (session_id, high)
+        };
+        let (second, high) = reputation(&jvalue);
+        assert!(!high);
+        assert!(session_report_bad(&second, 5.0).unwrap().unwrap() >= 10.0);
+        clean_session(&second).unwrap();
+        let (third, high) = reputation(&jvalue);
+        assert!(high);
+        clean_session(&third).unwrap();
+    }
+
+    #[test]
+    fn clean_tenant() {
+        for tenant in ["cleanup-a", "cleanup-b"] {
+            crate::config::TENANT_CONFIGS.write().unwrap().insert(
+                tenant.to_string(),
+                std::sync::Arc::new(crate::config::TenantConfig {
+                    config: RwLock::new(Config::empty()),
+                    hsdb: RwLock::new(None),
+                }),
+            );
+        }
+        let init = |tenant: &str| {
+            let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+            jvalue["tenant"] = serde_json::json!(tenant);
+            session_init(&jvalue.to_string()).unwrap()
+        };
+        let a = [init("cleanup-a"), init("cleanup-a")];
+        let b = init("cleanup-b");
+        assert_eq!(clean_tenant_sessions("cleanup-a").unwrap(), 2);
+        assert_eq!(clean_tenant_sessions("cleanup-a").unwrap(), 0);
+        for session_id in &a {
+            assert!(matches!(
+                session_tag_request(session_id),
+                Err(SessionError::UnknownSession)
+            ));
+        }
+        assert!(session_serialize_request_map(&b).is_ok());
+        clean_session(&b).unwrap();
+    }
+
+    #[test]
+    fn client_cert_acl() {
+        let acl_allowed = |cert: Option<serde_json::Value>| {
+            let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+            if let Some(cert) = cert {
+                jvalue["attrs"]["client_cert"] = cert;
+            }
+            let session_id = mk_session_from(&jvalue);
+            let uuid: Uuid = session_id.parse().unwrap();
+            if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+                sp.acl_profile.deny.insert("ip:127-0-0-1".to_string());
+                sp.acl_profile.allow.insert("mtls-issuer:partner-ca".to_string());
+            }
+            session_tag_request(&session_id).unwrap();
+            let tags = with_tags(uuid, |tags| Ok(tags.to_sorted_vec())).unwrap();
+            let allowed = match session_acl_check(&session_id).unwrap() {
+                AclResult::Match(bh) => bh.human.map(|d| d.allowed).unwrap_or(true),
+                AclResult::Passthrough(d) => d.allowed,
+            };
+            clean_session(&session_id).unwrap();
+            (allowed, tags)
+        };
+        let cert = |verify: &str, issuer: &str| {
+            serde_json::json!({
+                "subject": "CN=billing,O=Partner",
+                "sans": ["billing.partner.example"],
+                "issuer": issuer,
+                "fingerprint": "AB:CD",
+                "verify": verify
+            })
+        };
+
+        let (allowed, tags) = acl_allowed(Some(cert("SUCCESS", "CN=Partner CA,O=Partner")));
+        assert!(allowed);
+        for tag in [
+            "mtls-verified",
+            "mtls-issuer:partner-ca",
+            "cert-cn:billing",
+            "cert-issuer:partner-ca",
+        ] {
+            assert!(tags.contains(&tag.to_string()), "missing {} in {:?}", tag, tags);
+        }
+
+        let (allowed, tags) = acl_allowed(Some(cert("FAILED:certificate has expired", "CN=Partner CA")));
+        assert!(!allowed);
+        assert!(tags.contains(&"mtls-failed".to_string()));
+        assert!(!tags
+            .iter()
+            .any(|t| t == "mtls-verified" || t.starts_with("mtls-issuer:")));
+
+        let (allowed, _) = acl_allowed(Some(cert("SUCCESS", "/O=Other/CN=Other CA")));
+        assert!(!allowed);
+
+        let (allowed, tags) = acl_allowed(None);
+        assert!(!allowed);
+        assert!(!tags.iter().any(|t| t.starts_with("mtls-") || t.starts_with("cert-")));
+    }
+
+    #[test]
+    fn capacity_excess() {
+        let mut capacity = SessionCapacity::default();
+        assert_eq!(capacity.excess(1000, 1).unwrap(), 0);
+        capacity.max_sessions = Some(10);
+        assert_eq!(capacity.excess(9, 1).unwrap(), 0);
+        assert!(matches!(capacity.excess(10, 1), Err(SessionError::Capacity(10))));
+        capacity.eviction = EvictionPolicy::EvictOldest;
+        assert_eq!(capacity.excess(10, 1).unwrap(), 1);
+        assert_eq!(capacity.excess(12, 3).unwrap(), 5);
+        // evicting every session would not be enough
+        assert!(matches!(capacity.excess(0, 11), Err(SessionError::Capacity(10))));
+    }
+
+    #[test]
+    fn response_phase() {
+        let session_id = mk_session(&[]);
+        let uuid: Uuid = session_id.parse().unwrap();
+        assert!(matches!(
+            session_response_check(&session_id),
+            Err(SessionError::NoResponse)
+        ));
+        {
+            let mut policies = SECURITYPOLICY.write(&uuid).unwrap();
+            let (_, securitypolicy) = policies.get_mut(&uuid).unwrap();
+            securitypolicy.acl_profile.deny.insert("response-class:5xx".to_string());
+            securitypolicy.content_filter_profile.max_response_size = Some(1000);
+        }
+
+        let response = serde_json::json!({"status": 200, "headers": {"Content-Length": "20"}});
+        session_response_init(&session_id, &response.to_string()).unwrap();
+        assert!(with_tags(uuid, |tags| Ok(
+            tags.contains("response-status:200") && tags.contains("response-class:2xx")
+        ))
+        .unwrap());
+        assert!(matches!(session_response_check(&session_id).unwrap(), Decision::Pass));
+        assert_eq!(
+            session_access_log(&session_id).unwrap().response.map(|r| r.status),
+            Some(200)
+        );
+
+        let response = serde_json::json!({"status": 200, "size": 5000});
+        session_response_init(&session_id, &response.to_string()).unwrap();
+        match session_response_check(&session_id).unwrap() {
+            Decision::Action(action) => {
+                assert!(action.block_mode);
+                assert_eq!(action.decision_reason, DecisionReason::ResponseSize { limit: 1000 });
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert!(with_tags(uuid, |tags| Ok(tags.contains("response-too-large"))).unwrap());
+
+        let response = serde_json::json!({"status": 503, "body": "down"});
+        session_response_init(&session_id, &response.to_string()).unwrap();
+        match session_response_check(&session_id).unwrap() {
+            Decision::Action(action) => assert_eq!(
+                action.decision_reason,
+                DecisionReason::Acl {
+                    tags: vec!["response-class:5xx".to_string()]
+                }
+            ),
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert_eq!(
+            session_access_log(&session_id).unwrap().response.map(|r| r.status),
+            Some(403)
+        );
+        clean_session(&session_id).unwrap();
+        assert!(RESPONSES.read(&uuid).unwrap().get(&uuid).is_none());
+    }
+
+    #[test]
+    fn content_filter_feed_unknown_session() {
+        assert!(matches!(
+            session_content_filter_feed(&Uuid::new_v4().to_string(), b"data"),
+            Err(SessionError::UnknownSession)
+        ));
+        let session_id = mk_session(&[]);
+        // nothing was fed
+        assert!(matches!(session_content_filter_finish(&session_id), Ok(Decision::Pass)));
+    }
+
+    #[test]
+    fn decision_reason() {
+        let session_id = mk_session(&[("q", "1' or '1'='1")]);
+        assert!(session_serialize_request_map(&session_id)
+            .unwrap()
+            .get("decision_reason")
+            .is_none());
+
+        let decision = session_content_filter_check(&session_id).unwrap();
+        let expected = DecisionReason::ContentFilter {
+            rule_ids: vec!["libinjection-sqli".to_string()],
+        };
+        assert_eq!(decision.decision_reason(), Some(&expected));
+        let serialized = session_serialize_request_map(&session_id).unwrap();
+        assert_eq!(
+            serialized["decision_reason"],
+            serde_json::json!({"initiator": "content_filter", "rule_ids": ["libinjection-sqli"]})
+        );
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn content_filter_all_matches() {
+        let session_id = mk_session(&[
+            ("q", "1' or '1'='1"),
+            ("x", "<script>alert(1)</script>"),
+            ("ok", "hello"),
+        ]);
+        let mut matches = session_content_filter_matches(&session_id).unwrap();
+        matches.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].rule_id, "libinjection-sqli");
+        assert_eq!(matches[0].section, crate::config::contentfilter::SectionIdx::Args);
+        assert_eq!(matches[0].name, "q");
+        assert_eq!(matches[1].rule_id, "libinjection-xss");
+        assert_eq!(matches[1].matched, "<script>alert(1)</script>");
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn add_tags() {
+        let session_id = mk_session(&[]);
+        session_add_tags(&session_id, &["authenticated", "partner:api-v2"]).unwrap();
+        for bad in &["", "Upper", "a b", ":value", "key:"] {
+            match session_add_tags(&session_id, &["other", bad]) {
+                Err(SessionError::InvalidTag(t)) => assert_eq!(&t, bad),
+                r => panic!("unexpected result for {:?}: {:?}", bad, r),
+            }
+        }
+        let tags = with_tags(session_id.parse().unwrap(), |tags| Ok(tags.clone())).unwrap();
+        assert!(tags.contains("authenticated"));
+        assert!(tags.contains("partner:api-v2"));
+        assert!(!tags.contains("other"));
+        let uuid: Uuid = session_id.parse().unwrap();
+        if let Some((_, sp)) = SECURITYPOLICY.write(&uuid).unwrap().get_mut(&uuid) {
+            sp.acl_profile.passthrough.insert("partner:api-v2".to_string());
+        }
+        assert!(matches!(
+            session_acl_check(&session_id).unwrap(),
+            AclResult::Passthrough(_)
+        ));
+        assert!(matches!(
+            session_add_tags("283a4d8e-2528-4d0a-b6a0-000000000000", &["x"]),
+            Err(SessionError::UnknownSession)
+        ));
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn stable_tag_order() {
+        let names: Vec<String> = (0..32).map(|i| format!("tag-{:02}", i)).collect();
+        let serialized = |reversed: bool| {
+            let session_id = mk_session(&[]);
+            let mut ordered: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+            if reversed {
+                ordered.reverse();
+            }
+            session_add_tags(&session_id, &ordered).unwrap();
+            let out = session_serialize_request_map(&session_id).unwrap().to_string();
+            clean_session(&session_id).unwrap();
+            out
+        };
+        let out = serialized(false);
+        assert_eq!(out, serialized(true));
+        let positions: Vec<usize> = names.iter().map(|n| out.find(&format!("\"{}\"", n)).unwrap()).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        let tags = Tags::from_slice(&names.iter().rev().cloned().collect::<Vec<String>>());
+        assert_eq!(serde_json::to_value(&tags).unwrap(), serde_json::json!(names));
+    }
+
+    #[test]
+    fn tag_values() {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["attrs"]["tags"] = serde_json::json!({"risk-score": "87", "seen": 1});
+        let session_id = session_init(&jvalue.to_string()).unwrap();
+        let uuid: Uuid = session_id.parse().unwrap();
+        with_tags_mut(uuid, |tags| {
+            tags.insert_with_value("geo:country", "FR");
+            tags.insert("geo:country");
+            Ok(())
+        })
+        .unwrap();
+        let tags = with_tags(uuid, |tags| Ok(tags.clone())).unwrap();
+        assert!(tags.contains("risk-score") && tags.contains("seen"));
+        assert_eq!(tags.get_value("geo:country"), Some("FR"));
+        assert_eq!(tags.get_value("seen"), None);
+
+        let serialized = session_serialize_request_map(&session_id).unwrap();
+        assert_eq!(
+            serialized["attrs"]["tags"],
+            serde_json::json!({"risk-score": "87", "seen": 1, "geo:country": "FR"})
+        );
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn limit_status_without_limits() {
+        let session_id = mk_session(&[]);
+        assert!(session_limit_status(&session_id).unwrap().is_empty());
+        clean_session(&session_id).unwrap();
+        assert!(matches!(
+            session_limit_status(&session_id),
+            Err(SessionError::UnknownSession)
+        ));
+    }
+
+    #[test]
+    fn tag_humanity() {
+        let tags = |humanity: Option<bool>| {
+            let session_id = session_init(&mk_request_map()).unwrap();
+            match humanity {
+                None => session_tag_request(&session_id).unwrap(),
+                Some(h) => session_tag_request_with_humanity(&session_id, h).unwrap(),
+            };
+            let uuid: Uuid = session_id.parse().unwrap();
+            let r = with_tags(uuid, |tags| Ok((tags.contains("human"), tags.contains("bot")))).unwrap();
+            clean_session(&session_id).unwrap();
+            r
+        };
+        assert_eq!(tags(None), (false, false));
+        assert_eq!(tags(Some(true)), (true, false));
+        assert_eq!(tags(Some(false)), (false, true));
+    }
+
+    #[test]
+    fn smuggling_check() {
+        let mut jvalue: serde_json::Value = serde_json::from_str(&mk_request_map()).unwrap();
+        jvalue["header_list"] = serde_json::json!([
+            ["Host", "www.example.com"],
+            ["Content-Length", "4"],
+            ["Transfer-Encoding", "chunked"]
+        ]);
+        let session_id = session_init(&jvalue.to_string()).unwrap();
+        let action = match session_smuggling_check(&session_id).unwrap() {
+            Decision::Action(a) => a,
+            _ => panic!("expected an action"),
+        };
+        assert_eq!(action.atype, ActionType::Monitor);
+        let uuid: Uuid = session_id.parse().unwrap();
+        let tagged = |tag: &str| with_tags(uuid, |tags| Ok(tags.contains(tag))).unwrap();
+        assert!(tagged("smuggling-suspected"));
+        assert!(tagged("smuggling:cl-te"));
+        match session_smuggling_check_mode(&session_id, false).unwrap() {
+            Decision::Action(a) => assert_eq!(a.atype, ActionType::Block),
+            _ => panic!("expected an action"),
+        }
+        clean_session(&session_id).unwrap();
+
+        let session_id = session_init(&mk_request_map()).unwrap();
+        assert!(matches!(session_smuggling_check(&session_id).unwrap(), Decision::Pass));
+        clean_session(&session_id).unwrap();
+    }
+
+    #[test]
+    fn timings() {
+        let session_id = session_init(&mk_request_map()).unwrap();
+        let timings = session_timings(&session_id).unwrap();
+        assert_eq!(timings.tagging, 0);
+        session_tag_request(&session_id).unwrap();
+        session_tag_request(&session_id).unwrap();
+        let timings = session_timings(&session_id).unwrap();
+        assert!(timings.tagging > 0);
+        assert_eq!(timings.acl, 0);
+        clean_session(&session_id).unwrap();
+        assert!(matches!(
+            session_timings(&session_id),
+            Err(SessionError::UnknownSession)
+        ));
+    }
+
+    #[test]
+    fn batch_init() {
+        let good = mk_request_map();
+        let maps = [good.as_str(), "{}", good.as_str()];
+
+        match session_init_batch(&maps, false) {
+            Err(SessionError::BatchEntry(1, _)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let res = session_init_batch(&maps, true).unwrap();
+        assert_eq!(res.len(), 3);
+        assert!(matches!(res[1], Err(SessionError::DeserializeFailed(_))));
+        for r in [&res[0], &res[2]].iter() {
+            let session_id = r.as_ref().unwrap();
+            assert!(session_serialize_request_map(session_id).is_ok());
+            clean_session(session_id).unwrap();
+        }
+        assert_ne!(res[0].as_ref().unwrap(), res[2].as_ref().unwrap());
+    }
+
+    #[test]
+    fn active_session_ids() {
+        let session_id = session_init(&mk_request_map()).unwrap();
+        assert!(active_sessions().unwrap().contains(&session_id));
+        assert!(active_session_count().unwrap() >= 1);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(oldest_session_age().unwrap().unwrap() >= Duration::from_millis(5));
+        clean_session(&session_id).unwrap();
+        assert!(!active_sessions().unwrap().contains(&session_id));
+    }
+
+    #[test]
+    fn gc_removes_expired_sessions() {
+        let expired = session_init_with_ttl(&mk_request_map(), Duration::from_secs(0)).unwrap();
+        let alive = session_init_with_ttl(&mk_request_map(), Duration::from_secs(3600)).unwrap();
+        let forever = session_init(&mk_request_map()).unwrap();
+        session_gc().unwrap();
+        assert!(session_serialize_request_map(&expired).is_err());
+        assert!(session_serialize_request_map(&alive).is_ok());
+        assert!(session_serialize_request_map(&forever).is_ok());
+        clean_session(&alive).unwrap();
+        clean_session(&forever).unwrap();
+        assert!(session_serialize_request_map(&forever).is_err());
+    }
 }
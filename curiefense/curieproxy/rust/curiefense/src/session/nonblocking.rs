@@ -0,0 +1,289 @@
+/// async variants of the session functions that only work on the session maps
+///
+/// The session maps are locked without blocking the executor: a task that finds the shard of its session locked
+/// yields, and is woken when the shard is unlocked. The returned futures are `Send`, as no lock is held across an await.
+///
+/// The check functions have no async variants, as they run the security checks, and can query Redis: they should be
+/// run on a thread dedicated to blocking tasks, such as with `tokio::task::spawn_blocking`. Likewise, cleaning a
+/// session that holds concurrency limit slots releases them in the limit store.
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::{
+    decode_request_map, gc_due, release_session_slots, reserve_sessions, track_sessions, untrack_session, update_tags,
+    DecodedSession, SessionError, SessionTimes, SessionTimings, BASELINES, DECISIONS, LOGS, RAW, REASONS, RESPONSES,
+    RINFOS, SECURITYPOLICY, STREAMS, TAGS, TENANTS, TIMES, TIMINGS,
+};
+use crate::interface::Tags;
+
+/// same as `session_init`
+pub async fn session_init_async(encoded_request_map: &str) -> Result<String, SessionError> {
+    init_session_async(encoded_request_map, None).await
+}
+
+/// same as `session_init_with_ttl`
+pub async fn session_init_with_ttl_async(encoded_request_map: &str, ttl: Duration) -> Result<String, SessionError> {
+    init_session_async(encoded_request_map, Some(ttl)).await
+}
+
+async fn init_session_async(encoded_request_map: &str, ttl: Option<Duration>) -> Result<String, SessionError> {
+    if gc_due() {
+        session_gc_async().await?;
+    }
+    let decoded = decode_request_map(encoded_request_map)?;
+    // the capacity lock can't be held across the awaits: concurrent initializations can slightly exceed
+    // `max_sessions`, and evictions take the session locks without yielding
+    drop(reserve_sessions(1)?);
+    let uuid = Uuid::new_v4();
+    let times = SessionTimes {
+        created: Instant::now(),
+        ttl,
+    };
+    insert_session_async(uuid, decoded, times).await?;
+    Ok(format!("{}", uuid))
+}
+
+async fn insert_session_async(uuid: Uuid, session: DecodedSession, times: SessionTimes) -> Result<(), SessionError> {
+    RAW.write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RAW write lock {}", rr)))?
+        .insert(uuid, session.raw);
+    RINFOS
+        .write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get RINFOS write lock {}", rr)))?
+        .insert(uuid, session.rinfo);
+    TAGS.write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS write lock {}", rr)))?
+        .insert(uuid, session.tags);
+    if let Some(tenant) = session.tenant {
+        TENANTS
+            .write_async(&uuid)
+            .await
+            .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TENANTS write lock {}", rr)))?
+            .insert(uuid, tenant);
+    }
+    TIMES
+        .write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMES write lock {}", rr)))?
+        .insert(uuid, times);
+    track_sessions(&[uuid], times.created)?;
+    TIMINGS
+        .write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMINGS write lock {}", rr)))?
+        .insert(uuid, SessionTimings::default());
+    Ok(())
+}
+
+/// same as `clean_session`
+pub async fn clean_session_async(session_id: &str) -> Result<(), SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    remove_session_async(uuid).await;
+    Ok(())
+}
+
+async fn remove_session_async(uuid: Uuid) {
+    if let Ok(mut w) = RAW.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = RINFOS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = TAGS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = SECURITYPOLICY.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = BASELINES.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = LOGS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = TIMES.write_async(&uuid).await {
+        if let Some(times) = w.remove(&uuid) {
+            untrack_session(uuid, times.created);
+        }
+    }
+    if let Ok(mut w) = TIMINGS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = REASONS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = DECISIONS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = RESPONSES.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    if let Ok(mut w) = TENANTS.write_async(&uuid).await {
+        w.remove(&uuid);
+    }
+    // streams are only used by the body streaming functions, and are not sharded
+    if let Ok(mut w) = STREAMS.lock() {
+        w.remove(&uuid);
+    }
+    // only sessions that went through concurrency limits hold slots, releasing them updates the limit store
+    release_session_slots(uuid);
+}
+
+/// same as `session_gc`
+pub async fn session_gc_async() -> Result<usize, SessionError> {
+    let now = Instant::now();
+    let mut expired: Vec<Uuid> = Vec::new();
+    for shard in TIMES.shards() {
+        // each shard is read without waiting, shards that are locked are swept by the next call
+        if let Ok(times) = shard.try_read() {
+            expired.extend(
+                times
+                    .iter()
+                    .filter(|(_, times)| times.is_expired(now))
+                    .map(|(uuid, _)| *uuid),
+            );
+        }
+    }
+    for uuid in &expired {
+        remove_session_async(*uuid).await;
+    }
+    Ok(expired.len())
+}
+
+/// same as `session_add_tags`
+pub async fn session_add_tags_async(session_id: &str, new_tags: &[&str]) -> Result<(), SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    if let Some(bad) = new_tags.iter().find(|t| !Tags::is_valid(t)) {
+        return Err(SessionError::InvalidTag(bad.to_string()));
+    }
+    let mut wtags = TAGS
+        .write_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS write lock {}", rr)))?;
+    let tags = wtags.get_mut(&uuid).ok_or(SessionError::UnknownSession)?;
+    for tag in new_tags {
+        tags.insert(tag);
+    }
+    Ok(())
+}
+
+/// same as `session_serialize_request_map`
+pub async fn session_serialize_request_map_async(session_id: &str) -> Result<serde_json::Value, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    let raw = RAW
+        .read_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on RAW {}", rr)))?
+        .get(&uuid)
+        .cloned()
+        .ok_or(SessionError::UnknownSession)?;
+    let tags = TAGS
+        .read_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TAGS read lock {}", rr)))?
+        .get(&uuid)
+        .cloned()
+        .ok_or(SessionError::UnknownSession)?;
+    let mut out = update_tags(raw, tags)?;
+    let reason = REASONS
+        .read_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get read lock on REASONS {}", rr)))?
+        .get(&uuid)
+        .cloned();
+    if let (Some(reason), Some(obj)) = (reason, out.as_object_mut()) {
+        obj.insert("decision_reason".to_string(), serde_json::to_value(reason)?);
+    }
+    Ok(out)
+}
+
+/// same as `session_timings`
+pub async fn session_timings_async(session_id: &str) -> Result<SessionTimings, SessionError> {
+    let uuid: Uuid = session_id.parse()?;
+    TIMINGS
+        .read_async(&uuid)
+        .await
+        .map_err(|rr| SessionError::LockPoisoned(format!("Could not get TIMINGS read lock {}", rr)))?
+        .get(&uuid)
+        .cloned()
+        .ok_or(SessionError::UnknownSession)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::with_tags;
+    use crate::testutils::request_map;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    /// unparks the thread that polls the future
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// polls the future until it is ready, parking the thread until it is woken, and counting the times it yielded
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let mut pending = 0;
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(out) => return (out, pending),
+                Poll::Pending => {
+                    pending += 1;
+                    std::thread::park();
+                }
+            }
+        }
+    }
+
+    fn assert_send<T: Send>(t: T) -> T {
+        t
+    }
+
+    #[test]
+    fn async_sessions() {
+        let request_map = request_map(&[("host", "www.example.com")]).to_string();
+        let session_id = block_on(assert_send(session_init_async(&request_map))).0.unwrap();
+        let uuid: Uuid = session_id.parse().unwrap();
+
+        block_on(assert_send(session_add_tags_async(&session_id, &["async-tag"])))
+            .0
+            .unwrap();
+        // the sync and async functions work on the same sessions
+        assert!(with_tags(uuid, |tags| Ok(tags.contains("async-tag"))).unwrap());
+        let serialized = block_on(session_serialize_request_map_async(&session_id)).0.unwrap();
+        assert!(serialized["attrs"]["tags"]["async-tag"].is_number());
+
+        // the task yields while the shard of the session is locked by another thread, and is woken when it is
+        // unlocked, instead of being polled in a loop
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let locker = std::thread::spawn(move || {
+            let _guard = TAGS.write(&uuid).unwrap();
+            locked_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        locked_rx.recv().unwrap();
+        let (added, pending) = block_on(session_add_tags_async(&session_id, &["after-unlock"]));
+        locker.join().unwrap();
+        added.unwrap();
+        // parking spuriously returns at times
+        assert!(pending > 0 && pending < 10, "{}", pending);
+
+        block_on(clean_session_async(&session_id)).0.unwrap();
+        assert!(matches!(
+            block_on(session_timings_async(&session_id)).0,
+            Err(SessionError::UnknownSession)
+        ));
+    }
+}
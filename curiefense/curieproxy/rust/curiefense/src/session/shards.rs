@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "async")]
+use std::sync::TryLockResult;
+use std::sync::{LockResult, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::task::Waker;
+use uuid::Uuid;
+
+/// number of shards of each session map, a power of two
+pub const SHARDS: usize = 64;
+
+/// the index of the shard of a session
+fn shard_index(uuid: &Uuid) -> usize {
+    uuid.as_u128() as usize & (SHARDS - 1)
+}
+
+/// a shard of a session map, with the tasks waiting for it to be unlocked, see `read_async` and `write_async`
+pub struct Shard<V> {
+    lock: RwLock<HashMap<Uuid, V>>,
+    waiters: Mutex<Vec<Waker>>,
+    /// the number of parked wakers, so that releasing a lock does not take the waiters lock when nobody waits
+    waiting: AtomicUsize,
+}
+
+impl<V> Shard<V> {
+    fn new() -> Self {
+        Shard {
+            lock: RwLock::new(HashMap::new()),
+            waiters: Mutex::new(Vec::new()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn read(&self) -> LockResult<ShardReadGuard<'_, V>> {
+        wrap_result(self.lock.read(), |guard| ShardReadGuard {
+            guard: Some(guard),
+            shard: self,
+        })
+    }
+
+    pub fn write(&self) -> LockResult<ShardWriteGuard<'_, V>> {
+        wrap_result(self.lock.write(), |guard| ShardWriteGuard {
+            guard: Some(guard),
+            shard: self,
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub fn try_read(&self) -> TryLockResult<ShardReadGuard<'_, V>> {
+        wrap_try_result(self.lock.try_read(), |guard| ShardReadGuard {
+            guard: Some(guard),
+            shard: self,
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub fn try_write(&self) -> TryLockResult<ShardWriteGuard<'_, V>> {
+        wrap_try_result(self.lock.try_write(), |guard| ShardWriteGuard {
+            guard: Some(guard),
+            shard: self,
+        })
+    }
+
+    /// wakes the tasks that found the shard locked, called when a guard is released
+    fn unlocked(&self) {
+        if self.waiting.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let wakers = match self.waiters.lock() {
+            Ok(mut w) => {
+                self.waiting.store(0, Ordering::SeqCst);
+                std::mem::take(&mut *w)
+            }
+            Err(_) => return,
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+fn wrap_result<G, W>(result: LockResult<G>, wrap: impl FnOnce(G) -> W) -> LockResult<W> {
+    match result {
+        Ok(guard) => Ok(wrap(guard)),
+        Err(rr) => Err(PoisonError::new(wrap(rr.into_inner()))),
+    }
+}
+
+#[cfg(feature = "async")]
+fn wrap_try_result<G, W>(result: TryLockResult<G>, wrap: impl FnOnce(G) -> W) -> TryLockResult<W> {
+    match result {
+        Ok(guard) => Ok(wrap(guard)),
+        Err(std::sync::TryLockError::Poisoned(rr)) => Err(std::sync::TryLockError::Poisoned(PoisonError::new(wrap(
+            rr.into_inner(),
+        )))),
+        Err(std::sync::TryLockError::WouldBlock) => Err(std::sync::TryLockError::WouldBlock),
+    }
+}
+
+/// a read lock on a shard, that wakes the waiting tasks when it is released
+pub struct ShardReadGuard<'a, V> {
+    guard: Option<RwLockReadGuard<'a, HashMap<Uuid, V>>>,
+    shard: &'a Shard<V>,
+}
+
+impl<'a, V> Deref for ShardReadGuard<'a, V> {
+    type Target = HashMap<Uuid, V>;
+
+    fn deref(&self) -> &Self::Target {
+        // only taken by drop
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, V> Drop for ShardReadGuard<'a, V> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.shard.unlocked();
+    }
+}
+
+/// a write lock on a shard, that wakes the waiting tasks when it is released
+pub struct ShardWriteGuard<'a, V> {
+    guard: Option<RwLockWriteGuard<'a, HashMap<Uuid, V>>>,
+    shard: &'a Shard<V>,
+}
+
+impl<'a, V> Deref for ShardWriteGuard<'a, V> {
+    type Target = HashMap<Uuid, V>;
+
+    fn deref(&self) -> &Self::Target {
+        // only taken by drop
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, V> DerefMut for ShardWriteGuard<'a, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // only taken by drop
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, V> Drop for ShardWriteGuard<'a, V> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.shard.unlocked();
+    }
+}
+
+/// a session map, split in shards indexed by the low bits of the session id
+///
+/// sessions that are in different shards never contend on the same lock
+pub struct ShardedMap<V> {
+    shards: [Shard<V>; SHARDS],
+}
+
+impl<V> Default for ShardedMap<V> {
+    fn default() -> Self {
+        ShardedMap {
+            shards: [(); SHARDS].map(|_| Shard::new()),
+        }
+    }
+}
+
+impl<V> ShardedMap<V> {
+    /// the shard of a session
+    pub fn shard(&self, uuid: &Uuid) -> &Shard<V> {
+        &self.shards[shard_index(uuid)]
+    }
+
+    /// locks the shard of a session for reading
+    pub fn read(&self, uuid: &Uuid) -> LockResult<ShardReadGuard<'_, V>> {
+        self.shard(uuid).read()
+    }
+
+    /// locks the shard of a session for writing
+    pub fn write(&self, uuid: &Uuid) -> LockResult<ShardWriteGuard<'_, V>> {
+        self.shard(uuid).write()
+    }
+
+    /// all the shards, for operations that span all sessions
+    pub fn shards(&self) -> impl Iterator<Item = &Shard<V>> {
+        self.shards.iter()
+    }
+
+    /// inserts entries, taking the write lock of each shard only once
+    pub fn insert_batch(&self, entries: Vec<(Uuid, V)>) -> Result<(), PoisonError<()>> {
+        let mut by_shard: Vec<Vec<(Uuid, V)>> = (0..SHARDS).map(|_| Vec::new()).collect();
+        for (uuid, value) in entries {
+            by_shard[shard_index(&uuid)].push((uuid, value));
+        }
+        for (shard, entries) in self.shards.iter().zip(by_shard) {
+            if !entries.is_empty() {
+                shard.write().map_err(|_| PoisonError::new(()))?.extend(entries);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+mod nonblocking {
+    use super::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// a future that tries to lock a shard, and parks its waker until the shard is unlocked when it is held by
+    /// another thread, instead of blocking the executor
+    pub struct Acquire<'a, V, F> {
+        shard: &'a Shard<V>,
+        attempt: F,
+    }
+
+    impl<'a, V, G, F: FnMut(&'a Shard<V>) -> TryLockResult<G> + Unpin> Future for Acquire<'a, V, F> {
+        type Output = LockResult<G>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let shard = self.shard;
+            match (self.attempt)(shard) {
+                Ok(guard) => return Poll::Ready(Ok(guard)),
+                Err(std::sync::TryLockError::Poisoned(rr)) => return Poll::Ready(Err(rr)),
+                Err(std::sync::TryLockError::WouldBlock) => (),
+            }
+            match shard.waiters.lock() {
+                Ok(mut waiters) => {
+                    if !waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                        waiters.push(cx.waker().clone());
+                    }
+                    shard.waiting.store(waiters.len(), Ordering::SeqCst);
+                }
+                // the shard can't be waited for, try again without parking
+                Err(_) => cx.waker().wake_by_ref(),
+            }
+            // the lock may have been released before the waker was parked
+            match (self.attempt)(shard) {
+                Ok(guard) => Poll::Ready(Ok(guard)),
+                Err(std::sync::TryLockError::Poisoned(rr)) => Poll::Ready(Err(rr)),
+                Err(std::sync::TryLockError::WouldBlock) => Poll::Pending,
+            }
+        }
+    }
+
+    impl<V> ShardedMap<V> {
+        /// same as `read`, without blocking the executor
+        pub fn read_async<'a>(
+            &'a self,
+            uuid: &Uuid,
+        ) -> Acquire<'a, V, impl FnMut(&'a Shard<V>) -> TryLockResult<ShardReadGuard<'a, V>> + Unpin> {
+            Acquire {
+                shard: self.shard(uuid),
+                attempt: |shard: &'a Shard<V>| shard.try_read(),
+            }
+        }
+
+        /// same as `write`, without blocking the executor
+        pub fn write_async<'a>(
+            &'a self,
+            uuid: &Uuid,
+        ) -> Acquire<'a, V, impl FnMut(&'a Shard<V>) -> TryLockResult<ShardWriteGuard<'a, V>> + Unpin> {
+            Acquire {
+                shard: self.shard(uuid),
+                attempt: |shard: &'a Shard<V>| shard.try_write(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharding() {
+        let map: ShardedMap<usize> = ShardedMap::default();
+        let uuids: Vec<Uuid> = (0..4096).map(|_| Uuid::new_v4()).collect();
+        for (i, uuid) in uuids.iter().enumerate() {
+            map.write(uuid).unwrap().insert(*uuid, i);
+        }
+        assert_eq!(map.shards().map(|s| s.read().unwrap().len()).sum::<usize>(), 4096);
+        for (i, uuid) in uuids.iter().enumerate() {
+            assert_eq!(map.read(uuid).unwrap().get(uuid), Some(&i));
+        }
+        // random session ids are spread over all the shards
+        assert!(map.shards().all(|s| !s.read().unwrap().is_empty()));
+
+        // a locked shard does not block the other ones
+        let first = uuids[0];
+        let other = *uuids
+            .iter()
+            .find(|u| !std::ptr::eq(map.shard(u), map.shard(&first)))
+            .unwrap();
+        let _guard = map.write(&first).unwrap();
+        assert!(map.shard(&other).lock.try_write().is_ok());
+    }
+
+    #[test]
+    fn batch_insert() {
+        let map: ShardedMap<usize> = ShardedMap::default();
+        let entries: Vec<(Uuid, usize)> = (0..200).map(|i| (Uuid::new_v4(), i)).collect();
+        map.insert_batch(entries.clone()).unwrap();
+        for (uuid, i) in &entries {
+            assert_eq!(map.read(uuid).unwrap().get(uuid), Some(i));
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/// HTTP request smuggling indicators
+///
+/// A front-end and a back-end server that disagree on where a request ends can be abused to hide a request in the
+/// body of another one. These checks look for the header combinations that lead to such disagreements, using the
+/// ordered header list, so that repeated headers are seen.
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+
+use crate::interface::{Action, ActionType, DecisionReason};
+use crate::utils::RequestInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SmugglingIndicator {
+    /// both `content-length` and `transfer-encoding` are present
+    #[serde(rename = "cl-te")]
+    ContentLengthAndTransferEncoding,
+    /// several `content-length` values, that differ
+    #[serde(rename = "cl-cl")]
+    ConflictingContentLength,
+    /// several `transfer-encoding` headers
+    #[serde(rename = "te-te")]
+    RepeatedTransferEncoding,
+    /// a `transfer-encoding` header whose name, or `chunked` value, is altered
+    #[serde(rename = "te-obfuscated")]
+    ObfuscatedTransferEncoding,
+}
+
+impl SmugglingIndicator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SmugglingIndicator::ContentLengthAndTransferEncoding => "cl-te",
+            SmugglingIndicator::ConflictingContentLength => "cl-cl",
+            SmugglingIndicator::RepeatedTransferEncoding => "te-te",
+            SmugglingIndicator::ObfuscatedTransferEncoding => "te-obfuscated",
+        }
+    }
+}
+
+/// token characters, as defined in RFC 9110
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// a header name that only differs from `transfer-encoding` by its spacing or separator, such as `transfer_encoding`
+fn is_altered_te_name(name: &str) -> bool {
+    name != "transfer-encoding" && name.trim().replace('_', "-") == "transfer-encoding"
+}
+
+/// a `transfer-encoding` value mentioning `chunked`, that is not a clean list of codings ending with a single
+/// `chunked`, such as `xchunked`, `chunked\x0b` or `chunked, identity`
+fn is_obfuscated_chunked(value: &str) -> bool {
+    if !value.to_ascii_lowercase().contains("chunked") {
+        return false;
+    }
+    let codings: Vec<&str> = value
+        .split(',')
+        .map(|c| c.trim_matches(|c| c == ' ' || c == '\t'))
+        .collect();
+    let clean = codings.iter().all(|c| !c.is_empty() && c.chars().all(is_tchar));
+    let chunked_count = codings.iter().filter(|c| c.eq_ignore_ascii_case("chunked")).count();
+    let chunked_last = codings
+        .last()
+        .map(|c| c.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+    !(clean && chunked_count == 1 && chunked_last)
+}
+
+/// the smuggling indicators found in the request headers, in the order of `SmugglingIndicator`
+pub fn smuggling_indicators(rinfo: &RequestInfo) -> Vec<SmugglingIndicator> {
+    let mut out = Vec::new();
+    // comma separated values are also split, as some proxies merge repeated headers
+    let content_lengths: HashSet<&str> = rinfo
+        .header_values("content-length")
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .collect();
+    let transfer_encodings: Vec<&str> = rinfo.header_values("transfer-encoding").collect();
+    let altered_te_name = rinfo.header_list.iter().any(|(k, _)| is_altered_te_name(k));
+
+    if !content_lengths.is_empty() && (!transfer_encodings.is_empty() || altered_te_name) {
+        out.push(SmugglingIndicator::ContentLengthAndTransferEncoding);
+    }
+    if content_lengths.len() > 1 {
+        out.push(SmugglingIndicator::ConflictingContentLength);
+    }
+    if transfer_encodings.len() > 1 {
+        out.push(SmugglingIndicator::RepeatedTransferEncoding);
+    }
+    if altered_te_name || transfer_encodings.iter().any(|v| is_obfuscated_chunked(v)) {
+        out.push(SmugglingIndicator::ObfuscatedTransferEncoding);
+    }
+    out
+}
+
+/// the action for a suspicious request, that only blocks when `block` is set
+pub fn smuggling_action(indicators: &[SmugglingIndicator], block: bool) -> Action {
+    let names: Vec<String> = indicators.iter().map(|i| i.name().to_string()).collect();
+    Action {
+        atype: if block { ActionType::Block } else { ActionType::Monitor },
+        block_mode: block,
+        ban: false,
+        status: 400,
+        headers: None,
+        reason: json!({
+            "initiator": "smuggling",
+            "indicators": names,
+        }),
+        content: "Bad request".to_string(),
+        extra_tags: Some(std::iter::once("smuggling-suspected".to_string()).collect()),
+        decision_reason: DecisionReason::Smuggling { indicators: names },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::Logs;
+    use crate::utils::{map_request, RequestMeta};
+    use std::collections::HashMap;
+
+    fn indicators(headers: &[(&str, &str)]) -> Vec<&'static str> {
+        let meta = RequestMeta {
+            authority: Some("localhost".to_string()),
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            extra: HashMap::new(),
+        };
+        let mut rinfo = map_request(&mut Logs::default(), "1.2.3.4".to_string(), HashMap::new(), meta, None).unwrap();
+        rinfo.header_list = headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        smuggling_indicators(&rinfo).iter().map(|i| i.name()).collect()
+    }
+
+    #[test]
+    fn clean_requests() {
+        assert!(indicators(&[("content-length", "12")]).is_empty());
+        assert!(indicators(&[("transfer-encoding", "gzip, Chunked")]).is_empty());
+        assert!(indicators(&[("content-length", "12"), ("content-length", "12")]).is_empty());
+        assert!(indicators(&[("content-length", "12, 12")]).is_empty());
+    }
+
+    #[test]
+    fn length_conflicts() {
+        let cl_te = [("content-length", "12"), ("transfer-encoding", "chunked")];
+        assert_eq!(indicators(&cl_te), vec!["cl-te"]);
+        assert_eq!(
+            indicators(&[("content-length", "12"), ("content-length", "13")]),
+            vec!["cl-cl"]
+        );
+        assert_eq!(indicators(&[("content-length", "12, 0")]), vec!["cl-cl"]);
+        let te_te = [("transfer-encoding", "chunked"), ("transfer-encoding", "identity")];
+        assert_eq!(indicators(&te_te), vec!["te-te"]);
+    }
+
+    #[test]
+    fn obfuscated_transfer_encoding() {
+        for value in &[
+            "xchunked",
+            "chunked\u{b}",
+            "chunked, identity",
+            "chunked, chunked",
+            "chun ked, chunked",
+            "",
+        ] {
+            let expected: Vec<&str> = if value.is_empty() {
+                vec![]
+            } else {
+                vec!["te-obfuscated"]
+            };
+            assert_eq!(indicators(&[("transfer-encoding", value)]), expected, "{:?}", value);
+        }
+        let altered_name = [("content-length", "4"), ("transfer_encoding", "chunked")];
+        assert_eq!(indicators(&altered_name), vec!["cl-te", "te-obfuscated"]);
+        assert_eq!(indicators(&[("transfer-encoding ", "chunked")]), vec!["te-obfuscated"]);
+    }
+}
@@ -1,10 +1,15 @@
+use crate::anonymous::anonymous_categories;
 use crate::config::globalfilter::{PairEntry, GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterSSection, SingleEntry};
 use crate::config::raw::Relation;
+use crate::config::settings::FingerprintFields;
 use crate::config::Config;
-use crate::interface::{SimpleActionT, SimpleDecision, Tags};
+use crate::interface::{DecisionReason, SimpleActionT, SimpleDecision, Tags};
+use crate::reputation::reputation_score;
 use crate::requestfields::RequestField;
-use crate::utils::RequestInfo;
-use std::net::IpAddr;
+use crate::schedule::{TimeZone, TIME_TAGS};
+use crate::utils::{ip_forms, ip_in_net, CertVerification, ClientCert, RequestInfo};
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
 
 fn check_relation<A, F>(rinfo: &RequestInfo, rel: Relation, elems: &[A], checker: F) -> bool
 where
@@ -17,27 +22,48 @@ where
 }
 
 fn check_pair(pr: &PairEntry, s: &RequestField) -> bool {
-    s.get(&pr.key)
-        .map(|v| &pr.exact == v || pr.re.as_ref().map(|re| re.is_match(v)).unwrap_or(false))
-        .unwrap_or(false)
+    s.get(&pr.key).map(|v| check_pair_value(pr, v)).unwrap_or(false)
 }
 
+fn check_pair_value(pr: &PairEntry, v: &str) -> bool {
+    pr.exact == v || pr.re.as_ref().map(|re| re.is_match(v)).unwrap_or(false)
+}
+
+/// headers that can only appear once, a duplicate is a sign of request smuggling, or of a filter evasion attempt
+pub const SINGULAR_HEADERS: &[&str] = &[
+    "authorization",
+    "content-length",
+    "content-type",
+    "host",
+    "origin",
+    "proxy-authorization",
+    "referer",
+    "user-agent",
+];
+
 fn check_single(pr: &SingleEntry, s: &str) -> bool {
     pr.exact == s || pr.re.as_ref().map(|re| re.is_match(s)).unwrap_or(false)
 }
 
 fn check_entry(rinfo: &RequestInfo, sub: &GlobalFilterEntry) -> bool {
     let c = match &sub.entry {
-        GlobalFilterEntryE::Ip(addr) => rinfo.rinfo.geoip.ip.map(|i| &i == addr).unwrap_or(false),
-        GlobalFilterEntryE::Network(net) => rinfo.rinfo.geoip.ip.map(|i| net.contains(&i)).unwrap_or(false),
-        GlobalFilterEntryE::Range4(net4) => match rinfo.rinfo.geoip.ip {
-            Some(IpAddr::V4(ip4)) => net4.contains(&ip4),
-            _ => false,
-        },
-        GlobalFilterEntryE::Range6(net6) => match rinfo.rinfo.geoip.ip {
-            Some(IpAddr::V6(ip6)) => net6.contains(&ip6),
-            _ => false,
+        GlobalFilterEntryE::Ip(addr) => rinfo
+            .rinfo
+            .geoip
+            .ip
+            .map(|i| ip_forms(i) == ip_forms(*addr))
+            .unwrap_or(false),
+        GlobalFilterEntryE::Network(net) => rinfo.rinfo.geoip.ip.map(|i| ip_in_net(net, i)).unwrap_or(false),
+        GlobalFilterEntryE::Range4(net4) => match rinfo.rinfo.geoip.ip.and_then(|i| ip_forms(i).0) {
+            Some(ip4) => net4.contains(&ip4),
+            None => false,
         },
+        GlobalFilterEntryE::Range6(net6) => rinfo
+            .rinfo
+            .geoip
+            .ip
+            .map(|i| net6.contains(&ip_forms(i).1))
+            .unwrap_or(false),
         GlobalFilterEntryE::Path(pth) => check_single(pth, &rinfo.rinfo.qinfo.qpath),
         GlobalFilterEntryE::Query(qry) => check_single(qry, &rinfo.rinfo.qinfo.query),
         GlobalFilterEntryE::Uri(uri) => rinfo
@@ -55,7 +81,10 @@ fn check_entry(rinfo: &RequestInfo, sub: &GlobalFilterEntry) -> bool {
             .map(|ccty| check_single(cty, ccty.to_lowercase().as_ref()))
             .unwrap_or(false),
         GlobalFilterEntryE::Method(mtd) => check_single(mtd, &rinfo.rinfo.meta.method),
-        GlobalFilterEntryE::Header(hdr) => check_pair(hdr, &rinfo.headers),
+        // duplicated headers are matched both as a whole, and value by value
+        GlobalFilterEntryE::Header(hdr) => {
+            check_pair(hdr, &rinfo.headers) || rinfo.header_values(&hdr.key).any(|v| check_pair_value(hdr, v))
+        }
         GlobalFilterEntryE::Args(arg) => check_pair(arg, &rinfo.rinfo.qinfo.args),
         GlobalFilterEntryE::Cookies(arg) => check_pair(arg, &rinfo.cookies),
         GlobalFilterEntryE::Asn(asn) => rinfo.rinfo.geoip.asn.map(|casn| casn == *asn).unwrap_or(false),
@@ -75,10 +104,161 @@ fn check_subsection(rinfo: &RequestInfo, sub: &GlobalFilterSSection) -> bool {
     check_relation(rinfo, sub.relation, &sub.entries, check_entry)
 }
 
+/// the values an entry regex is matched against
+fn entry_values(rinfo: &RequestInfo, entry: &GlobalFilterEntryE) -> Vec<String> {
+    match entry {
+        GlobalFilterEntryE::Path(_) => vec![rinfo.rinfo.qinfo.qpath.clone()],
+        GlobalFilterEntryE::Query(_) => vec![rinfo.rinfo.qinfo.query.clone()],
+        GlobalFilterEntryE::Uri(_) => rinfo.rinfo.qinfo.uri.iter().cloned().collect(),
+        GlobalFilterEntryE::Country(_) => rinfo.rinfo.geoip.country_iso.iter().map(|c| c.to_lowercase()).collect(),
+        GlobalFilterEntryE::Method(_) => vec![rinfo.rinfo.meta.method.clone()],
+        GlobalFilterEntryE::Company(_) => rinfo.rinfo.geoip.company.iter().cloned().collect(),
+        GlobalFilterEntryE::Authority(_) => vec![rinfo.rinfo.host.clone()],
+        GlobalFilterEntryE::Header(hdr) => rinfo
+            .headers
+            .get(&hdr.key)
+            .cloned()
+            .into_iter()
+            .chain(rinfo.header_values(&hdr.key).map(|v| v.to_string()))
+            .collect(),
+        GlobalFilterEntryE::Args(arg) => rinfo.rinfo.qinfo.args.get(&arg.key).cloned().into_iter().collect(),
+        GlobalFilterEntryE::Cookies(arg) => rinfo.cookies.get(&arg.key).cloned().into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// the named groups captured by the regexes of the entries that are not negated, the first capture of a group wins
+fn section_captures(rinfo: &RequestInfo, sections: &[GlobalFilterSSection]) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    for entry in sections.iter().flat_map(|s| s.entries.iter()).filter(|e| !e.negated) {
+        let re = match entry.entry.regex() {
+            Some(re) if re.capture_names().flatten().next().is_some() => re,
+            _ => continue,
+        };
+        if let Some(captures) = entry_values(rinfo, &entry.entry).iter().find_map(|v| re.captures(v)) {
+            for name in re.capture_names().flatten() {
+                if let Some(m) = captures.name(name) {
+                    out.entry(name.to_string()).or_insert_with(|| m.as_str().to_string());
+                }
+            }
+        }
+    }
+    out
+}
+
+/// a path segment that is likely an identifier, such as a number, an uuid or a hash
+fn is_dynamic_segment(segment: &str) -> bool {
+    (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()))
+        || (segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-'))
+}
+
+/// the path, with its dynamic segments replaced by `{id}`, such as `/users/{id}/orders`
+pub(crate) fn path_template(path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| if is_dynamic_segment(s) { "{id}" } else { s })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// a hash of the shape of the request, that does not depend on the argument values, see `FingerprintFields`
+pub fn request_fingerprint(fields: &FingerprintFields, rinfo: &RequestInfo) -> String {
+    let mut shape = String::new();
+    if fields.method {
+        shape += "method:";
+        shape += &rinfo.rinfo.meta.method.to_uppercase();
+        shape.push('\n');
+    }
+    if fields.path {
+        shape += "path:";
+        shape += &path_template(&rinfo.rinfo.qinfo.qpath);
+        shape.push('\n');
+    }
+    if fields.args {
+        let mut names: Vec<&str> = rinfo.rinfo.qinfo.args.iter().map(|(k, _)| k.as_str()).collect();
+        names.sort_unstable();
+        shape += "args:";
+        shape += &names.join("&");
+        shape.push('\n');
+    }
+    for name in &fields.headers {
+        shape += name;
+        shape.push(':');
+        shape += rinfo.headers.get_str(name).unwrap_or("");
+        shape.push('\n');
+    }
+    // 64 bits are enough to group requests
+    format!("{:x}", md5::compute(shape))[..16].to_string()
+}
+
+/// replaces the `time:` tags with those of the `schedule` setting, in the given timezone, or in the timezone of the
+/// schedule
+pub fn tag_time(cfg: &Config, timezone: Option<&TimeZone>, now: SystemTime, tags: &mut Tags) {
+    if let Some(schedule) = &cfg.settings.schedule {
+        for tag in TIME_TAGS.iter() {
+            tags.remove(tag);
+        }
+        for tag in schedule.tags(timezone, now) {
+            tags.insert(tag);
+        }
+    }
+}
+
+/// tags the certificate of a mutual TLS client
+///
+/// `mtls-issuer` is only set for the verified certificates, so that an ACL can allow the clients of a given issuer.
+fn tag_client_cert(cert: &ClientCert, tags: &mut Tags) {
+    match cert.verification {
+        CertVerification::Verified => {
+            tags.insert("mtls-verified");
+            if let Some(issuer) = cert.issuer_name() {
+                tags.insert_qualified("mtls-issuer", issuer);
+            }
+        }
+        CertVerification::Failed => {
+            tags.insert("mtls-failed");
+        }
+        CertVerification::Unverified => {
+            tags.insert("mtls-unverified");
+        }
+    }
+    if let Some(cn) = cert.common_name() {
+        tags.insert_qualified("cert-cn", cn);
+    }
+    if let Some(issuer) = cert.issuer_name() {
+        tags.insert_qualified("cert-issuer", issuer);
+    }
+}
+
 pub fn tag_request(is_human: bool, cfg: &Config, rinfo: &RequestInfo) -> (Tags, SimpleDecision) {
     let mut tags = Tags::default();
     tags.insert_qualified("ip", &rinfo.rinfo.geoip.ipstr);
+    if let Some(ip) = rinfo.rinfo.geoip.ip {
+        for network in cfg.acl_networks.iter().filter(|n| n.contains(ip)) {
+            tags.insert(&network.tag);
+        }
+    }
     tags.insert_qualified("geo", rinfo.rinfo.geoip.country_name.as_deref().unwrap_or("nil"));
+    // only available with a city database
+    if let Some(subdivision) = &rinfo.rinfo.geoip.subdivision {
+        tags.insert_qualified("geo-subdivision", subdivision);
+    }
+    if let Some(city) = &rinfo.rinfo.geoip.city_name {
+        tags.insert_qualified("geo-city", city);
+    }
+    for category in &rinfo.rinfo.geoip.threat_categories {
+        tags.insert(&format!("geo-{}", category));
+    }
+    if let Some(ip) = rinfo.rinfo.geoip.ip {
+        for category in anonymous_categories(ip) {
+            tags.insert_qualified("anon", category.as_str());
+        }
+        let reputation = &cfg.settings.reputation;
+        if let Some(level) = reputation.level(reputation_score(ip, reputation.half_life, Instant::now())) {
+            tags.insert_qualified("reputation", level);
+        }
+    }
     match rinfo.rinfo.geoip.asn {
         None => {
             tags.insert_qualified("asn", "nil");
@@ -86,14 +266,81 @@ pub fn tag_request(is_human: bool, cfg: &Config, rinfo: &RequestInfo) -> (Tags,
         Some(asn) => {
             let sasn = format!("{}", asn);
             tags.insert_qualified("asn", &sasn);
+            tags.insert_qualified("asn", &format!("as{}", asn));
+        }
+    }
+    if let Some(company) = &rinfo.rinfo.geoip.company {
+        tags.insert_qualified("company", company);
+    }
+    tags.insert_qualified("reqfp", &request_fingerprint(&cfg.settings.fingerprint, rinfo));
+    if rinfo.is_preflight() {
+        tags.insert_qualified("method", "preflight");
+    }
+    let method = rinfo.rinfo.meta.method.to_uppercase();
+    if cfg.settings.safe_methods.contains(&method) {
+        tags.insert_qualified("method", "safe");
+    } else {
+        tags.insert_qualified("method", "unsafe");
+    }
+    if let Some(version) = &rinfo.rinfo.http_version {
+        tags.insert_qualified("httpver", version);
+    }
+    if let Some(alpn) = &rinfo.rinfo.alpn {
+        tags.insert_qualified("alpn", alpn);
+    }
+    if let Some(cert) = &rinfo.rinfo.client_cert {
+        tag_client_cert(cert, &mut tags);
+    }
+    if let Some(protocol) = &rinfo.rinfo.upgrade_protocol {
+        tags.insert("upgrade");
+        tags.insert_qualified("upgrade", protocol);
+    }
+    for name in SINGULAR_HEADERS {
+        if rinfo.header_values(name).nth(1).is_some() {
+            tags.insert("duplicate-header");
+            tags.insert_qualified("duplicate-header", name);
+        }
+    }
+    if rinfo.rinfo.decompress_bomb {
+        tags.insert("decompress-bomb");
+    }
+    if rinfo.rinfo.decompress_failed {
+        tags.insert("decompress-failed");
+    }
+    for (_, anomaly, _) in rinfo.field_anomalies() {
+        tags.insert(anomaly.tag());
+    }
+    if let Some(fingerprint) = &rinfo.rinfo.tls_fingerprint {
+        // JA4 fingerprints are made of three parts, separated by underscores
+        let kind = if fingerprint.contains('_') { "ja4" } else { "ja3" };
+        tags.insert_qualified(kind, fingerprint);
+        if let Some(known) = cfg.tls_fingerprints.get(&fingerprint.to_lowercase()) {
+            tags.insert_qualified("tls-client", &known.client);
+            let ua_matches = rinfo
+                .headers
+                .get("user-agent")
+                .map(|ua| known.user_agent.is_match(ua))
+                .unwrap_or(false);
+            if !ua_matches {
+                tags.insert("tls-mismatch");
+            }
         }
     }
     if let Some(container_name) = &cfg.container_name {
         tags.insert_qualified("container", container_name);
     }
+    tag_time(cfg, None, SystemTime::now(), &mut tags);
     for psection in &cfg.globalfilters {
         if check_relation(rinfo, psection.relation, &psection.sections, check_subsection) {
             tags.extend(psection.tags.clone());
+            if !psection.tag_templates.is_empty() {
+                let captures = section_captures(rinfo, &psection.sections);
+                for template in &psection.tag_templates {
+                    if let Some(tag) = template.render(&captures) {
+                        tags.insert(&tag);
+                    }
+                }
+            }
             if let Some(a) = &psection.action {
                 if a.atype == SimpleActionT::Monitor || (a.atype == SimpleActionT::Challenge && is_human) {
                     continue;
@@ -103,6 +350,9 @@ pub fn tag_request(is_human: bool, cfg: &Config, rinfo: &RequestInfo) -> (Tags,
                     SimpleDecision::Action(
                         a.clone(),
                         serde_json::json!({"initiator": "tag action", "tags": psection.tags}),
+                        DecisionReason::GlobalFilter {
+                            tags: psection.tags.to_sorted_vec(),
+                        },
                     ),
                 );
             }
@@ -115,6 +365,8 @@ pub fn tag_request(is_human: bool, cfg: &Config, rinfo: &RequestInfo) -> (Tags,
 mod tests {
     use super::*;
     use crate::config::globalfilter::optimize_ipranges;
+    use crate::config::raw::{RawFingerprintFields, RawSettings};
+    use crate::config::settings::Settings;
     use crate::logs::Logs;
     use crate::utils::map_request;
     use crate::utils::RequestMeta;
@@ -152,6 +404,414 @@ mod tests {
         map_request(&mut logs, "52.78.12.56".to_string(), headers, meta, None).unwrap()
     }
 
+    #[test]
+    fn tag_upgrade() {
+        let cfg = Config::empty();
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(!tags.contains("upgrade"));
+
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.is_upgrade = true;
+        rinfo.rinfo.upgrade_protocol = Some("websocket".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("upgrade"));
+        assert!(tags.contains("upgrade:websocket"));
+    }
+
+    #[test]
+    fn method_categories() {
+        let mut cfg = Config::empty();
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(tags.contains("method:safe"));
+        assert!(!tags.contains("method:unsafe"));
+        assert!(!tags.contains("method:preflight"));
+
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.meta.method = "OPTIONS".to_string();
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(!tags.contains("method:preflight"));
+        rinfo
+            .headers
+            .add("access-control-request-method".to_string(), "PUT".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("method:preflight"));
+        assert!(tags.contains("method:safe"));
+
+        rinfo.rinfo.meta.method = "DELETE".to_string();
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(!tags.contains("method:preflight"));
+        assert!(tags.contains("method:unsafe"));
+
+        // the safe methods are configurable
+        cfg.settings = Settings::resolve(RawSettings {
+            safe_methods: Some(vec!["get".to_string(), "delete".to_string()]),
+            ..RawSettings::default()
+        });
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("method:safe"));
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(tags.contains("method:safe"));
+        rinfo.rinfo.meta.method = "HEAD".to_string();
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("method:unsafe"));
+    }
+
+    #[test]
+    fn time_tags() {
+        use crate::config::raw::RawSchedule;
+        use crate::schedule::Schedule;
+        use std::time::Duration;
+
+        let mut cfg = Config::empty();
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(!tags.as_hash_ref().iter().any(|t| t.starts_with("time:")));
+
+        // every day is a business day, and the business hours last a minute, so that the request is after hours
+        cfg.settings.schedule = Some(
+            Schedule::resolve(&RawSchedule {
+                timezone: None,
+                days: Some(
+                    ["mon", "tue", "wed", "thu", "fri", "sat", "sun"]
+                        .iter()
+                        .map(|d| d.to_string())
+                        .collect(),
+                ),
+                start: Some("00:00".to_string()),
+                end: Some("00:01".to_string()),
+            })
+            .unwrap(),
+        );
+        let (mut tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(tags.contains("time:business-hours") != tags.contains("time:after-hours"));
+        assert!(!tags.contains("time:weekend"));
+
+        // monday 2021-03-29 00:00:30 UTC, that is still sunday in New York
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1616976030);
+        tag_time(&cfg, None, now, &mut tags);
+        assert!(tags.contains("time:business-hours"));
+        assert!(!tags.contains("time:after-hours"));
+        let new_york = TimeZone::parse("America/New_York").unwrap();
+        tag_time(&cfg, Some(&new_york), now, &mut tags);
+        assert!(!tags.contains("time:business-hours"));
+        assert!(tags.contains("time:after-hours"));
+    }
+
+    #[test]
+    fn fingerprint() {
+        let cfg = Config::empty();
+        let fingerprint = |rinfo: &RequestInfo| {
+            let (tags, _) = tag_request(true, &cfg, rinfo);
+            let fps: Vec<String> = tags
+                .as_hash_ref()
+                .iter()
+                .filter(|t| t.starts_with("reqfp:"))
+                .cloned()
+                .collect();
+            assert_eq!(fps.len(), 1);
+            fps[0].clone()
+        };
+        let base = fingerprint(&mk_rinfo());
+        assert_eq!(base.len(), "reqfp:".len() + 16);
+        assert_eq!(base, fingerprint(&mk_rinfo()));
+
+        // argument values and ordering, and identifiers in the path, do not change the fingerprint
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.qinfo.args = RequestField::default();
+        for (k, v) in &[("encoded", "x"), ("lol", "other"), ("bar", "1")] {
+            rinfo.rinfo.qinfo.args.add(k.to_string(), v.to_string());
+        }
+        let mut ids = rinfo.clone();
+        ids.rinfo.qinfo.qpath = "/users/1234/items/0af7651916cd43dd8448eb211c80319c".to_string();
+        let mut other_ids = rinfo.clone();
+        other_ids.rinfo.qinfo.qpath = "/users/42/items/b7ad6b7169203331b7ad6b7169203331".to_string();
+        assert_eq!(fingerprint(&ids), fingerprint(&other_ids));
+        rinfo.rinfo.qinfo.args.add("extra".to_string(), "1".to_string());
+        assert_ne!(fingerprint(&rinfo), fingerprint(&mk_rinfo()));
+        ids.rinfo.qinfo.qpath = "/users/1234/settings".to_string();
+        assert_ne!(fingerprint(&ids), fingerprint(&other_ids));
+
+        let mut post = mk_rinfo();
+        post.rinfo.meta.method = "POST".to_string();
+        assert_ne!(fingerprint(&post), base);
+        let mut json = mk_rinfo();
+        json.headers
+            .add("content-type".to_string(), "application/json".to_string());
+        assert_ne!(fingerprint(&json), base);
+
+        // the fields are configurable
+        let fields = Settings::resolve(RawSettings {
+            fingerprint: Some(RawFingerprintFields {
+                method: Some(false),
+                headers: Some(Vec::new()),
+                ..RawFingerprintFields::default()
+            }),
+            ..RawSettings::default()
+        })
+        .fingerprint;
+        assert!(fields.path && fields.args);
+        assert_eq!(
+            request_fingerprint(&fields, &post),
+            request_fingerprint(&fields, &mk_rinfo())
+        );
+        assert_eq!(
+            request_fingerprint(&fields, &json),
+            request_fingerprint(&fields, &mk_rinfo())
+        );
+    }
+
+    #[test]
+    fn captured_tags() {
+        use crate::config::globalfilter::GlobalFilterSection;
+
+        let raw = serde_json::json!({
+            "id": "tenants",
+            "name": "tenants",
+            "active": true,
+            "tags": ["tenant-api"],
+            "rule": {"relation": "AND", "sections": [{"relation": "AND", "entries": [
+                ["path", "^/t/(?P<slug>[^/]+)/"],
+                ["headers", ["x-api-key", "^(?P<prefix>[a-z]{2})_"]]
+            ]}]},
+            "action": null,
+            "tag_templates": ["tenant:${slug}", "key-${prefix}", "bad-${unknown}", "broken-${slug"]
+        });
+        let mut logs = Logs::default();
+        let mut cfg = Config::empty();
+        cfg.globalfilters =
+            GlobalFilterSection::resolve(&mut logs, vec![serde_json::from_value(raw).unwrap()], &HashMap::new());
+        // the templates with unknown or unterminated groups are rejected
+        let locations: Vec<&str> = logs.logs.iter().filter_map(|l| l.component.as_deref()).collect();
+        assert_eq!(
+            locations,
+            vec![
+                "globalfilter-lists[tenants].tag_templates[2]",
+                "globalfilter-lists[tenants].tag_templates[3]"
+            ]
+        );
+
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.qinfo.qpath = "/t/Acme Corp:admin/users".to_string();
+        rinfo.headers.add("x-api-key".to_string(), "pk_123".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("tenant-api"));
+        // captured values can't add qualifiers
+        assert!(tags.contains("tenant:acme-corp-admin"));
+        assert!(tags.contains("key-pk"));
+
+        // no tags without a match
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.qinfo.qpath = "/t/acme/users".to_string();
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(!tags.contains("tenant-api"));
+        assert!(!tags.as_hash_ref().iter().any(|t| t.starts_with("tenant:")));
+    }
+
+    #[test]
+    fn threat_categories() {
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.geoip.threat_categories = vec!["tor".to_string(), "Anonymizer".to_string()];
+        let (tags, _) = tag_request(true, &Config::empty(), &rinfo);
+        assert!(tags.contains("geo-tor"));
+        assert!(tags.contains("geo-anonymizer"));
+    }
+
+    #[test]
+    fn anonymous_networks() {
+        use crate::anonymous::{clear_anonymous_networks, load_anonymous_networks};
+
+        let path = std::env::temp_dir().join(format!("anonymous-ips-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{"network": "203.0.113.0/24", "category": "tor"}, {"network": "203.0.113.9", "category": "hosting"}]"#,
+        )
+        .unwrap();
+        load_anonymous_networks(path.to_str().unwrap()).unwrap();
+        let tags_of = |ip: &str| {
+            let mut rinfo = mk_rinfo();
+            rinfo.rinfo.geoip.ip = Some(ip.parse().unwrap());
+            tag_request(true, &Config::empty(), &rinfo).0
+        };
+        let tags = tags_of("203.0.113.9");
+        assert!(tags.contains("anon:tor"));
+        assert!(tags.contains("anon:hosting"));
+        assert!(!tags.contains("anon:proxy"));
+        assert!(!tags_of("203.0.114.1").contains("anon:tor"));
+
+        // a failed load keeps the previous networks
+        std::fs::write(&path, "[").unwrap();
+        assert!(load_anonymous_networks(path.to_str().unwrap()).is_err());
+        assert!(tags_of("203.0.113.1").contains("anon:tor"));
+        clear_anonymous_networks();
+        assert!(!tags_of("203.0.113.1").contains("anon:tor"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn acl_networks() {
+        use crate::acl::{check_acl, resolve_acl_networks, AclResult};
+        use crate::config::raw::AclProfile;
+
+        let mut acl = AclProfile::default();
+        acl.deny.insert("ip:10.0.0.0/8".to_string());
+        acl.deny.insert("ip:2001:db8::/32".to_string());
+        acl.deny.insert("ip:1.2.3.4".to_string());
+        let mut cfg = Config::empty();
+        cfg.acl_networks = resolve_acl_networks(&mut acl);
+        assert_eq!(cfg.acl_networks.len(), 2);
+        assert!(acl.deny.contains("ip:10-0-0-0-8"));
+        assert!(acl.deny.contains("ip:1.2.3.4"));
+
+        let samples = [
+            ("9.255.255.255", false),
+            ("10.0.0.0", true),
+            ("10.255.255.255", true),
+            ("11.0.0.0", false),
+            ("::ffff:10.0.0.1", true),
+            ("::ffff:11.0.0.1", false),
+            ("2001:db7:ffff:ffff:ffff:ffff:ffff:ffff", false),
+            ("2001:db8::", true),
+            ("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff", true),
+            ("2001:db9::", false),
+        ];
+        for (ip, expected) in samples.iter() {
+            let mut rinfo = mk_rinfo();
+            rinfo.rinfo.geoip.ip = Some(ip.parse().unwrap());
+            let (tags, _) = tag_request(true, &cfg, &rinfo);
+            let denied = match check_acl(&tags, &acl) {
+                AclResult::Match(bh) => bh.human.map(|d| !d.allowed).unwrap_or(false),
+                AclResult::Passthrough(_) => false,
+            };
+            assert_eq!(denied, *expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn check_entry_ipv4_mapped() {
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.geoip.ip = Some("::ffff:52.78.12.56".parse().unwrap());
+        let entries = [
+            GlobalFilterEntryE::Ip("52.78.12.56".parse().unwrap()),
+            GlobalFilterEntryE::Network("52.78.0.0/16".parse().unwrap()),
+        ];
+        for entry in entries.iter() {
+            let entry = GlobalFilterEntry {
+                negated: false,
+                entry: entry.clone(),
+            };
+            assert!(check_entry(&rinfo, &entry));
+        }
+        assert!(!check_entry(
+            &rinfo,
+            &GlobalFilterEntry {
+                negated: false,
+                entry: GlobalFilterEntryE::Ip("52.78.12.57".parse().unwrap()),
+            }
+        ));
+    }
+
+    #[test]
+    fn geo_tags() {
+        let cfg = Config::empty();
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(!tags.as_hash_ref().iter().any(|t| t.starts_with("geo-")));
+
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.geoip.subdivision = Some("US-CA".to_string());
+        rinfo.rinfo.geoip.city_name = Some("san francisco".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("geo-subdivision:us-ca"));
+        assert!(tags.contains("geo-city:san-francisco"));
+    }
+
+    #[test]
+    fn tls_fingerprint_tags() {
+        use crate::acl::{check_acl, AclResult};
+        use crate::config::raw::{AclProfile, RawTlsFingerprint};
+        use crate::config::tlsfingerprint::tls_fingerprints_resolve;
+
+        let mut cfg = Config::empty();
+        cfg.tls_fingerprints = tls_fingerprints_resolve(
+            &mut Logs::default(),
+            vec![RawTlsFingerprint {
+                fingerprint: "E7D705A3286E19EA42F587B344EE6865".to_string(),
+                client: "Chrome".to_string(),
+                user_agent: "chrome/".to_string(),
+            }],
+        );
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(!tags
+            .as_hash_ref()
+            .iter()
+            .any(|t| t.starts_with("ja3:") || t.starts_with("tls-")));
+
+        // known fingerprint, but curl user agent
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.tls_fingerprint = Some("e7d705a3286e19ea42f587b344ee6865".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("ja3:e7d705a3286e19ea42f587b344ee6865"));
+        assert!(tags.contains("tls-client:chrome"));
+        assert!(tags.contains("tls-mismatch"));
+        let mut acl = AclProfile::default();
+        acl.deny.insert("ja3:e7d705a3286e19ea42f587b344ee6865".to_string());
+        assert!(matches!(check_acl(&tags, &acl), AclResult::Match(_)));
+
+        rinfo.headers.0.insert(
+            "user-agent".to_string(),
+            "Mozilla/5.0 (X11; Linux x86_64) Chrome/96.0.4664.110".to_string(),
+        );
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(!tags.contains("tls-mismatch"));
+
+        // unknown JA4 fingerprint
+        rinfo.rinfo.tls_fingerprint = Some("t13d1516h2_8daaf6152771_b186095e22b6".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("ja4:t13d1516h2-8daaf6152771-b186095e22b6"));
+        assert!(!tags.as_hash_ref().iter().any(|t| t.starts_with("tls-")));
+    }
+
+    #[test]
+    fn asn_acl() {
+        use crate::acl::{check_acl, AclResult};
+        use crate::config::raw::AclProfile;
+
+        let mut acl = AclProfile::default();
+        acl.deny.insert("asn:as13335".to_string());
+        acl.allow_bot.insert("company:hosting-inc".to_string());
+        let cfg = Config::empty();
+
+        let (tags, _) = tag_request(true, &cfg, &mk_rinfo());
+        assert!(tags.contains("asn:nil"));
+        assert!(!tags.as_hash_ref().iter().any(|t| t.starts_with("company:")));
+
+        let mut rinfo = mk_rinfo();
+        rinfo.rinfo.geoip.asn = Some(13335);
+        rinfo.rinfo.geoip.company = Some("Cloudflare, Inc.".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        assert!(tags.contains("asn:13335"));
+        assert!(tags.contains("asn:as13335"));
+        assert!(tags.contains("company:cloudflare--inc-"));
+        match check_acl(&tags, &acl) {
+            AclResult::Match(bh) => {
+                let human = bh.human.unwrap();
+                assert!(!human.allowed);
+                assert_eq!(human.tags, vec!["asn:as13335".to_string()]);
+                assert!(bh.bot.is_none());
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        rinfo.rinfo.geoip.asn = Some(64512);
+        rinfo.rinfo.geoip.company = Some("Hosting Inc".to_string());
+        let (tags, _) = tag_request(true, &cfg, &rinfo);
+        match check_acl(&tags, &acl) {
+            AclResult::Match(bh) => {
+                assert!(bh.human.is_none());
+                assert!(bh.bot.unwrap().allowed);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
     fn t_check_entry(negated: bool, entry: GlobalFilterEntryE) -> bool {
         check_entry(&mk_rinfo(), &GlobalFilterEntry { negated, entry })
     }
@@ -211,6 +871,29 @@ mod tests {
         assert!(r);
     }
 
+    #[test]
+    fn duplicate_headers() {
+        let mut rinfo = mk_rinfo();
+        for (k, v) in &[("user-agent", "evil/1.0"), ("x-forwarded-for", "10.0.0.1")] {
+            rinfo.header_list.push((k.to_string(), v.to_string()));
+        }
+        let (tags, _) = tag_request(true, &Config::empty(), &rinfo);
+        assert!(tags.contains("duplicate-header"));
+        assert!(tags.contains("duplicate-header:user-agent"));
+        // forwarding hops are legitimately repeated
+        assert!(!tags.contains("duplicate-header:x-forwarded-for"));
+
+        // each value is matched individually
+        let entry = GlobalFilterEntry {
+            negated: false,
+            entry: GlobalFilterEntryE::Header(double_re("user-agent", "^evil/")),
+        };
+        assert!(check_entry(&rinfo, &entry));
+        assert!(!check_entry(&mk_rinfo(), &entry));
+        let (tags, _) = tag_request(true, &Config::empty(), &mk_rinfo());
+        assert!(!tags.contains("duplicate-header"));
+    }
+
     #[test]
     fn check_headers_match() {
         let r = t_check_entry(false, GlobalFilterEntryE::Header(double_re("user-agent", "^curl.*")));
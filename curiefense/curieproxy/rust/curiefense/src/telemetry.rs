@@ -0,0 +1,140 @@
+/// OpenTelemetry spans for the session checks
+///
+/// The spans are created by the global tracer of the `opentelemetry` crate, that the application sets up with its
+/// SDK. They are children of the span whose context the global propagator extracts from the `traceparent` attribute,
+/// or header, of the request map.
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, Context};
+
+use crate::acl::{AclDecision, AclResult};
+use crate::interface::Decision;
+
+/// the name of the tracer of the session spans
+pub const TRACER: &str = "curiefense";
+
+/// the `traceparent` of a request, as seen by the propagator
+struct TraceParent<'a>(Option<&'a str>);
+
+impl<'a> Extractor for TraceParent<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        if key == "traceparent" {
+            self.0
+        } else {
+            None
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.map(|_| vec!["traceparent"]).unwrap_or_default()
+    }
+}
+
+/// the context of the parent of the session spans, without a parent span when the `traceparent` is missing or invalid
+pub fn parent_context(traceparent: Option<&str>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&TraceParent(traceparent)))
+}
+
+/// the value of the `curiefense.decision` span attribute
+pub trait SpanOutcome {
+    fn outcome(&self) -> String;
+}
+
+impl SpanOutcome for Decision {
+    fn outcome(&self) -> String {
+        match self {
+            Decision::Pass => "pass".to_string(),
+            Decision::Action(a) | Decision::Ratelimit(a, _) => serde_json::to_value(a.atype)
+                .ok()
+                .and_then(|v| v.as_str().map(|s| s.to_string()))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn acl_outcome(decision: &Option<AclDecision>) -> &'static str {
+    match decision {
+        None => "none",
+        Some(d) if d.allowed => "allow",
+        Some(_) => "deny",
+    }
+}
+
+impl SpanOutcome for AclResult {
+    fn outcome(&self) -> String {
+        match self {
+            AclResult::Passthrough(_) => "passthrough".to_string(),
+            AclResult::Match(bh) => format!("bot:{},human:{}", acl_outcome(&bh.bot), acl_outcome(&bh.human)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_map;
+    use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+    use opentelemetry::{Key, Value};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+
+    fn attribute<'a>(span: &'a SpanData, key: &'static str) -> Option<&'a Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key == Key::from_static_str(key))
+            .map(|kv| &kv.value)
+    }
+
+    #[test]
+    fn session_spans() {
+        use crate::session::*;
+
+        let exporter = InMemorySpanExporter::default();
+        global::set_tracer_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build(),
+        );
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let parent = parent_context(Some(traceparent));
+        let trace_id = parent.span().span_context().trace_id();
+        assert_eq!(trace_id, TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap());
+        assert!(
+            !parent_context(Some("00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01"))
+                .span()
+                .span_context()
+                .is_valid()
+        );
+        assert!(!parent_context(None).span().span_context().is_valid());
+
+        let request_map = request_map(&[("host", "www.example.com"), ("traceparent", traceparent)]).to_string();
+        let session_id = session_init(&request_map).unwrap();
+        session_add_tags(&session_id, &["traced"]).unwrap();
+        // no security policy was selected for the session
+        assert!(session_acl_check(&session_id).is_err());
+        session_smuggling_check(&session_id).unwrap();
+        session_flow_check(&session_id).unwrap();
+        clean_session(&session_id).unwrap();
+
+        // the exporter receives the spans of all the tests
+        let spans: Vec<SpanData> = exporter
+            .get_finished_spans()
+            .unwrap()
+            .into_iter()
+            .filter(|span| span.span_context.trace_id() == trace_id)
+            .collect();
+        let names: Vec<&str> = spans.iter().map(|s| s.name.as_ref()).collect();
+        assert_eq!(names, vec!["curiefense.acl", "curiefense.smuggling", "curiefense.flow"]);
+        for span in spans.iter() {
+            assert_eq!(span.parent_span_id, SpanId::from_hex("b7ad6b7169203331").unwrap());
+            assert!(span.start_time <= span.end_time);
+        }
+        assert!(attribute(&spans[0], "curiefense.error").is_some());
+        assert_eq!(attribute(&spans[1], "curiefense.decision"), Some(&Value::from("pass")));
+        match attribute(&spans[2], "curiefense.tags") {
+            Some(Value::Array(tags)) => assert!(tags.to_string().contains("traced")),
+            other => panic!("unexpected tags {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! helpers shared by the unit tests
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::config::contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
+use crate::config::hostmap::{Rollout, SecurityPolicy};
+use crate::config::raw::{AclProfile, RawContentFilterRule};
+use crate::logs::Logs;
+use crate::session::JRequestMap;
+use crate::utils::RequestInfo;
+
+/// the request map of a `GET /` request from 127.0.0.1, with the given headers
+pub fn request_map(headers: &[(&str, &str)]) -> Value {
+    let headers: serde_json::Map<String, Value> = headers.iter().map(|(k, v)| (k.to_string(), json!(v))).collect();
+    json!({
+        "headers": headers,
+        "cookies": {},
+        "args": {},
+        "attrs": {
+            "path": "/",
+            "method": "GET",
+            "ip": "127.0.0.1",
+            "query": "",
+            "authority": null,
+            "uri": "/",
+            "tags": {}
+        }
+    })
+}
+
+/// the `JRequestMap` of `request_map`
+pub fn mk_jmap(headers: &[(&str, &str)], authority: Option<&str>, prefer_forwarded_host: bool) -> JRequestMap {
+    let mut jvalue = request_map(headers);
+    jvalue["attrs"]["authority"] = json!(authority);
+    jvalue["prefer_forwarded_host"] = json!(prefer_forwarded_host);
+    serde_json::from_value(jvalue).unwrap()
+}
+
+/// the request information of a request map, as built by `session_init`
+pub fn request_info(jvalue: Value) -> RequestInfo {
+    serde_json::from_value::<JRequestMap>(jvalue)
+        .unwrap()
+        .into_request_info(&mut Logs::default())
+        .0
+}
+
+/// the request information of `request_map`, with a single argument
+pub fn arg_request(name: &str, value: &str) -> RequestInfo {
+    let (mut rinfo, _) = mk_jmap(&[], None, false).into_request_info(&mut Logs::default());
+    rinfo.rinfo.qinfo.args.add(name.to_string(), value.to_string());
+    rinfo
+}
+
+/// a security policy with the default profiles, the ACL and the content filter being active
+pub fn mk_policy(name: &str) -> SecurityPolicy {
+    SecurityPolicy {
+        name: name.to_string(),
+        acl_active: true,
+        acl_profile: AclProfile::default(),
+        content_filter_active: true,
+        content_filter_profile: ContentFilterProfile::default(),
+        limits: Vec::new(),
+        methods: None,
+        inspect_preflight: false,
+        max_body_size: None,
+        timezone: None,
+        param_presence: Vec::new(),
+        rollout: Rollout::Disabled,
+    }
+}
+
+/// a content filter rule matching `operand` in all the sections, the other fields can be set with the struct update
+/// syntax
+pub fn content_filter_rule(id: &str, operand: &str) -> RawContentFilterRule {
+    RawContentFilterRule {
+        id: id.to_string(),
+        name: id.to_string(),
+        msg: id.to_string(),
+        operand: operand.to_string(),
+        severity: 5,
+        certainity: 5,
+        category: "test".to_string(),
+        subcategory: "test".to_string(),
+        max_regex_compiled_bytes: None,
+        score: None,
+        json_path: None,
+        sections: None,
+    }
+}
+
+/// the signatures of the rules, compiled as they are when the configuration is loaded
+pub fn content_filter_rules(raws: Vec<RawContentFilterRule>) -> ContentFilterRules {
+    resolve_rules(&mut Logs::default(), raws, &HashMap::new()).unwrap()
+}
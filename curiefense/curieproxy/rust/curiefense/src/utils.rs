@@ -1,29 +1,142 @@
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
 
 pub mod url;
 
 use crate::body::parse_body;
+use crate::config::contentfilter::SectionIdx;
 use crate::config::utils::{RequestSelector, RequestSelectorCondition};
+use crate::decompress::{inspected_body, DecompressionLimits, InspectedBody};
+use crate::geoip::geoip_lookup;
+use crate::graphql::{graphql_info, GraphQlInfo};
 use crate::interface::{Decision, Tags};
+use crate::jsonpath::JsonPaths;
 use crate::logs::Logs;
-use crate::maxmind::{get_asn, get_city, get_country};
-use crate::requestfields::RequestField;
-use crate::utils::url::parse_urlencoded_params;
-
-pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
-    // tries to split the cookie around "="
-    fn to_kv(cook: &str) -> (String, String) {
-        match cook.splitn(2, '=').collect_tuple() {
-            Some((k, v)) => (k.to_string(), v.to_string()),
-            None => (cook.to_string(), String::new()),
+use crate::requestfields::{FieldAnomaly, RequestField};
+use crate::utils::url::{parse_structured_params, parse_urlencoded_params, DEFAULT_MAX_ARG_DEPTH};
+
+/// how cookies that are sent several times with the same name are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieDuplicates {
+    /// only the last value is kept, as most servers do
+    #[default]
+    Last,
+    /// all values are kept, separated by a space
+    All,
+}
+
+/// splits a cookie header into name / value pairs, following RFC 6265
+///
+/// pairs are separated by `;`, and whitespace around names and values is removed. Quoted values can contain `;`, and
+/// are unquoted. Pairs without a `=` have an empty value.
+pub fn parse_cookie_header(cookie: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    for pair in split_cookie_pairs(cookie) {
+        let (name, value) = match pair.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (pair.trim(), ""),
+        };
+        if name.is_empty() && value.is_empty() {
+            continue;
         }
+        out.push((name.to_string(), unquote_cookie_value(value)));
     }
-    for (k, v) in cookie.split("; ").map(to_kv) {
-        cookies.add(k, v);
+    out
+}
+
+/// splits around the `;` separators that are not in a quoted value
+///
+/// an unterminated quote does not protect the rest of the header, so that it can't hide the following cookies
+fn split_cookie_pairs(cookie: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut rest = cookie;
+    while !rest.is_empty() {
+        let end = cookie_pair_end(rest);
+        out.push(&rest[..end]);
+        rest = rest.get(end + 1..).unwrap_or("");
     }
+    out
+}
+
+/// the position of the `;` that ends the first pair of `cookie`, or its length
+fn cookie_pair_end(cookie: &str) -> usize {
+    let unquoted = cookie.find(';').unwrap_or(cookie.len());
+    let value_start = match cookie[..unquoted].find('=') {
+        Some(eq) => eq + 1,
+        None => return unquoted,
+    };
+    let value = &cookie[value_start..];
+    let trimmed = value.trim_start();
+    if !trimmed.starts_with('"') {
+        return unquoted;
+    }
+    let quote_start = value_start + (value.len() - trimmed.len());
+    let mut escaped = false;
+    for (i, c) in cookie[quote_start + 1..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => {
+                let after = quote_start + 1 + i + 1;
+                return cookie[after..].find(';').map(|p| after + p).unwrap_or(cookie.len());
+            }
+            _ => {}
+        }
+    }
+    unquoted
+}
+
+fn unquote_cookie_value(value: &str) -> String {
+    let inner = match value.strip_prefix('"') {
+        Some(v) => v,
+        None => return value.to_string(),
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(n) => out.push(n),
+                None => out.push(c),
+            },
+            // characters after the closing quote are kept, so that they are still inspected
+            '"' => {
+                out.extend(chars);
+                return out;
+            }
+            _ => out.push(c),
+        }
+    }
+    // unterminated quote
+    value.to_string()
+}
+
+/// parses the cookie headers, as there can be several of them with HTTP/2
+///
+/// cookie names are kept as they are, including the `__Host-` and `__Secure-` prefixes
+pub fn cookie_map(cookie_headers: &[&str], duplicates: CookieDuplicates) -> RequestField {
+    let pairs = cookie_headers.iter().flat_map(|c| parse_cookie_header(c));
+    let mut cookies = RequestField::default();
+    match duplicates {
+        CookieDuplicates::All => {
+            for (k, v) in pairs {
+                cookies.add(k, v);
+            }
+        }
+        CookieDuplicates::Last => {
+            let last: HashMap<String, String> = pairs.collect();
+            for (k, v) in last {
+                cookies.add(k, v);
+            }
+        }
+    }
+    cookies
 }
 
 /// Parse raw headers and:
@@ -32,20 +145,40 @@ pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
 ///
 /// Returns (headers, cookies)
 pub fn map_headers(rawheaders: HashMap<String, String>) -> (RequestField, RequestField) {
-    let mut cookies = RequestField::default();
+    let mut cookie_headers = Vec::new();
     let mut headers = RequestField::default();
     for (k, v) in rawheaders {
         let lk = k.to_lowercase();
-        if k == "cookie" {
-            cookie_map(&mut cookies, &v);
+        if lk == "cookie" {
+            cookie_headers.push(v);
         } else {
             headers.add(lk, v);
         }
     }
+    let cookie_headers: Vec<&str> = cookie_headers.iter().map(|s| s.as_str()).collect();
+    let cookies = cookie_map(&cookie_headers, CookieDuplicates::default());
 
     (headers, cookies)
 }
 
+/// parses the `Connection` and `Upgrade` headers, returning the lowercased protocol of upgrade requests
+///
+/// a request is an upgrade request when the `Connection` header has the `upgrade` option, the protocol being the
+/// first entry of the `Upgrade` header
+pub fn upgrade_protocol(headers: &RequestField) -> Option<String> {
+    let is_upgrade = headers
+        .get_str("connection")?
+        .split(',')
+        .any(|opt| opt.trim().eq_ignore_ascii_case("upgrade"));
+    if !is_upgrade {
+        return None;
+    }
+    headers
+        .get_str("upgrade")
+        .and_then(|u| u.split(',').map(|p| p.trim()).find(|p| !p.is_empty()))
+        .map(|p| p.to_lowercase())
+}
+
 /// parses query parameters, such as
 fn parse_query_params(query: &str) -> RequestField {
     let mut rf = RequestField::default();
@@ -59,17 +192,26 @@ fn map_args(logs: &mut Logs, path: &str, mcontent_type: Option<&str>, mbody: Opt
     // this is necessary to do this in this convoluted way so at not to borrow attrs
     let uri = urlencoding::decode(&path).ok();
     let (qpath, query, mut args) = match path.splitn(2, '?').collect_tuple() {
-        Some((qpath, query)) => (qpath.to_string(), query.to_string(), parse_query_params(query)),
+        Some((qpath, query)) => {
+            let mut args = parse_query_params(query);
+            parse_structured_params(&mut args, query, DEFAULT_MAX_ARG_DEPTH);
+            (qpath.to_string(), query.to_string(), args)
+        }
         None => (path.to_string(), String::new(), RequestField::default()),
     };
 
+    let mut json_paths = JsonPaths::new();
     if let Some(body) = mbody {
-        if let Err(rr) = parse_body(logs, &mut args, mcontent_type, body) {
-            // if the body could not be parsed, store it in an argument, as if it was text
-            logs.error(rr);
-            args.add("RAW_BODY".to_string(), String::from_utf8_lossy(body).to_string());
-        } else {
-            logs.debug("body parsed");
+        match parse_body(logs, &mut args, mcontent_type, body) {
+            Err(rr) => {
+                // if the body could not be parsed, store it in an argument, as if it was text
+                logs.error(rr);
+                args.add("RAW_BODY".to_string(), String::from_utf8_lossy(body).to_string());
+            }
+            Ok(paths) => {
+                json_paths = paths;
+                logs.debug("body parsed");
+            }
         }
     }
 
@@ -78,10 +220,11 @@ fn map_args(logs: &mut Logs, path: &str, mcontent_type: Option<&str>, mbody: Opt
         query,
         uri,
         args,
+        json_paths,
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 /// data extracted from the query string
 pub struct QueryInfo {
     /// the "path" portion of the raw query path
@@ -91,13 +234,36 @@ pub struct QueryInfo {
     /// URL decoded path, if decoding worked
     pub uri: Option<String>,
     pub args: RequestField,
+    /// the JSON paths of the arguments that come from a JSON body, see `JsonSelector`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub json_paths: JsonPaths,
+}
+
+/// the IPv4 and IPv6 forms of an address, so that an address and its IPv4-mapped IPv6 counterpart are handled the
+/// same way
+pub fn ip_forms(ip: IpAddr) -> (Option<Ipv4Addr>, Ipv6Addr) {
+    match ip {
+        IpAddr::V4(i4) => (Some(i4), i4.to_ipv6_mapped()),
+        IpAddr::V6(i6) => (i6.to_ipv4_mapped(), i6),
+    }
+}
+
+/// checks whether an address belongs to a network, see `ip_forms`
+pub fn ip_in_net(net: &IpNet, ip: IpAddr) -> bool {
+    let (i4, i6) = ip_forms(ip);
+    match net {
+        IpNet::V4(n4) => i4.map(|i| n4.contains(&i)).unwrap_or(false),
+        IpNet::V6(n6) => n6.contains(&i6),
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeoIp {
     pub ipstr: String,
     pub ip: Option<IpAddr>,
     pub location: Option<(f64, f64)>, // (lat, lon)
+    /// in kilometers
+    pub accuracy_radius: Option<u16>,
     pub in_eu: Option<bool>,
     pub city_name: Option<String>,
     pub country_iso: Option<String>,
@@ -106,6 +272,11 @@ pub struct GeoIp {
     pub continent_code: Option<String>,
     pub asn: Option<u32>,
     pub company: Option<String>,
+    /// ISO 3166-2 code of the largest subdivision, such as `US-CA`
+    pub subdivision: Option<String>,
+    /// threat categories, such as `tor`, when the geolocation provider has them
+    #[serde(default)]
+    pub threat_categories: Vec<String>,
 }
 
 impl GeoIp {
@@ -120,7 +291,8 @@ impl GeoIp {
                 "location",
                 json!({
                     "lat": loc.0,
-                    "lon": loc.1
+                    "lon": loc.1,
+                    "accuracy_radius": self.accuracy_radius
                 }),
             );
         }
@@ -148,6 +320,7 @@ impl GeoIp {
             }),
         );
 
+        out.insert("subdivision", json!(self.subdivision));
         out.insert("asn", json!(self.asn));
         out.insert("company", json!(self.company));
 
@@ -155,7 +328,7 @@ impl GeoIp {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestMeta {
     pub authority: Option<String>,
     pub method: String,
@@ -180,22 +353,159 @@ impl RequestMeta {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RInfo {
     pub meta: RequestMeta,
     pub geoip: GeoIp,
     pub qinfo: QueryInfo,
     pub host: String,
+    /// set for connection upgrade requests, such as websocket handshakes
+    pub is_upgrade: bool,
+    /// the requested protocol, lowercased, for upgrade requests
+    pub upgrade_protocol: Option<String>,
+    /// set when the body is a GraphQL request
+    pub graphql: Option<GraphQlInfo>,
+    /// JA3 or JA4 fingerprint of the TLS client, when supplied by the proxy
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
+    /// set when the decompressed body exceeds the decompression limits, in which case it is not parsed
+    #[serde(default)]
+    pub decompress_bomb: bool,
+    /// set when the body is malformed, or uses an unsupported content-encoding, in which case it is parsed as it is
+    #[serde(default)]
+    pub decompress_failed: bool,
+    /// the W3C trace context of the request, used as the parent of the spans of the checks
+    #[serde(default)]
+    pub traceparent: Option<String>,
+    /// the HTTP version, such as `1.1`, `2` or `3`, when supplied by the proxy
+    #[serde(default)]
+    pub http_version: Option<String>,
+    /// the lowercased protocol negotiated with ALPN, such as `h2`, when supplied by the proxy
+    #[serde(default)]
+    pub alpn: Option<String>,
+    /// the size of the body as it was received, before it is decompressed
+    #[serde(default)]
+    pub body_size: Option<usize>,
+    /// the certificate presented by the client with mutual TLS, None for the unauthenticated connections
+    #[serde(default)]
+    pub client_cert: Option<ClientCert>,
 }
 
-#[derive(Debug, Clone)]
+/// the result of the verification of a client certificate by the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CertVerification {
+    Verified,
+    Failed,
+    /// the status was not supplied, or the proxy did not verify the certificate
+    Unverified,
+}
+
+impl CertVerification {
+    /// parses a verification status, such as the `SUCCESS`, `FAILED:reason` and `NONE` values of nginx
+    pub fn parse(status: &str) -> Self {
+        let status = status.trim().to_lowercase();
+        let word = status.split(':').next().unwrap_or_default();
+        match word {
+            "success" | "verified" | "ok" | "true" => CertVerification::Verified,
+            "failed" | "failure" | "false" => CertVerification::Failed,
+            _ => CertVerification::Unverified,
+        }
+    }
+}
+
+/// a TLS client certificate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCert {
+    /// the distinguished name of the subject, such as `CN=client,O=Org`, or `/O=Org/CN=client`
+    pub subject: String,
+    /// the subject alternative names
+    #[serde(default)]
+    pub sans: Vec<String>,
+    /// the distinguished name of the issuer
+    pub issuer: String,
+    pub fingerprint: Option<String>,
+    pub verification: CertVerification,
+}
+
+/// the value of an attribute of a distinguished name, in the RFC 4514 form or in the slash separated form of OpenSSL
+pub fn dn_attribute<'a>(dn: &'a str, name: &str) -> Option<&'a str> {
+    let separator = if dn.trim_start().starts_with('/') { '/' } else { ',' };
+    dn.split(separator).find_map(|rdn| {
+        let (k, v) = rdn.split_once('=')?;
+        if k.trim().eq_ignore_ascii_case(name) {
+            Some(v.trim()).filter(|v| !v.is_empty())
+        } else {
+            None
+        }
+    })
+}
+
+impl ClientCert {
+    /// the common name of the subject
+    pub fn common_name(&self) -> Option<&str> {
+        dn_attribute(&self.subject, "CN")
+    }
+
+    /// the common name of the issuer
+    pub fn issuer_name(&self) -> Option<&str> {
+        dn_attribute(&self.issuer, "CN")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestInfo {
     pub cookies: RequestField,
     pub headers: RequestField,
     pub rinfo: RInfo,
+    /// the headers, with lowercased names, in the order they were received, including the duplicates
+    ///
+    /// they are sorted by name when the caller only provided a map
+    #[serde(default)]
+    pub header_list: Vec<(String, String)>,
 }
 
 impl RequestInfo {
+    /// the malformed entries of the headers, cookies and arguments, see `RequestField::anomalies`
+    ///
+    /// line breaks are allowed in the arguments, and the unparsed body, that can be binary, is not checked
+    pub fn field_anomalies(&self) -> Vec<(SectionIdx, FieldAnomaly, &str)> {
+        let mut out: Vec<(SectionIdx, FieldAnomaly, &str)> = Vec::new();
+        for (idx, field, crlf) in [
+            (SectionIdx::Headers, &self.headers, true),
+            (SectionIdx::Cookies, &self.cookies, true),
+            (SectionIdx::Args, &self.rinfo.qinfo.args, false),
+        ] {
+            out.extend(
+                field
+                    .anomalies(crlf)
+                    .into_iter()
+                    .filter(|(_, name)| *name != "RAW_BODY" && *name != "body:RAW_BODY")
+                    .map(|(anomaly, name)| (idx, anomaly, name)),
+            );
+        }
+        out
+    }
+
+    /// the number of headers, cookies and arguments, that the evaluation budget is checked against
+    pub fn field_count(&self) -> usize {
+        self.headers.len() + self.cookies.len() + self.rinfo.qinfo.args.len()
+    }
+
+    /// all the values of a header, in order
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.header_list
+            .iter()
+            .filter(move |(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// a CORS preflight request, an OPTIONS request announcing the method of the actual request
+    pub fn is_preflight(&self) -> bool {
+        self.rinfo.meta.method.eq_ignore_ascii_case("OPTIONS")
+            && self.headers.get("access-control-request-method").is_some()
+    }
+
     pub fn into_json(self, tags: Tags) -> serde_json::Value {
         let ipnum: Option<String> = self.rinfo.geoip.ip.as_ref().map(|i| match i {
             IpAddr::V4(a) => u32::from_be_bytes(a.octets()).to_string(),
@@ -210,6 +520,8 @@ impl RequestInfo {
             ("ipnum", ipnum),
             ("authority", Some(self.rinfo.host)),
             ("method", Some(self.rinfo.meta.method)),
+            ("graphql_depth", self.rinfo.graphql.map(|g| g.depth.to_string())),
+            ("graphql_fields", self.rinfo.graphql.map(|g| g.fields.to_string())),
         ]
         .iter()
         .map(|(k, v)| (k.to_string(), v.clone()))
@@ -252,58 +564,42 @@ impl InspectionResult {
     }
 }
 
+/// geolocation data of the address, see `GeoIpProvider`
 pub fn find_geoip(ipstr: String) -> GeoIp {
     let ip = ipstr.parse().ok();
-    fn cty_info(c: &maxminddb::geoip2::model::Country) -> (Option<bool>, Option<String>, Option<String>) {
-        (
-            c.is_in_european_union,
-            c.iso_code.as_ref().map(|s| s.to_lowercase()),
-            c.names.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase()),
-        )
-    }
-    fn cont_info(c: &maxminddb::geoip2::model::Continent) -> (Option<String>, Option<String>) {
-        (
-            c.names.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase()),
-            c.code.clone(),
-        )
-    }
-    let (mcountry_info, mcontinent_info) = match ip.and_then(|i| get_country(i).ok()) {
-        None => (None, None),
-        Some(cty) => (
-            cty.country.as_ref().map(cty_info),
-            cty.continent.as_ref().map(cont_info),
-        ),
-    };
-    let (city_name, location) = match ip.and_then(|i| get_city(i).ok()) {
-        None => (None, None),
-        Some(cty) => (
-            None,
-            cty.location
-                .as_ref() // no applicative functors :(
-                .and_then(|l| l.latitude.and_then(|lat| l.longitude.map(|lon| (lat, lon)))),
-        ),
-    };
-    let (asn, company) = match ip.and_then(|i| get_asn(i).ok()) {
-        None => (None, None),
-        Some(iasn) => (iasn.autonomous_system_number, iasn.autonomous_system_organization),
-    };
-    let (in_eu, country_iso, country_name) = mcountry_info.unwrap_or((None, None, None));
-    let (continent_name, continent_code) = mcontinent_info.unwrap_or((None, None));
+    let data = ip.map(geoip_lookup).unwrap_or_default();
     GeoIp {
         ipstr,
         ip,
-        location,
-        in_eu,
-        city_name,
-        country_iso,
-        country_name,
-        continent_name,
-        continent_code,
-        asn,
-        company,
+        location: data.location,
+        accuracy_radius: data.accuracy_radius,
+        in_eu: data.in_eu,
+        city_name: data.city_name,
+        country_iso: data.country_iso,
+        country_name: data.country_name,
+        continent_name: data.continent_name,
+        continent_code: data.continent_code,
+        asn: data.asn,
+        company: data.company,
+        subdivision: data.subdivision,
+        threat_categories: data.threat_categories,
     }
 }
 
+/// the decompression limits of the `max_decompressed_size` and `max_decompression_ratio` meta entries, invalid values
+/// being ignored
+fn meta_decompression_limits(logs: &mut Logs, meta: &RequestMeta) -> DecompressionLimits {
+    let mut limit = |name: &str| {
+        let value = meta.extra.get(name)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            logs.warning(format!("invalid {} value {}", name, value));
+        }
+        parsed
+    };
+    DecompressionLimits::new(limit("max_decompressed_size"), limit("max_decompression_ratio"))
+}
+
 pub fn map_request(
     logs: &mut Logs,
     ipstr: String,
@@ -312,11 +608,18 @@ pub fn map_request(
     mbody: Option<&[u8]>,
 ) -> Result<RequestInfo, String> {
     logs.debug("map_request starts");
+    let mut header_list: Vec<(String, String)> = headers.iter().map(|(k, v)| (k.to_lowercase(), v.clone())).collect();
+    header_list.sort();
     let (headers, cookies) = map_headers(headers);
     logs.debug("headers mapped");
     let geoip = find_geoip(ipstr);
     logs.debug("geoip computed");
-    let qinfo = map_args(logs, &meta.path, headers.get_str("content-type"), mbody);
+    let limits = meta_decompression_limits(logs, &meta);
+    let inspected = mbody.map(|body| inspected_body(logs, headers.get_str("content-encoding"), body, &limits));
+    let decompress_bomb = matches!(inspected, Some(InspectedBody::TooLarge));
+    let decompress_failed = matches!(inspected, Some(InspectedBody::Failed(_)));
+    let body = inspected.as_ref().and_then(InspectedBody::body);
+    let qinfo = map_args(logs, &meta.path, headers.get_str("content-type"), body);
     logs.debug("args mapped");
 
     let host = match meta.authority.as_ref().or_else(|| headers.get("host")) {
@@ -326,17 +629,31 @@ pub fn map_request(
 
     // TODO : parse body
 
+    let upgrade_protocol = upgrade_protocol(&headers);
+    let graphql = body.and_then(|body| graphql_info(headers.get_str("content-type"), body));
     let rinfo = RInfo {
         meta,
         geoip,
         qinfo,
         host,
+        is_upgrade: upgrade_protocol.is_some(),
+        upgrade_protocol,
+        graphql,
+        tls_fingerprint: None,
+        decompress_bomb,
+        decompress_failed,
+        traceparent: headers.get("traceparent").cloned(),
+        http_version: None,
+        alpn: None,
+        body_size: mbody.map(|body| body.len()),
+        client_cert: None,
     };
 
     Ok(RequestInfo {
         cookies,
         headers,
         rinfo,
+        header_list,
     })
 }
 
@@ -363,6 +680,8 @@ fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector) -> Option<Selec
         RequestSelector::Authority => Some(Selected::Str(&reqinfo.rinfo.host)),
         RequestSelector::Company => reqinfo.rinfo.geoip.company.as_ref().map(Selected::Str),
         RequestSelector::Asn => reqinfo.rinfo.geoip.asn.map(Selected::U32),
+        RequestSelector::GraphqlDepth => reqinfo.rinfo.graphql.map(|g| Selected::U32(g.depth as u32)),
+        RequestSelector::GraphqlFields => reqinfo.rinfo.graphql.map(|g| Selected::U32(g.fields as u32)),
     }
 }
 
@@ -384,10 +703,133 @@ pub fn check_selector_cond(reqinfo: &RequestInfo, tags: &Tags, sel: &RequestSele
     }
 }
 
+/// the number of accesses to an `ExpiringMap` between two sweeps of all its expired entries
+pub const SWEEP_INTERVAL: usize = 1024;
+
+/// the counters of the in-memory flow and limit stores, along with their expiry
+///
+/// An expired counter is removed when its key is accessed, and the other expired counters every `SWEEP_INTERVAL`
+/// accesses, so that the keys that are never accessed again do not accumulate.
+#[derive(Debug)]
+pub struct ExpiringMap<V> {
+    entries: HashMap<String, (V, Instant)>,
+    /// accesses since the last sweep
+    ops: usize,
+}
+
+impl<V> Default for ExpiringMap<V> {
+    fn default() -> Self {
+        ExpiringMap {
+            entries: HashMap::new(),
+            ops: 0,
+        }
+    }
+}
+
+impl<V> ExpiringMap<V> {
+    /// the entries, once the entry of `key` is removed if it expired, along with the current time
+    pub fn access(&mut self, key: &str) -> (&mut HashMap<String, (V, Instant)>, Instant) {
+        let now = Instant::now();
+        self.ops += 1;
+        if self.ops >= SWEEP_INTERVAL {
+            self.ops = 0;
+            self.entries.retain(|_, (_, expiry)| *expiry > now);
+        } else if matches!(self.entries.get(key), Some((_, expiry)) if *expiry <= now) {
+            self.entries.remove(key);
+        }
+        (&mut self.entries, now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn expiring_map_sweep() {
+        let mut map: ExpiringMap<u64> = ExpiringMap::default();
+        let (entries, now) = map.access("live");
+        for i in 0..100 {
+            entries.insert(format!("expired-{}", i), (1, now));
+        }
+        entries.insert("live".to_string(), (1, now + std::time::Duration::from_secs(60)));
+        // expired entries are only removed when they are accessed, until the next sweep
+        assert!(map.access("expired-0").0.get("expired-0").is_none());
+        assert_eq!(map.len(), 100);
+        for _ in 0..SWEEP_INTERVAL {
+            map.access("live");
+        }
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.access("live").0.get("live").map(|(v, _)| *v), Some(1));
+    }
+
+    #[test]
+    fn distinguished_names() {
+        assert_eq!(dn_attribute("CN=client,O=Org", "CN"), Some("client"));
+        assert_eq!(dn_attribute("/C=US/O=Org/CN=client", "cn"), Some("client"));
+        assert_eq!(dn_attribute("O=Org", "CN"), None);
+        assert_eq!(CertVerification::parse("SUCCESS"), CertVerification::Verified);
+        assert_eq!(
+            CertVerification::parse("FAILED:unable to get issuer"),
+            CertVerification::Failed
+        );
+        assert_eq!(CertVerification::parse("NONE"), CertVerification::Unverified);
+    }
+
+    #[test]
+    fn cookie_parsing() {
+        let pairs = parse_cookie_header;
+        let owned = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+            v.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        assert_eq!(pairs("a=1; b=2;c=3"), owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+        assert_eq!(
+            pairs("sid=\"a=b;c\"; theme=dark"),
+            owned(&[("sid", "a=b;c"), ("theme", "dark")])
+        );
+        assert_eq!(pairs("q=\"say \\\"hi\\\";\""), owned(&[("q", "say \"hi\";")]));
+        assert_eq!(
+            pairs("__Host-sid=1; __Secure-token=2"),
+            owned(&[("__Host-sid", "1"), ("__Secure-token", "2")])
+        );
+        // data after the closing quote is kept
+        assert_eq!(pairs("a=\"x\"<script>; b=1"), owned(&[("a", "x<script>"), ("b", "1")]));
+
+        // malformed headers
+        assert_eq!(
+            pairs("a=\"unterminated; b=2"),
+            owned(&[("a", "\"unterminated"), ("b", "2")])
+        );
+        assert_eq!(
+            pairs(";;  ; a ; =v; b==c ;"),
+            owned(&[("a", ""), ("", "v"), ("b", "=c")])
+        );
+        assert_eq!(pairs("a=\"\\"), owned(&[("a", "\"\\")]));
+        assert_eq!(pairs("é=ü; \u{0}=\u{7f}"), owned(&[("é", "ü"), ("\u{0}", "\u{7f}")]));
+        for bad in &["", "=", "\"", "a=\"", "a=\";", ";\";\"", "\\", "a=\"\\\"", "=\"\"="] {
+            parse_cookie_header(bad);
+        }
+
+        let cookies = cookie_map(&["a=1; b=2; a=3"], CookieDuplicates::Last);
+        assert_eq!(cookies.get_str("a"), Some("3"));
+        let cookies = cookie_map(&["a=1; b=2", "a=3"], CookieDuplicates::All);
+        assert_eq!(cookies.get_str("a"), Some("1 3"));
+
+        let mut headers = HashMap::new();
+        headers.insert("Cookie".to_string(), "a=1".to_string());
+        let (headers, cookies) = map_headers(headers);
+        assert_eq!(cookies.get_str("a"), Some("1"));
+        assert!(headers.get("cookie").is_none());
+    }
+
     #[test]
     fn test_map_args_full() {
         let mut logs = Logs::default();
@@ -424,4 +866,63 @@ mod tests {
 
         assert_eq!(qinfo.args, RequestField::default());
     }
+
+    #[test]
+    fn map_request_decompression() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut e = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(&[b' '; 100_000]).unwrap();
+        let body = e.finish().unwrap();
+        let headers: HashMap<String, String> =
+            std::iter::once(("content-encoding".to_string(), "gzip".to_string())).collect();
+        let map = |extra: &[(&str, &str)], body: &[u8]| {
+            let mut meta: HashMap<String, String> = extra.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            meta.insert("method".to_string(), "POST".to_string());
+            meta.insert("path".to_string(), "/".to_string());
+            let meta = RequestMeta::from_map(meta).unwrap();
+            map_request(
+                &mut Logs::default(),
+                "1.2.3.4".to_string(),
+                headers.clone(),
+                meta,
+                Some(body),
+            )
+            .unwrap()
+        };
+
+        let rinfo = map(&[], &body);
+        assert!(!rinfo.rinfo.decompress_bomb);
+        assert!(!rinfo.rinfo.decompress_failed);
+        let rinfo = map(&[("max_decompressed_size", "50000")], &body);
+        assert!(rinfo.rinfo.decompress_bomb);
+        let rinfo = map(&[("max_decompression_ratio", "10")], &body);
+        assert!(rinfo.rinfo.decompress_bomb);
+        // invalid values are ignored
+        let rinfo = map(&[("max_decompressed_size", "small")], &body);
+        assert!(!rinfo.rinfo.decompress_bomb);
+
+        let rinfo = map(&[], b"not compressed");
+        assert!(rinfo.rinfo.decompress_failed);
+        assert_eq!(rinfo.rinfo.qinfo.args.get_str("RAW_BODY"), Some("not compressed"));
+    }
+
+    #[test]
+    fn test_upgrade_protocol() {
+        let mk = |hdrs: &[(&str, &str)]| {
+            let headers: RequestField = hdrs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            upgrade_protocol(&headers)
+        };
+        assert_eq!(
+            mk(&[("connection", "keep-alive, Upgrade"), ("upgrade", "WebSocket")]),
+            Some("websocket".to_string())
+        );
+        assert_eq!(
+            mk(&[("connection", "upgrade"), ("upgrade", "h2c, foo")]),
+            Some("h2c".to_string())
+        );
+        assert_eq!(mk(&[("connection", "keep-alive"), ("upgrade", "websocket")]), None);
+        assert_eq!(mk(&[("upgrade", "websocket")]), None);
+        assert_eq!(mk(&[("connection", "upgrade")]), None);
+    }
 }
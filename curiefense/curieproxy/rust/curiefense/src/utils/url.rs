@@ -1,5 +1,10 @@
 use crate::requestfields::RequestField;
 use itertools::Itertools;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// default nesting depth of the structured query parameters, see `parse_structured_params`
+pub const DEFAULT_MAX_ARG_DEPTH: usize = 4;
 
 #[inline]
 fn from_hex_digit(digit: u8) -> Option<u8> {
@@ -60,6 +65,80 @@ pub fn urldecode_str(input: &str) -> String {
     String::from_utf8_lossy(&urldecode(input)).into_owned()
 }
 
+/// decodes an url encoded string up to `max_passes` times, for values that were encoded several times
+///
+/// Stops as soon as a pass does not change the value.
+pub fn urldecode_repeated(input: &str, max_passes: usize) -> Vec<u8> {
+    let mut out = input.as_bytes().to_vec();
+    for _ in 0..max_passes {
+        if !out.contains(&b'%') {
+            break;
+        }
+        let decoded = urldecode_bytes(&out);
+        if decoded == out {
+            break;
+        }
+        out = decoded;
+    }
+    out
+}
+
+/// replaces the overlong UTF-8 encodings of ASCII characters, such as `C0 AE` for `.`, with the characters
+pub fn decode_overlong_utf8(input: &[u8]) -> Vec<u8> {
+    let is_cont = |b: u8| b & 0xc0 == 0x80;
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i..] {
+            [b0, b1, ..] if (b0 == 0xc0 || b0 == 0xc1) && is_cont(b1) => {
+                out.push(((b0 & 0x1f) << 6) | (b1 & 0x3f));
+                i += 2;
+            }
+            [0xe0, b1, b2, ..] if b1 & 0xfe == 0x80 && is_cont(b2) => {
+                out.push(((b1 & 0x01) << 6) | (b2 & 0x3f));
+                i += 3;
+            }
+            _ => {
+                out.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// url encodes a decoded path, keeping the characters that are allowed in a path segment, and the `/` separator
+pub fn urlencode_path(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'!'
+            | b'$'
+            | b'&'
+            | b'\''
+            | b'('
+            | b')'
+            | b'*'
+            | b'+'
+            | b','
+            | b';'
+            | b'='
+            | b':'
+            | b'@'
+            | b'/' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
 /// parses query parameters, that look like a=b&c=d
 pub fn parse_urlencoded_params(args: &mut RequestField, query: &str) {
     for kv in query.split('&') {
@@ -71,6 +150,92 @@ pub fn parse_urlencoded_params(args: &mut RequestField, query: &str) {
     }
 }
 
+/// splits a bracket notation name, such as `filter[status][]`, into its segments
+///
+/// returns `None` when the name has no brackets, or is malformed
+fn bracket_segments(name: &str) -> Option<Vec<&str>> {
+    let open = name.find('[')?;
+    if open == 0 {
+        return None;
+    }
+    let mut segments = vec![&name[..open]];
+    let mut rest = &name[open..];
+    while !rest.is_empty() {
+        let inner = rest.strip_prefix('[')?;
+        let close = inner.find(']')?;
+        segments.push(&inner[..close]);
+        rest = &inner[close + 1..];
+    }
+    Some(segments)
+}
+
+/// adds the scalar values of a json value, the structures deeper than `max_depth` being kept as json
+fn add_json_values(args: &mut RequestField, name: String, value: Value, depth: usize, max_depth: usize) {
+    match value {
+        Value::Array(array) if depth < max_depth => {
+            for (i, v) in array.into_iter().enumerate() {
+                add_json_values(args, format!("{}.{}", name, i), v, depth + 1, max_depth);
+            }
+        }
+        Value::Object(mp) if depth < max_depth => {
+            for (k, v) in mp.into_iter() {
+                add_json_values(args, format!("{}.{}", name, k), v, depth + 1, max_depth);
+            }
+        }
+        Value::String(s) => args.add(name, s),
+        other => args.add(name, other.to_string()),
+    }
+}
+
+/// adds the nested values of the structured query parameters, as individual arguments
+///
+/// Bracket notation names, such as `filter[status][]`, become dotted names, the empty brackets being numbered in
+/// order (`filter.status.0`), and values that are json objects or arrays are expanded the same way. The raw
+/// parameters are kept, and the names nested deeper than `max_depth` are not expanded, 0 disabling the expansion.
+pub fn parse_structured_params(args: &mut RequestField, query: &str, max_depth: usize) {
+    if max_depth == 0 {
+        return;
+    }
+    let mut next_index: HashMap<String, usize> = HashMap::new();
+    for kv in query.split('&') {
+        let (k, v) = match kv.splitn(2, '=').collect_tuple() {
+            Some((k, v)) => (urldecode_str(k), urldecode_str(v)),
+            None => (urldecode_str(kv), String::new()),
+        };
+        let bracket_name = match bracket_segments(&k) {
+            Some(segments) if segments.len() - 1 <= max_depth => {
+                let mut name = segments[0].to_string();
+                for segment in &segments[1..] {
+                    name.push('.');
+                    if segment.is_empty() {
+                        let idx = next_index.entry(name.clone()).or_insert(0);
+                        name += &idx.to_string();
+                        *idx += 1;
+                    } else {
+                        name += segment;
+                    }
+                }
+                Some((name, segments.len() - 1))
+            }
+            _ => None,
+        };
+        let trimmed = v.trim_start();
+        let json = if trimmed.starts_with('{') || trimmed.starts_with('[') {
+            serde_json::from_str::<Value>(&v).ok()
+        } else {
+            None
+        };
+        match (bracket_name, json) {
+            (Some((name, depth)), Some(value)) if depth < max_depth => {
+                add_json_values(args, name, value, depth, max_depth)
+            }
+            (Some((name, _)), _) => args.add(name, v),
+            (None, Some(value)) => add_json_values(args, k, value, 0, max_depth),
+            (None, None) => (),
+        }
+    }
+}
+
 fn urldecode_bytes_str(input: &[u8]) -> String {
     String::from_utf8_lossy(&urldecode_bytes(input)).into_owned()
 }
@@ -88,7 +253,8 @@ pub fn parse_urlencoded_params_bytes(args: &mut RequestField, query: &[u8]) {
 
 #[cfg(test)]
 mod test_lib {
-    use super::urldecode_str;
+    use super::{decode_overlong_utf8, parse_structured_params, urldecode_repeated, urldecode_str, urlencode_path};
+    use crate::requestfields::RequestField;
 
     #[test]
     fn test_urldecode_normal() {
@@ -119,4 +285,67 @@ mod test_lib {
         assert!(urldecode_str("%F0%9F%91%BE%20Exterminate%21%") == "👾 Exterminate!%");
         assert!(urldecode_str("%F0%9F%BE%20%21%") == "� !%");
     }
+
+    #[test]
+    fn test_urldecode_repeated() {
+        assert_eq!(urldecode_repeated("%252e%252e%252f", 2), b"../");
+        assert_eq!(urldecode_repeated("%252e%252e%252f", 1), b"%2e%2e%2f");
+        assert_eq!(urldecode_repeated("%25252e", 5), b".");
+        assert_eq!(urldecode_repeated("100%", 3), b"100%");
+    }
+
+    #[test]
+    fn test_decode_overlong_utf8() {
+        assert_eq!(decode_overlong_utf8(b"\xc0\xae\xc0\xae\xc0\xaf"), b"../");
+        assert_eq!(decode_overlong_utf8(b"\xe0\x80\xae"), b".");
+        // regular multibyte characters are kept
+        assert_eq!(decode_overlong_utf8("é/👾".as_bytes()), "é/👾".as_bytes());
+    }
+
+    #[test]
+    fn test_urlencode_path() {
+        assert_eq!(urlencode_path("/a/b"), "/a/b");
+        assert_eq!(urlencode_path("/a b"), "/a%20b");
+        assert_eq!(urlencode_path("/a%2Fb"), "/a%252Fb");
+        assert_eq!(urlencode_path("/é"), "/%C3%A9");
+        assert_eq!(urlencode_path("/a?b#c"), "/a%3Fb%23c");
+    }
+
+    #[test]
+    fn test_urlencode_path_roundtrip() {
+        for path in &["/a%2Fb", "/a b/c d", "/👾/é/ü", "/a+b;c=d@e", "/%"] {
+            assert_eq!(&urldecode_str(&urlencode_path(path)), path);
+        }
+    }
+
+    #[test]
+    fn test_structured_params() {
+        let parse = |query: &str, max_depth: usize| {
+            let mut args = RequestField::default();
+            parse_structured_params(&mut args, query, max_depth);
+            let mut out: Vec<(String, String)> = args.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            out.sort();
+            out
+        };
+        let owned = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+            v.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        assert_eq!(
+            parse("filter[status][]=open&filter[status][]=closed&page=2", 4),
+            owned(&[("filter.status.0", "open"), ("filter.status.1", "closed")])
+        );
+        assert_eq!(
+            parse("q=%7B%22a%22%3A%5B1%2C%7B%22b%22%3Atrue%7D%5D%7D&f[x]=%5B%22y%22%5D", 4),
+            owned(&[("f.x.0", "y"), ("q.a.0", "1"), ("q.a.1.b", "true")])
+        );
+        // the deeper structures are kept as they are
+        assert_eq!(parse("a[b][c]=1", 1), vec![]);
+        assert_eq!(
+            parse("q=%7B%22a%22%3A%7B%22b%22%3A1%7D%7D", 1),
+            owned(&[("q.a", "{\"b\":1}")])
+        );
+        // malformed brackets, invalid json, and a disabled expansion
+        assert_eq!(parse("a[b=1&[c]=2&d=%5Bx", 4), vec![]);
+        assert_eq!(parse("filter[status][]=open", 0), vec![]);
+    }
 }
@@ -0,0 +1,74 @@
+//! `max_sessions` is global to the process: these tests are run in their own binary, so that the limit does not
+//! affect the sessions of the unit tests, and one at a time
+use std::sync::Mutex;
+use std::time::Duration;
+
+use curiefense::session::{
+    clean_all_sessions, session_capacity_stats, session_init, session_serialize_request_map, set_max_sessions,
+    EvictionPolicy, SessionError,
+};
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn mk_request_map() -> String {
+    serde_json::json!({
+        "headers": {"host": "www.example.com", "user-agent": "curl/7.68.0"},
+        "cookies": {},
+        "args": {},
+        "attrs": {
+            "path": "/",
+            "method": "GET",
+            "ip": "127.0.0.1",
+            "query": "",
+            "authority": null,
+            "uri": "/",
+            "tags": {}
+        }
+    })
+    .to_string()
+}
+
+#[test]
+fn reject_over_capacity() {
+    let _serial = SERIAL.lock().unwrap_or_else(|rr| rr.into_inner());
+    clean_all_sessions().unwrap();
+    set_max_sessions(Some(2), EvictionPolicy::Reject).unwrap();
+    let first = session_init(&mk_request_map()).unwrap();
+    session_init(&mk_request_map()).unwrap();
+    assert!(matches!(
+        session_init(&mk_request_map()),
+        Err(SessionError::Capacity(2))
+    ));
+    // the live sessions are kept
+    assert!(session_serialize_request_map(&first).is_ok());
+    let stats = session_capacity_stats().unwrap();
+    assert_eq!(stats.sessions, 2);
+    assert_eq!(stats.rejected, 1);
+    set_max_sessions(None, EvictionPolicy::Reject).unwrap();
+    clean_all_sessions().unwrap();
+    assert_eq!(session_capacity_stats().unwrap().sessions, 0);
+}
+
+#[test]
+fn evict_oldest() {
+    let _serial = SERIAL.lock().unwrap_or_else(|rr| rr.into_inner());
+    clean_all_sessions().unwrap();
+    set_max_sessions(Some(2), EvictionPolicy::EvictOldest).unwrap();
+    let oldest = session_init(&mk_request_map()).unwrap();
+    // sessions created within the same instant are evicted in an arbitrary order
+    std::thread::sleep(Duration::from_millis(2));
+    let second = session_init(&mk_request_map()).unwrap();
+    std::thread::sleep(Duration::from_millis(2));
+    let newest = session_init(&mk_request_map()).unwrap();
+    assert!(matches!(
+        session_serialize_request_map(&oldest),
+        Err(SessionError::UnknownSession)
+    ));
+    assert!(session_serialize_request_map(&second).is_ok());
+    assert!(session_serialize_request_map(&newest).is_ok());
+    let stats = session_capacity_stats().unwrap();
+    assert_eq!(stats.sessions, 2);
+    assert_eq!(stats.evicted, 1);
+    set_max_sessions(None, EvictionPolicy::Reject).unwrap();
+    clean_all_sessions().unwrap();
+}